@@ -11,7 +11,7 @@ use futures::executor::block_on;
 use db::cmdscript::Command;
 
 fn main() -> Result<()> {
-    Ok(block_on(run())?)
+    block_on(run())
 }
 
 async fn run() -> Result<()> {
@@ -0,0 +1,46 @@
+//! Small files replaced whole.
+//!
+//! A file is replaced by writing a temporary file beside it
+//! and renaming it over the old one,
+//! so after a crash it holds either the old or the new contents.
+
+use anyhow::Result;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub fn replace(path: &Path, bytes: &[u8]) -> Result<()> {
+    let temp_path = temp_path(path);
+
+    // FIXME async
+    let mut file = File::create(&temp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    if cfg!(unix) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Reads a file, or returns `None` if it doesn't exist.
+pub fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+    // FIXME async
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = OsString::from(path.as_os_str());
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
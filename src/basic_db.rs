@@ -1,16 +1,25 @@
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use futures::lock::{Mutex, MutexGuard};
+use futures::future::{self, BoxFuture, FutureExt};
 use std::sync::Arc;
 use std::collections::BTreeMap;
+use std::ops::Range;
 use std::path::PathBuf;
-use crate::tree::{self, Tree};
-use anyhow::{Result, Context, anyhow};
-use crate::types::{Batch, BatchCommit, Commit, Key, Value};
+use crate::tree::{self, Tree, TreeConfig};
+use crate::index::{self, Lookup};
+use anyhow::{Result, Context, anyhow, bail};
+use crate::types::{Address, Batch, BatchCommit, Commit, Key, Value};
 use crate::commit_log::{CommitLog, CommitCommand};
 use crate::command::Command;
+use crate::durability::Durability;
 use crate::log::Log;
 use crate::loader;
+use crate::error::{self, DbError};
+use crate::epoch::{Epochs, EpochGuard};
+use crate::compaction::{CompactionMark, CompactionPolicy};
+use crate::stats::{CollapseReport, CompactionStats, RecoverySummary, Stats, StatsCollector, TreeStats, TreeStorageStats};
 use std::fmt;
+use std::time::{Duration, Instant};
 
 pub struct Db {
     initialized: AtomicBool,
@@ -18,37 +27,115 @@ pub struct Db {
     next_batch_commit: Arc<AtomicU64>,
     next_commit: Arc<AtomicU64>,
     view_commit_limit: Arc<AtomicU64>,
-    commit_lock: Arc<Mutex<()>>,
-    trees: Arc<BTreeMap<String, Tree>>,
+    /// One past the last commit known to be durable
+    durable_commit_limit: AtomicU64,
+    commit_lock: Arc<Mutex<Option<PendingCommit>>>,
+    trees: SharedTrees,
     commit_log: Arc<CommitLog>,
+    epochs: Epochs,
+    stats: Arc<StatsCollector>,
 }
 
+/// The trees, replaced rather than changed when a tree is created,
+/// so views and batches keep the trees they began with.
+type SharedTrees = Arc<std::sync::RwLock<Arc<BTreeMap<String, Arc<Tree>>>>>;
+
 pub struct BatchWriter {
     batch: Batch,
     batch_writers: BTreeMap<String, tree::BatchWriter>,
+    has_writes: AtomicBool,
     next_batch_commit: Arc<AtomicU64>,
     next_commit: Arc<AtomicU64>,
     view_commit_limit: Arc<AtomicU64>,
-    commit_lock: Arc<Mutex<()>>,
+    commit_lock: Arc<Mutex<Option<PendingCommit>>>,
     commit_log: Arc<CommitLog>,
+    epochs: Epochs,
+    stats: Arc<StatsCollector>,
+    trees: SharedTrees,
+    /// The trees when the batch began,
+    /// kept from being dropped while it is alive
+    batch_trees: Arc<BTreeMap<String, Arc<Tree>>>,
+    /// Keys whose committed value the batch depends on,
+    /// with the version it read.
+    reads: std::sync::Mutex<Vec<(String, Key, Option<Lookup>)>>,
+    /// With conflict detection, the commit limit the batch read at,
+    /// and the keys it has written.
+    conflict_check: std::sync::Mutex<Option<ConflictCheck>>,
 }
 
+/// The commit limit a batch read at, and the keys it has written.
+type ConflictCheck = (Commit, Vec<(String, Key)>);
+
 #[derive(Clone)]
 pub struct ViewReader {
     commit_limit: Commit,
-    trees: Arc<BTreeMap<String, Tree>>,
+    trees: Arc<BTreeMap<String, Arc<Tree>>>,
+    /// Also pins the view's commit limit
+    epoch: Arc<EpochGuard>,
 }
 
 pub struct Cursor {
     tree_cursor: tree::Cursor,
+    /// Keeps the tree from being dropped
+    _trees: Arc<BTreeMap<String, Arc<Tree>>>,
+    _epoch: Arc<EpochGuard>,
+}
+
+/// The commit lock, held so that nothing commits meanwhile.
+pub struct CommitGuard<'batch>(MutexGuard<'batch, Option<PendingCommit>>);
+
+impl Drop for CommitGuard<'_> {
+    fn drop(&mut self) {
+        // A commit cancelled after its write finished
+        // becomes visible now, rather than with the next commit.
+        try_finish_pending_commit(&mut self.0);
+    }
+}
+
+/// A master commit that has been issued but not yet applied.
+///
+/// This lives under the commit lock while the master commit is written,
+/// so if the committing future is cancelled the commit is finished
+/// on its behalf: when it is dropped or a view is taken, if the write is done,
+/// or else by the next holder of the commit lock.
+pub struct PendingCommit {
+    batch: Batch,
+    batch_commit: BatchCommit,
+    commit: Commit,
+    write: BoxFuture<'static, Result<()>>,
+    batch_writers: BTreeMap<String, tree::BatchWriter>,
+    /// Every tree, including any created since the batch began
+    trees: Arc<BTreeMap<String, Arc<Tree>>>,
+    /// For a commit that replaces a tree, how to put `trees` in place
+    replacing: Option<Replacing>,
+    next_commit: Arc<AtomicU64>,
+    view_commit_limit: Arc<AtomicU64>,
+    epochs: Epochs,
+    stats: Arc<StatsCollector>,
+}
+
+/// A tree being replaced, and its replacement.
+struct Replacing {
+    shared_trees: SharedTrees,
+    old_tree: Arc<Tree>,
+    new_tree: Arc<Tree>,
 }
 
 impl Db {
     pub fn new(tree_logs: BTreeMap<String, Log<Command>>, commit_log: Log<CommitCommand>) -> Db {
+        Db::with_tree_configs(tree_logs, commit_log, BTreeMap::new())
+    }
+
+    /// Trees missing from `tree_configs` get the default configuration.
+    pub fn with_tree_configs(tree_logs: BTreeMap<String, Log<Command>>,
+                             commit_log: Log<CommitCommand>,
+                             mut tree_configs: BTreeMap<String, TreeConfig>) -> Db {
         let trees = tree_logs.into_iter().map(|(tree_name, log)| {
-            (tree_name, Tree::new(log))
-        }).collect();
-        let trees = Arc::new(trees);
+            let config = tree_configs.remove(&tree_name).unwrap_or_default();
+            (tree_name, Arc::new(Tree::new(log, config)))
+        }).collect::<BTreeMap<_, _>>();
+        let stats = Arc::new(StatsCollector::new(trees.len()));
+        let trees = Arc::new(std::sync::RwLock::new(Arc::new(trees)));
 
         let commit_log = Arc::new(CommitLog::new(commit_log));
 
@@ -58,16 +145,25 @@ impl Db {
             next_batch_commit: Arc::new(AtomicU64::new(0)),
             next_commit: Arc::new(AtomicU64::new(0)),
             view_commit_limit: Arc::new(AtomicU64::new(0)),
-            commit_lock: Arc::new(Mutex::new(())),
+            durable_commit_limit: AtomicU64::new(0),
+            commit_lock: Arc::new(Mutex::new(None)),
             trees,
             commit_log,
+            epochs: Epochs::new(),
+            stats,
         }
     }
 
-    pub async fn init(&self) -> Result<()> {
+    pub async fn init(&self) -> Result<RecoverySummary> {
+        self.init_concurrently(loader::default_concurrency()).await
+    }
+
+    /// Initializes, replaying at most `recovery_concurrency` trees at once.
+    pub async fn init_concurrently(&self, recovery_concurrency: usize) -> Result<RecoverySummary> {
         assert!(!self.initialized.load(Ordering::SeqCst));
 
-        let init_state = loader::load(&self.commit_log, &self.trees).await?;
+        let start = Instant::now();
+        let init_state = loader::load(&self.commit_log, &self.trees(), recovery_concurrency).await?;
         log::trace!("init state {:?}", init_state);
 
         let view_commit_limit = init_state.next_commit.0;
@@ -79,48 +175,405 @@ impl Db {
         
         self.initialized.store(true, Ordering::SeqCst);
 
-        Ok(())
+        Ok(RecoverySummary {
+            batches_applied: init_state.batches_applied,
+            batches_aborted: init_state.batches_aborted,
+            duration: start.elapsed(),
+        })
     }
 
     pub fn batch(&self) -> BatchWriter {
         assert!(self.initialized.load(Ordering::SeqCst));
 
+        // Numbered under the lock, so a created tree is in
+        // every batch numbered after the one it starts at.
+        let trees = self.trees.read().expect("lock");
         let batch = Batch(self.next_batch.fetch_add(1, Ordering::SeqCst));
-        assert_ne!(batch.0, u64::max_value());
+        assert_ne!(batch.0, u64::MAX);
 
-        let batch_writers = self.trees.iter().map(|(name, tree)| {
+        let batch_writers = trees.iter().map(|(name, tree)| {
             (name.clone(), tree.batch(batch))
         }).collect();
+        let batch_trees = trees.clone();
+        drop(trees);
 
         BatchWriter {
             batch,
             batch_writers,
+            has_writes: AtomicBool::new(false),
+            reads: std::sync::Mutex::new(vec![]),
+            conflict_check: std::sync::Mutex::new(None),
             next_batch_commit: self.next_batch_commit.clone(),
             next_commit: self.next_commit.clone(),
             view_commit_limit: self.view_commit_limit.clone(),
             commit_lock: self.commit_lock.clone(),
             commit_log: self.commit_log.clone(),
+            epochs: self.epochs.clone(),
+            stats: self.stats.clone(),
+            trees: self.trees.clone(),
+            batch_trees,
         }
     }
 
     pub fn view(&self) -> ViewReader {
         assert!(self.initialized.load(Ordering::SeqCst));
 
-        let commit_limit = Commit(self.view_commit_limit.load(Ordering::SeqCst));
+        // Let readers see a cancelled commit once its write is done
+        if let Some(mut commit_lock) = self.commit_lock.try_lock() {
+            try_finish_pending_commit(&mut commit_lock);
+        }
+
+        // Under the lock the trees are as of the commit limit,
+        // even as a commit replaces one of them
+        let trees = self.trees.read().expect("lock");
+        let epoch = self.epochs.enter_pinned(|| {
+            Commit(self.view_commit_limit.load(Ordering::SeqCst))
+        });
+        let trees = trees.clone();
 
         ViewReader {
+            commit_limit: epoch.commit_limit().expect("pinned"),
+            trees,
+            epoch: Arc::new(epoch),
+        }
+    }
+
+    /// Adds a tree with an empty log to the initialized database.
+    ///
+    /// Batches begun before this don't write to it.
+    pub async fn create_tree(&self, name: &str, log: Log<Command>, config: TreeConfig) -> Result<()> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let tree = Arc::new(Tree::new(log, config));
+        tree.skip_init();
+        let starting = tree.starting();
+        let _starting = starting.try_lock().expect("new tree");
+
+        let batch = {
+            // Under the commit lock no commit is applied
+            // without advancing the new tree's index
+            let mut commit_lock = self.commit_lock.lock().await;
+            finish_cancelled_commit(&mut commit_lock).await;
+            tree.advance_to(Commit(self.next_commit.load(Ordering::SeqCst)));
+
+            let mut trees = self.trees.write().expect("lock");
+            if trees.contains_key(name) {
+                bail!("tree {:?} already exists", name);
+            }
+            // Every batch numbered after this one writes to the tree
+            let batch = Batch(self.next_batch.fetch_add(1, Ordering::SeqCst));
+            assert_ne!(batch.0, u64::MAX);
+            let mut new_trees = (**trees).clone();
+            new_trees.insert(name.to_string(), tree.clone());
+            *trees = Arc::new(new_trees);
+            self.stats.add_tree();
+            batch
+        };
+
+        // Later batches wait for this to open the tree,
+        // so it is the tree's first batch when replayed.
+        tree.start_at_batch(batch).await
+    }
+
+    /// Removes a tree from the initialized database.
+    ///
+    /// Views, cursors and batches that can reach the tree keep it,
+    /// though batches fail to commit,
+    /// and its log is deleted once the last of them is gone.
+    pub async fn drop_tree(&self, name: &str) -> Result<()> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        // Under the commit lock no pending commit holds the trees
+        let mut commit_lock = self.commit_lock.lock().await;
+        finish_cancelled_commit(&mut commit_lock).await;
+
+        let mut trees = self.trees.write().expect("lock");
+        get_tree(&trees, name)?.set_remove_log_on_drop(true);
+        let mut new_trees = (**trees).clone();
+        new_trees.remove(name);
+        *trees = Arc::new(new_trees);
+
+        Ok(())
+    }
+
+    /// Replaces a tree of the initialized database
+    /// with one whose log holds only `entries`,
+    /// committed durably as a batch of its own.
+    ///
+    /// The new log must be empty.
+    /// Views begun before the commit keep reading the old tree,
+    /// whose log is deleted once nothing uses it,
+    /// and batches begun before it fail to commit.
+    /// Other commits wait until this is done.
+    pub async fn replace_tree(&self, name: &str, log: Log<Command>, config: TreeConfig,
+                              entries: impl Iterator<Item = (Key, Value)>) -> Result<Commit> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let tree = Arc::new(Tree::new(log, config));
+        tree.skip_init();
+        // Until it is committed the replacement is discarded with its log
+        tree.set_remove_log_on_drop(true);
+
+        // Under the commit lock no batch numbered after the replacing one
+        // commits before it, so the new log takes part in no earlier commit.
+        let mut commit_lock = self.commit_lock.lock().await;
+        finish_cancelled_commit(&mut commit_lock).await;
+
+        let commit = Commit(self.next_commit.load(Ordering::SeqCst));
+        assert_ne!(commit.0, u64::MAX);
+        tree.advance_to(commit);
+
+        let (trees, old_tree, batch) = {
+            let trees = self.trees.read().expect("lock");
+            let old_tree = get_tree(&trees, name)?.clone();
+            let batch = Batch(self.next_batch.fetch_add(1, Ordering::SeqCst));
+            assert_ne!(batch.0, u64::MAX);
+            (trees.clone(), old_tree, batch)
+        };
+        let mut new_trees = (*trees).clone();
+        new_trees.insert(name.to_string(), tree.clone());
+
+        // Every tree takes part in the batch, as in any other
+        let batch_writers: BTreeMap<String, tree::BatchWriter> = new_trees.iter()
+            .map(|(name, tree)| (name.clone(), tree.batch(batch)))
+            .collect();
+        let batch_commit = BatchCommit(self.next_batch_commit.fetch_add(1, Ordering::SeqCst));
+        assert_ne!(batch_commit.0, u64::MAX);
+
+        let r: Result<()> = async {
+            for writer in batch_writers.values() {
+                writer.wait_started().await;
+                writer.open().await?;
+            }
+            let writer = &batch_writers[name];
+            for (key, value) in entries {
+                writer.write(key, value).await?;
+            }
+            for writer in batch_writers.values() {
+                writer.ready_commit(batch_commit).await.map_err(error::classify)?;
+            }
+            Ok(())
+        }.await;
+
+        if let Err(e) = r {
+            drop(commit_lock);
+            close_replacing_batch(&batch_writers, batch, Some(batch_commit)).await;
+            return Err(e);
+        }
+
+        // The old log is deleted once the commit applies,
+        // so the replacement must be durable first.
+        *commit_lock = Some(PendingCommit {
+            batch,
+            batch_commit,
+            commit,
+            write: write_commit(self.commit_log.clone(), self.stats.clone(),
+                                batch_writers.values().cloned().collect(),
+                                batch, batch_commit, commit, Durability::Fsync),
+            batch_writers: batch_writers.clone(),
+            trees: Arc::new(new_trees),
+            replacing: Some(Replacing {
+                shared_trees: self.trees.clone(),
+                old_tree,
+                new_tree: tree,
+            }),
+            next_commit: self.next_commit.clone(),
+            view_commit_limit: self.view_commit_limit.clone(),
+            epochs: self.epochs.clone(),
+            stats: self.stats.clone(),
+        });
+
+        let r = finish_pending_commit(&mut commit_lock).await;
+        drop(commit_lock);
+        close_replacing_batch(&batch_writers, batch, None).await;
+        r?;
+
+        Ok(commit)
+    }
+
+    pub fn has_tree(&self, tree: &str) -> bool {
+        self.trees.read().expect("lock").contains_key(tree)
+    }
+
+    fn trees(&self) -> Arc<BTreeMap<String, Arc<Tree>>> {
+        self.trees.read().expect("lock").clone()
+    }
+
+    /// Discards every version of the keys in `range`
+    /// that no view can observe.
+    pub fn collapse_range(&self, tree: &str, range: Range<Key>) -> Result<CollapseReport> {
+        let trees = self.trees();
+        let tree = get_tree(&trees, tree)?;
+        let commit_limit = self.epochs.oldest_pinned(|| {
+            Commit(self.view_commit_limit.load(Ordering::SeqCst))
+        });
+        Ok(tree.collapse_range(commit_limit, range))
+    }
+
+    /// Collapses every key of `tree` if `policy` finds it due,
+    /// given how the tree was when it was last compacted,
+    /// or if the tree requested compaction.
+    ///
+    /// Unless requested, a tree is not compacted again while that would discard nothing.
+    /// Returns how the tree was after compacting, if it was compacted.
+    pub fn compact_if_due(&self, tree: &str, policy: &CompactionPolicy,
+                          last: Option<&CompactionMark>) -> Result<Option<CompactionMark>> {
+        let trees = self.trees();
+        let tree = get_tree(&trees, tree)?;
+        self.stats.record_compaction_check();
+
+        let commit_limit = self.epochs.oldest_pinned(|| {
+            Commit(self.view_commit_limit.load(Ordering::SeqCst))
+        });
+        let requested = tree.compaction_requested();
+        let (keys, versions) = tree.version_counts();
+        if !requested && last.map(|last| last.is_fruitless(versions, commit_limit)).unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let log_size = tree.log_size();
+        let log_growth = log_size.saturating_sub(last.map(|last| last.log_size).unwrap_or(0));
+        if !requested && !policy.is_due(keys, versions, log_growth) {
+            return Ok(None);
+        }
+
+        let report = tree.collapse_all(commit_limit);
+        tree.finish_compaction(log_size);
+        self.stats.record_compaction(&report);
+        Ok(Some(CompactionMark {
+            log_size,
+            versions: tree.version_counts().1,
             commit_limit,
-            trees: self.trees.clone(),
+            versions_discarded: report.versions_discarded,
+        }))
+    }
+
+    /// Discards every version of `key` that no view can observe.
+    ///
+    /// Returns the number of versions discarded.
+    pub fn collapse_key(&self, tree: &str, key: &Key) -> Result<usize> {
+        let trees = self.trees();
+        let tree = get_tree(&trees, tree)?;
+        let commit_limit = self.epochs.oldest_pinned(|| {
+            Commit(self.view_commit_limit.load(Ordering::SeqCst))
+        });
+        Ok(tree.collapse_key(commit_limit, key))
+    }
+
+    /// Reclamation epochs shared by views and tree maintenance.
+    pub fn epochs(&self) -> &Epochs {
+        &self.epochs
+    }
+
+    pub fn stats(&self) -> Stats {
+        #[allow(unused_mut)]
+        let mut stats = self.stats.snapshot();
+        #[cfg(feature = "lock-stats")]
+        for tree in self.trees().values() {
+            tree.index_write_lock_holds().add_to_index_stats(&mut stats);
         }
+        stats
     }
 
-    pub async fn sync(&self) -> Result<()> {
-        for (_, tree) in self.trees.iter() {
-            tree.sync().await?;
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.stats.compaction_snapshot()
+    }
+
+    /// Trees that have requested compaction.
+    pub fn pending_compactions(&self) -> Vec<String> {
+        self.trees().iter()
+            .filter(|(_, tree)| tree.compaction_requested())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub async fn log_extent(&self, tree: &str) -> Result<(u64, u64)> {
+        let trees = self.trees();
+        let tree = get_tree(&trees, tree)?;
+        tree.log_extent().await
+    }
+
+    /// Storage used by `tree`, as of the latest commit.
+    pub async fn tree_storage_stats(&self, tree: &str) -> Result<TreeStorageStats> {
+        let trees = self.trees();
+        let tree = get_tree(&trees, tree)?;
+        let commit_limit = Commit(self.view_commit_limit.load(Ordering::SeqCst));
+        tree.storage_stats(commit_limit).await
+    }
+
+    pub fn record_dir_sync(&self) {
+        self.stats.record_dir_sync()
+    }
+
+    pub fn record_commit_latency(&self, latency: Duration) {
+        self.stats.record_commit_latency(latency)
+    }
+
+    /// Syncs every log, as [`Db::barrier`] does.
+    ///
+    /// Returns the last commit made durable,
+    /// or `None` if nothing has been committed.
+    pub async fn sync(&self) -> Result<Option<Commit>> {
+        self.barrier().await
+    }
+
+    /// Notes that `commit`, and every commit before it, is durable.
+    pub fn record_durable(&self, commit: Commit) {
+        let limit = commit.0.checked_add(1).expect("overflow");
+        self.durable_commit_limit.fetch_max(limit, Ordering::SeqCst);
+    }
+
+    /// Whether every commit is known to be durable.
+    pub fn is_all_durable(&self) -> bool {
+        self.next_commit.load(Ordering::SeqCst) <= self.durable_commit_limit.load(Ordering::SeqCst)
+    }
+
+    /// Whether `commit` is known to be durable.
+    ///
+    /// Commits replayed on opening are not known to be
+    /// until the next sync.
+    pub fn is_durable(&self, commit: Commit) -> bool {
+        commit.0 < self.durable_commit_limit.load(Ordering::SeqCst)
+    }
+
+    /// Writes every buffered log record to the OS without syncing.
+    pub async fn flush(&self) -> Result<()> {
+        {
+            let mut commit_lock = self.commit_lock.lock().await;
+            finish_cancelled_commit(&mut commit_lock).await;
+        }
+
+        for (_, tree) in self.trees().iter() {
+            tree.flush().await?;
         }
+        self.commit_log.flush().await?;
 
         Ok(())
     }
+
+    /// Makes every commit finished before this call durable.
+    ///
+    /// Returns the last such commit,
+    /// or `None` if nothing has been committed.
+    pub async fn barrier(&self) -> Result<Option<Commit>> {
+        // Wait out any commit in flight,
+        // and note the last commit issued before the barrier.
+        let last_commit = {
+            let mut commit_lock = self.commit_lock.lock().await;
+            finish_cancelled_commit(&mut commit_lock).await;
+            let next_commit = self.next_commit.load(Ordering::SeqCst);
+            next_commit.checked_sub(1).map(Commit)
+        };
+
+        // Trees first, so the commit log never
+        // durably refers to batches that are not.
+        for (_, tree) in self.trees().iter() {
+            tree.sync().await?;
+        }
+        self.commit_log.sync().await?;
+
+        Ok(last_commit)
+    }
 }
 
 impl BatchWriter {
@@ -128,114 +581,536 @@ impl BatchWriter {
         self.batch
     }
 
+    /// The trees the batch can write to,
+    /// which were all the trees when it began.
+    pub fn tree_names(&self) -> Vec<String> {
+        self.batch_writers.keys().cloned().collect()
+    }
+
     pub async fn open(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.open().await?)
+        let writer = self.tree_writer(tree)?;
+        writer.wait_started().await;
+        writer.open().await
     }
 
     pub async fn write(&self, tree: &str, key: Key, value: Value) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.write(key, value).await?)
+        let writer = self.tree_writer(tree)?;
+        self.has_writes.store(true, Ordering::SeqCst);
+        self.record_write(tree, &key);
+        writer.write(key, value).await
     }
 
     pub async fn delete(&self, tree: &str, key: Key) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.delete(key).await?)
+        let writer = self.tree_writer(tree)?;
+        self.has_writes.store(true, Ordering::SeqCst);
+        self.record_write(tree, &key);
+        writer.delete(key).await
+    }
+
+    pub async fn merge(&self, tree: &str, key: Key, operand: Value) -> Result<()> {
+        let writer = self.tree_writer(tree)?;
+        self.has_writes.store(true, Ordering::SeqCst);
+        self.record_write(tree, &key);
+        writer.merge(key, operand).await
+    }
+
+    pub async fn copy(&self, tree: &str, src_key: Key, dst_key: Key) -> Result<()> {
+        let writer = self.tree_writer(tree)?;
+        self.has_writes.store(true, Ordering::SeqCst);
+        self.record_write(tree, &dst_key);
+        writer.copy(src_key, dst_key).await
+    }
+
+    /// Appends a command encoded in the tree log's record format
+    /// to this batch, returning its address.
+    ///
+    /// See `tree::BatchWriter::decode_raw`.
+    pub async fn append_raw(&self, tree: &str, bytes: &[u8]) -> Result<Address> {
+        let writer = self.tree_writer(tree)?;
+        let cmd = writer.decode_raw(bytes)?;
+        self.has_writes.store(true, Ordering::SeqCst);
+        match &cmd {
+            Command::Write { key, .. } |
+            Command::WriteCompressed { key, .. } |
+            Command::Merge { key, .. } |
+            Command::Delete { key, .. } |
+            Command::Copy { dst_key: key, .. } => {
+                self.record_write(tree, key);
+            },
+            _ => { },
+        }
+        writer.append_raw(cmd).await
     }
 
     pub async fn delete_range(&self, tree: &str, start_key: Key, end_key: Key) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.delete_range(start_key, end_key).await?)
+        let writer = self.tree_writer(tree)?;
+        self.has_writes.store(true, Ordering::SeqCst);
+        writer.delete_range(start_key, end_key).await
+    }
+
+    /// Whether any write, delete, merge, or delete-range was ever issued.
+    ///
+    /// Rolled-back writes still count.
+    pub fn has_writes(&self) -> bool {
+        self.has_writes.load(Ordering::SeqCst)
+    }
+
+    /// The number of operations staged across every tree.
+    ///
+    /// Unlike `has_writes` this leaves out rolled-back operations,
+    /// and writes, deletes and merges a later write or delete of the same key replaces.
+    pub fn staged_ops(&self) -> usize {
+        self.batch_writers.values().map(|writer| writer.pending_ops()).sum()
     }
 
     pub async fn push_save_point(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.push_save_point().await?)
+        let writer = self.tree_writer(tree)?;
+        writer.push_save_point().await
     }
 
     pub async fn pop_save_point(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.pop_save_point().await?)
+        let writer = self.tree_writer(tree)?;
+        writer.pop_save_point().await
     }
 
     pub async fn rollback_save_point(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.rollback_save_point().await?)
+        let writer = self.tree_writer(tree)?;
+        writer.rollback_save_point().await
     }
 
     pub fn new_batch_commit_number(&self) -> BatchCommit {
         // Take a new batch_commit number
         let batch_commit = BatchCommit(self.next_batch_commit.fetch_add(1, Ordering::SeqCst));
-        assert_ne!(batch_commit.0, u64::max_value());
+        assert_ne!(batch_commit.0, u64::MAX);
         batch_commit
     }
 
     pub async fn ready_commit(&self, tree: &str, batch_commit: BatchCommit) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.ready_commit(batch_commit).await?)
+        let writer = self.tree_writer(tree)?;
+        writer.ready_commit(batch_commit).await.map_err(error::classify)
     }
 
     pub async fn abort_commit(&self, tree: &str, batch_commit: BatchCommit) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.abort_commit(batch_commit).await?)
+        let writer = self.tree_writer(tree)?;
+        writer.abort_commit(batch_commit).await
     }
 
-    pub async fn commit(&self, batch_commit: BatchCommit) -> Result<()> {
+    pub async fn commit(&self, batch_commit: BatchCommit) -> Result<Commit> {
+        self.commit_with(batch_commit, Durability::None).await
+    }
+
+    pub async fn commit_with(&self, batch_commit: BatchCommit, durability: Durability) -> Result<Commit> {
+        let commit_lock = self.lock_commits().await;
+        self.commit_locked(commit_lock, batch_commit, durability).await
+    }
+
+    /// Takes the commit lock, for work that must see
+    /// the latest commit stay the latest until this batch commits.
+    pub async fn lock_commits(&self) -> CommitGuard<'_> {
+        let mut commit_lock = self.commit_lock.lock().await;
+        finish_cancelled_commit(&mut commit_lock).await;
+        CommitGuard(commit_lock)
+    }
+
+    /// Commits under a commit lock taken with `lock_commits`.
+    pub async fn commit_locked(&self, mut guard: CommitGuard<'_>,
+                               batch_commit: BatchCommit, durability: Durability) -> Result<Commit> {
         // Next steps are under the commit lock in order
         // to keep commit numbers stored monotonically
-        let commit_lock = self.commit_lock.lock().await;
+        let commit_lock = &mut *guard.0;
 
-        // Take a new commit number
-        let commit = Commit(self.next_commit.fetch_add(1, Ordering::SeqCst));
-        assert_ne!(commit.0, u64::max_value());
+        // Take a new commit number,
+        // but don't consume it until the commit is durable.
+        let commit = Commit(self.next_commit.load(Ordering::SeqCst));
+        assert_ne!(commit.0, u64::MAX);
+
+        // Every tree must be ready, when validating that.
+        // Copies read the latest committed values,
+        // which can't change while the commit lock is held.
+        let checks_ok = self.batch_writers.values()
+            .map(|writer| writer.check_ready(batch_commit))
+            .collect::<Result<Vec<_>>>()
+            .and_then(|_| {
+                self.batch_writers.values()
+                    .map(|writer| writer.check_copies(batch_commit, commit))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .and_then(|_| self.check_trees())
+            .and_then(|_| self.check_reads(commit))
+            .and_then(|_| self.check_conflicts(commit));
+        if let Err(e) = checks_ok {
+            for (tree, writer) in self.batch_writers.iter() {
+                let r = writer.abort_commit(batch_commit).await;
+                if let Err(e) = r {
+                    log::error!("error aborting batch commit {} for batch {} for tree {}: {}",
+                                batch_commit.0, self.batch.0, tree, e);
+                }
+            }
+            return Err(e);
+        }
 
         // Write the master commit.
         // This is the only source of failure in the commit method,
         // and if this fails then the commit is effectively aborted;
         // if this succeeds then the remaining commit process must succeed.
-        self.write_commit(&commit_lock, batch_commit, commit).await?;
+        //
+        // From here the commit is owned by the commit lock,
+        // and will be completed even if this future is cancelled.
+        *commit_lock = Some(PendingCommit {
+            batch: self.batch,
+            batch_commit,
+            commit,
+            write: self.write_commit(batch_commit, commit, durability),
+            batch_writers: self.batch_writers.clone(),
+            trees: self.trees.read().expect("lock").clone(),
+            replacing: None,
+            next_commit: self.next_commit.clone(),
+            view_commit_limit: self.view_commit_limit.clone(),
+            epochs: self.epochs.clone(),
+            stats: self.stats.clone(),
+        });
+
+        finish_pending_commit(commit_lock).await?;
+
+        Ok(commit)
+    }
+
+    /// NB: This must be called after the batch is committed
+    pub async fn close(&self, tree: &str) -> Result<()> {
+        // A cancelled commit needs this batch's data to complete
+        {
+            let mut commit_lock = self.commit_lock.lock().await;
+            finish_cancelled_commit(&mut commit_lock).await;
+        }
+
+        let writer = self.tree_writer(tree)?;
+        writer.close().await
+    }
+
+    /// Reads `key` as the batch would commit it,
+    /// on top of the values committed before `commit_limit`.
+    pub async fn read(&self, tree: &str, key: &Key, commit_limit: Commit) -> Result<Option<Value>> {
+        let writer = self.tree_writer(tree)?;
+        writer.read(commit_limit, key).await
+    }
+
+    /// Makes the commit fail if the committed value of `key`
+    /// changes after `commit_limit`.
+    pub fn expect_unchanged(&self, tree: &str, key: Key, commit_limit: Commit) -> Result<()> {
+        let lookup = self.tree_writer(tree)?.lookup(commit_limit, &key);
+        self.reads.lock().expect("lock").push((tree.to_string(), key, lookup));
+        Ok(())
+    }
+
+    /// Makes the commit fail with `DbError::Conflict`
+    /// if another batch commits a change to a key this batch writes
+    /// at or after `commit_limit`.
+    ///
+    /// Only keys written after this is called are checked,
+    /// including ones later rolled back.
+    /// Range deletes issued by the batch are not checked.
+    pub fn detect_conflicts(&self, commit_limit: Commit) {
+        *self.conflict_check.lock().expect("lock") = Some((commit_limit, vec![]));
+    }
+
+    fn record_write(&self, tree: &str, key: &Key) {
+        if let Some((_, keys)) = &mut *self.conflict_check.lock().expect("lock") {
+            keys.push((tree.to_string(), key.clone()));
+        }
+    }
+
+    /// NB: This must be called under the commit lock.
+    fn check_conflicts(&self, commit_limit: Commit) -> Result<()> {
+        if let Some((read_at, keys)) = &*self.conflict_check.lock().expect("lock") {
+            for (tree, key) in keys {
+                let latest = self.tree_writer(tree)?.latest_commit(commit_limit, key);
+                if matches!(latest, Some(latest) if latest >= *read_at) {
+                    return Err(DbError::Conflict(tree.clone()).into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// NB: This must be called under the commit lock.
+    fn check_trees(&self) -> Result<()> {
+        let trees = self.trees.read().expect("lock");
+        for (name, tree) in self.batch_trees.iter() {
+            // A tree the batch holds can't have been dropped
+            if !Arc::ptr_eq(get_tree(&trees, name)?, tree) {
+                bail!("tree {:?} was replaced after the batch began", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// NB: This must be called under the commit lock.
+    fn check_reads(&self, commit_limit: Commit) -> Result<()> {
+        for (tree, key, lookup) in self.reads.lock().expect("lock").iter() {
+            if self.tree_writer(tree)?.lookup(commit_limit, key) != *lookup {
+                bail!("key read by the batch in tree {:?} has changed", tree);
+            }
+        }
+        Ok(())
+    }
+
+    fn tree_writer(&self, tree: &str) -> Result<&tree::BatchWriter> {
+        get_tree(&self.batch_writers, tree)
+    }
+
+    fn write_commit(&self, batch_commit: BatchCommit, commit: Commit,
+                    durability: Durability) -> BoxFuture<'static, Result<()>> {
+        write_commit(self.commit_log.clone(), self.stats.clone(),
+                     self.batch_writers.values().cloned().collect(),
+                     self.batch, batch_commit, commit, durability)
+    }
+}
+
+/// Write the master commit for `batch`, written by `writers`.
+fn write_commit(commit_log: Arc<CommitLog>, stats: Arc<StatsCollector>,
+                writers: Vec<tree::BatchWriter>,
+                batch: Batch, batch_commit: BatchCommit, commit: Commit,
+                durability: Durability) -> BoxFuture<'static, Result<()>> {
+    let sync_writers = match durability {
+        Durability::None => vec![],
+        Durability::Fsync => writers,
+    };
+    Box::pin(async move {
+        // The batch's records must be durable
+        // before the master commit that refers to them.
+        for writer in &sync_writers {
+            writer.sync().await?;
+        }
+        commit_log.commit(batch, batch_commit, commit).await?;
+        if durability == Durability::Fsync {
+            commit_log.sync().await?;
+            stats.record_synced_commit();
+        }
+        Ok(())
+    })
+}
+
+impl PendingCommit {
+    /// Infallibly make the commit visible.
+    ///
+    /// This doesn't await,
+    /// so can't be interrupted by cancellation.
+    fn apply(self) {
+        let next_commit = self.commit.0.checked_add(1).expect("overflow");
+        self.next_commit.store(next_commit, Ordering::SeqCst);
+
+        // Views can't yet read this commit,
+        // so none reads older than the current limit or a pinned one.
+        let oldest_read = self.epochs.oldest_pinned(|| {
+            Commit(self.view_commit_limit.load(Ordering::SeqCst))
+        });
 
         // Infallably promote each tree's writes to its index.
+        let mut trees_modified = 0;
         for (tree, writer) in self.batch_writers.iter() {
-            writer.commit_to_index(batch_commit, commit)
+            let op_count = writer.commit_to_index_trimming(self.batch_commit, self.commit, oldest_read);
+            if op_count > 0 {
+                trees_modified += 1;
+            }
+        }
+
+        self.stats.record_commit(trees_modified);
+
+        // Trees created since the batch began took no part in it
+        for tree in self.trees.values() {
+            tree.advance_to(Commit(next_commit));
         }
 
+        // Views take the trees and the commit limit under the trees lock,
+        // so see a replaced tree only before this commit
+        // and its replacement only from it on.
+        let shared_trees = self.replacing.as_ref().map(|replacing| {
+            let mut shared_trees = replacing.shared_trees.write().expect("lock");
+            *shared_trees = self.trees.clone();
+            shared_trees
+        });
+
         // Bump the view commit limit
-        let new_commit_limit = commit.0.checked_add(1).expect("overflow");
+        let new_commit_limit = next_commit;
         let old_commit_limit = self.view_commit_limit.swap(new_commit_limit, Ordering::SeqCst);
         assert!(old_commit_limit < new_commit_limit);
+        drop(shared_trees);
 
-        Ok(())
+        if let Some(replacing) = &self.replacing {
+            replacing.new_tree.set_remove_log_on_drop(false);
+            replacing.old_tree.set_remove_log_on_drop(true);
+        }
     }
 
-    /// NB: This must be called after the batch is committed
-    pub async fn close(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.close().await?)
+    async fn abort(self) {
+        // Invalidate the ready-commit records so the batch
+        // can't be committed under this batch_commit number.
+        for (tree, writer) in self.batch_writers.iter() {
+            let r = writer.abort_commit(self.batch_commit).await;
+            if let Err(e) = r {
+                log::error!("error aborting batch commit {} for batch {} for tree {}: {}",
+                            self.batch_commit.0, self.batch.0, tree, e);
+            }
+        }
     }
+}
+
+/// Wait for the pending master commit write, then apply or abort it.
+async fn finish_pending_commit(commit_lock: &mut Option<PendingCommit>) -> Result<()> {
+    let r = match commit_lock.as_mut() {
+        Some(pending) => pending.write.as_mut().await,
+        None => return Ok(()),
+    };
+
+    let pending = commit_lock.take().expect("pending commit");
+
+    match r {
+        Ok(()) => {
+            pending.apply();
+            Ok(())
+        },
+        Err(e) => {
+            pending.abort().await;
+            Err(error::classify(e))
+        },
+    }
+}
+
+/// Apply a pending commit if its master commit write is done,
+/// without waiting.
+///
+/// A failed write is left for the next holder of the commit lock to abort.
+fn try_finish_pending_commit(commit_lock: &mut Option<PendingCommit>) {
+    let r = match commit_lock.as_mut().map(|pending| pending.write.as_mut().now_or_never()) {
+        Some(Some(r)) => r,
+        _ => return,
+    };
 
-    fn tree_writer(&self, tree: &str) -> &tree::BatchWriter {
-        self.batch_writers.get(tree).expect("tree")
+    match r {
+        Ok(()) => {
+            commit_lock.take().expect("pending commit").apply();
+        },
+        Err(e) => {
+            commit_lock.as_mut().expect("pending commit").write = Box::pin(future::ready(Err(e)));
+        },
     }
+}
 
-    async fn write_commit(&self, _commit_lock: &MutexGuard<'_, ()>, batch_commit: BatchCommit, commit: Commit) -> Result<()> {
-        Ok(self.commit_log.commit(self.batch, batch_commit, commit).await?)
+/// Close the batch replacing a tree in every tree,
+/// first aborting `batch_commit` if it failed.
+async fn close_replacing_batch(batch_writers: &BTreeMap<String, tree::BatchWriter>,
+                               batch: Batch, batch_commit: Option<BatchCommit>) {
+    for (tree, writer) in batch_writers.iter() {
+        if let Some(batch_commit) = batch_commit {
+            if let Err(e) = writer.abort_commit(batch_commit).await {
+                log::error!("error aborting batch commit {} for batch {} for tree {}: {}",
+                            batch_commit.0, batch.0, tree, e);
+            }
+        }
+        if let Err(e) = writer.close().await {
+            log::error!("error closing batch {} for tree {}: {}", batch.0, tree, e);
+        }
     }
 }
 
+/// Finish a commit abandoned by a cancelled commit future.
+async fn finish_cancelled_commit(commit_lock: &mut Option<PendingCommit>) {
+    let batch = commit_lock.as_ref().map(|pending| pending.batch);
+    if let Err(e) = finish_pending_commit(commit_lock).await {
+        log::error!("error finishing cancelled commit for batch {}: {}",
+                    batch.expect("batch").0, e);
+    }
+}
+
+fn get_tree<'a, T>(trees: &'a BTreeMap<String, T>, tree: &str) -> Result<&'a T> {
+    trees.get(tree).ok_or_else(|| DbError::UnknownTree(tree.to_string()).into())
+}
+
 impl ViewReader {
+    pub fn commit_limit(&self) -> Commit {
+        self.commit_limit
+    }
+
+    /// The trees the view can read,
+    /// which were all the trees when it was made.
+    pub fn tree_names(&self) -> Vec<String> {
+        self.trees.keys().cloned().collect()
+    }
+
+    pub fn has_tree(&self, tree: &str) -> bool {
+        self.trees.contains_key(tree)
+    }
+
+    /// A view of the same database as of an earlier `commit_limit`.
+    ///
+    /// Only history this view keeps is guaranteed to be there,
+    /// so versions older than the oldest pinned view may be gone.
+    pub fn at(&self, commit_limit: Commit) -> ViewReader {
+        assert!(commit_limit <= self.commit_limit);
+        ViewReader {
+            commit_limit,
+            trees: self.trees.clone(),
+            epoch: self.epoch.clone(),
+        }
+    }
+
+    /// The keys written or deleted, and the ranges deleted,
+    /// by commits in this view from `low` on, ordered by commit.
+    pub fn changes_since(&self, tree: &str, low: Commit) -> Result<index::Changes> {
+        let tree = get_tree(&self.trees, tree)?;
+        tree.changes_since(self.commit_limit, low)
+    }
+
     pub async fn read(&self, tree: &str, key: &Key) -> Result<Option<Value>> {
-        let tree = self.trees.get(tree).expect("tree");
-        Ok(tree.read(self.commit_limit, key).await?)
+        let tree = get_tree(&self.trees, tree)?;
+        tree.read(self.commit_limit, key).await
+    }
+
+    pub async fn read_arc(&self, tree: &str, key: &Key) -> Result<Option<Arc<[u8]>>> {
+        let tree = get_tree(&self.trees, tree)?;
+        tree.read_arc(self.commit_limit, key).await
+    }
+
+    pub async fn history(&self, tree: &str, key: &Key) -> Result<Vec<(Commit, Option<Value>)>> {
+        let tree = get_tree(&self.trees, tree)?;
+        tree.history(self.commit_limit, key).await
+    }
+
+    /// Keys whose newest change in this view is in `(low, high]`.
+    pub fn changed_keys(&self, tree: &str, low: Commit, high: Commit, prefix: &[u8]) -> Result<Vec<Key>> {
+        let tree = get_tree(&self.trees, tree)?;
+        let high_limit = Commit(high.0.saturating_add(1));
+        let commit_limit = self.commit_limit.min(high_limit);
+        Ok(tree.changed_keys(commit_limit, low, prefix))
     }
 
-    pub fn cursor(&self, tree: &str) -> Cursor {
-        let tree = self.trees.get(tree).expect("tree");
+    /// The first key after `after` with a version in this view,
+    /// whether it is live or deleted.
+    pub fn next_versioned_key(&self, tree: &str, after: Option<&Key>) -> Result<Option<Key>> {
+        let tree = get_tree(&self.trees, tree)?;
+        Ok(tree.next_versioned_key(self.commit_limit, after))
+    }
+
+    /// Reads every live value of the tree; see [`Tree::scan_stats`].
+    pub async fn scan_tree_stats(&self, tree: &str, prefix: &[u8]) -> Result<TreeStats> {
+        let tree = get_tree(&self.trees, tree)?;
+        tree.scan_stats(self.commit_limit, prefix).await
+    }
+
+    pub fn count(&self, tree: &str, prefix: &[u8]) -> Result<usize> {
+        let tree = get_tree(&self.trees, tree)?;
+        Ok(tree.count(self.commit_limit, prefix))
+    }
+
+    pub fn cursor(&self, tree: &str) -> Result<Cursor> {
+        let tree = get_tree(&self.trees, tree)?;
         let tree_cursor = tree.cursor(self.commit_limit);
 
-        Cursor {
+        Ok(Cursor {
             tree_cursor,
-        }
+            _trees: self.trees.clone(),
+            _epoch: self.epoch.clone(),
+        })
     }
 }
 
@@ -249,7 +1124,7 @@ impl Cursor {
     }
 
     pub async fn value(&mut self) -> Result<Value> {
-        Ok(self.tree_cursor.value().await?)
+        self.tree_cursor.value().await
     }
 
     pub fn next(&mut self) {
@@ -1,4 +1,5 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
 use std::sync::Mutex;
 use crate::command::Command;
 use crate::types::{Address, Key, Batch, BatchCommit};
@@ -9,6 +10,57 @@ pub struct BatchPlayer {
 
 struct BatchData {
     commands: Vec<SimpleCommand>,
+    staged: Staged,
+}
+
+/// The value of each key as the batch's next commit would leave it,
+/// kept up to date as commands are recorded,
+/// so the batch can read its own writes without replaying them.
+#[derive(Default)]
+struct Staged {
+    keys: BTreeMap<Key, StagedValue>,
+    deleted_ranges: Vec<Range<Key>>,
+    /// The index operations the next commit would apply
+    ops: usize,
+    /// The operations on each key a later write or delete of it would replace
+    replaceable: BTreeMap<Key, usize>,
+    /// Where each open save point starts in `undo`,
+    /// and `ops` when it was pushed
+    save_points: Vec<(usize, usize)>,
+    /// Changes to undo on rollback,
+    /// recorded only while a save point is open
+    undo: Vec<Undo>,
+}
+
+enum Undo {
+    Key(Key, Option<StagedValue>),
+    Replaceable(Key, Option<usize>),
+    DeleteRange,
+}
+
+/// A key's value as a batch has staged it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StagedValue {
+    Deleted,
+    Records {
+        base: StagedBase,
+        /// Merges logged by the batch, oldest first
+        merges: Vec<Address>,
+        /// If the value was copied, the number of `merges` copied with it.
+        /// The base is always copied.
+        copied: Option<usize>,
+    },
+}
+
+/// The value a batch's merges apply to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StagedBase {
+    /// A write logged by the batch
+    Written(Address),
+    /// Nothing, as the key was deleted
+    Empty,
+    /// The committed value of a key
+    Committed(Key),
 }
 
 enum SimpleCommand {
@@ -25,6 +77,15 @@ enum SimpleCommand {
         end_key: Key,
         address: Address,
     },
+    Merge {
+        key: Key,
+        address: Address,
+    },
+    Copy {
+        src_key: Key,
+        dst_key: Key,
+        address: Address,
+    },
     PushSavePoint,
     PopSavePoint,
     RollbackSavePoint,
@@ -36,6 +97,7 @@ enum SimpleCommand {
     },
 }
 
+#[derive(Debug, Eq, PartialEq)]
 pub enum IndexOp {
     Write {
         key: Key,
@@ -50,6 +112,21 @@ pub enum IndexOp {
         end_key: Key,
         address: Address
     },
+    Merge {
+        key: Key,
+        address: Address
+    },
+    Copy {
+        src_key: Key,
+        dst_key: Key,
+        address: Address
+    },
+}
+
+impl Default for BatchPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BatchPlayer {
@@ -66,14 +143,22 @@ impl BatchPlayer {
                 assert!(!batches.contains_key(batch));
                 batches.insert(*batch, BatchData {
                     commands: vec![],
+                    staged: Staged::default(),
                 });
             },
-            Command::Write { batch, key, .. } => {
+            Command::Write { batch, key, .. }
+            | Command::WriteCompressed { batch, key, .. } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
                 batch_data.commands.push(SimpleCommand::Write {
                     key: key.clone(),
                     address,
                 });
+                batch_data.staged.set(key, StagedValue::Records {
+                    base: StagedBase::Written(address),
+                    merges: vec![],
+                    copied: None,
+                });
+                batch_data.staged.replace(key, 1);
             },
             Command::Delete { batch, key } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
@@ -81,6 +166,8 @@ impl BatchPlayer {
                     key: key.clone(),
                     address,
                 });
+                batch_data.staged.set(key, StagedValue::Deleted);
+                batch_data.staged.replace(key, 1);
             },
             Command::DeleteRange { batch, start_key, end_key } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
@@ -89,18 +176,47 @@ impl BatchPlayer {
                     end_key: end_key.clone(),
                     address,
                 });
+                batch_data.staged.delete_range(start_key, end_key);
+                batch_data.staged.ops += 1;
+            },
+            Command::Merge { batch, key, .. } => {
+                let mut batch_data = batches.get_mut(batch).expect("batch");
+                batch_data.commands.push(SimpleCommand::Merge {
+                    key: key.clone(),
+                    address,
+                });
+                batch_data.staged.merge(key, address);
+                batch_data.staged.ops += 1;
+                let replaceable = batch_data.staged.replaceable.get(key).copied().unwrap_or(0);
+                batch_data.staged.set_replaceable(key, replaceable + 1);
+            },
+            Command::Copy { batch, src_key, dst_key } => {
+                let mut batch_data = batches.get_mut(batch).expect("batch");
+                batch_data.commands.push(SimpleCommand::Copy {
+                    src_key: src_key.clone(),
+                    dst_key: dst_key.clone(),
+                    address,
+                });
+                batch_data.staged.copy(src_key, dst_key);
+                // The copy is always applied,
+                // as are the operations on the key it reads
+                batch_data.staged.replace(dst_key, 0);
+                batch_data.staged.set_replaceable(src_key, 0);
             },
             Command::PushSavePoint { batch } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
                 batch_data.commands.push(SimpleCommand::PushSavePoint);
+                batch_data.staged.push_save_point();
             },
             Command::PopSavePoint { batch } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
                 batch_data.commands.push(SimpleCommand::PopSavePoint);
+                batch_data.staged.pop_save_point();
             },
             Command::RollbackSavePoint { batch } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
                 batch_data.commands.push(SimpleCommand::RollbackSavePoint);
+                batch_data.staged.rollback_save_point();
             },
             Command::ReadyCommit { batch, batch_commit } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
@@ -128,60 +244,258 @@ impl BatchPlayer {
     }
 
     pub fn replay(&self, batch: Batch, batch_commit: BatchCommit) -> impl Iterator<Item = IndexOp> {
-        let mut batches = self.batches.lock().expect("lock");
+        let batches = self.batches.lock().expect("lock");
         let batch_data = batches.get(&batch).expect("batch");
-        let mut ops = vec![];
-        let mut save_point_indexes = vec![];
-        for cmd in &batch_data.commands {
-            match cmd {
-                SimpleCommand::Write { key, address } => {
-                    ops.push(IndexOp::Write {
-                        key: key.clone(),
-                        address: *address,
-                    });
-                },
-                SimpleCommand::Delete { key, address } => {
-                    ops.push(IndexOp::Delete {
-                        key: key.clone(),
-                        address: *address,
-                    });
-                },
-                SimpleCommand::DeleteRange { start_key, end_key, address } => {
-                    ops.push(IndexOp::DeleteRange {
-                        start_key: start_key.clone(),
-                        end_key: end_key.clone(),
-                        address: *address,
-                    });
+        match play(&batch_data.commands, batch_commit) {
+            Some(ops) => ops.into_iter(),
+            None => panic!("uncommitted/unaborted batch replay"),
+        }
+    }
+
+    /// Whether the batch logged a ready-commit for `batch_commit`.
+    pub fn is_ready(&self, batch: Batch, batch_commit: BatchCommit) -> bool {
+        let batches = self.batches.lock().expect("lock");
+        let batch_data = batches.get(&batch).expect("batch");
+        batch_data.commands.iter().any(|cmd| {
+            matches!(cmd, SimpleCommand::ReadyCommit { batch_commit: bc } if *bc == batch_commit)
+        })
+    }
+
+    /// The number of index operations the batch's next commit would apply.
+    ///
+    /// Rolled-back operations are left out,
+    /// as are those `replay` would leave out.
+    pub fn staged_ops(&self, batch: Batch) -> usize {
+        let batches = self.batches.lock().expect("lock");
+        let batch_data = batches.get(&batch).expect("batch");
+        batch_data.staged.ops
+    }
+
+    /// The value of `key` as the batch's next commit would leave it,
+    /// or `None` if the batch hasn't changed it.
+    ///
+    /// Rolled-back changes are left out.
+    pub fn staged(&self, batch: Batch, key: &Key) -> Option<StagedValue> {
+        let batches = self.batches.lock().expect("lock");
+        let batch_data = batches.get(&batch).expect("batch");
+        batch_data.staged.get(key)
+    }
+}
+
+impl Staged {
+    fn get(&self, key: &Key) -> Option<StagedValue> {
+        match self.keys.get(key) {
+            Some(value) => Some(value.clone()),
+            None if self.deleted_ranges.iter().any(|r| r.contains(key)) => Some(StagedValue::Deleted),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, key: &Key, value: StagedValue) {
+        let old = self.keys.insert(key.clone(), value);
+        if !self.save_points.is_empty() {
+            self.undo.push(Undo::Key(key.clone(), old));
+        }
+    }
+
+    /// Counts an operation that replaces those on `key` made so far,
+    /// leaving `ops` operations a later write or delete of it would replace.
+    fn replace(&mut self, key: &Key, ops: usize) {
+        let replaced = self.replaceable.get(key).copied().unwrap_or(0);
+        self.ops = self.ops + 1 - replaced;
+        self.set_replaceable(key, ops);
+    }
+
+    fn set_replaceable(&mut self, key: &Key, ops: usize) {
+        let old = self.replaceable.insert(key.clone(), ops);
+        if !self.save_points.is_empty() {
+            self.undo.push(Undo::Replaceable(key.clone(), old));
+        }
+    }
+
+    fn delete_range(&mut self, start_key: &Key, end_key: &Key) {
+        let keys: Vec<Key> = self.keys.range(start_key.clone()..end_key.clone())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.set(&key, StagedValue::Deleted);
+        }
+        self.deleted_ranges.push(start_key.clone()..end_key.clone());
+        if !self.save_points.is_empty() {
+            self.undo.push(Undo::DeleteRange);
+        }
+    }
+
+    fn merge(&mut self, key: &Key, address: Address) {
+        let value = match self.get(key) {
+            Some(StagedValue::Records { base, mut merges, copied }) => {
+                merges.push(address);
+                StagedValue::Records { base, merges, copied }
+            },
+            Some(StagedValue::Deleted) => StagedValue::Records {
+                base: StagedBase::Empty,
+                merges: vec![address],
+                copied: None,
+            },
+            None => StagedValue::Records {
+                base: StagedBase::Committed(key.clone()),
+                merges: vec![address],
+                copied: None,
+            },
+        };
+        self.set(key, value);
+    }
+
+    fn copy(&mut self, src_key: &Key, dst_key: &Key) {
+        let value = match self.get(src_key) {
+            Some(StagedValue::Records { base, merges, .. }) => StagedValue::Records {
+                base,
+                copied: Some(merges.len()),
+                merges,
+            },
+            Some(StagedValue::Deleted) => StagedValue::Deleted,
+            None => StagedValue::Records {
+                base: StagedBase::Committed(src_key.clone()),
+                merges: vec![],
+                copied: Some(0),
+            },
+        };
+        self.set(dst_key, value);
+    }
+
+    fn push_save_point(&mut self) {
+        self.save_points.push((self.undo.len(), self.ops));
+    }
+
+    fn pop_save_point(&mut self) {
+        self.save_points.pop();
+        if self.save_points.is_empty() {
+            self.undo.clear();
+        }
+    }
+
+    fn rollback_save_point(&mut self) {
+        let (save_point, ops) = self.save_points.pop().expect("rollback without save point");
+        self.ops = ops;
+        for undo in self.undo.drain(save_point..).rev() {
+            match undo {
+                Undo::Key(key, Some(value)) => {
+                    self.keys.insert(key, value);
                 },
-                SimpleCommand::PushSavePoint => {
-                    save_point_indexes.push(ops.len());
+                Undo::Key(key, None) => {
+                    self.keys.remove(&key);
                 },
-                SimpleCommand::PopSavePoint => {
-                    save_point_indexes.pop();
+                Undo::Replaceable(key, Some(ops)) => {
+                    self.replaceable.insert(key, ops);
                 },
-                SimpleCommand::RollbackSavePoint => {
-                    if let Some(save_point) = save_point_indexes.pop() {
-                        assert!(save_point <= ops.len());
-                        ops.truncate(save_point);
-                    } else {
-                        panic!("rollback without save point");
-                    }
+                Undo::Replaceable(key, None) => {
+                    self.replaceable.remove(&key);
                 },
-                SimpleCommand::ReadyCommit { batch_commit: bc } => {
-                    if batch_commit == *bc {
-                        return ops.into_iter();
-                    }
+                Undo::DeleteRange => {
+                    self.deleted_ranges.pop();
                 },
-                SimpleCommand::AbortCommit { batch_commit: bc }=> {
-                    if batch_commit == *bc {
-                        ops.clear();
-                        return ops.into_iter();
-                    }
-                },                    
             }
         }
-        panic!("uncommitted/unaborted batch replay");
-        ops.clear();
-        ops.into_iter()
     }
 }
+
+/// Plays the commands up to the ready- or abort-commit of `batch_commit`.
+///
+/// Returns `None` if `batch_commit` is neither readied nor aborted.
+fn play(commands: &[SimpleCommand], batch_commit: BatchCommit) -> Option<Vec<IndexOp>> {
+    let mut ops = vec![];
+    let mut save_point_indexes = vec![];
+    for cmd in commands {
+        match cmd {
+            SimpleCommand::Write { key, address } => {
+                ops.push(IndexOp::Write {
+                    key: key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::Delete { key, address } => {
+                ops.push(IndexOp::Delete {
+                    key: key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::DeleteRange { start_key, end_key, address } => {
+                ops.push(IndexOp::DeleteRange {
+                    start_key: start_key.clone(),
+                    end_key: end_key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::Merge { key, address } => {
+                ops.push(IndexOp::Merge {
+                    key: key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::Copy { src_key, dst_key, address } => {
+                ops.push(IndexOp::Copy {
+                    src_key: src_key.clone(),
+                    dst_key: dst_key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::PushSavePoint => {
+                save_point_indexes.push(ops.len());
+            },
+            SimpleCommand::PopSavePoint => {
+                save_point_indexes.pop();
+            },
+            SimpleCommand::RollbackSavePoint => {
+                if let Some(save_point) = save_point_indexes.pop() {
+                    assert!(save_point <= ops.len());
+                    ops.truncate(save_point);
+                } else {
+                    panic!("rollback without save point");
+                }
+            },
+            SimpleCommand::ReadyCommit { batch_commit: bc } => {
+                if batch_commit == *bc {
+                    return Some(last_ops(ops));
+                }
+            },
+            SimpleCommand::AbortCommit { batch_commit: bc } => {
+                if batch_commit == *bc {
+                    ops.clear();
+                    return Some(ops);
+                }
+            },
+        }
+    }
+
+    None
+}
+
+/// Drops the writes, deletes and merges of a key
+/// that a later write or delete of it replaces,
+/// so the last operation on a key, by log order, is the one applied.
+///
+/// Range deletes and copies are always kept,
+/// as are the operations on a key a kept copy reads.
+fn last_ops(ops: Vec<IndexOp>) -> Vec<IndexOp> {
+    let mut replaced = BTreeSet::new();
+    let mut kept: Vec<IndexOp> = ops.into_iter().rev().filter(|op| {
+        match op {
+            IndexOp::Write { key, .. } | IndexOp::Delete { key, .. } => {
+                replaced.insert(key.clone())
+            },
+            IndexOp::Merge { key, .. } => {
+                !replaced.contains(key)
+            },
+            IndexOp::DeleteRange { .. } => {
+                true
+            },
+            IndexOp::Copy { src_key, dst_key, .. } => {
+                replaced.remove(src_key);
+                replaced.insert(dst_key.clone());
+                true
+            },
+        }
+    }).collect();
+    kept.reverse();
+    kept
+}
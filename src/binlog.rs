@@ -0,0 +1,114 @@
+//! An external log of committed changes, for other programs to tail.
+//!
+//! Each change is a line of JSON, in commit order.
+//! Beside the binlog a position file records the next commit to write
+//! and the length of the binlog when it was last synced,
+//! so tailing resumes where it left off,
+//! discarding anything written after the last sync.
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use crate::atomic_file;
+use crate::basic_db::ViewReader;
+use crate::types::Commit;
+
+/// The most commits read into memory at once.
+pub const MAX_WINDOW_COMMITS: u64 = 1024;
+
+/// A change to one key or range of a tree.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BinlogRecord {
+    pub commit: u64,
+    pub tree: String,
+    #[serde(flatten)]
+    pub change: BinlogChange,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BinlogChange {
+    Write {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    DeleteRange {
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Position {
+    /// Every commit before this one is in the binlog.
+    pub next_commit: u64,
+    /// The length of the binlog holding those commits.
+    pub length: u64,
+}
+
+impl Position {
+    /// The position recorded for the binlog at `path`,
+    /// or the start if there is none.
+    pub fn read(path: &Path) -> Result<Position> {
+        match atomic_file::read(&position_path(path))? {
+            Some(bytes) => Ok(toml::from_slice(&bytes)?),
+            None => Ok(Position::default()),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let bytes = toml::to_string_pretty(self)?.into_bytes();
+        atomic_file::replace(&position_path(path), &bytes)
+    }
+}
+
+/// The changes committed in `view` from `low` on, in commit order.
+///
+/// Within a commit, a tree's range deletes come before its keys,
+/// whose values are read as of that commit.
+pub async fn records_since(view: &ViewReader, trees: &[String], low: Commit) -> Result<Vec<BinlogRecord>> {
+    let mut records = vec![];
+    for tree in trees {
+        let (keys, ranges) = view.changes_since(tree, low)?;
+        for (commit, range) in ranges {
+            records.push(BinlogRecord {
+                commit: commit.0,
+                tree: tree.clone(),
+                change: BinlogChange::DeleteRange {
+                    start_key: range.start.0,
+                    end_key: range.end.0,
+                },
+            });
+        }
+        for (commit, key) in keys {
+            let value = view.at(Commit(commit.0 + 1)).read(tree, &key).await?;
+            let change = match value {
+                Some(value) => BinlogChange::Write { key: key.0, value: value.0 },
+                None => BinlogChange::Delete { key: key.0 },
+            };
+            records.push(BinlogRecord {
+                commit: commit.0,
+                tree: tree.clone(),
+                change,
+            });
+        }
+    }
+
+    // Stable, so each commit keeps the order above
+    records.sort_by_key(|record| record.commit);
+
+    Ok(records)
+}
+
+fn position_path(path: &Path) -> PathBuf {
+    let mut position_path = OsString::from(path.as_os_str());
+    position_path.push(".position");
+    PathBuf::from(position_path)
+}
@@ -94,6 +94,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
     let config = db::DbConfig {
         dir: path,
         trees: vec!["t1".to_string(), "t2".to_string()],
+        ..db::DbConfig::default()
     };
 
     let db = db::Db::open(config).await?;
@@ -106,7 +107,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
         match command {
             Command::Write { tree, key, value } => {
                 let batch = db.write_batch().await?;
-                let tree = batch.tree(&tree);
+                let tree = batch.tree(&tree)?;
                 tree.write(key.as_bytes(), value.as_bytes()).await?;
                 drop(tree);
                 batch.commit().await?;
@@ -114,7 +115,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             },
             Command::Delete { tree, key } => {
                 let batch = db.write_batch().await?;
-                let tree = batch.tree(&tree);
+                let tree = batch.tree(&tree)?;
                 tree.delete(key.as_bytes()).await?;
                 drop(tree);
                 batch.commit().await?;
@@ -122,7 +123,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             },
             Command::DeleteRange { tree, start, end } => {
                 let batch = db.write_batch().await?;
-                let tree = batch.tree(&tree);
+                let tree = batch.tree(&tree)?;
                 tree.delete_range(start.as_bytes(), end.as_bytes()).await?;
                 drop(tree);
                 batch.commit().await?;
@@ -131,7 +132,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
 
             Command::Read { tree, key } => {
                 let view = db.read_view();
-                let tree = view.tree(&tree);
+                let tree = view.tree(&tree)?;
                 let value = tree.read(key.as_bytes()).await?;
                 if let Some(value) = value {
                     let value = String::from_utf8(value).expect("utf8");
@@ -142,7 +143,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             },
             Command::ReadAssert { tree, key, expected_value } => {
                 let view = db.read_view();
-                let tree = view.tree(&tree);
+                let tree = view.tree(&tree)?;
                 let value = tree.read(key.as_bytes()).await?;
                 if let Some(value) = value {
                     let value = String::from_utf8(value).expect("utf8");
@@ -155,7 +156,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             },
             Command::Iterate { tree } => {
                 let view = db.read_view();
-                let tree = view.tree(&tree);
+                let tree = view.tree(&tree)?;
                 let mut cursor = tree.cursor();
                 cursor.seek_first();
                 while cursor.valid() {
@@ -172,17 +173,17 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             },
             Command::BatchWrite { batch, tree, key, value } => {
                 let batch = batches.get(&batch).expect("batch");
-                let tree = batch.tree(&tree);
+                let tree = batch.tree(&tree)?;
                 tree.write(key.as_bytes(), value.as_bytes()).await?;
             },
             Command::BatchDelete { batch, tree, key } => {
                 let batch = batches.get(&batch).expect("batch");
-                let tree = batch.tree(&tree);
+                let tree = batch.tree(&tree)?;
                 tree.delete(key.as_bytes()).await?;
             },
             Command::BatchDeleteRange { batch, tree, start, end } => {
                 let batch = batches.get(&batch).expect("batch");
-                let tree = batch.tree(&tree);
+                let tree = batch.tree(&tree)?;
                 tree.delete_range(start.as_bytes(), end.as_bytes()).await?;
             },
             Command::BatchPushSavePoint { batch } => {
@@ -216,7 +217,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             },
             Command::ViewRead { view, tree, key } => {
                 let view = views.get(&view).expect("view");
-                let tree = view.tree(&tree);
+                let tree = view.tree(&tree)?;
                 let value = tree.read(key.as_bytes()).await?;
                 if let Some(value) = value {
                     let value = String::from_utf8(value).expect("utf8");
@@ -227,7 +228,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             },
             Command::ViewIterate { view, tree } => {
                 let view = views.get(&view).expect("view");
-                let tree = view.tree(&tree);
+                let tree = view.tree(&tree)?;
                 let mut cursor = tree.cursor();
                 cursor.seek_first();
                 while cursor.valid() {
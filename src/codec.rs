@@ -0,0 +1,84 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+/// Encodes log records to bytes and back.
+pub trait RecordCodec {
+    fn encode<Cmd: Serialize>(&self, cmd: &Cmd) -> Result<Vec<u8>>;
+    fn decode<Cmd: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Cmd>;
+}
+
+/// Human-readable TOML, the original log format.
+pub struct TomlCodec;
+
+/// Compact binary CBOR.
+pub struct CborCodec;
+
+/// JSON, readable by most tools.
+pub struct JsonCodec;
+
+impl RecordCodec for TomlCodec {
+    fn encode<Cmd: Serialize>(&self, cmd: &Cmd) -> Result<Vec<u8>> {
+        Ok(toml::to_string_pretty(cmd)?.into_bytes())
+    }
+
+    fn decode<Cmd: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Cmd> {
+        Ok(toml::from_slice(bytes)?)
+    }
+}
+
+impl RecordCodec for CborCodec {
+    fn encode<Cmd: Serialize>(&self, cmd: &Cmd) -> Result<Vec<u8>> {
+        Ok(serde_cbor::to_vec(cmd)?)
+    }
+
+    fn decode<Cmd: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Cmd> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+}
+
+impl RecordCodec for JsonCodec {
+    fn encode<Cmd: Serialize>(&self, cmd: &Cmd) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(cmd)?)
+    }
+
+    fn decode<Cmd: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Cmd> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// The encoding of records written to on-disk logs.
+///
+/// Every frame header names the format of its record,
+/// so a log can be read whatever format it was written in,
+/// and changing the format of an existing database is safe.
+#[derive(Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    /// TOML, with [`TomlCodec`].
+    #[default]
+    Toml,
+    /// CBOR, with [`CborCodec`].
+    Cbor,
+    /// JSON, with [`JsonCodec`].
+    Json,
+}
+
+impl RecordCodec for RecordFormat {
+    fn encode<Cmd: Serialize>(&self, cmd: &Cmd) -> Result<Vec<u8>> {
+        match self {
+            RecordFormat::Toml => TomlCodec.encode(cmd),
+            RecordFormat::Cbor => CborCodec.encode(cmd),
+            RecordFormat::Json => JsonCodec.encode(cmd),
+        }
+    }
+
+    fn decode<Cmd: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Cmd> {
+        match self {
+            RecordFormat::Toml => TomlCodec.decode(bytes),
+            RecordFormat::Cbor => CborCodec.decode(bytes),
+            RecordFormat::Json => JsonCodec.decode(bytes),
+        }
+    }
+}
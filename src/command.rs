@@ -15,6 +15,12 @@ pub enum Command {
         key: Key,
         value: Value,
     },
+    /// A write whose value was compressed before being transformed.
+    WriteCompressed {
+        batch: Batch,
+        key: Key,
+        value: Value,
+    },
     Delete {
         batch: Batch,
         key: Key,
@@ -24,6 +30,16 @@ pub enum Command {
         start_key: Key,
         end_key: Key,
     },
+    Merge {
+        batch: Batch,
+        key: Key,
+        operand: Value,
+    },
+    Copy {
+        batch: Batch,
+        src_key: Key,
+        dst_key: Key,
+    },
     PushSavePoint {
         batch: Batch,
     },
@@ -47,13 +63,29 @@ pub enum Command {
 }
 
 impl Command {
+    /// Whether the command changes a key,
+    /// rather than managing its batch.
+    pub fn is_change(&self) -> bool {
+        use Command::*;
+        matches!(self,
+                 Write { .. }
+                 | WriteCompressed { .. }
+                 | Delete { .. }
+                 | DeleteRange { .. }
+                 | Merge { .. }
+                 | Copy { .. })
+    }
+
     pub fn batch(&self) -> Batch {
         use Command::*;
         match self {
             Open { batch }
             | Write { batch, .. }
+            | WriteCompressed { batch, .. }
             | Delete { batch, .. }
             | DeleteRange { batch, .. }
+            | Merge { batch, .. }
+            | Copy { batch, .. }
             | PushSavePoint { batch, .. }
             | PopSavePoint { batch, .. }
             | RollbackSavePoint { batch, .. }
@@ -1,6 +1,6 @@
 use serde::{Serialize, Deserialize};
 use crate::log::Log;
-use crate::types::{Commit, BatchCommit, Batch};
+use crate::types::{Address, Commit, BatchCommit, Batch};
 use futures::{Stream, StreamExt};
 use anyhow::Result;
 
@@ -22,13 +22,18 @@ impl CommitLog {
     }
 
     pub async fn is_empty(&self) -> Result<bool> {
-        Ok(self.log.is_empty().await?)
+        self.log.is_empty().await
     }
 
     pub fn replay(&self) -> impl Stream<Item = Result<CommitCommand>> + Unpin {
         self.log.replay().map(|r| r.map(|(cmd, _)| cmd))
     }
 
+    /// Discards the commit log from `address` on.
+    pub async fn truncate(&self, address: Address) -> Result<()> {
+        self.log.truncate(address).await
+    }
+
     pub async fn commit(&self, batch: Batch, batch_commit: BatchCommit, commit: Commit) -> Result<()> {
         self.log.append(CommitCommand {
             batch, batch_commit, commit
@@ -36,4 +41,12 @@ impl CommitLog {
 
         Ok(())
     }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.log.flush().await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.log.sync().await
+    }
 }
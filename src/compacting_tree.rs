@@ -21,7 +21,9 @@
 //! * compacted
 //!
 //!   This is the previously compacted state of the tree.
-//!   It contains a single commit and is immutable.
+//!   It contains a commit for each read view open during
+//!   its compaction, and one for the last commit compacted,
+//!   and is immutable.
 //!
 //!   This is the third and last tree search for reads.
 //!
@@ -31,49 +33,127 @@
 //!   from the `compacting` log.
 //!
 //!   It is not searched for reads.
+//!   If compaction fails it is discarded,
+//!   leaving the compacting tree in place
+//!   for the next compaction to pick up.
 //!
-//! * trash
+//! Trees only move between these roles while the `trees`
+//! write lock is held, and each move is a single state
+//! transition under one acquisition of that lock.
+//! Readers take the read lock once per read or cursor,
+//! so they see either the layering before a move or the
+//! layering after it, and a key is never missing from
+//! every layer in between.
 //!
-//!   These are trees with outstanding read views at
-//!   the time a compaction finished.
-//!
-//!   They are waiting to be deleted.
-
-use anyhow::Result;
+//! Trees replaced by a finished compaction may still be
+//! in use by outstanding read views and cursors.
+//! Rather than being dropped they are retired to the
+//! database's [`Epochs`], which drops them once no
+//! reader that could observe them remains.
+//! Cursors and reads enter an epoch before finding
+//! the layers, and leave it once done with them.
+
+use anyhow::{Result, bail};
+use std::convert::TryFrom;
+use std::cmp::Ordering;
 use async_channel::{self, Sender, Receiver};
-use std::sync::{RwLock, Mutex, Arc, RwLockWriteGuard};
+use std::sync::{RwLock, Mutex, Arc};
 use crate::tree::{self, Tree};
 use crate::types::{Commit, Batch, BatchCommit, Key, Value};
+use crate::epoch::{Epochs, EpochGuard};
+
+/// Creates an empty tree backed by a new log.
+///
+/// The tree must already be initialized.
+pub type NewTreeFn = Arc<dyn Fn(NewTreeRole) -> Result<Tree> + Send + Sync>;
+
+/// The role a tree from a [`NewTreeFn`] is created for.
+///
+/// A compacted_wip tree should be given a distinctly named log,
+/// so that after a crash mid-compaction it can be told apart
+/// from a finished compacted log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NewTreeRole {
+    Active,
+    CompactedWip,
+}
 
-/// Just one batch number in compacted logs
-const COMPACTED_BATCH_NUM: Batch = Batch(0);
+/// Compacted logs number their batches from 0,
+/// and commit each batch just once
 const COMPACTED_BATCH_COMMIT_NUM: BatchCommit = BatchCommit(0);
 
 pub struct CompactingTree {
-    trees: Arc<RwLock<Trees>>,
+    /// `None` only while trees are moving between roles,
+    /// which happens entirely under the write lock.
+    trees: Arc<RwLock<Option<Trees>>>,
     compact_state: Arc<Mutex<CompactState>>,
+    epochs: Epochs,
+    new_tree: Option<NewTreeFn>,
+    active_writers: Mutex<Writers>,
+    /// Closes once no batch writes to the compacting tree.
+    compacting_writers: Mutex<Option<Receiver<()>>>,
+}
+
+/// Tracks the batches writing to one tree.
+///
+/// Each batch holds a clone of `sender`.
+/// Nothing is ever sent,
+/// so `receiver` sees the channel close
+/// once the last batch is dropped.
+struct Writers {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl Writers {
+    fn new() -> Writers {
+        let (sender, receiver) = async_channel::bounded(1);
+        Writers { sender, receiver }
+    }
 }
 
+/// Each tree is shared so that readers can hold on to
+/// the layers they found without holding the `trees` lock.
 enum Trees {
     Initial {
-        active: Tree,
+        active: Arc<Tree>,
     },
+    /// `compacted_wip` is `None` after a failed compaction.
     InitialCompacting {
-        active: Tree,
-        compacting: Tree,
-        compacted_wip: Tree,
+        active: Arc<Tree>,
+        compacting: Arc<Tree>,
+        compacted_wip: Option<Arc<Tree>>,
     },
     Normal {
-        active: Tree,
-        compacted: Tree,
-        trash: Vec<Tree>,
+        active: Arc<Tree>,
+        compacted: Arc<Tree>,
     },
+    /// `compacted_wip` is `None` after a failed compaction.
     Compacting {
-        active: Tree,
-        compacting: Tree,
-        compacted: Tree,
-        compacted_wip: Tree,
-        trash: Vec<Tree>,
+        active: Arc<Tree>,
+        compacting: Arc<Tree>,
+        compacted: Arc<Tree>,
+        compacted_wip: Option<Arc<Tree>>,
+    }
+}
+
+impl Trees {
+    /// The layers that exist, in read-preference order.
+    fn layers(&self) -> Vec<(Layer, Arc<Tree>)> {
+        match self {
+            Trees::Initial { active } => {
+                vec![(Layer::Active, active.clone())]
+            },
+            Trees::InitialCompacting { active, compacting, .. } => {
+                vec![(Layer::Active, active.clone()), (Layer::Compacting, compacting.clone())]
+            },
+            Trees::Normal { active, compacted } => {
+                vec![(Layer::Active, active.clone()), (Layer::Compacted, compacted.clone())]
+            },
+            Trees::Compacting { active, compacting, compacted, .. } => {
+                vec![(Layer::Active, active.clone()), (Layer::Compacting, compacting.clone()), (Layer::Compacted, compacted.clone())]
+            },
+        }
     }
 }
 
@@ -82,17 +162,62 @@ enum CompactState {
     Compacting,
 }
 
+/// One of the trees making up a `CompactingTree`.
+///
+/// `compacted_wip` is not a layer as it is never read.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Layer {
+    Active,
+    Compacting,
+    Compacted,
+}
+
+/// A batch writing to whichever tree was active when it was created.
+///
+/// Compaction of that tree waits for the batch to be dropped.
 pub struct BatchWriter {
+    inner: tree::BatchWriter,
+    _writers: Sender<()>,
 }
 
 pub struct Cursor {
     trees: Vec<tree::Cursor>,
     current: Option<usize>,
+    /// No tree holds deletes,
+    /// so a key found in any tree is live.
+    append_only: bool,
+    /// Keeps the trees from being dropped
+    /// if compaction retires them.
+    _epoch: Option<EpochGuard>,
 }
 
 impl CompactingTree {
+    pub fn new(active: Tree, epochs: Epochs) -> CompactingTree {
+        CompactingTree {
+            trees: Arc::new(RwLock::new(Some(Trees::Initial { active: Arc::new(active) }))),
+            compact_state: Arc::new(Mutex::new(CompactState::NotCompacting)),
+            epochs,
+            new_tree: None,
+            active_writers: Mutex::new(Writers::new()),
+            compacting_writers: Mutex::new(None),
+        }
+    }
+
+    /// Sets how new active and compacted trees are created.
+    ///
+    /// Without this, compaction can't start.
+    pub fn with_new_tree_fn(mut self, new_tree: NewTreeFn) -> CompactingTree {
+        self.new_tree = Some(new_tree);
+        self
+    }
+
     /// Compacts the tree, removing any stale data.
     ///
+    /// `commit_limits` are the limits of read views that must
+    /// go on reading the tree as it was at their limit.
+    /// The compacted tree keeps one commit for each of them,
+    /// and one for the last commit compacted.
+    ///
     /// Although this is async, it should probably be run in
     /// a dedicated thread, is it may take a long time to complete
     /// (and so probably should not be awaited),
@@ -100,7 +225,7 @@ impl CompactingTree {
     ///
     /// Returns `true` if a compaction was performed.
     /// Returns `false` if a compaction was already in progress.
-    pub async fn compact(&self) -> Result<bool> {
+    pub async fn compact(&self, commit_limits: &[Commit]) -> Result<bool> {
 
         // Claim the compaction routine for this tree
         {
@@ -119,93 +244,63 @@ impl CompactingTree {
         }
 
         let compaction_result: Result<_> = async {
-            // Set up trees for compaction mode
-            {
-                let mut trees = self.trees.write().expect("lock");
-
-                // FIXME holding lock across await
-                self.move_trees_for_compaction(&mut trees).await?;
-
-                drop(trees);
+            // Set up trees for compaction mode,
+            // or pick up the compacting tree a failed compaction left
+            if self.is_compacting() {
+                self.install_compacted_wip_tree()?;
+            } else {
+                self.move_active_tree_to_compacting()?;
             }
 
-            // Open a cursor for the compacting tree,
-            // and the compacted tree, and a writer
-            // for the compacted_wip tree.
-            let (cursor, writer) = {
-                let last_commit = self.wait_for_all_writes_to_compacting_tree().await?;
-                let commit_limit = Commit(last_commit.0.checked_add(1).expect("overflow"));
-
-                let trees = self.trees.read().expect("lock");
-                let (tree_cursors, compacted_wip_writer) = match &*trees {
-                    Trees::InitialCompacting { active, compacting, compacted_wip } => {
-                        drop(active);
-                        let compacting_cursor = compacting.cursor(commit_limit);
-                        let compacted_wip_writer = compacted_wip.batch(COMPACTED_BATCH_NUM);
-                        (vec![compacting_cursor], compacted_wip_writer)
-                    },
-                    Trees::Compacting { active, compacting, compacted, compacted_wip, trash } => {
-                        drop(active);
-                        drop(trash);
-                        let compacting_cursor = compacting.cursor(commit_limit);
-                        let compacted_cursor = compacted.cursor(commit_limit);
-                        let compacted_wip_writer = compacted_wip.batch(COMPACTED_BATCH_NUM);
-                        (vec![compacting_cursor, compacted_cursor], compacted_wip_writer)
-                    },
-                    _ => {
-                        panic!("invalid state during compaction");
-                    }
-                };
-
-                drop(trees);
-
-                let cursor = Cursor {
-                    trees: tree_cursors,
-                    current: None,
-                };
-
-                (cursor, compacted_wip_writer)
+            let last_commit = match self.wait_for_all_writes_to_compacting_tree().await? {
+                Some(last_commit) => last_commit,
+                None => return Ok(()),
             };
+            let last_commit_limit = Commit(last_commit.0.checked_add(1).expect("overflow"));
+
+            // Nothing is visible at a limit of 0,
+            // and views past the last commit see it.
+            let mut commit_limits: Vec<Commit> = commit_limits.iter().copied()
+                .filter(|limit| Commit(0) < *limit && *limit < last_commit_limit)
+                .collect();
+            commit_limits.sort();
+            commit_limits.dedup();
+            commit_limits.push(last_commit_limit);
+
+            // Each limit is written as its own batch and commit,
+            // holding the changes since the limit before it.
+            let compacted_wip = self.compacted_wip()?;
+            let mut previous_limit = None;
+            for (batch, commit_limit) in commit_limits.into_iter().enumerate() {
+                let previous = match previous_limit {
+                    Some(previous_limit) => Some(self.compaction_cursor(previous_limit)?),
+                    None => None,
+                };
+                let current = self.compaction_cursor(commit_limit)?;
+                let writer = compacted_wip.batch(Batch(u64::try_from(batch).expect("u64")));
 
-            {
-                let mut cursor = cursor;
-                let writer = writer;
-
-                cursor.seek_first();
                 writer.open().await?;
-
-                while cursor.valid() {
-                    let key = cursor.key();
-                    let value = cursor.value().await?;
-                    writer.write(key, value).await?;
-                }
-
+                write_changes(&writer, previous, current).await?;
                 writer.ready_commit(COMPACTED_BATCH_COMMIT_NUM).await?;
+                writer.commit_to_index(COMPACTED_BATCH_COMMIT_NUM, Commit(commit_limit.0 - 1));
                 writer.close().await?;
+
+                previous_limit = Some(commit_limit);
             }
 
             Ok(())
         }.await;
 
-        // Move trees around to end compaction
-        let end_compaction_result = async {
-            match compaction_result {
-                Ok(_) => {
-                    let mut trees = self.trees.write().expect("lock");
-
-                    // FIXME holding lock across await
-                    self.move_trees_for_end_compaction(&mut trees).await?;
-                }
-                Err(e) => {
-                    todo!()
-                }
-            }
-
+        // Move trees around to end compaction.
+        // On failure the partly written compacted_wip tree is discarded,
+        // and the compacting tree goes on being read
+        // until a later compaction finishes it.
+        let end_compaction_result = compaction_result.and_then(|_| {
+            self.move_trees_for_end_compaction()?;
             Ok(true)
-        }.await;
-
-        if let Err(e) = end_compaction_result {
-            todo!()
+        });
+        if end_compaction_result.is_err() {
+            self.discard_compacted_wip_tree();
         }
 
         {
@@ -213,70 +308,444 @@ impl CompactingTree {
             *compact_state = CompactState::NotCompacting;
         }
 
-        self.try_empty_trash().await?;
+        self.epochs.collect();
 
         end_compaction_result
     }
 
-    async fn move_trees_for_compaction(&self, trees: &mut RwLockWriteGuard<'_, Trees>) -> Result<()> {
-        // Move active to compacting
-        // Create compacted_wip
-        match &**trees {
-            Trees::Initial { active } => {
-                todo!()
+    /// A cursor over the layers compaction replaces,
+    /// the compacting and compacted trees.
+    fn compaction_cursor(&self, commit_limit: Commit) -> Result<Cursor> {
+        let trees = self.trees.read().expect("lock");
+        let layers = match trees.as_ref().expect("trees") {
+            Trees::InitialCompacting { compacting, .. } => {
+                vec![compacting.clone()]
             },
-            Trees::Normal { active, compacted, trash } => {
-                todo!()
+            Trees::Compacting { compacting, compacted, .. } => {
+                vec![compacting.clone(), compacted.clone()]
             },
-            Trees::InitialCompacting { .. } | Trees::Compacting { .. } => {
-                panic!("already compacting");
-            }
+            Trees::Initial { .. } | Trees::Normal { .. } => {
+                bail!("not compacting");
+            },
+        };
+
+        Ok(layered_cursor(&layers, commit_limit))
+    }
+
+    /// The tree compaction is writing to.
+    fn compacted_wip(&self) -> Result<Arc<Tree>> {
+        let trees = self.trees.read().expect("lock");
+        match trees.as_ref().expect("trees") {
+            Trees::InitialCompacting { compacted_wip: Some(compacted_wip), .. } |
+            Trees::Compacting { compacted_wip: Some(compacted_wip), .. } => Ok(compacted_wip.clone()),
+            Trees::InitialCompacting { compacted_wip: None, .. } |
+            Trees::Compacting { compacted_wip: None, .. } => bail!("no compacted_wip tree"),
+            Trees::Initial { .. } | Trees::Normal { .. } => bail!("not compacting"),
         }
     }
 
-    async fn move_trees_for_end_compaction(&self, trees: &mut RwLockWriteGuard<'_, Trees>) -> Result<()> {
-        // Move compacted to trash
-        // Move compacted_wip to compacted
-        match &**trees {
-            Trees::InitialCompacting { active, compacting, compacted_wip } => {
-                todo!()
+    /// Whether there is a compacting tree,
+    /// as there is during compaction and after one fails.
+    fn is_compacting(&self) -> bool {
+        let trees = self.trees.read().expect("lock");
+        matches!(trees.as_ref().expect("trees"), Trees::InitialCompacting { .. } | Trees::Compacting { .. })
+    }
+
+    /// Installs an empty compacted_wip tree
+    /// in place of the one a failed compaction discarded.
+    fn install_compacted_wip_tree(&self) -> Result<()> {
+        let mut trees = self.trees.write().expect("lock");
+        match trees.as_mut().expect("trees") {
+            Trees::InitialCompacting { compacted_wip, .. } |
+            Trees::Compacting { compacted_wip, .. } => {
+                if compacted_wip.is_some() {
+                    bail!("already compacting");
+                }
+                *compacted_wip = Some(Arc::new(self.create_compacted_wip_tree()?));
+                Ok(())
             },
-            Trees::Compacting { active, compacting, compacted, compacted_wip, trash } => {
-                todo!()
+            Trees::Initial { .. } | Trees::Normal { .. } => bail!("not compacting"),
+        }
+    }
+
+    /// Discards the compacted_wip tree of a failed compaction,
+    /// deleting its log.
+    fn discard_compacted_wip_tree(&self) {
+        let mut trees = self.trees.write().expect("lock");
+        let compacted_wip = match trees.as_mut().expect("trees") {
+            Trees::InitialCompacting { compacted_wip, .. } |
+            Trees::Compacting { compacted_wip, .. } => compacted_wip.take(),
+            Trees::Initial { .. } | Trees::Normal { .. } => None,
+        };
+        if let Some(compacted_wip) = compacted_wip {
+            self.epochs.retire(RetiredTree(compacted_wip));
+        }
+    }
+
+    /// Moves the active tree to compacting,
+    /// and installs a new empty active tree for later writes,
+    /// along with an empty compacted_wip tree.
+    ///
+    /// Batches already writing to the old active tree
+    /// keep writing to it as the compacting tree.
+    /// Batch numbers are assigned by the caller,
+    /// so later batches continue the same numbering
+    /// in the new active tree.
+    pub fn move_active_tree_to_compacting(&self) -> Result<()> {
+        let new_tree = match self.new_tree {
+            Some(ref new_tree) => new_tree,
+            None => bail!("no way to create trees for compaction"),
+        };
+
+        let mut trees = self.trees.write().expect("lock");
+
+        if let Some(Trees::InitialCompacting { .. }) | Some(Trees::Compacting { .. }) = *trees {
+            bail!("already compacting");
+        }
+
+        // Create the new trees before moving anything,
+        // so that failure leaves the layering unchanged.
+        let active = Arc::new(new_tree(NewTreeRole::Active)?);
+        let compacted_wip = Some(Arc::new(self.create_compacted_wip_tree()?));
+
+        // New batches write to the new active tree,
+        // and compaction waits for the rest to finish with the old.
+        let writers = std::mem::replace(&mut *self.active_writers.lock().expect("lock"), Writers::new());
+        *self.compacting_writers.lock().expect("lock") = Some(writers.receiver);
+
+        let old_trees = trees.take().expect("trees");
+        *trees = Some(match old_trees {
+            Trees::Initial { active: compacting } => {
+                Trees::InitialCompacting { active, compacting, compacted_wip }
             },
-            Trees::Initial { .. } | Trees::Normal { .. } => {
-                panic!("not compacting");
-            }
+            Trees::Normal { active: compacting, compacted } => {
+                Trees::Compacting { active, compacting, compacted, compacted_wip }
+            },
+            Trees::InitialCompacting { .. } | Trees::Compacting { .. } => unreachable!(),
+        });
+
+        Ok(())
+    }
+
+    /// Creates an empty tree for compaction to write into.
+    ///
+    /// Compaction writes it as the single batch `COMPACTED_BATCH_NUM`,
+    /// committed with `COMPACTED_BATCH_COMMIT_NUM`.
+    pub fn create_compacted_wip_tree(&self) -> Result<Tree> {
+        match self.new_tree {
+            Some(ref new_tree) => new_tree(NewTreeRole::CompactedWip),
+            None => bail!("no way to create trees for compaction"),
         }
     }
 
-    async fn wait_for_all_writes_to_compacting_tree(&self) -> Result<Commit> {
-        todo!()
+    /// Moves compacted_wip to compacted,
+    /// and retires the compacting and old compacted trees.
+    ///
+    /// Retired trees are dropped, and their logs deleted,
+    /// once no read view can observe them.
+    fn move_trees_for_end_compaction(&self) -> Result<()> {
+        let mut trees = self.trees.write().expect("lock");
+
+        if let Some(Trees::Initial { .. }) | Some(Trees::Normal { .. }) = *trees {
+            bail!("not compacting");
+        }
+        if let Some(Trees::InitialCompacting { compacted_wip: None, .. }) |
+               Some(Trees::Compacting { compacted_wip: None, .. }) = *trees {
+            bail!("no compacted_wip tree");
+        }
+
+        let old_trees = trees.take().expect("trees");
+        *trees = Some(match old_trees {
+            Trees::InitialCompacting { active, compacting, compacted_wip: Some(compacted_wip) } => {
+                self.epochs.retire(RetiredTree(compacting));
+                Trees::Normal { active, compacted: compacted_wip }
+            },
+            Trees::Compacting { active, compacting, compacted, compacted_wip: Some(compacted_wip) } => {
+                self.epochs.retire(RetiredTree(compacting));
+                self.epochs.retire(RetiredTree(compacted));
+                Trees::Normal { active, compacted: compacted_wip }
+            },
+            Trees::InitialCompacting { compacted_wip: None, .. } |
+            Trees::Compacting { compacted_wip: None, .. } => unreachable!(),
+            Trees::Initial { .. } | Trees::Normal { .. } => unreachable!(),
+        });
+
+        *self.compacting_writers.lock().expect("lock") = None;
+
+        Ok(())
     }
 
-    async fn try_empty_trash(&self) -> Result<()> {
-        todo!()
+    /// Waits until every batch writing to the compacting tree is dropped.
+    ///
+    /// Returns the last commit to the compacting tree,
+    /// or `None` if nothing was ever committed to it.
+    pub async fn wait_for_all_writes_to_compacting_tree(&self) -> Result<Option<Commit>> {
+        let receiver = match self.compacting_writers.lock().expect("lock").clone() {
+            Some(receiver) => receiver,
+            None => bail!("not compacting"),
+        };
+
+        // Nothing is sent, so this returns once every sender is dropped
+        assert!(receiver.recv().await.is_err());
+
+        let trees = self.trees.read().expect("lock");
+        let next_commit = match trees.as_ref().expect("trees") {
+            Trees::InitialCompacting { compacting, .. } |
+            Trees::Compacting { compacting, .. } => compacting.next_commit(),
+            Trees::Initial { .. } | Trees::Normal { .. } => {
+                bail!("not compacting");
+            }
+        };
+
+        Ok(next_commit.0.checked_sub(1).map(Commit))
     }
 }
 
 impl CompactingTree {
     pub fn batch(&self, batch: Batch) -> BatchWriter {
-        todo!()
+        // Holding the trees lock keeps the tree and its writers together
+        let trees = self.trees.read().expect("lock");
+        let active = match trees.as_ref().expect("trees") {
+            Trees::Initial { active } |
+            Trees::InitialCompacting { active, .. } |
+            Trees::Normal { active, .. } |
+            Trees::Compacting { active, .. } => active,
+        };
+        BatchWriter {
+            inner: active.batch(batch),
+            _writers: self.active_writers.lock().expect("lock").sender.clone(),
+        }
     }
 
+    /// Reads a key from the first layer that has a version of it.
+    ///
+    /// The layers are read from a single consistent layering,
+    /// so a key is found even if compaction moves it meanwhile.
+    /// A key a preferred layer deleted is not read from the layers after it,
+    /// unless the layers are append-only and hold no deletes.
     pub async fn read(&self, commit_limit: Commit, key: &Key) -> Result<Option<Value>> {
-        todo!()
+        let _epoch = self.epochs.enter();
+        let layers = {
+            let trees = self.trees.read().expect("lock");
+            trees.as_ref().expect("trees").layers()
+        };
+
+        let append_only = layers.iter().all(|(_, tree)| tree.is_append_only());
+        for (_, tree) in layers {
+            let layer_limit = layer_commit_limit(&tree, commit_limit);
+            if let Some(value) = tree.read(layer_limit, key).await? {
+                return Ok(Some(value));
+            }
+            if !append_only && tree.latest_commit(layer_limit, key).is_some() {
+                return Ok(None);
+            }
+        }
+
+        Ok(None)
     }
 
+    /// A cursor over every layer,
+    /// reading each key from the first layer that has it.
     pub fn cursor(&self, commit_limit: Commit) -> Cursor {
-        todo!()
+        let epoch = self.epochs.enter();
+        let trees = self.trees.read().expect("lock");
+        let layers: Vec<Arc<Tree>> = trees.as_ref().expect("trees").layers()
+            .into_iter()
+            .map(|(_, tree)| tree)
+            .collect();
+        drop(trees);
+
+        Cursor {
+            _epoch: Some(epoch),
+            ..layered_cursor(&layers, commit_limit)
+        }
+    }
+
+    /// Reads a key from every layer that exists, for debugging.
+    ///
+    /// The layers are read from a single consistent layering,
+    /// in read-preference order.
+    pub async fn read_layers(&self, commit_limit: Commit, key: &Key) -> Result<Vec<(Layer, Option<Value>)>> {
+        let _epoch = self.epochs.enter();
+        let layers = {
+            let trees = self.trees.read().expect("lock");
+            trees.as_ref().expect("trees").layers()
+        };
+
+        let mut values = vec![];
+        for (layer, tree) in layers {
+            values.push((layer, tree.read(layer_commit_limit(&tree, commit_limit), key).await?));
+        }
+
+        Ok(values)
+    }
+
+    /// Reads a key from a single layer, for debugging.
+    ///
+    /// Returns `None` if the layer does not currently exist.
+    pub async fn read_layer(&self, layer: Layer, commit_limit: Commit, key: &Key) -> Result<Option<Value>> {
+        let _epoch = self.epochs.enter();
+        let tree = {
+            let trees = self.trees.read().expect("lock");
+            match (trees.as_ref().expect("trees"), layer) {
+                (Trees::Initial { active }, Layer::Active) |
+                (Trees::InitialCompacting { active, .. }, Layer::Active) |
+                (Trees::Normal { active, .. }, Layer::Active) |
+                (Trees::Compacting { active, .. }, Layer::Active) => active,
+                (Trees::InitialCompacting { compacting, .. }, Layer::Compacting) |
+                (Trees::Compacting { compacting, .. }, Layer::Compacting) => compacting,
+                (Trees::Normal { compacted, .. }, Layer::Compacted) |
+                (Trees::Compacting { compacted, .. }, Layer::Compacted) => compacted,
+                _ => return Ok(None),
+            }.clone()
+        };
+
+        tree.read(layer_commit_limit(&tree, commit_limit), key).await
+    }
+
+    /// Syncs the log of every layer.
+    pub async fn sync(&self) -> Result<()> {
+        let _epoch = self.epochs.enter();
+        let layers = {
+            let trees = self.trees.read().expect("lock");
+            trees.as_ref().expect("trees").layers()
+        };
+
+        for (_, tree) in layers {
+            tree.sync().await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BatchWriter {
+    pub async fn open(&self) -> Result<()> {
+        self.inner.open().await
     }
 
-    pub fn sync(&self) -> Result<()> {
-        todo!()
+    pub async fn write(&self, key: Key, value: Value) -> Result<()> {
+        self.inner.write(key, value).await
+    }
+
+    pub async fn delete(&self, key: Key) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    pub async fn delete_range(&self, start_key: Key, end_key: Key) -> Result<()> {
+        self.inner.delete_range(start_key, end_key).await
+    }
+
+    pub async fn ready_commit(&self, batch_commit: BatchCommit) -> Result<()> {
+        self.inner.ready_commit(batch_commit).await
+    }
+
+    pub async fn abort_commit(&self, batch_commit: BatchCommit) -> Result<()> {
+        self.inner.abort_commit(batch_commit).await
+    }
+
+    pub fn commit_to_index(&self, batch_commit: BatchCommit, commit: Commit) -> usize {
+        self.inner.commit_to_index(batch_commit, commit)
+    }
+
+    /// NB: This must only be called after the batch is committed
+    pub async fn close(&self) -> Result<()> {
+        self.inner.close().await
     }
 }
 
+/// A cursor over `layers`, given in read-preference order.
+///
+/// The cursor doesn't keep the layers from being retired.
+fn layered_cursor(layers: &[Arc<Tree>], commit_limit: Commit) -> Cursor {
+    let append_only = layers.iter().all(|tree| tree.is_append_only());
+    let tree_cursors = layers.iter()
+        .map(|tree| tree.cursor(layer_commit_limit(tree, commit_limit)))
+        .collect();
+
+    Cursor {
+        trees: tree_cursors,
+        current: None,
+        append_only,
+        _epoch: None,
+    }
+}
+
+/// Writes the keys whose values differ between two views,
+/// and deletes the keys only in `previous`.
+///
+/// Without `previous` every key in `current` is written.
+/// Only one value from each view is held in memory at a time,
+/// so compaction does not grow with the size of the tree.
+async fn write_changes(writer: &tree::BatchWriter, previous: Option<Cursor>, current: Cursor) -> Result<()> {
+    let mut previous = previous;
+    let mut current = current;
+
+    if let Some(ref mut previous) = previous {
+        previous.seek_first();
+    }
+    current.seek_first();
+
+    loop {
+        let previous_key = match previous {
+            Some(ref previous) if previous.valid() => Some(previous.key()),
+            _ => None,
+        };
+        let current_key = match current.valid() {
+            true => Some(current.key()),
+            false => None,
+        };
+
+        // Whichever view is at the lesser key goes next
+        let order = match (&previous_key, &current_key) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(previous_key), Some(current_key)) => previous_key.cmp(current_key),
+        };
+
+        match order {
+            Ordering::Less => {
+                writer.delete(previous_key.expect("key")).await?;
+                previous.as_mut().expect("cursor").next();
+            },
+            Ordering::Greater => {
+                let value = current.value().await?;
+                writer.write(current_key.expect("key"), value).await?;
+                current.next();
+            },
+            Ordering::Equal => {
+                let previous = previous.as_mut().expect("cursor");
+                let value = current.value().await?;
+                if previous.value().await? != value {
+                    writer.write(current_key.expect("key"), value).await?;
+                }
+                previous.next();
+                current.next();
+            },
+        }
+    }
+}
+
+/// A tree replaced by compaction.
+///
+/// Its log is deleted when it is dropped,
+/// which the database's [`Epochs`] defers
+/// until no reader can observe it.
+struct RetiredTree(Arc<Tree>);
+
+impl Drop for RetiredTree {
+    fn drop(&mut self) {
+        self.0.remove_log();
+    }
+}
+
+/// Layers only see the commits that wrote to them,
+/// so reading a layer past its last commit reads as of that commit.
+fn layer_commit_limit(tree: &Tree, commit_limit: Commit) -> Commit {
+    commit_limit.min(tree.next_commit())
+}
+
 impl Cursor {
     pub fn valid(&self) -> bool {
         self.current.is_some()
@@ -296,107 +765,112 @@ impl Cursor {
 
     pub fn next(&mut self) {
         assert!(self.valid());
-        let current_idx = self.current.expect("valid");
-        let current_key = self.trees[current_idx].key();
-        panic!()
+        let current_key = self.key();
+        for tree in self.trees.iter_mut() {
+            tree.seek_key(current_key.clone());
+            if tree.valid() && tree.key() == current_key {
+                tree.next();
+            }
+        }
+        self.settle(Direction::Forward);
     }
 
     pub fn prev(&mut self) {
         assert!(self.valid());
-        let current_idx = self.current.expect("valid");
-        let current_key = self.trees[current_idx].key();
-        panic!()
+        let current_key = self.key();
+        for tree in self.trees.iter_mut() {
+            tree.seek_key_rev(current_key.clone());
+            if tree.valid() && tree.key() == current_key {
+                tree.prev();
+            }
+        }
+        self.settle(Direction::Backward);
     }
 
     pub fn seek_first(&mut self) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_first();
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key < *old_key {
-                        key_idx = Some((new_key, new_idx));
-                    } else {
-                        /* pass */
-                    }
-                } else {
-                    key_idx = Some((new_key, new_idx));
-                }
-            }
-        }
-
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
         }
+        self.settle(Direction::Forward);
     }
 
     pub fn seek_last(&mut self) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_last();
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key > *old_key {
-                        key_idx = Some((new_key, new_idx));
-                    } else {
-                        /* pass */
-                    }
-                } else {
-                    key_idx = Some((new_key, new_idx));
-                }
-            }
-        }
-
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
         }
+        self.settle(Direction::Backward);
     }
 
     pub fn seek_key(&mut self, key: Key) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_key(key.clone());
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key < *old_key {
-                        key_idx = Some((new_key, new_idx));
-                    } else {
-                        /* pass */
-                    }
-                } else {
-                    key_idx = Some((new_key, new_idx));
-                }
-            }
-        }
-
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
         }
+        self.settle(Direction::Forward);
     }
 
     pub fn seek_key_rev(&mut self, key: Key) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_key_rev(key.clone());
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key > *old_key {
-                        key_idx = Some((new_key, new_idx));
-                    } else {
-                        /* pass */
-                    }
-                } else {
-                    key_idx = Some((new_key, new_idx));
+        }
+        self.settle(Direction::Backward);
+    }
+
+    /// Points at the nearest key in `direction` among the trees,
+    /// given each tree is at its own nearest key.
+    ///
+    /// Trees are in read-preference order,
+    /// so a key in several trees is read from the first.
+    /// A key that a preferred tree deleted is skipped,
+    /// unless the trees are append-only and hold no deletes.
+    fn settle(&mut self, direction: Direction) {
+        loop {
+            let mut nearest: Option<(Key, usize)> = None;
+            for (idx, tree) in self.trees.iter().enumerate() {
+                if !tree.valid() {
+                    continue;
+                }
+                let key = tree.key();
+                let nearer = match nearest {
+                    None => true,
+                    Some((ref nearest_key, _)) => match direction {
+                        Direction::Forward => key < *nearest_key,
+                        Direction::Backward => key > *nearest_key,
+                    },
+                };
+                if nearer {
+                    nearest = Some((key, idx));
                 }
             }
-        }
 
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
+            let (key, idx) = match nearest {
+                Some(nearest) => nearest,
+                None => {
+                    self.current = None;
+                    return;
+                }
+            };
+
+            let deleted = !self.append_only
+                && self.trees[..idx].iter().any(|tree| tree.touches(&key));
+            if !deleted {
+                self.current = Some(idx);
+                return;
+            }
+
+            for tree in self.trees[idx..].iter_mut() {
+                if tree.valid() && tree.key() == key {
+                    match direction {
+                        Direction::Forward => tree.next(),
+                        Direction::Backward => tree.prev(),
+                    }
+                }
+            }
         }
     }
 }
+
+#[derive(Copy, Clone)]
+enum Direction {
+    Forward,
+    Backward,
+}
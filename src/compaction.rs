@@ -0,0 +1,72 @@
+use std::time::Duration;
+use crate::types::Commit;
+
+/// When the history of trees is compacted in the background.
+///
+/// Every `interval` each tree is checked against both thresholds,
+/// and compacted if either is crossed.
+/// Compacting here discards the versions of keys
+/// that no read view can observe, as `collapse_range` does.
+/// It reclaims memory only: the tree's log is never rewritten
+/// and does not shrink.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CompactionPolicy {
+    /// How often each tree is checked.
+    pub interval: Duration,
+    /// Compact when the tree holds fewer keys than this fraction
+    /// of its versions. 0 to disable.
+    pub min_live_ratio: f64,
+    /// Compact when this many bytes have been logged to the tree
+    /// since it was last compacted. 0 to disable.
+    ///
+    /// As the log never shrinks, this fires again
+    /// after each further `log_growth_bytes` written.
+    pub log_growth_bytes: u64,
+}
+
+/// A tree as it was after it was last compacted.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CompactionMark {
+    pub log_size: u64,
+    pub versions: u64,
+    /// The oldest commit limit any view could read at.
+    pub commit_limit: Commit,
+    pub versions_discarded: u64,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> CompactionPolicy {
+        CompactionPolicy {
+            interval: Duration::from_secs(10),
+            min_live_ratio: 0.5,
+            log_growth_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    /// Whether a tree holding `keys` keys with `versions` versions,
+    /// whose log grew `log_growth` bytes since it was last compacted,
+    /// should be compacted.
+    pub fn is_due(&self, keys: u64, versions: u64, log_growth: u64) -> bool {
+        let sparse = self.min_live_ratio > 0.0
+            && keys < versions
+            && (keys as f64) < self.min_live_ratio * versions as f64;
+        let grown = self.log_growth_bytes > 0
+            && log_growth >= self.log_growth_bytes;
+        sparse || grown
+    }
+}
+
+impl CompactionMark {
+    /// Whether compacting the tree again would find nothing to discard.
+    ///
+    /// That is so if the last compaction discarded nothing,
+    /// and since then the tree gained no versions
+    /// and the oldest commit views read at is the same.
+    pub fn is_fruitless(&self, versions: u64, commit_limit: Commit) -> bool {
+        self.versions_discarded == 0
+            && self.versions == versions
+            && self.commit_limit == commit_limit
+    }
+}
@@ -0,0 +1,31 @@
+//! Compression of large values written to a tree's log.
+//!
+//! Compressed values are logged as `Command::WriteCompressed`,
+//! so values logged before a tree was compressed still read as written.
+//! A compressed value starts with a byte naming its algorithm.
+
+use anyhow::{Result, bail};
+
+/// Values shorter than this are not worth compressing.
+pub const DEFAULT_MIN_BYTES: usize = 256;
+
+const LZ4: u8 = 1;
+
+/// `value` compressed, or `None` if that doesn't make it smaller.
+pub fn compress(value: &[u8]) -> Option<Vec<u8>> {
+    let mut compressed = vec![LZ4];
+    compressed.extend_from_slice(&lz4_flex::compress_prepend_size(value));
+    if compressed.len() < value.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+pub fn decompress(compressed: &[u8]) -> Result<Vec<u8>> {
+    match compressed.split_first() {
+        Some((&LZ4, body)) => Ok(lz4_flex::decompress_size_prepended(body)?),
+        Some((algorithm, _)) => bail!("unknown value compression {}", algorithm),
+        None => bail!("compressed value is empty"),
+    }
+}
@@ -1,10 +1,113 @@
 use crate::pretty as imp;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub use anyhow::{self, Result};
 
 /// Configuration for a database.
 pub type DbConfig = imp::DbConfig;
 
+/// How many runtime invariant checks to perform.
+///
+/// Set with `DbConfig::validation`.
+pub type Validation = imp::Validation;
+
+/// How durable a commit is when it completes.
+///
+/// Passed to `WriteBatch::commit_with`.
+pub type Durability = imp::Durability;
+
+/// When commits are made durable without being asked.
+///
+/// Set with `DbConfig::sync_policy`.
+/// Syncing more often loses fewer commits in a crash
+/// but lowers commit throughput;
+/// see each variant for its tradeoff.
+pub type SyncPolicy = imp::SyncPolicy;
+
+/// A reversible transformation of stored values, such as encryption.
+///
+/// Set with `DbConfig::value_transform`.
+/// Values are transformed before being written to disk,
+/// and transformed back when read.
+/// Keys are stored as-is so that they stay ordered.
+pub use imp::ValueTransform;
+
+/// Combines a value with a merge operand, for [`WriteTree::merge`].
+///
+/// Set per tree with `DbConfig::merge_operators`.
+/// Trees without one add counters, as [`WriteTree::increment`] does.
+pub use imp::MergeOperator;
+
+/// When the history of trees is compacted in the background.
+///
+/// Set with `DbConfig::compaction_policy`.
+/// A background thread checks each tree every `interval`
+/// and discards old versions as [`Db::collapse_range`] would for the whole tree.
+/// This frees memory, but logs are never rewritten;
+/// [`Db::compact_to`] reclaims their space.
+/// Progress is reported by [`Db::compaction_stats`].
+pub type CompactionPolicy = imp::CompactionPolicy;
+
+/// The encoding of records written to on-disk logs.
+///
+/// Set with `DbConfig::record_format`.
+/// Each record names its own format,
+/// so a database can be reopened with a different one.
+pub type RecordFormat = imp::RecordFormat;
+
+/// Errors that callers may want to handle specifically.
+///
+/// Recover these from a returned error with `downcast_ref::<DbError>()`.
+pub type DbError = imp::DbError;
+
+/// A snapshot of database statistics.
+pub type Stats = imp::Stats;
+
+/// A key changed by a committing batch,
+/// passed to hooks registered with [`Db::register_index_hook`].
+pub type Change = imp::Change;
+
+/// A write derived by an index hook.
+pub type IndexWrite = imp::IndexWrite;
+
+/// A line of a binlog written by [`Db::tail_to`].
+pub type BinlogRecord = imp::BinlogRecord;
+
+/// The change a [`BinlogRecord`] makes.
+pub type BinlogChange = imp::BinlogChange;
+
+/// The result of [`Db::collapse_range`].
+pub type CollapseReport = imp::CollapseReport;
+
+/// The progress of background compaction, from [`Db::compaction_stats`].
+pub type CompactionStats = imp::CompactionStats;
+
+/// What [`Db::open_with_summary`] recovered from the logs.
+pub type RecoverySummary = imp::RecoverySummary;
+
+/// Statistics for a single tree.
+pub type TreeStats = imp::TreeStats;
+
+/// Storage used by a single tree's log, from [`Db::tree_stats`].
+pub type TreeStorageStats = imp::TreeStorageStats;
+
+/// A histogram of latencies, such as `Stats::commit_latency`.
+pub type LatencyHistogram = imp::LatencyHistogram;
+
+/// A batch number.
+pub type Batch = imp::Batch;
+
+/// A number identifying one attempt to commit a batch.
+pub type BatchCommit = imp::BatchCommit;
+
+/// A commit number.
+///
+/// Commits are numbered in the order they become visible.
+pub type Commit = imp::Commit;
+
 /// A key-value data store with
 /// multiple trees,
 /// batch commits,
@@ -13,6 +116,9 @@ pub type DbConfig = imp::DbConfig;
 pub struct Db(imp::Db);
 
 /// An atomically-committed series of write commands.
+///
+/// A write that would take the batch's keys and values
+/// past `DbConfig::max_batch_bytes` fails with [`DbError::BatchTooLarge`].
 pub struct WriteBatch(imp::WriteBatch);
 
 /// A write handle to a single tree in a `WriteBatch`.
@@ -28,46 +134,497 @@ pub struct ReadTree<'view>(imp::ReadTree<'view>);
 /// A cursor over the keys and values of a `ReadTree`.
 pub struct Cursor(imp::Cursor);
 
+/// The keys and values of a `ReadTree` in a range, from [`ReadTree::range`].
+pub struct RangeIter(imp::RangeIter);
+
+/// Every key a tree has a version of, live or deleted,
+/// from [`ReadView::sync_cursor`].
+pub struct SyncCursor(imp::SyncCursor);
+
+/// A [`Cursor`] as a stream of keys and values.
+pub type CursorStream = imp::CursorStream;
+
 impl Db {
     /// Open a new or existing database.
+    ///
+    /// Trees in `DbConfig::trees` that the database does not have yet
+    /// are added, empty.
     pub async fn open(config: DbConfig) -> Result<Db> { imp::Db::open(config).await.map(Db) }
 
+    /// Open a database as [`Db::open`] does,
+    /// also returning a [`RecoverySummary`] of what was replayed,
+    /// for logging or checking startup recovery.
+    pub async fn open_with_summary(config: DbConfig) -> Result<(Db, RecoverySummary)> { imp::Db::open_with_summary(config).await.map(|(db, summary)| (Db(db), summary)) }
+
     /// Create a write batch ([`WriteBatch`]).
+    ///
+    /// Fails if the database was opened with `DbConfig::read_only`.
+    ///
+    /// With `DbConfig::detect_write_conflicts` the batch reads
+    /// the values committed when it was created,
+    /// and its commit fails with [`DbError::Conflict`]
+    /// if another batch has since committed a change
+    /// to a key it writes.
     pub async fn write_batch(&self) -> Result<WriteBatch> { Ok(WriteBatch(self.0.write_batch().await?)) }
 
     /// Create a read view ([`ReadView`]).
     pub fn read_view(&self) -> ReadView { ReadView(self.0.read_view()) }
 
+    /// Get a read view that may be up to `max_staleness` old.
+    ///
+    /// A shared view is reused until it is older than `max_staleness`,
+    /// then replaced with a fresh one,
+    /// so commits made in the meantime may not be visible.
+    /// Until it is older than every `max_staleness` it was returned for,
+    /// the shared view holds back collapsing and compaction like any view,
+    /// and keeps dropped and replaced trees in use.
+    pub fn read_view_stale(&self, max_staleness: Duration) -> ReadView { ReadView(self.0.read_view_stale(max_staleness)) }
+
+    /// Get the names of the database's trees, in configuration order,
+    /// followed by any created with [`Db::create_tree`] in creation order.
+    pub fn tree_names(&self) -> Vec<String> { self.0.tree_names() }
+
+    /// Check whether the database has a tree named `tree`.
+    pub fn has_tree(&self, tree: &str) -> bool { self.0.has_tree(tree) }
+
+    /// Create a new, empty tree named `tree` without reopening the database.
+    ///
+    /// Names follow the rules of `DbConfig::validate`,
+    /// and fail if the database has the tree already,
+    /// or if its directory holds a log for it.
+    /// The tree takes the database's per-tree settings.
+    ///
+    /// Write batches and read views made before this don't see the tree.
+    /// To open it again, list it in `DbConfig::trees`,
+    /// and like a new database, call [`Db::sync`] to make its creation durable.
+    pub async fn create_tree(&self, tree: &str) -> Result<()> { self.0.create_tree(tree).await }
+
+    /// Remove the tree named `tree`, deleting its log and metadata.
+    ///
+    /// Later views and batches don't see the tree.
+    /// Any [`ReadView`] or cursor that could already reach it keeps reading it,
+    /// and any such [`WriteBatch`] fails to commit.
+    /// The log is deleted once the last of them is dropped,
+    /// including the view shared by [`Db::read_view_stale`] once it expires.
+    ///
+    /// Leave it out of `DbConfig::trees` when reopening,
+    /// or it is created again, empty.
+    /// Call [`Db::sync`] to make the deletion durable.
+    pub async fn drop_tree(&self, tree: &str) -> Result<()> { self.0.drop_tree(tree).await }
+
+    /// Durably set a metadata `key` of `tree` to `value`.
+    ///
+    /// Metadata is for small per-tree settings,
+    /// like a schema version, that should be discoverable on reopen.
+    /// It is stored outside the logs and is not part of any commit;
+    /// each change is atomic and durable when this returns.
+    pub async fn set_tree_metadata(&self, tree: &str, key: &str, value: &str) -> Result<()> { self.0.set_tree_metadata(tree, key, value).await }
+
+    /// Get a metadata `key` of `tree` set with [`Db::set_tree_metadata`].
+    pub fn get_tree_metadata(&self, tree: &str, key: &str) -> Result<Option<String>> { self.0.get_tree_metadata(tree, key) }
+
+    /// Register a hook that derives index writes from changes to `tree`.
+    ///
+    /// When a batch commits, every hook registered for a tree it changed
+    /// is called with the tree's changes,
+    /// and the [`IndexWrite`]s it returns are committed
+    /// atomically with the batch.
+    ///
+    /// Only writes and deletes are passed to hooks,
+    /// not range deletes, copies, merges, increments or raw records,
+    /// nor the writes made by hooks themselves.
+    /// Old values are read as of the latest commit when the batch commits,
+    /// so if concurrent batches change the same keys
+    /// an old value may already be out of date.
+    /// Hooks apply to batches created after they are registered.
+    pub fn register_index_hook(&self, tree: &str, hook: impl Fn(&[Change]) -> Vec<IndexWrite> + Send + Sync + 'static) -> Result<()> { self.0.register_index_hook(tree, hook) }
+
+    /// Get a snapshot of database statistics ([`Stats`]).
+    pub fn stats(&self) -> Stats { self.0.stats() }
+
+    /// Atomically write and delete many keys in one tree.
+    ///
+    /// Each entry is written, or deleted if its value is `None`,
+    /// all in a single commit,
+    /// and the commit number is returned.
+    /// Fails if `entries` is empty,
+    /// and with [`DbError::BatchTooLarge`], committing nothing,
+    /// if their keys and values exceed `DbConfig::max_batch_bytes`.
+    /// Like other commits it waits while `DbConfig::max_inflight_commits` are in progress.
+    pub async fn apply_map(&self, tree: &str, entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Result<u64> { self.0.apply_map(tree, entries).await }
+
+    /// Atomically replace every key in a tree with `entries`.
+    ///
+    /// The entries are written to a new log for the tree,
+    /// which takes the place of the old one in a single durable commit.
+    /// Read views opened before the commit still see the old contents,
+    /// and the old log is deleted once none of them is left.
+    ///
+    /// Write batches begun before the commit fail to commit,
+    /// and other commits wait until it is done.
+    /// Index hooks are not run for the entries.
+    pub async fn replace_tree(&self, tree: &str, entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> { self.0.replace_tree(tree, entries).await }
+
+    /// Discard the old versions of every key
+    /// from `start_key` up to but not including `end_key`.
+    ///
+    /// This is [`Db::collapse_key`] for a range of keys,
+    /// and is useful after overwriting or deleting many keys.
+    /// Only memory is reclaimed; the tree's log is not rewritten.
+    pub fn collapse_range(&self, tree: &str, start_key: &[u8], end_key: &[u8]) -> Result<CollapseReport> { self.0.collapse_range(tree, start_key, end_key) }
+
+    /// Discard the old versions of a single key.
+    ///
+    /// Only versions that no open [`ReadView`] can observe are discarded,
+    /// so this frees memory held by a frequently overwritten key
+    /// without a full compaction.
+    /// Discarded versions no longer appear in [`ReadTree::history`].
+    pub fn collapse_key(&self, tree: &str, key: &[u8]) -> Result<()> { self.0.collapse_key(tree, key) }
+
+    /// The trees waiting to be compacted.
+    ///
+    /// A tree requests compaction when its log grows
+    /// by more than `DbConfig::max_log_bytes` since it was last compacted.
+    /// The write that crosses the threshold is not delayed;
+    /// the background compaction thread compacts the tree
+    /// at its next check, and the request is then cleared.
+    pub fn pending_compactions(&self) -> Vec<String> { self.0.pending_compactions() }
+
+    /// Counts of the checks and history compactions
+    /// made under `DbConfig::compaction_policy`,
+    /// or requested through `DbConfig::max_log_bytes`.
+    ///
+    /// The counts are since the database was opened,
+    /// and stay zero without either.
+    pub fn compaction_stats(&self) -> CompactionStats { self.0.compaction_stats() }
+
+    /// The start and end byte offsets of the live part of a tree's log.
+    ///
+    /// Every committed record lies within this range,
+    /// so replication and backup tools need copy no more of the log.
+    /// Buffered appends not yet flushed are included.
+    /// Logs are currently never truncated at the front, so the start is always 0.
+    pub async fn log_extent(&self, tree: &str) -> Result<(u64, u64)> { self.0.log_extent(tree).await }
+
+    /// Get the storage used by a tree's log ([`TreeStorageStats`]).
+    ///
+    /// The live and dead figures are estimated from the index.
+    /// Counting the live keys walks the tree's whole index,
+    /// so on large trees call this sparingly rather than polling it.
+    /// [`Db::collapse_range`] only reclaims memory,
+    /// so dead bytes stay until the log is rewritten by [`Db::compact_to`].
+    pub async fn tree_stats(&self, tree: &str) -> Result<TreeStorageStats> { self.0.tree_stats(tree).await }
+
+    /// Write a compacted copy of the database to a new directory.
+    ///
+    /// The copy contains only the latest value of every key,
+    /// as a single commit, and can be opened with [`Db::open`].
+    /// Tree metadata is copied too.
+    /// The original database is not modified.
+    /// `dest_dir` must not exist or be empty.
+    ///
+    /// Values are copied one at a time,
+    /// so memory use does not grow with the size of the database.
+    pub async fn compact_to(&self, dest_dir: &Path) -> Result<()> { self.0.compact_to(dest_dir).await }
+
+    /// Open a checkpoint written by [`Db::compact_to`] for reading only.
+    ///
+    /// The checkpoint's trees are found from its files,
+    /// and its files are opened without write access.
+    /// Only a [`ReadView`] of the checkpoint is returned,
+    /// so nothing can be written to it.
+    ///
+    /// Opening replays the checkpoint's logs,
+    /// which hold a single commit,
+    /// so it takes time in proportion to the data in the checkpoint
+    /// but not to the history of the database it was copied from.
+    /// The checkpoint is read with default settings,
+    /// so it must not have been written with a `DbConfig::value_transform`.
+    pub async fn open_checkpoint_readonly(dir: &Path) -> Result<ReadView> { imp::Db::open_checkpoint_readonly(dir).await.map(ReadView) }
+
+    /// Append committed changes to the binlog at `path`, as they commit.
+    ///
+    /// Each change is a [`BinlogRecord`] on a line of JSON,
+    /// in commit order,
+    /// giving the value of every key a commit wrote or deleted,
+    /// and the ranges it deleted.
+    /// The next commit to write, and the length of the binlog holding
+    /// the commits before it, are kept in `<path>.position`,
+    /// updated after each sync of the binlog.
+    /// A later call resumes from there,
+    /// first truncating anything written after it.
+    ///
+    /// The returned future never completes unless there is an error;
+    /// drop it to stop tailing.
+    /// Commits never wait for the tailer.
+    /// A tailer that falls behind catches up
+    /// reading a bounded number of commits at a time.
+    /// If history limits or collapsing discarded versions
+    /// the tailer has yet to read, it fails rather than skip them.
+    ///
+    /// Only one tailer may write to a binlog at a time.
+    pub async fn tail_to(&self, path: &Path) -> Result<()> { self.0.tail_to(path).await }
+
+    /// Write all buffered log data to the OS, without syncing.
+    ///
+    /// Flushed data survives the process crashing,
+    /// but not the machine losing power.
+    /// This is much cheaper than [`Db::sync`].
+    pub async fn flush(&self) -> Result<()> { self.0.flush().await }
+
     /// Sync file system to disk.
     pub async fn sync(&self) -> Result<()> { self.0.sync().await }
+
+    /// Wait until every commit finished before this call is durable.
+    ///
+    /// Returns the number of the last such commit,
+    /// or `None` if nothing has been committed.
+    /// Commits made after the call are not waited for.
+    pub async fn barrier(&self) -> Result<Option<u64>> { self.0.barrier().await }
+
+    /// Whether commit number `commit` is known to be durable.
+    ///
+    /// A commit becomes durable through [`Db::sync`], [`Db::barrier`],
+    /// or committing it or a later commit with `Durability::Fsync`.
+    /// Commits made before the database was opened
+    /// count as durable only after the next sync.
+    pub fn is_durable(&self, commit: u64) -> bool { self.0.is_durable(commit) }
 }
 
 impl WriteBatch {
+    /// The number of this batch, as logged in its records.
+    pub fn number(&self) -> Batch { self.0.number() }
+
     /// Get a write handle to a single tree ([`WriteTree`]).
-    pub fn tree<'batch>(&'batch self, tree: &str) -> WriteTree<'batch> { WriteTree(self.0.tree(tree)) }
+    ///
+    /// Returns an error if the database has no tree named `tree`.
+    pub fn tree<'batch>(&'batch self, tree: &str) -> Result<WriteTree<'batch>> { self.0.tree(tree).map(WriteTree) }
+
+    /// Get a write handle to a namespace within a single tree.
+    ///
+    /// Every key written through the handle is prefixed with `prefix`.
+    pub fn tree_ns<'batch>(&'batch self, tree: &str, prefix: &[u8]) -> Result<WriteTree<'batch>> { self.0.tree_ns(tree, prefix).map(WriteTree) }
+
+    /// Move `key` and its committed value from one tree to another.
+    ///
+    /// The key is deleted from `from_tree` and written to `to_tree`
+    /// in this batch, so every view sees it in exactly one of them.
+    /// Fails if `key` has no value in `from_tree`,
+    /// counting this batch's own earlier writes and deletes.
+    ///
+    /// The value is read when this is called.
+    /// If it is changed by another commit before this batch commits
+    /// then [`WriteBatch::commit`] fails.
+    pub async fn move_key(&self, from_tree: &str, to_tree: &str, key: &[u8]) -> Result<()> { self.0.move_key(from_tree, to_tree, key).await }
 
+    /// Move a key between trees within a namespace,
+    /// as [`WriteBatch::move_key`] does.
+    ///
+    /// The key is prefixed with `prefix` in both trees.
+    pub async fn move_key_ns(&self, from_tree: &str, to_tree: &str, prefix: &[u8], key: &[u8]) -> Result<()> { self.0.move_key_ns(from_tree, to_tree, prefix, key).await }
+
+    /// Push a save point covering every tree in the batch.
+    ///
+    /// If a save point operation fails part way through,
+    /// the trees' save points no longer agree,
+    /// and every further save point operation and commit fails.
+    /// The batch must then be aborted.
     pub async fn push_save_point(&self) -> Result<()> { self.0.push_save_point().await }
+
+    /// Discard the most recent save point, keeping its writes.
+    ///
+    /// Fails if there is no save point.
     pub async fn pop_save_point(&self) -> Result<()> { self.0.pop_save_point().await }
+
+    /// Undo every write to every tree since the most recent save point,
+    /// and discard the save point.
+    ///
+    /// Fails if there is no save point.
     pub async fn rollback_save_point(&self) -> Result<()> { self.0.rollback_save_point().await }
+
+    /// The number of operations staged in this batch, across every tree.
+    ///
+    /// Writes, deletes, merges, copies and range deletes count,
+    /// except those rolled back to a save point,
+    /// and writes, deletes and merges replaced by
+    /// a later write or delete of the same key.
+    pub fn len(&self) -> usize { self.0.len() }
+
+    /// Whether this batch has no staged operations,
+    /// so committing it would be a no-op.
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+
+    /// Atomically commit every write made so far in this batch.
+    ///
+    /// Committing an empty batch ([`WriteBatch::is_empty`])
+    /// is a no-op: it writes nothing to disk and
+    /// does not advance the commit number.
+    ///
+    /// If `DbConfig::max_inflight_commits` other commits are in progress,
+    /// this first waits for one of them to finish.
+    ///
+    /// With [`SyncPolicy::PerCommit`] this is
+    /// [`WriteBatch::commit_with`] `Durability::Fsync`.
     pub async fn commit(&self) -> Result<()> { self.0.commit().await }
+
+    /// Like [`WriteBatch::commit`],
+    /// but with [`Durability::Fsync`] the batch's writes and the commit
+    /// are synced to disk before this returns.
+    ///
+    /// This does not sync the database directory;
+    /// call [`Db::sync`] once after creating a database.
+    pub async fn commit_with(&self, durability: Durability) -> Result<()> { self.0.commit_with(durability).await }
+
     pub async fn abort(&self) { self.0.abort().await }
     pub async fn close(self) { self.0.close().await }
 }
 
 impl ReadView {
     /// Get a read handle to a single tree ([`ReadTree`]).
-    pub fn tree<'view>(&'view self, tree: &str) -> ReadTree<'view> { ReadTree(self.0.tree(tree)) }
+    ///
+    /// Returns an error if the database has no tree named `tree`.
+    pub fn tree<'view>(&'view self, tree: &str) -> Result<ReadTree<'view>> { self.0.tree(tree).map(ReadTree) }
+
+    /// Get a read handle to a namespace within a single tree.
+    ///
+    /// Every key read through the handle is prefixed with `prefix`,
+    /// and its cursors only see keys within the namespace,
+    /// with the prefix removed.
+    pub fn tree_ns<'view>(&'view self, tree: &str, prefix: &[u8]) -> Result<ReadTree<'view>> { self.0.tree_ns(tree, prefix).map(ReadTree) }
+
+    /// Get a [`SyncCursor`] over every key `tree` has a version of in this view,
+    /// for a replica to sync its full state.
+    ///
+    /// Unlike a [`Cursor`] it yields deleted keys too,
+    /// with a value of `None`,
+    /// so a replica also learns of deletions.
+    /// Keys in a deleted range are yielded only if they were written
+    /// before the range was deleted.
+    pub fn sync_cursor(&self, tree: &str) -> Result<SyncCursor> { self.0.sync_cursor(tree).map(SyncCursor) }
 }
 
 impl<'batch> WriteTree<'batch> {
     pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> { self.0.write(key, value).await }
+
+    /// Delete `key`.
+    ///
+    /// Fails for trees in `DbConfig::append_only_trees`.
     pub async fn delete(&self, key: &[u8]) -> Result<()> { self.0.delete(key).await }
+
+    /// Read `key` as this batch would commit it.
+    ///
+    /// Writes, deletes, merges and copies made by the batch so far are seen,
+    /// except those rolled back to a save point,
+    /// on top of the latest committed value,
+    /// or with `DbConfig::detect_write_conflicts`
+    /// the value committed when the batch was created.
+    pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
+
+    /// Delete the keys from `start_key` up to but not including `end_key`.
+    ///
+    /// Fails for trees in `DbConfig::append_only_trees`.
     pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> { self.0.delete_range(start_key, end_key).await }
+
+    /// Copy the value of `src_key` to `dst_key`.
+    ///
+    /// The value is taken when the batch commits,
+    /// including earlier writes to `src_key` in this batch,
+    /// so the copy is atomic with the commit.
+    /// The commit fails if `src_key` does not exist.
+    pub async fn copy(&self, src_key: &[u8], dst_key: &[u8]) -> Result<()> { self.0.copy(src_key, dst_key).await }
+
+    /// Append an already-encoded write, delete, merge or copy record,
+    /// such as one forwarded from another database's log.
+    ///
+    /// The record must be encoded in `DbConfig::record_format`.
+    /// It is appended to this batch whatever batch it was logged in.
+    /// Its keys are used as-is,
+    /// and its values as they are stored,
+    /// so this fails on a namespace handle,
+    /// and `DbConfig::value_transform` is not applied.
+    pub async fn append_raw(&self, record: &[u8]) -> Result<()> { self.0.append_raw(record).await }
+
+    /// Merge `operand` into the value of `key`.
+    ///
+    /// The value isn't read: the operand is logged,
+    /// and combined with the value by the tree's [`MergeOperator`]
+    /// when the key is read,
+    /// so merges committed by concurrent batches are never lost.
+    /// Merges apply in commit order on top of the last write,
+    /// or on no value after a delete or if the key was never written.
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> { self.0.merge(key, operand).await }
+
+    /// Add `delta` to a counter.
+    ///
+    /// Counters are little-endian `i64` values,
+    /// and a missing counter counts as zero.
+    /// Increments are merged at read time,
+    /// so increments committed by concurrent batches are never lost.
+    /// This relies on the tree's default merge operator.
+    pub async fn increment(&self, key: &[u8], delta: i64) -> Result<()> { self.0.increment(key, delta).await }
 }
 
 impl<'view> ReadTree<'view> {
     pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
+
+    /// Read a value as shared bytes.
+    ///
+    /// With `DbConfig::value_cache_entries` set,
+    /// repeated reads share the cached allocation.
+    pub async fn read_arc(&self, key: &[u8]) -> Result<Option<Arc<[u8]>>> { self.0.read_arc(key).await }
+
+    /// Read a counter written by [`WriteTree::increment`].
+    ///
+    /// Returns zero if the counter does not exist.
+    pub async fn read_counter(&self, key: &[u8]) -> Result<i64> { self.0.read_counter(key).await }
+
+    /// Every version of a key visible to this view, oldest first.
+    ///
+    /// Each entry is a commit that changed the key,
+    /// and the key's value as of that commit,
+    /// or `None` if it was deleted.
+    /// Only versions still retained by the tree are returned.
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<(u64, Option<Vec<u8>>)>> { self.0.history(key).await }
+
+    /// The keys whose newest version was committed after commit `low`
+    /// and no later than commit `high`, in key order.
+    ///
+    /// Deleted keys are included,
+    /// as are keys covered by a [`WriteTree::delete_range`] in those commits.
+    /// Commits after this view are never included.
+    pub fn changed_keys(&self, low: u64, high: u64) -> Vec<Vec<u8>> { self.0.changed_keys(low, high) }
+
+    /// Get statistics for this tree ([`TreeStats`]).
+    ///
+    /// The counts are exact as of this view,
+    /// but every live value is read from the log to compute them,
+    /// so this is as expensive as reading the whole tree.
+    /// It is not meant to be polled;
+    /// [`ReadTree::count`] and [`Db::tree_stats`] are computed from the index.
+    /// For a namespace only its own keys are counted.
+    pub async fn stats(&self) -> Result<TreeStats> { self.0.stats().await }
+
+    /// Count the live keys as of this view.
+    ///
+    /// Unlike [`ReadTree::stats`] no values are read,
+    /// only the in-memory index.
+    /// For a namespace only its own keys are counted.
+    pub fn count(&self) -> Result<usize> { self.0.count() }
+
+    /// Read up to `limit` keys and values,
+    /// from `start_key` up to but not including `end_key`, in key order.
+    ///
+    /// The values are read concurrently.
+    pub async fn read_range(&self, start_key: &[u8], end_key: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> { self.0.read_range(start_key, end_key, limit).await }
+
     pub fn cursor(&self) -> Cursor { Cursor(self.0.cursor()) }
+
+    /// Iterate the keys and values from `start` up to but not including `end`,
+    /// in key order.
+    ///
+    /// A `None` bound leaves that end of the range open.
+    /// Values are read one at a time, as the iterator advances.
+    pub fn range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> RangeIter { RangeIter(self.0.range(start, end)) }
 }
 
 impl Cursor {
@@ -80,4 +637,30 @@ impl Cursor {
     pub fn seek_last(&mut self) { self.0.seek_last() }
     pub fn seek_key(&mut self, key: &[u8]) { self.0.seek_key(key) }
     pub fn seek_key_rev(&mut self, key: &[u8]) { self.0.seek_key_rev(key) }
+
+    /// Seek to the first key starting with `prefix`.
+    ///
+    /// Iterate with [`Cursor::next`] while [`Cursor::valid_for_prefix`] holds.
+    pub fn seek_prefix(&mut self, prefix: &[u8]) { self.0.seek_prefix(prefix) }
+
+    /// Whether the cursor is valid and its key starts with `prefix`.
+    pub fn valid_for_prefix(&self, prefix: &[u8]) -> bool { self.0.valid_for_prefix(prefix) }
+
+    /// Convert to a `futures::Stream` of keys and values ([`CursorStream`]).
+    ///
+    /// The stream starts at the current position and moves forward,
+    /// ending when the cursor becomes invalid.
+    /// A failed read is yielded as an error and ends the stream.
+    pub fn into_stream(self) -> CursorStream { self.0.into_stream() }
+}
+
+impl RangeIter {
+    /// The next key and value, or `None` at the end of the range.
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> { self.0.next().await }
+}
+
+impl SyncCursor {
+    /// The next key in order and its value, `None` if it is deleted,
+    /// or `None` after the last key.
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Option<Vec<u8>>)>> { self.0.next().await }
 }
@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+/// How durable a commit is when it completes.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Durability {
+    /// Leave the commit buffered,
+    /// to be made durable by a later sync or barrier.
+    #[default]
+    None,
+    /// Sync the commit to disk before it completes.
+    Fsync,
+}
+
+/// When commits are made durable without being asked.
+///
+/// Syncing costs a disk flush of each log a commit wrote to,
+/// so syncing more often makes fewer recent commits
+/// lost in a crash, at the cost of commit throughput.
+/// Whatever the policy, `Db::sync`, `Db::barrier`
+/// and committing with `Durability::Fsync` still sync.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// Only sync when asked.
+    ///
+    /// The fastest, but a crash may lose any commit since the last sync.
+    #[default]
+    Never,
+    /// Commit with `Durability::Fsync` by default,
+    /// so each commit is durable before it completes.
+    ///
+    /// Nothing committed is lost in a crash,
+    /// but each commit waits for the disk.
+    PerCommit,
+    /// Sync from a background thread at this interval
+    /// whenever there are commits that aren't durable.
+    ///
+    /// Commits don't wait for the disk,
+    /// and a crash loses about an interval of commits at most.
+    Interval(Duration),
+}
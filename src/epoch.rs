@@ -0,0 +1,183 @@
+//! Epoch-based deferred reclamation.
+//!
+//! Readers enter an epoch for as long as they need a stable view
+//! of shared resources (trees, logs).
+//! Writers that replace a resource "retire" the old one instead of
+//! dropping it directly.
+//! A retired resource is only dropped once every reader
+//! that could have observed it has left its epoch.
+//!
+//! Readers never block writers, and writers never block readers;
+//! the only cost is that retired resources live a little longer.
+//!
+//! A reader can also pin the commit limit it reads at.
+//! History older than the oldest pinned limit
+//! can never be observed again and may be discarded.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use crate::types::Commit;
+
+#[derive(Clone)]
+pub struct Epochs {
+    state: Arc<Mutex<EpochState>>,
+}
+
+/// Keeps everything not yet retired at the time of entry alive,
+/// and any commit limit it pins pinned.
+pub struct EpochGuard {
+    epoch: u64,
+    commit_limit: Option<Commit>,
+    state: Arc<Mutex<EpochState>>,
+}
+
+struct EpochState {
+    current: u64,
+    /// Number of guards in each epoch
+    active: BTreeMap<u64, usize>,
+    /// Resources waiting for all guards at or before their epoch to exit
+    retired: Vec<(u64, Box<dyn Any + Send>)>,
+    /// Number of guards pinning each commit limit
+    pinned: BTreeMap<Commit, usize>,
+}
+
+impl Default for Epochs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Epochs {
+    pub fn new() -> Epochs {
+        Epochs {
+            state: Arc::new(Mutex::new(EpochState {
+                current: 0,
+                active: BTreeMap::new(),
+                retired: Vec::new(),
+                pinned: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn enter(&self) -> EpochGuard {
+        let mut state = self.state.lock().expect("lock");
+        self.enter_locked(&mut state, None)
+    }
+
+    /// Enters an epoch that also pins the commit limit
+    /// returned by `commit_limit`.
+    ///
+    /// The limit is read under the lock,
+    /// so it can't fall below the limit seen by a concurrent `oldest_pinned`.
+    pub fn enter_pinned(&self, commit_limit: impl FnOnce() -> Commit) -> EpochGuard {
+        let mut state = self.state.lock().expect("lock");
+        let commit_limit = commit_limit();
+        *state.pinned.entry(commit_limit).or_insert(0) += 1;
+        self.enter_locked(&mut state, Some(commit_limit))
+    }
+
+    fn enter_locked(&self, state: &mut EpochState, commit_limit: Option<Commit>) -> EpochGuard {
+        let epoch = state.current;
+        *state.active.entry(epoch).or_insert(0) += 1;
+
+        EpochGuard {
+            epoch,
+            commit_limit,
+            state: self.state.clone(),
+        }
+    }
+
+    /// The oldest pinned commit limit,
+    /// or `latest` if it is older or nothing is pinned.
+    pub fn oldest_pinned(&self, latest: impl FnOnce() -> Commit) -> Commit {
+        let state = self.state.lock().expect("lock");
+        let latest = latest();
+        match state.pinned.keys().next() {
+            Some(oldest) => (*oldest).min(latest),
+            None => latest,
+        }
+    }
+
+    /// Drop `item` once no guard that might observe it remains.
+    ///
+    /// Guards entered after this call never keep `item` alive.
+    pub fn retire<T>(&self, item: T)
+    where T: Send + 'static
+    {
+        let freed = {
+            let mut state = self.state.lock().expect("lock");
+            let epoch = state.current;
+            state.current = epoch.checked_add(1).expect("overflow");
+            state.retired.push((epoch, Box::new(item)));
+            state.take_reclaimable()
+        };
+
+        // Run destructors outside the lock
+        drop(freed);
+    }
+
+    /// Drop any retired resources that are no longer observable.
+    ///
+    /// Returns the number of resources dropped.
+    pub fn collect(&self) -> usize {
+        let freed = {
+            let mut state = self.state.lock().expect("lock");
+            state.take_reclaimable()
+        };
+
+        let count = freed.len();
+        drop(freed);
+        count
+    }
+
+    /// The number of retired resources not yet dropped.
+    pub fn pending(&self) -> usize {
+        let state = self.state.lock().expect("lock");
+        state.retired.len()
+    }
+}
+
+impl EpochState {
+    fn take_reclaimable(&mut self) -> Vec<Box<dyn Any + Send>> {
+        let oldest_active = self.active.keys().next().copied();
+        let (freed, kept) = self.retired.drain(..).partition(|(epoch, _)| {
+            match oldest_active {
+                Some(oldest_active) => *epoch < oldest_active,
+                None => true,
+            }
+        });
+        self.retired = kept;
+        freed.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+impl EpochGuard {
+    /// The commit limit pinned by `Epochs::enter_pinned`.
+    pub fn commit_limit(&self) -> Option<Commit> {
+        self.commit_limit
+    }
+}
+
+impl Drop for EpochGuard {
+    fn drop(&mut self) {
+        let freed = {
+            let mut state = self.state.lock().expect("lock");
+            let count = state.active.get_mut(&self.epoch).expect("epoch");
+            *count -= 1;
+            if *count == 0 {
+                state.active.remove(&self.epoch);
+            }
+            if let Some(commit_limit) = self.commit_limit {
+                let count = state.pinned.get_mut(&commit_limit).expect("commit limit");
+                *count -= 1;
+                if *count == 0 {
+                    state.pinned.remove(&commit_limit);
+                }
+            }
+            state.take_reclaimable()
+        };
+
+        drop(freed);
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io;
+
+/// Errors that callers may want to react to specifically.
+///
+/// These are returned wrapped in `anyhow::Error`,
+/// and can be recovered with `downcast_ref::<DbError>()`.
+#[derive(Debug)]
+pub enum DbError {
+    /// The storage device ran out of space.
+    ///
+    /// The operation that failed had no effect,
+    /// and may be retried once space is available.
+    DiskFull,
+    /// A key was longer than the tree allows.
+    ///
+    /// Nothing was written.
+    KeyTooLarge,
+    /// A value was longer than the tree allows.
+    ///
+    /// Nothing was written.
+    ValueTooLarge,
+    /// A batch's keys and values were larger in total
+    /// than `DbConfig::max_batch_bytes` allows.
+    ///
+    /// The write that went past the limit was not made.
+    BatchTooLarge,
+    /// The database has no tree by this name.
+    UnknownTree(String),
+    /// Another batch committed a change to a key in this tree
+    /// that the batch wrote, after the commit the batch read at.
+    ///
+    /// Nothing was committed.
+    Conflict(String),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::DiskFull => write!(f, "disk full"),
+            DbError::KeyTooLarge => write!(f, "key too large"),
+            DbError::ValueTooLarge => write!(f, "value too large"),
+            DbError::BatchTooLarge => write!(f, "batch too large"),
+            DbError::UnknownTree(tree) => write!(f, "no tree named {:?}", tree),
+            DbError::Conflict(tree) => write!(f, "conflicting write in tree {:?}", tree),
+        }
+    }
+}
+
+impl std::error::Error for DbError { }
+
+/// Tags I/O errors that have a `DbError` equivalent.
+pub fn classify(e: anyhow::Error) -> anyhow::Error {
+    let disk_full = e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::StorageFull);
+
+    if disk_full {
+        e.context(DbError::DiskFull)
+    } else {
+        e
+    }
+}
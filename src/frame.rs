@@ -1,30 +1,42 @@
-//! A human-readable write log format
+//! A write log format with human-readable headers
+//!
+//! Each frame's header names the [`RecordFormat`] of its body,
+//! which is human-readable too unless the format is binary,
+//! and a CRC32 of the body that is checked when it is read.
 
 use serde::{Serialize, Deserialize};
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 use std::io::{Read, Write, BufRead};
 use std::convert::TryFrom;
+use std::fmt;
+use crate::codec::{RecordCodec, RecordFormat};
 
-pub fn write<Io, Cmd>(io: &mut Io, cmd: &Cmd) -> Result<()>
+pub fn write<Io, Cmd>(io: &mut Io, cmd: &Cmd, format: RecordFormat) -> Result<()>
 where Io: Write,
       Cmd: Serialize,
 {
-    let body = toml::to_string_pretty(cmd)?;
+    let body = format.encode(cmd)?;
     let length = u64::try_from(body.len()).expect("u64");
     let length = length.checked_add(4).expect("overflow"); // + 4 newlines
-    let header = Header { length };
+    if length > MAX_BODY_LENGTH {
+        bail!("frame body of {} bytes exceeds the {} byte limit", length, MAX_BODY_LENGTH);
+    }
+    let crc32 = Some(crc32fast::hash(&body));
+    let header = Header { length, format, crc32 };
     let header = toml::to_string_pretty(&header)?;
-    let frame = format!(
+    let frame_header = format!(
         "{}\n\
          \n{}\n\
          {}\n\
-         \n{}\n\n\n",
+         \n",
         FRAME_HEADER_MARKER,
         header,
-        FRAME_BODY_MARKER,
-        body);
+        FRAME_BODY_MARKER);
 
-    io.write_all(frame.as_bytes())?;
+    let mut frame = frame_header.into_bytes();
+    frame.extend_from_slice(&body);
+    frame.extend_from_slice(b"\n\n\n");
+    io.write_all(&frame)?;
 
     Ok(())
 }
@@ -33,14 +45,20 @@ pub fn read<Io, Cmd>(io: &mut Io) -> Result<Cmd>
 where Io: Read + BufRead,
       Cmd: for <'de> Deserialize<'de>,
 {
+    // The bytes of the frame read so far
+    let mut frame_bytes = 0;
+
     // Verify FRAME_HEADER_MARKER
     {
         let mut probable_header = String::new();
-        io.read_line(&mut probable_header)?;
+        frame_bytes += io.read_line(&mut probable_header)?;
 
         if probable_header.is_empty() {
             return Err(anyhow!("missing frame header"));
         }
+        if !probable_header.ends_with('\n') {
+            return Err(IncompleteFrame.into());
+        }
 
         // Remove trailing newline
         let probable_header_marker = &probable_header[..probable_header.len() - 1];
@@ -52,16 +70,18 @@ where Io: Read + BufRead,
 
     // Read header lines until FRAME_BODY_MARKER
     let body_length;
+    let format;
+    let crc32;
     {
         let mut header = String::new();
         let mut line = String::new();
 
         loop {
             line.truncate(0);
-            io.read_line(&mut line)?;
+            frame_bytes += io.read_line(&mut line)?;
 
-            if line.is_empty() {
-                return Err(anyhow!("broken frame header"));
+            if !line.ends_with('\n') {
+                return Err(IncompleteFrame.into());
             }
 
             let maybe_body_marker = &line[..line.len() - 1];
@@ -74,22 +94,96 @@ where Io: Read + BufRead,
 
         let header: Header = toml::from_str(&header)?;
         body_length = header.length;
+        format = header.format;
+        crc32 = header.crc32;
     }
 
     // Read the body
     let body_length = usize::try_from(body_length).expect("usize");
     let mut buf = vec![0; body_length];
-    io.read_exact(&mut buf)?;
+    match io.read_exact(&mut buf) {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Err(IncompleteFrame.into());
+        },
+        r => r?,
+    }
+
+    // Strip the newlines around the body
+    if buf.len() < 4 {
+        return Err(anyhow!("broken frame body"));
+    }
+    let body = &buf[1..buf.len() - 3];
 
-    let cmd: Cmd = toml::from_slice(&buf)?;
+    if let Some(crc32) = crc32 {
+        if crc32fast::hash(body) != crc32 {
+            let frame_bytes = u64::try_from(frame_bytes).expect("u64")
+                .checked_add(u64::try_from(buf.len()).expect("u64")).expect("overflow");
+            return Err(ChecksumMismatch { frame_bytes }.into());
+        }
+    }
+
+    let cmd: Cmd = format.decode(body)?;
 
     Ok(cmd)
 }
 
-static FRAME_HEADER_MARKER: &'static str = "[[frames]] # HEADER";
-static FRAME_BODY_MARKER: &'static str = "# BODY";
+/// The largest frame body that will be written.
+///
+/// Readers allocate the whole body at once,
+/// so it must fit in a 32-bit `usize`.
+pub const MAX_BODY_LENGTH: u64 = u32::MAX as u64;
+
+static FRAME_HEADER_MARKER: &str = "[[frames]] # HEADER";
+static FRAME_BODY_MARKER: &str = "# BODY";
+
+/// The error reading a frame whose body doesn't match its checksum.
+///
+/// This is what a torn or bit-rotted write looks like.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    /// The length of the whole frame, as its header gives it.
+    pub frame_bytes: u64,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame body does not match its checksum")
+    }
+}
+
+impl std::error::Error for ChecksumMismatch { }
+
+/// The error reading a frame that ends before it is complete.
+///
+/// This is what the last frame looks like if appending it was interrupted.
+#[derive(Debug)]
+pub struct IncompleteFrame;
+
+impl fmt::Display for IncompleteFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame ends before it is complete")
+    }
+}
+
+impl std::error::Error for IncompleteFrame { }
+
+/// Whether `e` is from reading a frame that a crash could have left
+/// half-written, rather than from failing to read it at all.
+pub fn is_torn(e: &anyhow::Error) -> bool {
+    e.is::<ChecksumMismatch>() || e.is::<IncompleteFrame>()
+}
 
 #[derive(Serialize, Deserialize)]
 struct Header {
     length: u64,
+    // Omitted for TOML, so frames written before formats existed still read
+    #[serde(default, skip_serializing_if = "is_toml")]
+    format: RecordFormat,
+    // Missing from frames written before checksums, which aren't checked
+    #[serde(default)]
+    crc32: Option<u32>,
+}
+
+fn is_toml(format: &RecordFormat) -> bool {
+    *format == RecordFormat::Toml
 }
@@ -1,5 +1,4 @@
 use log::debug;
-use std::collections::btree_map::Entry;
 use log::error;
 use std::collections::BTreeMap;
 use std::future::Future;
@@ -10,16 +9,32 @@ use std::thread::{self, JoinHandle};
 use async_channel::{self, Sender, Receiver, TrySendError};
 use futures::executor::{LocalPool, block_on};
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 #[derive(Debug)]
 pub struct FsThread {
     handle: JoinHandle<()>,
     tx: Sender<Message>,
+    dir_dirty: Arc<AtomicBool>,
+    operations: AtomicU64,
 }
 
 pub struct FsThreadContext {
-    append_handles: BTreeMap<PathBuf, File>,
-    read_handles: BTreeMap<PathBuf, File>,
+    append_handles: BTreeMap<PathBuf, Handle>,
+    read_handles: BTreeMap<PathBuf, Handle>,
+    dir_dirty: Arc<AtomicBool>,
+    /// 0 for unlimited
+    max_open_files: usize,
+    /// Files are opened without write access, and never created
+    read_only: bool,
+    /// Incremented on each use of a handle
+    clock: u64,
+}
+
+struct Handle {
+    file: File,
+    last_used: u64,
 }
 
 enum Message {
@@ -35,9 +50,30 @@ impl Drop for FsThread {
 
 impl FsThread {
     pub fn start() -> Result<FsThread> {
+        FsThread::start_with_max_open_files(0)
+    }
+
+    /// Starts a thread that keeps at most `max_open_files` files open,
+    /// closing the least recently used as needed.
+    ///
+    /// A `max_open_files` of 0 leaves every file open.
+    pub fn start_with_max_open_files(max_open_files: usize) -> Result<FsThread> {
+        FsThread::start_with_options(max_open_files, false)
+    }
+
+    /// Starts a thread that opens every file without write access,
+    /// so writes to them fail, and creates no files.
+    pub fn start_read_only(max_open_files: usize) -> Result<FsThread> {
+        FsThread::start_with_options(max_open_files, true)
+    }
+
+    fn start_with_options(max_open_files: usize, read_only: bool) -> Result<FsThread> {
         let (tx, rx) = async_channel::unbounded();
+        // Conservatively assume the directory has never been synced
+        let dir_dirty = Arc::new(AtomicBool::new(true));
+        let context_dir_dirty = dir_dirty.clone();
         let handle = thread::spawn(move || {
-            let mut context = FsThreadContext::new();
+            let mut context = FsThreadContext::new(context_dir_dirty, max_open_files, read_only);
             loop {
                 let msg = block_on(rx.recv()).expect("recv");
                 match msg {
@@ -54,14 +90,33 @@ impl FsThread {
         });
 
         Ok(FsThread {
-            handle, tx
+            handle, tx, dir_dirty,
+            operations: AtomicU64::new(0),
         })
     }
 
+    /// Returns whether files have been created since the last call.
+    ///
+    /// When this returns `true` the directory must be synced
+    /// for the new files to be durable.
+    pub fn take_dir_dirty(&self) -> bool {
+        self.dir_dirty.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn mark_dir_dirty(&self) {
+        self.dir_dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// The number of operations run so far.
+    pub fn operations(&self) -> u64 {
+        self.operations.load(Ordering::Relaxed)
+    }
+
     pub fn run<F, R>(&self, f: F) -> impl Future<Output = R>
     where F: FnOnce(&mut FsThreadContext) -> R + Send + 'static,
           R: Send + 'static,
     {
+        self.operations.fetch_add(1, Ordering::Relaxed);
         let (rsp_tx, rsp_rx) = async_channel::bounded(1);
 
         let simple_f = move |ctx: &mut FsThreadContext| {
@@ -76,6 +131,15 @@ impl FsThread {
             rsp_rx.recv().await.expect("recv")
         }
     }
+
+    /// Runs `f` after the operations already queued,
+    /// without waiting for it.
+    pub fn spawn<F>(&self, f: F)
+    where F: FnOnce(&mut FsThreadContext) + Send + 'static,
+    {
+        self.operations.fetch_add(1, Ordering::Relaxed);
+        self.tx.try_send(Message::Run(Box::new(f))).expect("send");
+    }
 }
 
 impl FsThread {
@@ -88,62 +152,147 @@ impl FsThread {
 }
 
 impl FsThreadContext {
+    /// Opens a file for appending.
+    ///
+    /// Writes always go to the end of the file,
+    /// even if the handle was closed and reopened.
     pub fn open_append(&mut self, path: &Path) -> Result<&mut File> {
-        let mut entry = self.append_handles.entry(path.to_owned());
-        match entry {
-            Entry::Vacant(mut entry) => {
-                let file = OpenOptions::new()
+        let tick = self.tick();
+        if !self.append_handles.contains_key(path) {
+            self.make_room()?;
+            mark_dir_dirty_if_creating(path, &self.dir_dirty);
+            let file = if self.read_only {
+                OpenOptions::new()
+                    .read(true)
+                    .open(path)?
+            } else {
+                OpenOptions::new()
                     .create(true)
                     .append(true)
-                    .open(path)?;
-                Ok(entry.insert(file))
-            }
-            Entry::Occupied(entry) => {
-                Ok(entry.into_mut())
-            }
+                    .open(path)?
+            };
+            self.append_handles.insert(path.to_owned(), Handle { file, last_used: tick });
         }
+        let handle = self.append_handles.get_mut(path).expect("handle");
+        handle.last_used = tick;
+        Ok(&mut handle.file)
     }
 
     pub fn open_read(&mut self, path: &Path) -> Result<&mut File> {
-        let mut entry = self.read_handles.entry(path.to_owned());
-        match entry {
-            Entry::Vacant(mut entry) => {
-                let file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .read(true)
-                    .open(path)?;
-                Ok(entry.insert(file))
-            }
-            Entry::Occupied(entry) => {
-                Ok(entry.into_mut())
-            }
+        let tick = self.tick();
+        if !self.read_handles.contains_key(path) {
+            self.make_room()?;
+            mark_dir_dirty_if_creating(path, &self.dir_dirty);
+            let file = OpenOptions::new()
+                .create(!self.read_only)
+                .write(!self.read_only)
+                .read(true)
+                .open(path)?;
+            self.read_handles.insert(path.to_owned(), Handle { file, last_used: tick });
         }
+        let handle = self.read_handles.get_mut(path).expect("handle");
+        handle.last_used = tick;
+        Ok(&mut handle.file)
     }
 
     pub fn close(&mut self, path: &Path) {
-        sync_close(path, self.append_handles.remove(path).as_mut());
-        sync_close(path, self.read_handles.remove(path).as_mut());
+        sync_close(path, self.append_handles.remove(path).as_mut().map(|h| &mut h.file));
+        sync_close(path, self.read_handles.remove(path).as_mut().map(|h| &mut h.file));
+    }
+
+    /// Notes that a file was removed,
+    /// so the directory needs syncing.
+    pub fn mark_dir_dirty(&self) {
+        self.dir_dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether files are opened without write access.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The number of files currently open.
+    pub fn open_files(&self) -> usize {
+        self.append_handles.len() + self.read_handles.len()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock = self.clock.checked_add(1).expect("overflow");
+        self.clock
+    }
+
+    /// Closes the least recently used handle if opening another would exceed the limit.
+    ///
+    /// An append handle is synced before it is closed,
+    /// since a later sync through another handle
+    /// isn't guaranteed to cover its writes.
+    /// If that fails the handle stays open.
+    fn make_room(&mut self) -> Result<()> {
+        if self.max_open_files == 0 || self.open_files() < self.max_open_files {
+            return Ok(());
+        }
+
+        let oldest_append = self.append_handles.iter()
+            .min_by_key(|(_, h)| h.last_used)
+            .map(|(path, h)| (h.last_used, path.clone()));
+        let oldest_read = self.read_handles.iter()
+            .min_by_key(|(_, h)| h.last_used)
+            .map(|(path, h)| (h.last_used, path.clone()));
+
+        match (oldest_append, oldest_read) {
+            (Some((append_used, path)), Some((read_used, _))) if append_used < read_used => {
+                self.close_append(&path)?;
+            },
+            (Some((_, path)), None) => {
+                self.close_append(&path)?;
+            },
+            (_, Some((_, path))) => {
+                self.read_handles.remove(&path);
+            },
+            (None, None) => { },
+        }
+
+        Ok(())
+    }
+
+    fn close_append(&mut self, path: &Path) -> Result<()> {
+        if let Some(handle) = self.append_handles.get_mut(path) {
+            if !self.read_only {
+                handle.file.sync_all()?;
+            }
+        }
+        self.append_handles.remove(path);
+        Ok(())
     }
 }
 
 impl FsThreadContext {
-    fn new() -> FsThreadContext {
+    fn new(dir_dirty: Arc<AtomicBool>, max_open_files: usize, read_only: bool) -> FsThreadContext {
         FsThreadContext {
             append_handles: BTreeMap::new(),
             read_handles: BTreeMap::new(),
+            dir_dirty,
+            max_open_files,
+            read_only,
+            clock: 0,
         }
     }
 
     fn shutdown(&mut self) {
         let files = self.append_handles.iter_mut()
             .chain(self.read_handles.iter_mut());
-        for (path, file) in files {
-            sync_close(path, Some(file));
+        for (path, handle) in files {
+            sync_close(path, Some(&mut handle.file));
         }
     }
 }
 
+fn mark_dir_dirty_if_creating(path: &Path, dir_dirty: &AtomicBool) {
+    if !path.exists() {
+        dir_dirty.store(true, Ordering::SeqCst);
+    }
+}
+
 fn sync_close(path: &Path, file: Option<&mut File>) {
     if let Some(file) = file {
         if let Err(e) = file.sync_all() {
@@ -1,64 +1,288 @@
 use log::error;
 use std::fs::{self, File};
-use std::collections::BTreeMap;
-use anyhow::Result;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+use anyhow::{Result, bail};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::thread;
+use std::sync::mpsc::RecvTimeoutError;
 use std::path::{PathBuf, Path};
-use crate::log::Log;
+use crate::log::{Log, torn_tail};
 use crate::simple_log_file;
 use crate::mem_log_file;
 use crate::command::Command;
-use crate::commit_log::CommitCommand;
+use crate::commit_log::{CommitLog, CommitCommand};
 use crate::fs_thread::FsThread;
 use crate::basic_db as bdb;
+use crate::tree::{self, TreeConfig};
+use crate::compression;
+use crate::merge;
+use crate::index_hook::{IndexHook, IndexHooks};
+use crate::tree_metadata::TreeMetadata;
+use crate::binlog::{self, Position};
 use crate::types::{Key, Value};
 use std::ops::Deref;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use async_channel::{Receiver, Sender, TrySendError};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::future;
+use futures::stream::{self, Stream, StreamExt, BoxStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-#[derive(Clone, Debug)]
+pub use crate::error::DbError;
+pub use crate::types::{Batch, BatchCommit, Commit};
+pub use crate::validation::Validation;
+pub use crate::durability::{Durability, SyncPolicy};
+pub use crate::value_transform::ValueTransform;
+pub use crate::merge::MergeOperator;
+pub use crate::compaction::CompactionPolicy;
+use crate::compaction::CompactionMark;
+pub use crate::codec::RecordFormat;
+pub use crate::stats::{CollapseReport, CompactionStats, LatencyHistogram, RecoverySummary, Stats, TreeStats, TreeStorageStats};
+pub use crate::index_hook::{Change, IndexWrite};
+pub use crate::binlog::{BinlogChange, BinlogRecord};
+
+#[derive(Clone, Debug, Default)]
 pub struct DbConfig {
     pub dir: Option<PathBuf>,
     pub trees: Vec<String>,
+    pub value_cache_entries: usize, // per tree, 0 to disable
+    pub max_log_bytes: Option<u64>, // per tree, log growth that requests compaction
+    pub log_buffer_bytes: usize, // per tree, 0 for unbuffered
+    pub log_read_ahead_bytes: usize, // per log, 0 for the default
+    pub recovery_concurrency: usize, // 0 for the number of CPUs
+    pub validation: Validation,
+    pub max_open_files: usize, // 0 for unlimited
+    pub value_transform: Option<Arc<dyn ValueTransform>>,
+    pub merge_operators: BTreeMap<String, Arc<dyn MergeOperator>>, // per tree, counters by default
+    pub max_key_bytes: usize, // 0 for the default limit
+    pub max_value_bytes: usize, // 0 for the default limit
+    pub append_only_trees: Vec<String>, // reject deletes
+    pub compressed_trees: Vec<String>, // compress large written values
+    pub compression_min_bytes: usize, // 0 for the default threshold
+    pub max_history_per_key: usize, // 0 for unlimited
+    pub read_only: bool, // open files without write access
+    pub record_format: RecordFormat, // for new log records
+    pub max_inflight_commits: usize, // 0 for unlimited
+    pub max_batch_bytes: usize, // keys and values written per batch, 0 for unlimited
+    pub sync_policy: SyncPolicy,
+    pub detect_write_conflicts: bool, // fail commits that raced on a key
+    pub compaction_policy: Option<CompactionPolicy>, // None to keep history until collapsed by hand
+}
+
+impl DbConfig {
+    /// An on-disk database in `dir` with the given trees,
+    /// and every other setting at its default.
+    pub fn new(dir: impl Into<PathBuf>, trees: Vec<String>) -> DbConfig {
+        DbConfig {
+            dir: Some(dir.into()),
+            trees,
+            ..DbConfig::default()
+        }
+    }
+
+    /// A database with the given trees that keeps its logs in memory,
+    /// creating no files,
+    /// and every other setting at its default.
+    ///
+    /// It behaves as an on-disk database
+    /// except that nothing survives dropping it.
+    pub fn in_memory(trees: Vec<String>) -> DbConfig {
+        DbConfig {
+            dir: None,
+            trees,
+            ..DbConfig::default()
+        }
+    }
+
+    /// Checks that the tree names can be used.
+    ///
+    /// Names must be non-empty, unique, usable as file names,
+    /// and not the reserved name `commits`.
+    /// Append-only and compressed trees,
+    /// and trees with merge operators, must be among them.
+    pub fn validate(&self) -> Result<()> {
+        for (i, tree) in self.trees.iter().enumerate() {
+            check_tree_name(tree)?;
+            if self.trees[..i].contains(tree) {
+                bail!("tree name {:?} is repeated", tree);
+            }
+        }
+
+        for tree in &self.append_only_trees {
+            if !self.trees.contains(tree) {
+                bail!("append-only tree {:?} is not a tree", tree);
+            }
+        }
+
+        for tree in self.merge_operators.keys() {
+            if !self.trees.contains(tree) {
+                bail!("merge operator tree {:?} is not a tree", tree);
+            }
+        }
+
+        for tree in &self.compressed_trees {
+            if !self.trees.contains(tree) {
+                bail!("compressed tree {:?} is not a tree", tree);
+            }
+        }
+
+        if let Some(policy) = self.compaction_policy {
+            if policy.interval.is_zero() {
+                bail!("compaction interval must be non-zero");
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Db {
     config: Arc<DbConfig>,
     inner: Arc<bdb::Db>,
-    trees: Arc<Vec<String>>,
+    trees: Arc<RwLock<Vec<String>>>, // configured, then created
     dir_handle: Option<Arc<File>>, // Unix only, non-mem only
+    fs_thread: Option<Arc<FsThread>>, // non-mem only
+    log_numbers: Arc<Mutex<BTreeMap<String, u64>>>, // of each tree's current log
+    next_log_number: Arc<AtomicU64>,
+    stale_view: Arc<Mutex<Option<StaleView>>>,
+    index_hooks: Arc<RwLock<IndexHooks>>,
+    tree_metadata: Arc<TreeMetadata>,
+    commit_signals: CommitSignals,
+    commit_slots: CommitSlots,
+    flusher: Option<Arc<Flusher>>,
+    compactor: Option<Arc<Compactor>>,
 }
 
 pub struct WriteBatch {
     inner: bdb::BatchWriter,
+    db: Arc<bdb::Db>,
     trees: Arc<Vec<String>>,
+    save_point_depth: AtomicUsize,
+    save_points_diverged: AtomicBool,
+    index_hooks: IndexHooks,
+    /// Writes and deletes to trees with index hooks
+    changes: Mutex<Vec<(String, Key, Option<Value>)>>,
+    /// The number of changes when each save point was pushed
+    save_point_changes: Mutex<Vec<usize>>,
+    /// Key and value bytes written so far, including rolled back writes
+    bytes: AtomicUsize,
+    max_batch_bytes: usize,
+    commit_signals: CommitSignals,
+    commit_slots: CommitSlots,
+    /// For commits that don't give one
+    durability: Durability,
+    /// With conflict detection, the view the batch reads beneath
+    read_view: Option<bdb::ViewReader>,
     closed: bool,
 }
 
+/// The tree logs, the commit log, the thread doing their file I/O,
+/// and the number of each tree's log.
+type Logs = (BTreeMap<String, Log<Command>>, Log<CommitCommand>, Option<Arc<FsThread>>, BTreeMap<String, u64>);
+
+/// Wakes tailers after each commit.
+///
+/// Each tailer has room for one wake-up,
+/// and catches up on every commit however many it misses.
+#[derive(Clone, Debug, Default)]
+struct CommitSignals(Arc<Mutex<Vec<Sender<()>>>>);
+
+/// Limits the commits in progress at once.
+///
+/// A commit holds a slot, a message in a bounded channel,
+/// from when it is submitted until it succeeds or fails.
+#[derive(Clone, Debug)]
+struct CommitSlots(Option<(Sender<()>, Receiver<()>)>);
+
+/// A commit's place in `CommitSlots`, given up on drop.
+struct CommitSlot<'slots>(&'slots CommitSlots);
+
+/// Syncs the database at `SyncPolicy::Interval`.
+///
+/// The thread stops when the last `Db` holding this drops.
+#[derive(Debug)]
+struct Flusher {
+    _stop: std::sync::mpsc::Sender<()>,
+}
+
+/// Compacts the history of trees under `DbConfig::compaction_policy`,
+/// and those requesting it under `DbConfig::max_log_bytes`.
+///
+/// The thread stops when the last `Db` holding this drops.
+#[derive(Debug)]
+struct Compactor {
+    _stop: std::sync::mpsc::Sender<()>,
+}
+
 #[derive(Clone, Debug)]
 pub struct ReadView {
     inner: bdb::ViewReader,
 }
 
+/// The view shared by `Db::read_view_stale`.
+#[derive(Debug)]
+struct StaleView {
+    created: Instant,
+    /// The most staleness any caller given the view accepted
+    max_staleness: Duration,
+    view: ReadView,
+}
+
 pub struct WriteTree<'batch> {
     tree: String,
+    prefix: Vec<u8>,
     batch: &'batch WriteBatch,
 }
 
 pub struct ReadTree<'view> {
     tree: String,
+    prefix: Vec<u8>,
     view: &'view ReadView,
 }
 
 pub struct Cursor {
     inner: bdb::Cursor,
+    prefix: Vec<u8>,
+}
+
+pub struct RangeIter {
+    cursor: Cursor,
+    end: Option<Vec<u8>>,
+}
+
+pub struct CursorStream {
+    inner: BoxStream<'static, Result<(Vec<u8>, Vec<u8>)>>,
+}
+
+pub struct SyncCursor {
+    view: bdb::ViewReader,
+    tree: String,
+    /// The last key returned
+    after: Option<Key>,
 }
 
 impl Db {
     pub async fn open(config: DbConfig) -> Result<Db> {
-        let (tree_logs, commit_log) = make_logs(&config)?;
+        Ok(Db::open_with_summary(config).await?.0)
+    }
 
-        let db = bdb::Db::new(tree_logs, commit_log);
-        db.init().await?;
+    pub async fn open_with_summary(config: DbConfig) -> Result<(Db, RecoverySummary)> {
+        config.validate()?;
+        let (tree_logs, commit_log, fs_thread, log_numbers) = make_logs(&config).await?;
+        let next_log_number = log_numbers.values().max().map_or(1, |n| n + 1);
+
+        let tree_configs = config.trees.iter().map(|tree| {
+            (tree.clone(), tree_config(&config, tree))
+        }).collect();
+
+        let db = bdb::Db::with_tree_configs(tree_logs, commit_log, tree_configs);
+        let summary = match config.recovery_concurrency {
+            0 => db.init().await?,
+            n => db.init_concurrently(n).await?,
+        };
 
         let dir_handle = if cfg!(unix) {
             if let Some(ref dir) = config.dir {
@@ -71,144 +295,896 @@ impl Db {
             None
         };
 
-        let trees = Arc::new(config.trees.clone());
+        let tree_metadata = match config.dir {
+            Some(ref dir) => TreeMetadata::load(dir)?,
+            None => TreeMetadata::in_memory(),
+        };
+
+        let trees = Arc::new(RwLock::new(config.trees.clone()));
+        let commit_slots = CommitSlots::new(config.max_inflight_commits);
 
-        return Ok(Db {
+        let mut db = Db {
             config: Arc::new(config),
             inner: Arc::new(db),
             trees,
             dir_handle,
-        });
+            fs_thread,
+            log_numbers: Arc::new(Mutex::new(log_numbers)),
+            next_log_number: Arc::new(AtomicU64::new(next_log_number)),
+            stale_view: Arc::new(Mutex::new(None)),
+            index_hooks: Arc::new(RwLock::new(IndexHooks::default())),
+            tree_metadata: Arc::new(tree_metadata),
+            commit_signals: CommitSignals::default(),
+            commit_slots,
+            flusher: None,
+            compactor: None,
+        };
+
+        if let SyncPolicy::Interval(interval) = db.config.sync_policy {
+            if !db.config.read_only {
+                db.flusher = Some(Arc::new(Flusher::start(db.clone(), interval)?));
+            }
+        }
+
+        // Trees requesting compaction are compacted
+        // even without a policy of when else to
+        let policy = match (db.config.compaction_policy, db.config.max_log_bytes) {
+            (Some(policy), _) => Some(policy),
+            (None, Some(_)) => Some(CompactionPolicy {
+                min_live_ratio: 0.0,
+                log_growth_bytes: 0,
+                ..CompactionPolicy::default()
+            }),
+            (None, None) => None,
+        };
+        if let Some(policy) = policy {
+            db.compactor = Some(Arc::new(Compactor::start(db.clone(), policy)?));
+        }
 
-        fn make_logs(config: &DbConfig) -> Result<(BTreeMap<String, Log<Command>>, Log<CommitCommand>)> {
+        return Ok((db, summary));
+
+        async fn make_logs(config: &DbConfig) -> Result<Logs> {
 
             if let Some(ref dir) = config.dir {
-                // FIXME: async create dir
-                fs::create_dir_all(dir)?;
+                let fs_thread = if config.read_only {
+                    FsThread::start_read_only(config.max_open_files)?
+                } else {
+                    // FIXME: async create dir
+                    fs::create_dir_all(dir)?;
+                    FsThread::start_with_max_open_files(config.max_open_files)?
+                };
+                let fs_thread = Arc::new(fs_thread);
 
-                let fs_thread = Arc::new(FsThread::start()?);
+                assert!(!config.trees.iter().any(|t| t == COMMIT_LOG_NAME));
+                let commit_log = dir.join(format!("{}.toml", COMMIT_LOG_NAME));
 
+                let log_numbers = current_log_numbers(config, dir, &commit_log, &fs_thread).await?;
                 let tree_logs = config.trees.iter()
                     .map(|tree| {
-                        let path = dir.join(format!("{}.toml", tree));
+                        let path = log_path(dir, tree, log_numbers[tree]);
                         (tree.clone(), path)
                     });
 
-                assert!(!config.trees.iter().any(|t| t == "commits"));
-                let commit_log = dir.join(format!("commits.toml"));
+                let read_ahead_bytes = read_ahead_bytes(config);
 
                 let tree_logs = tree_logs.into_iter()
                     .map(|(tree, path)| {
-                        (tree, Log::new(simple_log_file::create(path, fs_thread.clone())))
+                        let log_file = simple_log_file::create_with_format(
+                            path, fs_thread.clone(), config.log_buffer_bytes, read_ahead_bytes,
+                            config.record_format);
+                        (tree, Log::new(log_file))
                     }).collect();
 
-                let commit_log = Log::new(simple_log_file::create(commit_log, fs_thread.clone()));
+                let commit_log = simple_log_file::create_with_format(
+                    commit_log, fs_thread.clone(), 0, read_ahead_bytes, config.record_format);
+                let commit_log = Log::new(commit_log);
 
-                Ok((tree_logs, commit_log))
+                Ok((tree_logs, commit_log, Some(fs_thread), log_numbers))
             } else {
                 let tree_logs = config.trees.iter().cloned().map(|tree| {
                     (tree, Log::new(mem_log_file::create()))
                 }).collect();
 
                 let commit_log = Log::new(mem_log_file::create());
+                let log_numbers = config.trees.iter().map(|tree| (tree.clone(), 0)).collect();
 
-                Ok((tree_logs, commit_log))
+                Ok((tree_logs, commit_log, None, log_numbers))
             }
         }
     }
 
     pub async fn write_batch(&self) -> Result<WriteBatch> {
+        if self.config.read_only {
+            bail!("database is read-only");
+        }
+
         let batch = self.inner.batch();
-        for tree in &*self.trees {
+        let trees = Arc::new(batch.tree_names());
+        for tree in &*trees {
             batch.open(tree).await?;
         }
+        let read_view = if self.config.detect_write_conflicts {
+            let view = self.inner.view();
+            batch.detect_conflicts(view.commit_limit());
+            Some(view)
+        } else {
+            None
+        };
         Ok(WriteBatch {
             inner: batch,
-            trees: self.trees.clone(),
+            db: self.inner.clone(),
+            trees,
+            save_point_depth: AtomicUsize::new(0),
+            save_points_diverged: AtomicBool::new(false),
+            index_hooks: self.index_hooks.read().expect("lock").clone(),
+            changes: Mutex::new(vec![]),
+            save_point_changes: Mutex::new(vec![]),
+            bytes: AtomicUsize::new(0),
+            max_batch_bytes: self.config.max_batch_bytes,
+            commit_signals: self.commit_signals.clone(),
+            commit_slots: self.commit_slots.clone(),
+            durability: match self.config.sync_policy {
+                SyncPolicy::PerCommit => Durability::Fsync,
+                SyncPolicy::Never | SyncPolicy::Interval(_) => Durability::None,
+            },
+            read_view,
             closed: false,
         })
     }
 
+    pub fn tree_names(&self) -> Vec<String> {
+        self.trees.read().expect("lock").clone()
+    }
+
+    pub fn has_tree(&self, tree: &str) -> bool {
+        self.inner.has_tree(tree)
+    }
+
+    pub async fn create_tree(&self, tree: &str) -> Result<()> {
+        if self.config.read_only {
+            bail!("database is read-only");
+        }
+        check_tree_name(tree)?;
+        if self.has_tree(tree) {
+            bail!("tree {:?} already exists", tree);
+        }
+
+        let log = match (&self.config.dir, &self.fs_thread) {
+            (Some(dir), Some(fs_thread)) => {
+                let path = log_path(dir, tree, 0);
+                // FIXME: async fs
+                if !find_log_numbers(dir, &[tree.to_string()])?.is_empty() {
+                    bail!("tree {:?} already has a log in {}", tree, dir.display());
+                }
+                let log_file = simple_log_file::create_with_format(
+                    path, fs_thread.clone(), self.config.log_buffer_bytes,
+                    read_ahead_bytes(&self.config), self.config.record_format);
+                Log::new(log_file)
+            },
+            _ => Log::new(mem_log_file::create()),
+        };
+
+        self.inner.create_tree(tree, log, tree_config(&self.config, tree)).await?;
+        self.log_numbers.lock().expect("lock").insert(tree.to_string(), 0);
+        self.trees.write().expect("lock").push(tree.to_string());
+
+        Ok(())
+    }
+
+    pub async fn drop_tree(&self, tree: &str) -> Result<()> {
+        if self.config.read_only {
+            bail!("database is read-only");
+        }
+
+        self.release_stale_view();
+
+        self.inner.drop_tree(tree).await?;
+        self.trees.write().expect("lock").retain(|t| t != tree);
+        self.log_numbers.lock().expect("lock").remove(tree);
+        self.tree_metadata.remove_tree(tree)?;
+
+        Ok(())
+    }
+
+    pub async fn set_tree_metadata(&self, tree: &str, key: &str, value: &str) -> Result<()> {
+        if self.config.read_only {
+            bail!("database is read-only");
+        }
+        check_tree(&self.tree_names(), tree)?;
+        self.tree_metadata.set(tree, key, value)
+    }
+
+    pub fn get_tree_metadata(&self, tree: &str, key: &str) -> Result<Option<String>> {
+        check_tree(&self.tree_names(), tree)?;
+        Ok(self.tree_metadata.get(tree, key))
+    }
+
+    pub fn register_index_hook(&self, tree: &str, hook: impl Fn(&[Change]) -> Vec<IndexWrite> + Send + Sync + 'static) -> Result<()> {
+        check_tree(&self.tree_names(), tree)?;
+        let hook: IndexHook = Arc::new(hook);
+        self.index_hooks.write().expect("lock").register(tree, hook);
+        Ok(())
+    }
+
     pub fn read_view(&self) -> ReadView {
         ReadView {
             inner: self.inner.view(),
         }
     }
 
+    pub fn read_view_stale(&self, max_staleness: Duration) -> ReadView {
+        let mut stale_view = self.stale_view.lock().expect("lock");
+        let now = Instant::now();
+        match &mut *stale_view {
+            Some(stale) if now.duration_since(stale.created) <= max_staleness => {
+                stale.max_staleness = stale.max_staleness.max(max_staleness);
+                stale.view.clone()
+            },
+            _ => {
+                let view = self.read_view();
+                let replaced = stale_view.replace(StaleView {
+                    created: now,
+                    max_staleness,
+                    view: view.clone(),
+                });
+                drop(stale_view);
+                drop(replaced);
+                view
+            },
+        }
+    }
+
+    /// Drops the view shared by `read_view_stale`
+    /// once it is older than any caller accepted,
+    /// so it no longer pins its commit or its trees.
+    fn release_stale_view(&self) {
+        let expired = {
+            let mut stale_view = self.stale_view.lock().expect("lock");
+            match &*stale_view {
+                Some(stale) if stale.created.elapsed() > stale.max_staleness => stale_view.take(),
+                _ => None,
+            }
+        };
+        // Dropping the view may delete logs, so not under the lock
+        drop(expired);
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.inner.stats()
+    }
+
+    pub async fn log_extent(&self, tree: &str) -> Result<(u64, u64)> {
+        self.inner.log_extent(tree).await
+    }
+
+    pub async fn tree_stats(&self, tree: &str) -> Result<TreeStorageStats> {
+        self.inner.tree_storage_stats(tree).await
+    }
+
+    pub fn collapse_range(&self, tree: &str, start_key: &[u8], end_key: &[u8]) -> Result<CollapseReport> {
+        self.release_stale_view();
+        self.inner.collapse_range(tree, Key::from_slice(start_key)..Key::from_slice(end_key))
+    }
+
+    pub fn collapse_key(&self, tree: &str, key: &[u8]) -> Result<()> {
+        self.release_stale_view();
+        self.inner.collapse_key(tree, &Key::from_slice(key))?;
+        Ok(())
+    }
+
+    pub fn pending_compactions(&self) -> Vec<String> {
+        self.inner.pending_compactions()
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.inner.compaction_stats()
+    }
+
+    pub async fn compact_to(&self, dest_dir: &Path) -> Result<()> {
+        // FIXME: async fs
+        if dest_dir.exists() && fs::read_dir(dest_dir)?.next().is_some() {
+            bail!("compaction destination {} is not empty", dest_dir.display());
+        }
+
+        // Everything visible in one view becomes a single commit,
+        // including trees created since opening
+        let view = self.read_view();
+        let trees = view.inner.tree_names();
+        let dest = Db::open(DbConfig {
+            dir: Some(dest_dir.to_owned()),
+            trees: trees.clone(),
+            read_only: false,
+            ..(*self.config).clone()
+        }).await?;
+        let batch = dest.write_batch().await?;
+
+        let r: Result<()> = async {
+            for tree in trees.iter() {
+                let write_tree = batch.tree(tree)?;
+                let mut cursor = view.tree(tree)?.cursor();
+                cursor.seek_first();
+                // Each value is written before the next is read
+                while cursor.valid() {
+                    let key = cursor.key();
+                    let value = cursor.value().await?;
+                    write_tree.write(&key, &value).await?;
+                    cursor.next();
+                }
+            }
+
+            batch.commit().await?;
+
+            Ok(())
+        }.await;
+
+        if r.is_err() {
+            batch.abort().await;
+        }
+        batch.close().await;
+        r?;
+
+        dest.sync().await?;
+        dest.tree_metadata.replace(self.tree_metadata.snapshot())?;
+
+        Ok(())
+    }
+
+    pub async fn open_checkpoint_readonly(dir: &Path) -> Result<ReadView> {
+        // The checkpoint's trees are the logs beside its commit log
+        let commit_log = format!("{}.toml", COMMIT_LOG_NAME);
+        let mut has_commit_log = false;
+        let mut trees = vec![];
+        // FIXME async fs
+        for entry in fs::read_dir(dir)? {
+            let file_name = entry?.file_name();
+            let file_name = match file_name.to_str() {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            if file_name == commit_log {
+                has_commit_log = true;
+            } else if let Some((tree, _)) = parse_log_name(file_name) {
+                trees.push(tree.to_string());
+            }
+        }
+        if !has_commit_log {
+            bail!("{} is not a database", dir.display());
+        }
+        // A replaced tree may have more than one log
+        trees.sort();
+        trees.dedup();
+
+        let db = Db::open(DbConfig {
+            read_only: true,
+            ..DbConfig::new(dir, trees)
+        }).await?;
+
+        Ok(db.read_view())
+    }
+
+    pub async fn tail_to(&self, path: &Path) -> Result<()> {
+        let commits = self.commit_signals.subscribe();
+
+        // In-memory databases have no thread for file I/O of their own
+        let fs_thread = match &self.fs_thread {
+            Some(fs_thread) => fs_thread.clone(),
+            None => Arc::new(FsThread::start()?),
+        };
+
+        let binlog_path = path.to_path_buf();
+        let (mut position, mut file) = fs_thread.run(move |_| -> Result<_> {
+            let position = Position::read(&binlog_path)?;
+            let mut file = fs::OpenOptions::new().create(true).write(true).truncate(false).open(&binlog_path)?;
+            // Drop whatever was written after the last recorded position
+            file.set_len(position.length)?;
+            file.seek(SeekFrom::End(0))?;
+            Ok((position, file))
+        }).await?;
+
+        // Keeps the history from the position on
+        let mut pinned = self.inner.view();
+        loop {
+            let latest = self.inner.view();
+            let next_commit = position.next_commit;
+            if latest.commit_limit().0 < next_commit {
+                bail!("binlog {} is ahead of the database", path.display());
+            }
+            let end = latest.commit_limit().0.min(next_commit.saturating_add(binlog::MAX_WINDOW_COMMITS));
+            if end == next_commit {
+                // Never errs, since the db keeps the sender
+                commits.recv().await?;
+                continue;
+            }
+
+            let window = latest.at(Commit(end));
+            let records = binlog::records_since(&window, &window.tree_names(), Commit(next_commit)).await?;
+            let binlog_path = path.to_path_buf();
+            let written = fs_thread.run(move |_| -> Result<_> {
+                let mut writer = BufWriter::new(&mut file);
+                for record in &records {
+                    serde_json::to_writer(&mut writer, record)?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.flush()?;
+                drop(writer);
+                file.sync_data()?;
+
+                let position = Position {
+                    next_commit: end,
+                    length: file.stream_position()?,
+                };
+                position.write(&binlog_path)?;
+                Ok((position, file))
+            }).await?;
+            position = written.0;
+            file = written.1;
+            pinned = window;
+        }
+    }
+
+    pub async fn apply_map(&self, tree: &str, entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Result<u64> {
+        if entries.is_empty() {
+            bail!("no entries to apply");
+        }
+
+        let batch = self.write_batch().await?;
+
+        let r: Result<Option<Commit>> = async {
+            let write_tree = batch.tree(tree)?;
+            for (key, value) in &entries {
+                match value {
+                    Some(value) => write_tree.write(key, value).await?,
+                    None => write_tree.delete(key).await?,
+                }
+            }
+
+            batch.commit_numbered(batch.durability).await
+        }.await;
+
+        if r.is_err() {
+            batch.abort().await;
+        }
+        batch.close().await;
+
+        match r? {
+            Some(commit) => Ok(commit.0),
+            None => bail!("no entries were applied"),
+        }
+    }
+
+    pub async fn replace_tree(&self, tree: &str, entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        if self.config.read_only {
+            bail!("database is read-only");
+        }
+        check_tree(&self.tree_names(), tree)?;
+
+        let _slot = self.commit_slots.acquire().await;
+
+        // The replacement gets a log of its own,
+        // so the old one can be read by older views and deleted after
+        let log_number = self.next_log_number.fetch_add(1, Ordering::SeqCst);
+        let log = match (&self.config.dir, &self.fs_thread) {
+            (Some(dir), Some(fs_thread)) => {
+                let path = log_path(dir, tree, log_number);
+                // The new log must be found after a crash
+                // once the commit replacing the tree is durable
+                let create_path = path.clone();
+                fs_thread.run(move |ctx| -> Result<()> {
+                    ctx.open_append(&create_path)?;
+                    Ok(())
+                }).await?;
+                self.sync_dir()?;
+                let log_file = simple_log_file::create_with_format(
+                    path, fs_thread.clone(), self.config.log_buffer_bytes,
+                    read_ahead_bytes(&self.config), self.config.record_format);
+                Log::new(log_file)
+            },
+            _ => Log::new(mem_log_file::create()),
+        };
+
+        let entries = entries.map(|(key, value)| (Key(key), Value(value)));
+        let commit = self.inner.replace_tree(tree, log, tree_config(&self.config, tree), entries).await?;
+        self.log_numbers.lock().expect("lock").insert(tree.to_string(), log_number);
+        self.inner.record_durable(commit);
+        self.commit_signals.signal();
+        // The old log is deleted once no view needs it
+        self.release_stale_view();
+
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
     pub async fn sync(&self) -> Result<()> {
-        self.inner.sync().await?;
+        self.barrier().await?;
+        Ok(())
+    }
+
+    pub async fn barrier(&self) -> Result<Option<u64>> {
+        let commit = self.inner.barrier().await?;
+        self.sync_dir()?;
+        if let Some(commit) = commit {
+            self.inner.record_durable(commit);
+        }
+
+        Ok(commit.map(|commit| commit.0))
+    }
 
-        // Also need to sync the directory
+    pub fn is_durable(&self, commit: u64) -> bool {
+        self.inner.is_durable(Commit(commit))
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        // Also need to sync the directory,
+        // but only if files were created or removed since the last sync
         if let Some(dir) = &self.dir_handle {
-            // FIXME async
-            dir.sync_all()?;
+            let fs_thread = self.fs_thread.as_ref().expect("fs_thread");
+            if fs_thread.take_dir_dirty() {
+                // FIXME async
+                if let Err(e) = dir.sync_all() {
+                    fs_thread.mark_dir_dirty();
+                    return Err(e.into());
+                }
+                self.inner.record_dir_sync();
+            }
         }
 
         Ok(())
     }
 }
 
+impl CommitSignals {
+    fn subscribe(&self) -> Receiver<()> {
+        let (sender, receiver) = async_channel::bounded(1);
+        self.0.lock().expect("lock").push(sender);
+        receiver
+    }
+
+    fn signal(&self) {
+        self.0.lock().expect("lock").retain(|sender| {
+            !matches!(sender.try_send(()), Err(TrySendError::Closed(())))
+        });
+    }
+}
+
+impl Flusher {
+    /// Starts a thread syncing `db` every `interval`.
+    fn start(db: Db, interval: Duration) -> Result<Flusher> {
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        thread::Builder::new()
+            .name("blocksy3-flusher".to_string())
+            .spawn(move || {
+                // Nothing is ever sent, so this waits until the sender drops
+                while stopped.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                    if db.inner.is_all_durable() {
+                        continue;
+                    }
+                    if let Err(e) = futures::executor::block_on(db.barrier()) {
+                        error!("error syncing database: {}", e);
+                    }
+                }
+            })?;
+
+        Ok(Flusher { _stop: stop })
+    }
+}
+
+impl Compactor {
+    /// Starts a thread checking each tree of `db` against `policy`.
+    fn start(db: Db, policy: CompactionPolicy) -> Result<Compactor> {
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        thread::Builder::new()
+            .name("blocksy3-compactor".to_string())
+            .spawn(move || {
+                // Each tree as of its last compaction
+                let mut marks = BTreeMap::<String, CompactionMark>::new();
+                // Nothing is ever sent, so this waits until the sender drops
+                while stopped.recv_timeout(policy.interval) == Err(RecvTimeoutError::Timeout) {
+                    db.release_stale_view();
+                    let trees = db.tree_names();
+                    marks.retain(|tree, _| trees.contains(tree));
+                    for tree in trees {
+                        match db.inner.compact_if_due(&tree, &policy, marks.get(&tree)) {
+                            Ok(Some(mark)) => {
+                                marks.insert(tree, mark);
+                            },
+                            Ok(None) => { },
+                            // Dropped since listed
+                            Err(e) if matches!(e.downcast_ref(), Some(DbError::UnknownTree(_))) => { },
+                            Err(e) => {
+                                error!("error compacting tree {:?}: {}", tree, e);
+                            },
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Compactor { _stop: stop })
+    }
+}
+
+impl CommitSlots {
+    fn new(max_inflight_commits: usize) -> CommitSlots {
+        match max_inflight_commits {
+            0 => CommitSlots(None),
+            n => CommitSlots(Some(async_channel::bounded(n))),
+        }
+    }
+
+    /// Waits until fewer than the maximum commits are in progress.
+    async fn acquire(&self) -> CommitSlot<'_> {
+        if let Some((sender, _)) = &self.0 {
+            // Never errs, since the slots keep the receiver
+            sender.send(()).await.expect("receiver");
+        }
+        CommitSlot(self)
+    }
+}
+
+impl Drop for CommitSlot<'_> {
+    fn drop(&mut self) {
+        if let Some((_, receiver)) = &(self.0).0 {
+            receiver.try_recv().expect("held slot");
+        }
+    }
+}
+
 impl WriteBatch {
-    pub fn tree<'batch>(&'batch self, tree: &str) -> WriteTree<'batch> {
-        WriteTree {
+    pub fn number(&self) -> Batch {
+        self.inner.number()
+    }
+
+    pub fn tree<'batch>(&'batch self, tree: &str) -> Result<WriteTree<'batch>> {
+        self.tree_ns(tree, &[])
+    }
+
+    pub fn tree_ns<'batch>(&'batch self, tree: &str, prefix: &[u8]) -> Result<WriteTree<'batch>> {
+        check_tree(&self.trees, tree)?;
+        Ok(WriteTree {
             tree: tree.to_string(),
+            prefix: prefix.to_vec(),
             batch: self,
-        }
+        })
     }
 
-    pub async fn push_save_point(&self) -> Result<()> {
-        for tree in self.trees.iter() {
-            self.inner.push_save_point(tree).await?;
+    pub async fn move_key(&self, from_tree: &str, to_tree: &str, key: &[u8]) -> Result<()> {
+        self.move_key_ns(from_tree, to_tree, &[], key).await
+    }
+
+    pub async fn move_key_ns(&self, from_tree: &str, to_tree: &str, prefix: &[u8], key: &[u8]) -> Result<()> {
+        if from_tree == to_tree {
+            bail!("cannot move a key within tree {:?}", from_tree);
         }
+        let (from, to) = (self.tree_ns(from_tree, prefix)?, self.tree_ns(to_tree, prefix)?);
+
+        // Read through the batch so its own earlier writes are moved.
+        // The view pins the committed version until the commit checks it.
+        let view = match &self.read_view {
+            Some(view) => view.clone(),
+            None => self.db.view(),
+        };
+        let value = match self.inner.read(from_tree, &from.key(key), view.commit_limit()).await? {
+            Some(value) => value,
+            None => bail!("move source key does not exist"),
+        };
+        self.inner.expect_unchanged(from_tree, from.key(key), view.commit_limit())?;
+
+        from.delete(key).await?;
+        to.write(key, &value.0).await?;
 
         Ok(())
     }
 
-    pub async fn pop_save_point(&self) -> Result<()> {
-        for tree in self.trees.iter() {
-            self.inner.pop_save_point(tree).await?;
-        }
+    pub async fn push_save_point(&self) -> Result<()> {
+        self.save_point_op(SavePointOp::Push).await?;
+        self.save_point_depth.fetch_add(1, Ordering::SeqCst);
+        let changes = self.changes.lock().expect("lock").len();
+        self.save_point_changes.lock().expect("lock").push(changes);
+        Ok(())
+    }
 
+    pub async fn pop_save_point(&self) -> Result<()> {
+        self.save_point_op(SavePointOp::Pop).await?;
+        self.save_point_depth.fetch_sub(1, Ordering::SeqCst);
+        self.save_point_changes.lock().expect("lock").pop();
         Ok(())
     }
 
     pub async fn rollback_save_point(&self) -> Result<()> {
-        for tree in self.trees.iter() {
-            self.inner.rollback_save_point(tree).await?;
+        self.save_point_op(SavePointOp::Rollback).await?;
+        self.save_point_depth.fetch_sub(1, Ordering::SeqCst);
+        let changes = self.save_point_changes.lock().expect("lock").pop().expect("save point");
+        self.changes.lock().expect("lock").truncate(changes);
+        Ok(())
+    }
+
+    /// Applies a save point operation to every tree.
+    ///
+    /// If this fails after some trees have applied it,
+    /// the trees' save point stacks no longer match,
+    /// and the batch can only be aborted.
+    async fn save_point_op(&self, op: SavePointOp) -> Result<()> {
+        if self.save_points_diverged.load(Ordering::SeqCst) {
+            bail!(SAVE_POINTS_DIVERGED);
+        }
+
+        match op {
+            SavePointOp::Push => { },
+            SavePointOp::Pop | SavePointOp::Rollback => {
+                if self.save_point_depth.load(Ordering::SeqCst) == 0 {
+                    bail!("no save point");
+                }
+            }
+        }
+
+        for (i, tree) in self.trees.iter().enumerate() {
+            let r = match op {
+                SavePointOp::Push => self.inner.push_save_point(tree).await,
+                SavePointOp::Pop => self.inner.pop_save_point(tree).await,
+                SavePointOp::Rollback => self.inner.rollback_save_point(tree).await,
+            };
+            if let Err(e) = r {
+                if i > 0 {
+                    self.save_points_diverged.store(true, Ordering::SeqCst);
+                }
+                return Err(e);
+            }
         }
 
         Ok(())
     }
 
+    pub fn len(&self) -> usize {
+        self.inner.staged_ops()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub async fn commit(&self) -> Result<()> {
+        self.commit_numbered(self.durability).await?;
+        Ok(())
+    }
+
+    pub async fn commit_with(&self, durability: Durability) -> Result<()> {
+        self.commit_numbered(durability).await?;
+        Ok(())
+    }
+
+    /// Returns the commit number, or `None` if nothing was committed.
+    async fn commit_numbered(&self, durability: Durability) -> Result<Option<Commit>> {
+        // Committing nothing is a no-op,
+        // and does not consume a commit number.
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        let start = Instant::now();
+        let _slot = self.commit_slots.acquire().await;
+
+        if self.save_points_diverged.load(Ordering::SeqCst) {
+            bail!(SAVE_POINTS_DIVERGED);
+        }
+
+        // Hooks see old values that stay current until the batch commits
+        let commit_lock = if self.has_hook_changes() {
+            let commit_lock = self.inner.lock_commits().await;
+            self.run_index_hooks().await?;
+            Some(commit_lock)
+        } else {
+            None
+        };
+
         let batch_commit = self.inner.new_batch_commit_number();
         let mut error = None;
         for tree in self.trees.iter() {
-            if error.is_none() {
-                let r = self.inner.ready_commit(tree, batch_commit).await;
-                if let Err(e) = r {
-                    error = Some(e);
-                }
-            } else {
-                let r = self.inner.abort_commit(tree, batch_commit).await;
-                if let Err(e) = r {
-                    error!("error aborting batch commit {} for batch {} for tree {}: {}",
-                           batch_commit.0, self.inner.number().0, tree, e);
-                }
+            let r = self.inner.ready_commit(tree, batch_commit).await;
+            if let Err(e) = r {
+                error = Some(e);
+                break;
             }
         }
 
         if let Some(e) = error {
+            // Invalidate any ready-commit records already written
+            self.abort_batch_commit(batch_commit).await;
             return Err(e);
         }
 
-        self.inner.commit(batch_commit).await?;
+        let commit = match commit_lock {
+            Some(commit_lock) => self.inner.commit_locked(commit_lock, batch_commit, durability).await?,
+            None => self.inner.commit_with(batch_commit, durability).await?,
+        };
+        self.db.record_commit_latency(start.elapsed());
+        if durability == Durability::Fsync {
+            // Syncing the batch's tree logs and the commit log
+            // also made every earlier commit durable.
+            self.db.record_durable(commit);
+        }
+
+        self.commit_signals.signal();
+
+        // Committed changes are never passed to hooks again
+        self.changes.lock().expect("lock").clear();
+        for changes in self.save_point_changes.lock().expect("lock").iter_mut() {
+            *changes = 0;
+        }
+
+        Ok(Some(commit))
+    }
+
+    fn add_bytes(&self, bytes: usize) -> Result<()> {
+        if self.max_batch_bytes == 0 {
+            return Ok(());
+        }
+        self.bytes.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+            total.checked_add(bytes).filter(|&total| total <= self.max_batch_bytes)
+        }).map_err(|_| DbError::BatchTooLarge)?;
+        Ok(())
+    }
+
+    fn record_change(&self, tree: &str, key: Key, value: Option<Value>) {
+        if !self.index_hooks.get(tree).is_empty() {
+            self.changes.lock().expect("lock").push((tree.to_string(), key, value));
+        }
+    }
+
+    fn has_hook_changes(&self) -> bool {
+        !self.index_hooks.is_empty() && !self.changes.lock().expect("lock").is_empty()
+    }
+
+    /// Adds the writes derived by index hooks to the batch.
+    ///
+    /// Old values are read as of the latest commit,
+    /// so this must be called under the commit lock.
+    async fn run_index_hooks(&self) -> Result<()> {
+        // The last change to each key wins
+        let mut changes: BTreeMap<String, BTreeMap<Key, Option<Value>>> = BTreeMap::new();
+        for (tree, key, value) in self.changes.lock().expect("lock").iter() {
+            changes.entry(tree.clone()).or_default().insert(key.clone(), value.clone());
+        }
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let view = self.db.view();
+        for (tree, tree_changes) in changes {
+            let mut hook_changes = vec![];
+            for (key, new_value) in tree_changes {
+                let old_value = view.read(&tree, &key).await?;
+                hook_changes.push(Change {
+                    key: key.0,
+                    old_value: old_value.map(|v| v.0),
+                    new_value: new_value.map(|v| v.0),
+                });
+            }
+
+            for hook in self.index_hooks.get(&tree) {
+                for write in hook(&hook_changes) {
+                    if !self.trees.contains(&write.tree) {
+                        bail!("index hook wrote to unknown tree {:?}", write.tree);
+                    }
+                    let key = Key(write.key);
+                    match write.value {
+                        Some(value) => self.inner.write(&write.tree, key, Value(value)).await?,
+                        None => self.inner.delete(&write.tree, key).await?,
+                    }
+                }
+            }
+        }
 
         Ok(())
     }
 
     pub async fn abort(&self) {
         let batch_commit = self.inner.new_batch_commit_number();
+        self.abort_batch_commit(batch_commit).await;
+    }
+
+    async fn abort_batch_commit(&self, batch_commit: BatchCommit) {
         for tree in self.trees.iter() {
             let r = self.inner.abort_commit(tree, batch_commit).await;
             if let Err(e) = r {
@@ -241,51 +1217,192 @@ impl Drop for WriteBatch {
 }
 
 impl ReadView {
-    pub fn tree<'view>(&'view self, tree: &str) -> ReadTree<'view> {
-        ReadTree {
+    pub fn tree<'view>(&'view self, tree: &str) -> Result<ReadTree<'view>> {
+        self.tree_ns(tree, &[])
+    }
+
+    pub fn tree_ns<'view>(&'view self, tree: &str, prefix: &[u8]) -> Result<ReadTree<'view>> {
+        self.check_tree(tree)?;
+        Ok(ReadTree {
             tree: tree.to_string(),
+            prefix: prefix.to_vec(),
             view: self,
+        })
+    }
+
+    pub fn sync_cursor(&self, tree: &str) -> Result<SyncCursor> {
+        self.check_tree(tree)?;
+        Ok(SyncCursor {
+            view: self.inner.clone(),
+            tree: tree.to_string(),
+            after: None,
+        })
+    }
+
+    fn check_tree(&self, tree: &str) -> Result<()> {
+        if !self.inner.has_tree(tree) {
+            return Err(DbError::UnknownTree(tree.to_string()).into());
         }
+        Ok(())
     }
 }
 
 impl<'batch> WriteTree<'batch> {
     pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        Ok(self.batch.inner.write(&self.tree, Key::from_slice(key), Value::from_slice(value)).await?)
+        self.batch.add_bytes(key.len().saturating_add(value.len()))?;
+        self.batch.inner.write(&self.tree, self.key(key), Value::from_slice(value)).await?;
+        self.batch.record_change(&self.tree, self.key(key), Some(Value::from_slice(value)));
+        Ok(())
     }
 
     pub async fn delete(&self, key: &[u8]) -> Result<()> {
-        Ok(self.batch.inner.delete(&self.tree, Key::from_slice(key)).await?)
+        self.batch.add_bytes(key.len())?;
+        self.batch.inner.delete(&self.tree, self.key(key)).await?;
+        self.batch.record_change(&self.tree, self.key(key), None);
+        Ok(())
+    }
+
+    pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // The view pins the committed values read beneath the batch
+        let view = match &self.batch.read_view {
+            Some(view) => view.clone(),
+            None => self.batch.db.view(),
+        };
+        Ok(self.batch.inner.read(&self.tree, &self.key(key), view.commit_limit()).await?
+           .map(|value| value.0))
     }
 
     pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> {
-        Ok(self.batch.inner.delete_range(&self.tree, Key::from_slice(start_key), Key::from_slice(end_key)).await?)
+        self.batch.add_bytes(start_key.len().saturating_add(end_key.len()))?;
+        self.batch.inner.delete_range(&self.tree, self.key(start_key), self.key(end_key)).await
+    }
+
+    pub async fn copy(&self, src_key: &[u8], dst_key: &[u8]) -> Result<()> {
+        self.batch.add_bytes(src_key.len().saturating_add(dst_key.len()))?;
+        self.batch.inner.copy(&self.tree, self.key(src_key), self.key(dst_key)).await
+    }
+
+    pub async fn append_raw(&self, record: &[u8]) -> Result<()> {
+        if !self.prefix.is_empty() {
+            bail!("raw commands cannot be appended to a namespace");
+        }
+        self.batch.add_bytes(record.len())?;
+        self.batch.inner.append_raw(&self.tree, record).await?;
+        Ok(())
+    }
+
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.batch.add_bytes(key.len().saturating_add(operand.len()))?;
+        self.batch.inner.merge(&self.tree, self.key(key), Value::from_slice(operand)).await
+    }
+
+    pub async fn increment(&self, key: &[u8], delta: i64) -> Result<()> {
+        let operand = Value(merge::encode_counter(delta));
+        self.batch.add_bytes(key.len().saturating_add(operand.0.len()))?;
+        self.batch.inner.merge(&self.tree, self.key(key), operand).await
+    }
+
+    fn key(&self, key: &[u8]) -> Key {
+        prefixed_key(&self.prefix, key)
     }
 }
 
 impl<'view> ReadTree<'view> {
     pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        Ok(self.view.inner.read(&self.tree, &Key::from_slice(key)).await?
-           .map(|v| v.0.clone()))
+        Ok(self.view.inner.read(&self.tree, &self.key(key)).await?
+           .map(|v| v.0))
+    }
+
+    pub async fn read_arc(&self, key: &[u8]) -> Result<Option<Arc<[u8]>>> {
+        self.view.inner.read_arc(&self.tree, &self.key(key)).await
+    }
+
+    pub async fn read_counter(&self, key: &[u8]) -> Result<i64> {
+        let value = self.view.inner.read(&self.tree, &self.key(key)).await?;
+        match value {
+            Some(value) => Ok(merge::decode_counter(&value.0)?),
+            None => Ok(0),
+        }
+    }
+
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        Ok(self.view.inner.history(&self.tree, &self.key(key)).await?
+           .into_iter()
+           .map(|(commit, value)| (commit.0, value.map(|v| v.0)))
+           .collect())
+    }
+
+    pub fn changed_keys(&self, low: u64, high: u64) -> Vec<Vec<u8>> {
+        self.view.inner.changed_keys(&self.tree, Commit(low), Commit(high), &self.prefix)
+            .expect("tree checked by ReadView::tree")
+            .into_iter()
+            .map(|key| key.0[self.prefix.len()..].to_vec())
+            .collect()
+    }
+
+    pub async fn stats(&self) -> Result<TreeStats> {
+        self.view.inner.scan_tree_stats(&self.tree, &self.prefix).await
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        self.view.inner.count(&self.tree, &self.prefix)
+    }
+
+    pub async fn read_range(&self, start_key: &[u8], end_key: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut keys = vec![];
+        let mut cursor = self.cursor();
+        cursor.seek_key(start_key);
+        while cursor.valid() && keys.len() < limit {
+            let key = cursor.key();
+            if key.as_slice() >= end_key {
+                break;
+            }
+            keys.push(key);
+            cursor.next();
+        }
+
+        let values = future::try_join_all(keys.iter().map(|key| self.read(key))).await?;
+        Ok(keys.into_iter().zip(values)
+           .filter_map(|(key, value)| Some((key, value?)))
+           .collect())
     }
 
     pub fn cursor(&self) -> Cursor {
         Cursor {
-            inner: self.view.inner.cursor(&self.tree),
+            inner: self.view.inner.cursor(&self.tree).expect("tree checked by ReadView::tree"),
+            prefix: self.prefix.clone(),
         }
     }
+
+    pub fn range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> RangeIter {
+        let mut cursor = self.cursor();
+        match start {
+            Some(start) => cursor.seek_key(start),
+            None => cursor.seek_first(),
+        }
+        RangeIter {
+            cursor,
+            end: end.map(|end| end.to_vec()),
+        }
+    }
+
+    fn key(&self, key: &[u8]) -> Key {
+        prefixed_key(&self.prefix, key)
+    }
 }
 
 impl Cursor {
     pub fn valid(&self) -> bool {
-        self.inner.valid()
+        self.inner.valid() && self.inner.key().0.starts_with(&self.prefix)
     }
 
     pub fn key(&self) -> Vec<u8> {
-        self.inner.key().0.clone()
+        assert!(self.valid());
+        self.inner.key().0[self.prefix.len()..].to_vec()
     }
 
     pub async fn value(&mut self) -> Result<Vec<u8>> {
+        assert!(self.valid());
         Ok(self.inner.value().await?.0.clone())
     }
 
@@ -298,19 +1415,326 @@ impl Cursor {
     }
 
     pub fn seek_first(&mut self) {
-        self.inner.seek_first()
+        if self.prefix.is_empty() {
+            self.inner.seek_first()
+        } else {
+            self.inner.seek_key(Key::from_slice(&self.prefix))
+        }
     }
 
     pub fn seek_last(&mut self) {
-        self.inner.seek_last()
+        match prefix_successor(&self.prefix) {
+            Some(successor) => {
+                self.inner.seek_key_rev(successor.clone());
+                if self.inner.valid() && self.inner.key() == successor {
+                    self.inner.prev();
+                }
+            },
+            None => {
+                self.inner.seek_last()
+            },
+        }
     }
 
     pub fn seek_key(&mut self, key: &[u8]) {
-        self.inner.seek_key(Key::from_slice(key))
+        self.inner.seek_key(prefixed_key(&self.prefix, key))
     }
 
     pub fn seek_key_rev(&mut self, key: &[u8]) {
-        self.inner.seek_key_rev(Key::from_slice(key))
+        self.inner.seek_key_rev(prefixed_key(&self.prefix, key))
+    }
+
+    pub fn seek_prefix(&mut self, prefix: &[u8]) {
+        // The first key starting with a prefix is the first key at or after it
+        self.seek_key(prefix)
+    }
+
+    pub fn valid_for_prefix(&self, prefix: &[u8]) -> bool {
+        self.valid() && self.inner.key().0[self.prefix.len()..].starts_with(prefix)
+    }
+
+    pub fn into_stream(self) -> CursorStream {
+        let inner = stream::unfold(Some(self), |cursor| async {
+            let mut cursor = cursor?;
+            if !cursor.valid() {
+                return None;
+            }
+            let key = cursor.key();
+            match cursor.value().await {
+                Ok(value) => {
+                    cursor.next();
+                    Some((Ok((key, value)), Some(cursor)))
+                },
+                Err(e) => {
+                    // End the stream after the error
+                    Some((Err(e), None))
+                },
+            }
+        });
+
+        CursorStream {
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+impl RangeIter {
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if !self.cursor.valid() {
+            return Ok(None);
+        }
+        let key = self.cursor.key();
+        if let Some(end) = &self.end {
+            if key >= *end {
+                return Ok(None);
+            }
+        }
+        let value = self.cursor.value().await?;
+        self.cursor.next();
+        Ok(Some((key, value)))
+    }
+}
+
+impl SyncCursor {
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Option<Vec<u8>>)>> {
+        let key = match self.view.next_versioned_key(&self.tree, self.after.as_ref())? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        // Deleted keys, including those in deleted ranges, read as `None`
+        let value = self.view.read(&self.tree, &key).await?;
+        self.after = Some(key.clone());
+        Ok(Some((key.0, value.map(|value| value.0))))
+    }
+}
+
+impl Stream for CursorStream {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum SavePointOp {
+    Push,
+    Pop,
+    Rollback,
+}
+
+static COMMIT_LOG_NAME: &str = "commits";
+
+static SAVE_POINTS_DIVERGED: &str = "save point failed for some trees; batch must be aborted";
+
+fn check_tree(trees: &[String], tree: &str) -> Result<()> {
+    if !trees.iter().any(|t| t == tree) {
+        return Err(DbError::UnknownTree(tree.to_string()).into());
+    }
+    Ok(())
+}
+
+fn check_tree_name(tree: &str) -> Result<()> {
+    if tree.is_empty() {
+        bail!("tree name is empty");
+    }
+    if tree == COMMIT_LOG_NAME {
+        bail!("tree name {:?} is reserved", tree);
+    }
+    if tree.contains(&['/', '\\'][..]) {
+        bail!("tree name {:?} contains a path separator", tree);
+    }
+    Ok(())
+}
+
+fn tree_config(config: &DbConfig, tree: &str) -> TreeConfig {
+    TreeConfig {
+        value_cache_entries: config.value_cache_entries,
+        max_log_bytes: config.max_log_bytes,
+        validation: config.validation,
+        value_transform: config.value_transform.clone(),
+        max_key_bytes: max_key_bytes(config),
+        max_value_bytes: match config.max_value_bytes {
+            0 => tree::DEFAULT_MAX_VALUE_BYTES,
+            n => n,
+        },
+        append_only: config.append_only_trees.iter().any(|t| t == tree),
+        merge_fn: match config.merge_operators.get(tree) {
+            Some(operator) => merge::from_operator(operator.clone()),
+            None => merge::counter(),
+        },
+        record_format: config.record_format,
+        compression_min_bytes: if config.compressed_trees.iter().any(|t| t == tree) {
+            match config.compression_min_bytes {
+                0 => Some(compression::DEFAULT_MIN_BYTES),
+                n => Some(n),
+            }
+        } else {
+            None
+        },
+        max_history_per_key: config.max_history_per_key,
+    }
+}
+
+/// The path of a tree's log.
+///
+/// A tree's first log is numbered 0,
+/// and each log replacing it gets a higher number.
+fn log_path(dir: &Path, tree: &str, log_number: u64) -> PathBuf {
+    match log_number {
+        0 => dir.join(format!("{}.toml", tree)),
+        n => dir.join(format!("{}.toml.{}", tree, n)),
+    }
+}
+
+/// The tree and log number of a tree's log file.
+fn parse_log_name(file_name: &str) -> Option<(&str, u64)> {
+    if let Some(tree) = file_name.strip_suffix(".toml") {
+        return Some((tree, 0));
+    }
+    let (name, log_number) = file_name.rsplit_once('.')?;
+    let tree = name.strip_suffix(".toml")?;
+    if !log_number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match log_number.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(n) => Some((tree, n)),
+    }
+}
+
+/// The numbers of the logs of each of `trees` in `dir`, in order.
+fn find_log_numbers(dir: &Path, trees: &[String]) -> Result<BTreeMap<String, Vec<u64>>> {
+    let mut log_numbers: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    // FIXME async fs
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let (tree, log_number) = match file_name.to_str().and_then(parse_log_name) {
+            Some(log_name) => log_name,
+            None => continue,
+        };
+        if trees.iter().any(|t| t == tree) {
+            log_numbers.entry(tree.to_string()).or_default().push(log_number);
+        }
     }
+    for numbers in log_numbers.values_mut() {
+        numbers.sort_unstable();
+    }
+    Ok(log_numbers)
 }
 
+/// The number of each tree's current log in `dir`.
+///
+/// A crash while replacing a tree can leave its old log and its new one.
+/// A new log is current only if its first batch, the replacing one,
+/// was committed, and the logs that aren't current are deleted.
+async fn current_log_numbers(config: &DbConfig, dir: &Path, commit_log: &Path,
+                             fs_thread: &Arc<FsThread>) -> Result<BTreeMap<String, u64>> {
+    // The replacing batch of each log newer than a tree's oldest
+    let mut replacements = vec![];
+    let found = find_log_numbers(dir, &config.trees)?;
+    for (tree, numbers) in found.iter() {
+        for &log_number in numbers.iter().skip(1) {
+            let log_file = simple_log_file::create_with_format(
+                log_path(dir, tree, log_number), fs_thread.clone(), 0,
+                read_ahead_bytes(config), config.record_format);
+            let log: Log<Command> = Log::new(log_file);
+            let first = match log.is_empty().await? {
+                true => None,
+                false => log.replay().next().await,
+            };
+            let batch = match first {
+                Some(Ok((cmd, _))) => Some(cmd.batch()),
+                Some(Err(e)) if torn_tail(&e).is_some() => None,
+                Some(Err(e)) => return Err(e),
+                None => None,
+            };
+            replacements.push((tree, log_number, batch));
+        }
+    }
+
+    let mut committed = BTreeSet::new();
+    if !replacements.is_empty() && commit_log.exists() {
+        let batches: BTreeSet<Batch> = replacements.iter().filter_map(|(_, _, batch)| *batch).collect();
+        let log_file = simple_log_file::create_with_format(
+            commit_log.to_owned(), fs_thread.clone(), 0,
+            read_ahead_bytes(config), config.record_format);
+        let commit_log = CommitLog::new(Log::new(log_file));
+        let mut commits = commit_log.replay();
+        while let Some(cmd) = commits.next().await {
+            match cmd {
+                Ok(cmd) if batches.contains(&cmd.batch) => {
+                    committed.insert(cmd.batch);
+                },
+                Ok(_) => { },
+                // A torn commit was never acknowledged
+                Err(e) if torn_tail(&e).is_some() => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    let mut log_numbers: BTreeMap<String, u64> = found.iter()
+        .map(|(tree, numbers)| (tree.clone(), numbers[0]))
+        .collect();
+    for (tree, log_number, batch) in replacements {
+        if matches!(batch, Some(batch) if committed.contains(&batch)) {
+            log_numbers.insert(tree.clone(), log_number);
+        }
+    }
+
+    for (tree, numbers) in found.iter() {
+        for &log_number in numbers.iter() {
+            if log_number == log_numbers[tree] || config.read_only {
+                continue;
+            }
+            let path = log_path(dir, tree, log_number);
+            fs_thread.run(move |ctx| -> Result<()> {
+                ctx.close(&path);
+                fs::remove_file(&path)?;
+                Ok(())
+            }).await?;
+            fs_thread.mark_dir_dirty();
+        }
+    }
+
+    for tree in config.trees.iter() {
+        log_numbers.entry(tree.clone()).or_insert(0);
+    }
+
+    Ok(log_numbers)
+}
+
+fn read_ahead_bytes(config: &DbConfig) -> usize {
+    match config.log_read_ahead_bytes {
+        0 => simple_log_file::DEFAULT_READ_AHEAD_BYTES,
+        n => n,
+    }
+}
+
+fn max_key_bytes(config: &DbConfig) -> usize {
+    match config.max_key_bytes {
+        0 => tree::DEFAULT_MAX_KEY_BYTES,
+        n => n,
+    }
+}
+
+fn prefixed_key(prefix: &[u8], key: &[u8]) -> Key {
+    let mut prefixed = Vec::with_capacity(prefix.len() + key.len());
+    prefixed.extend_from_slice(prefix);
+    prefixed.extend_from_slice(key);
+    Key(prefixed)
+}
+
+/// The smallest key greater than every key starting with `prefix`,
+/// or `None` if there is no such key.
+fn prefix_successor(prefix: &[u8]) -> Option<Key> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last != u8::MAX {
+            successor.push(last + 1);
+            return Some(Key(successor));
+        }
+    }
+    None
+}
@@ -1,22 +1,46 @@
-use log::error;
+use log::{error, warn};
 use std::sync::Arc;
+use std::convert::TryFrom;
 // Using parking lot specifically to avoid poisoning on the delete_range assertion
 use parking_lot::{RwLock as PlRwLock, RwLockWriteGuard as PlRwLockWriteGuard};
-use std::sync::{RwLock, RwLockWriteGuard};
+use std::sync::{Mutex, RwLock, RwLockWriteGuard};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeSet;
 use std::collections::btree_map::{BTreeMap, Entry};
-use std::ops::Range;
+use std::ops::{Bound, Range};
 use crate::types::{Key, Address, Commit};
+use crate::validation::Validation;
+#[cfg(feature = "lock-stats")]
+use crate::stats::LockHolds;
+#[cfg(feature = "lock-stats")]
+use std::time::Instant;
+
+/// Keys with a version, and range deletes, each with its commit.
+pub type Changes = (Vec<(Commit, Key)>, Vec<(Commit, Range<Key>)>);
 
 /// An index from keys to addresses in a log.
 pub struct Index {
     state: Arc<PlRwLock<IndexState>>,
     maybe_next_commit: AtomicU64,
+    validation: Validation,
+    /// The versions to keep per key; 0 keeps every version.
+    max_history: usize,
+    #[cfg(feature = "lock-stats")]
+    write_lock_holds: LockHolds,
 }
 
 struct IndexState {
     keymap: BTreeMap<Key, Arc<Node>>,
     range_deletes: Vec<(Commit, Range<Key>, BatchIdx)>,
+    /// The number of versions across every key.
+    versions: AtomicU64,
+    /// The keys with more than one version,
+    /// which are all that collapsing can shrink.
+    collapsible: Mutex<BTreeSet<Key>>,
+    /// The keys each commit gave a version, while that version is kept.
+    changes: Mutex<BTreeMap<Commit, BTreeSet<Key>>>,
+    /// One past the newest commit with a discarded version.
+    discarded_below: AtomicU64,
 }
 
 #[derive(Debug)]
@@ -34,7 +58,7 @@ struct BatchIdx(pub u32);
 
 pub struct Cursor {
     commit_limit: Commit,
-    current: Option<(Arc<Node>, Address)>,
+    current: Option<(Arc<Node>, Lookup)>,
     state: Arc<PlRwLock<IndexState>>,
 }
 
@@ -43,6 +67,14 @@ pub struct Writer<'index> {
     maybe_next_commit: &'index AtomicU64,
     state: PlRwLockWriteGuard<'index, IndexState>,
     batch_index: BatchIdx,
+    max_history: usize,
+    /// Set to trim history as keys are written,
+    /// keeping what reads at or after this limit need.
+    oldest_read: Option<Commit>,
+    #[cfg(feature = "lock-stats")]
+    write_lock_holds: &'index LockHolds,
+    #[cfg(feature = "lock-stats")]
+    locked_at: Instant,
 }
 
 #[derive(Copy, Clone)]
@@ -50,6 +82,25 @@ pub struct Writer<'index> {
 pub enum ReadValue {
     Written(Address),
     Deleted(Address),
+    Merged(Address),
+    /// A write to another key, copied to this one.
+    CopiedWrite(Address),
+    /// A merge into another key, copied to this one.
+    CopiedMerge(Address),
+}
+
+/// The log records that make up a key's value.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Eq, PartialEq)]
+pub struct Lookup {
+    /// The most recent full write, if any.
+    pub base: Option<Address>,
+    /// Merge operands to apply to `base`, oldest first.
+    pub merges: Vec<Address>,
+    /// How many of the records, from `base` on,
+    /// belong to another key and were copied to this one.
+    pub copied: usize,
 }
 
 impl Index {
@@ -58,19 +109,240 @@ impl Index {
             state: Arc::new(PlRwLock::new(IndexState {
                 keymap: BTreeMap::new(),
                 range_deletes: Vec::new(),
+                versions: AtomicU64::new(0),
+                collapsible: Mutex::new(BTreeSet::new()),
+                changes: Mutex::new(BTreeMap::new()),
+                discarded_below: AtomicU64::new(0),
             })),
             maybe_next_commit: AtomicU64::new(0),
+            validation: Validation::default(),
+            max_history: 0,
+            #[cfg(feature = "lock-stats")]
+            write_lock_holds: LockHolds::default(),
+        }
+    }
+
+    pub fn with_validation(mut self, validation: Validation) -> Index {
+        self.validation = validation;
+        self
+    }
+
+    /// Limits each key to `max_history` versions,
+    /// trimmed by writers given the oldest read.
+    /// 0 keeps every version.
+    pub fn with_max_history(mut self, max_history: usize) -> Index {
+        self.max_history = max_history;
+        self
+    }
+
+    /// One past the last commit written to the index.
+    pub fn next_commit(&self) -> Commit {
+        Commit(self.maybe_next_commit.load(Ordering::SeqCst))
+    }
+
+    /// Accounts for commits before `next_commit` that wrote nothing here.
+    pub fn advance_to(&self, next_commit: Commit) {
+        self.maybe_next_commit.fetch_max(next_commit.0, Ordering::SeqCst);
+    }
+
+    fn check_commit_limit(&self, commit_limit: Commit) {
+        if self.validation != Validation::Off {
+            assert!(commit_limit <= Commit(self.maybe_next_commit.load(Ordering::SeqCst)));
         }
     }
 
-    pub fn read(&self, commit_limit: Commit, key: &Key) -> Option<Address> {
-        assert!(commit_limit <= Commit(self.maybe_next_commit.load(Ordering::SeqCst)));
+    pub fn read(&self, commit_limit: Commit, key: &Key) -> Option<Lookup> {
+        self.check_commit_limit(commit_limit);
         let state = self.state.read();
         state.key_true_value(commit_limit, key)
     }
 
+    /// Every version of `key` committed before `commit_limit`, oldest first.
+    ///
+    /// When a key is written more than once in the same commit only
+    /// the last of those writes is reported.
+    pub fn history(&self, commit_limit: Commit, key: &Key) -> Vec<(Commit, ReadValue)> {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        state.history_within_commit_limit(commit_limit, key)
+    }
+
+    /// The newest commit before `commit_limit` that wrote, deleted,
+    /// merged into, or range deleted `key`.
+    pub fn latest_commit(&self, commit_limit: Commit, key: &Key) -> Option<Commit> {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        state.latest_commit(commit_limit, key)
+    }
+
+    /// Discards the versions of `key` that no read at or after
+    /// `commit_limit` can observe.
+    ///
+    /// Returns the number of versions discarded.
+    pub fn collapse(&self, commit_limit: Commit, key: &Key) -> usize {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        let node = match state.keymap.get(key) {
+            Some(node) => node,
+            None => return 0,
+        };
+        let mut history = node.history.write().expect("lock");
+
+        let oldest_needed = oldest_needed(&history, commit_limit);
+        let discarded: Vec<_> = history.drain(..oldest_needed).collect();
+        state.discarded(key, &history, &discarded);
+        oldest_needed
+    }
+
+    /// The keys in `range` with more than one version.
+    pub fn collapsible_keys_in_range(&self, range: Range<Key>) -> Vec<Key> {
+        if range.start >= range.end {
+            return vec![];
+        }
+        let state = self.state.read();
+        let collapsible = state.collapsible.lock().expect("lock");
+        collapsible.range(range).cloned().collect()
+    }
+
+    /// Every key with more than one version.
+    pub fn collapsible_keys(&self) -> Vec<Key> {
+        let state = self.state.read();
+        let collapsible = state.collapsible.lock().expect("lock");
+        collapsible.iter().cloned().collect()
+    }
+
+    /// The number of keys, and of versions across all of them.
+    pub fn version_counts(&self) -> (u64, u64) {
+        let state = self.state.read();
+        (
+            u64::try_from(state.keymap.len()).expect("u64"),
+            state.versions.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Keys starting with `prefix` whose newest change
+    /// before `commit_limit` was committed after `low`,
+    /// counting range deletes as changing every key they cover.
+    pub fn changed_keys(&self, commit_limit: Commit, low: Commit, prefix: &[u8]) -> Vec<Key> {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        let window = Commit(low.0.saturating_add(1))..commit_limit;
+        if window.start >= window.end {
+            return vec![];
+        }
+
+        // Keys given versions in the window
+        let mut keys: BTreeSet<Key> = {
+            let changes = state.changes.lock().expect("lock");
+            changes.range(window.clone())
+                .flat_map(|(_, keys)| keys.iter())
+                .filter(|key| key.0.starts_with(prefix))
+                .cloned()
+                .collect()
+        };
+
+        // Keys in ranges deleted in the window,
+        // which are logged in commit order
+        let start = state.range_deletes.partition_point(|(commit, _, _)| *commit < window.start);
+        let range_deletes = state.range_deletes[start..].iter()
+            .take_while(|(commit, _, _)| *commit < window.end);
+        for (_, range, _) in range_deletes {
+            let from = range.start.clone().max(Key(prefix.to_vec()));
+            if from >= range.end {
+                continue;
+            }
+            keys.extend(state.keymap.range(from..range.end.clone())
+                        .map(|(key, _)| key)
+                        .take_while(|key| key.0.starts_with(prefix))
+                        .cloned());
+        }
+
+        // Only those not changed again before the commit limit
+        keys.into_iter()
+            .filter(|key| matches!(state.latest_commit(commit_limit, key), Some(latest) if latest > low))
+            .collect()
+    }
+
+    /// Every key with a version committed before `commit_limit`
+    /// and starting with `prefix`, and its value,
+    /// or `None` if it has been deleted.
+    pub fn entries(&self, commit_limit: Commit, prefix: &[u8]) -> Vec<(Key, Option<Lookup>)> {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        let start = Key(prefix.to_vec());
+        state.keymap.range(start..)
+            .take_while(|(key, _)| key.0.starts_with(prefix))
+            .filter(|(_, node)| {
+                let history = node.history.read().expect("lock");
+                history.iter().any(|(commit, _, _)| *commit < commit_limit)
+            })
+            .map(|(key, node)| (key.clone(), state.node_true_value(commit_limit, node)))
+            .collect()
+    }
+
+    /// The first key after `after`, or the first key if `None`,
+    /// with a version committed before `commit_limit`,
+    /// whether it is live or deleted.
+    pub fn next_versioned_key(&self, commit_limit: Commit, after: Option<&Key>) -> Option<Key> {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        let mut range = match after {
+            Some(after) => state.keymap.range((Bound::Excluded(after), Bound::Unbounded)),
+            None => state.keymap.range::<Key, _>(..),
+        };
+        range
+            .find(|(_, node)| {
+                let history = node.history.read().expect("lock");
+                history.iter().any(|(commit, _, _)| *commit < commit_limit)
+            })
+            .map(|(key, _)| key.clone())
+    }
+
+    /// The number of keys starting with `prefix`
+    /// that are live before `commit_limit`.
+    pub fn count(&self, commit_limit: Commit, prefix: &[u8]) -> usize {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        let start = Key(prefix.to_vec());
+        state.keymap.range(start..)
+            .take_while(|(key, _)| key.0.starts_with(prefix))
+            .filter(|(_, node)| state.node_true_value(commit_limit, node).is_some())
+            .count()
+    }
+
+    /// The keys with a version, and the range deletes,
+    /// committed in `[low, commit_limit)`, each with its commit,
+    /// ordered by commit.
+    ///
+    /// Returns `None` if versions committed from `low` on
+    /// have been discarded from history.
+    pub fn changes_since(&self, commit_limit: Commit, low: Commit) -> Option<Changes> {
+        self.check_commit_limit(commit_limit);
+        let state = self.state.read();
+        if low < Commit(state.discarded_below.load(Ordering::Relaxed)) {
+            return None;
+        }
+        if low >= commit_limit {
+            return Some((vec![], vec![]));
+        }
+
+        let changes = state.changes.lock().expect("lock");
+        let keys = changes.range(low..commit_limit)
+            .flat_map(|(commit, keys)| keys.iter().map(move |key| (*commit, key.clone())))
+            .collect();
+
+        // Range deletes are logged in commit order
+        let start = state.range_deletes.partition_point(|(commit, _, _)| *commit < low);
+        let ranges = state.range_deletes[start..].iter()
+            .take_while(|(commit, _, _)| *commit < commit_limit)
+            .map(|(commit, range, _)| (*commit, range.clone()))
+            .collect();
+
+        Some((keys, ranges))
+    }
+
     pub fn cursor(&self, commit_limit: Commit) -> Cursor {
-        assert!(commit_limit <= Commit(self.maybe_next_commit.load(Ordering::SeqCst)));
+        self.check_commit_limit(commit_limit);
         Cursor {
             commit_limit,
             current: None,
@@ -78,15 +350,26 @@ impl Index {
         }
     }
 
-    pub fn writer(&self, commit: Commit) -> Writer {
+    pub fn writer(&self, commit: Commit) -> Writer<'_> {
         assert!(commit >= Commit(self.maybe_next_commit.load(Ordering::SeqCst)));
         Writer {
-            commit: commit,
+            commit,
             maybe_next_commit: &self.maybe_next_commit,
             state: self.state.write(),
             batch_index: BatchIdx(0),
+            max_history: self.max_history,
+            oldest_read: None,
+            #[cfg(feature = "lock-stats")]
+            write_lock_holds: &self.write_lock_holds,
+            #[cfg(feature = "lock-stats")]
+            locked_at: Instant::now(),
         }
     }
+
+    #[cfg(feature = "lock-stats")]
+    pub fn write_lock_holds(&self) -> &LockHolds {
+        &self.write_lock_holds
+    }
 }
 
 impl Drop for Index {
@@ -110,22 +393,76 @@ impl Drop for Index {
 }
 
 impl IndexState {
-    fn point_query(&self, commit_limit: Commit, key: &Key) -> Option<(Commit, ReadValue, BatchIdx)> {
-        if let Some(node) = self.keymap.get(key) {
-            self.node_value_within_commit_limit(commit_limit, node)
-        } else {
-            None
+    fn latest_commit(&self, commit_limit: Commit, key: &Key) -> Option<Commit> {
+        let range_deleted = self.range_delete_query(commit_limit, key)
+            .map(|(commit, _)| commit);
+        let versioned = self.keymap.get(key).and_then(|node| {
+            let history = node.history.read().expect("lock");
+            history.iter().rev()
+                .map(|(commit, _, _)| *commit)
+                .find(|commit| *commit < commit_limit)
+        });
+        versioned.max(range_deleted)
+    }
+
+    /// Accounts for the `discarded` versions of `key`,
+    /// leaving `history`.
+    fn discarded(&self, key: &Key,
+                 history: &[(Commit, ReadValue, BatchIdx)],
+                 discarded: &[(Commit, ReadValue, BatchIdx)]) {
+        self.versions.fetch_sub(u64::try_from(discarded.len()).expect("u64"), Ordering::Relaxed);
+        if history.len() <= 1 {
+            self.collapsible.lock().expect("lock").remove(key);
+        }
+
+        let mut changes = self.changes.lock().expect("lock");
+        for (commit, _, _) in discarded {
+            // A commit can write a key more than once
+            if history.iter().any(|(kept, _, _)| kept == commit) {
+                continue;
+            }
+            if let Entry::Occupied(mut keys) = changes.entry(*commit) {
+                keys.get_mut().remove(key);
+                if keys.get().is_empty() {
+                    keys.remove();
+                }
+            }
+            let below = commit.0.checked_add(1).expect("overflow");
+            self.discarded_below.fetch_max(below, Ordering::Relaxed);
         }
     }
 
-    fn node_value_within_commit_limit(&self, commit_limit: Commit, node: &Node) -> Option<(Commit, ReadValue, BatchIdx)> {
+    fn history_within_commit_limit(&self, commit_limit: Commit, key: &Key) -> Vec<(Commit, ReadValue)> {
+        let node = match self.keymap.get(key) {
+            Some(node) => node,
+            None => return vec![],
+        };
         let history = node.history.read().expect("lock");
-        let mut rev_iter = history.iter().rev();
-        let most_recent = rev_iter.find(|(commit, _, _)| *commit < commit_limit);
-        most_recent.cloned()
+        let mut versions: Vec<(Commit, ReadValue)> = vec![];
+        for (commit, value, _) in history.iter() {
+            if *commit >= commit_limit {
+                continue;
+            }
+            match versions.last_mut() {
+                Some((last_commit, last_value)) if last_commit == commit => {
+                    *last_value = *value;
+                },
+                _ => {
+                    versions.push((*commit, *value));
+                }
+            }
+        }
+        versions
     }
 
+    /// The newest range delete of `key` before `commit_limit`.
+    ///
+    /// Versions of `key` older than it are hidden,
+    /// but later ones, even in the same commit, are not.
     fn range_delete_query(&self, commit_limit: Commit, key: &Key) -> Option<(Commit, BatchIdx)> {
+        // Writers are made in commit order and number their changes in order,
+        // so range deletes are pushed in (commit, batch index) order
+        // and the last match is the newest.
         let mut rev_iter = self.range_deletes.iter().rev();
         let match_ = rev_iter.find(|(commit, range, _)| {
             *commit < commit_limit && range.contains(key)
@@ -133,50 +470,60 @@ impl IndexState {
         match_.map(|(commit, _, batch_idx)| (*commit, *batch_idx))
     }
 
-    fn key_true_value(&self, commit_limit: Commit, key: &Key) -> Option<Address> {
-        let point_result = self.point_query(commit_limit, key);
-        let range_delete_result = self.range_delete_query(commit_limit, key);
-        self.inner_true_value(point_result, range_delete_result)
+    fn key_true_value(&self, commit_limit: Commit, key: &Key) -> Option<Lookup> {
+        if let Some(node) = self.keymap.get(key) {
+            self.node_true_value(commit_limit, node)
+        } else {
+            None
+        }
     }
 
-    fn node_true_value(&self, commit_limit: Commit, node: &Node) -> Option<Address> {
-        let point_result = self.node_value_within_commit_limit(commit_limit, node);
+    fn node_true_value(&self, commit_limit: Commit, node: &Node) -> Option<Lookup> {
         let range_delete_result = self.range_delete_query(commit_limit, &node.key);
-        self.inner_true_value(point_result, range_delete_result)
-    }
+        let history = node.history.read().expect("lock");
 
-    fn inner_true_value(&self,
-                        point_result: Option<(Commit, ReadValue, BatchIdx)>,
-                        range_delete_result: Option<(Commit, BatchIdx)>) -> Option<Address> {
-        match (point_result, range_delete_result) {
-            (None, Some(_)) => {
-                None
-            },
-            (Some((p_commit, ReadValue::Written(addr), p_batch_idx)), Some((rd_commit, rd_batch_idx))) => {
-                if p_commit > rd_commit {
-                    Some(addr)
-                } else if p_commit == rd_commit {
-                    if p_batch_idx > rd_batch_idx {
-                        Some(addr)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+        // Walk back from the most recent version,
+        // collecting merges until the value they apply to.
+        let mut merges = vec![];
+        let mut base = None;
+        let mut copied = 0;
+        for (p_commit, value, p_batch_idx) in history.iter().rev() {
+            if *p_commit >= commit_limit {
+                continue;
+            }
+            if let Some((rd_commit, rd_batch_idx)) = range_delete_result {
+                if (*p_commit, *p_batch_idx) < (rd_commit, rd_batch_idx) {
+                    break;
                 }
-            },
-            (Some((_, ReadValue::Deleted(addr), _)), Some(_)) => {
-                None
-            },
-            (Some((_, ReadValue::Written(addr), _)), None) => {
-                Some(addr)
-            },
-            (Some((_, ReadValue::Deleted(addr), _)), None) => {
-                None
-            },
-            (None, None) => {
-                None
-            },
+            }
+            match value {
+                ReadValue::Written(addr) => {
+                    base = Some(*addr);
+                    break;
+                },
+                ReadValue::Deleted(_) => {
+                    break;
+                },
+                ReadValue::Merged(addr) => {
+                    merges.push(*addr);
+                },
+                ReadValue::CopiedWrite(addr) => {
+                    base = Some(*addr);
+                    copied += 1;
+                    break;
+                },
+                ReadValue::CopiedMerge(addr) => {
+                    merges.push(*addr);
+                    copied += 1;
+                },
+            }
+        }
+
+        if base.is_none() && merges.is_empty() {
+            None
+        } else {
+            merges.reverse();
+            Some(Lookup { base, merges, copied })
         }
     }
 }
@@ -191,9 +538,18 @@ impl Cursor {
         self.current.as_ref().expect("valid").0.key.clone()
     }
 
-    pub fn address(&self) -> Address {
+    pub fn lookup(&self) -> Lookup {
         assert!(self.valid());
-        self.current.as_ref().expect("valid").1
+        self.current.as_ref().expect("valid").1.clone()
+    }
+
+    /// Whether `key` has any version,
+    /// or is in a deleted range,
+    /// before the cursor's commit limit.
+    pub fn touches(&self, key: &Key) -> bool {
+        let state = self.state.read();
+        !state.history_within_commit_limit(self.commit_limit, key).is_empty()
+            || state.range_delete_query(self.commit_limit, key).is_some()
     }
 
     pub fn next(&mut self) {
@@ -250,12 +606,12 @@ impl Cursor {
         self.current = self.first_within_commit_limit(iter);
     }
 
-    fn value_within_commit_limit(&self, node: &Node) -> Option<Address> {
+    fn value_within_commit_limit(&self, node: &Node) -> Option<Lookup> {
         let state = self.state.read();
         state.node_true_value(self.commit_limit, node)
     }
 
-    fn first_within_commit_limit<'a>(&self, iter: impl Iterator<Item = (&'a Key, &'a Arc<Node>)>) -> Option<(Arc<Node>, Address)> {
+    fn first_within_commit_limit<'a>(&self, iter: impl Iterator<Item = (&'a Key, &'a Arc<Node>)>) -> Option<(Arc<Node>, Lookup)> {
         iter.map(|(_, node)| node)
             .filter_map(|node| {
                 self.value_within_commit_limit(node).map(|addr| (node.clone(), addr))
@@ -264,7 +620,29 @@ impl Cursor {
     }
 }
 
+/// The number of versions at the start of `history`
+/// that no read at or after `commit_limit` can observe.
+fn oldest_needed(history: &[(Commit, ReadValue, BatchIdx)], commit_limit: Commit) -> usize {
+    // Reads at `commit_limit` walk back from the newest
+    // version before it to a write or delete,
+    // and never look further.
+    let visible = history.iter()
+        .rposition(|(commit, _, _)| *commit < commit_limit);
+    let oldest_needed = visible.and_then(|visible| {
+        history[..=visible].iter()
+            .rposition(|(_, value, _)| !matches!(value, ReadValue::Merged(_) | ReadValue::CopiedMerge(_)))
+    });
+    oldest_needed.unwrap_or(0)
+}
+
 impl<'index> Writer<'index> {
+    /// Trims the history of each key written
+    /// to the index's `max_history` versions,
+    /// keeping any version reads at `oldest_read` or later need.
+    pub fn trim_history(&mut self, oldest_read: Commit) {
+        self.oldest_read = Some(oldest_read);
+    }
+
     pub fn write(&mut self, key: Key, addr: Address) {
         let batch_idx = self.next_batch_index();
         self.update_value(key, ReadValue::Written(addr), batch_idx)
@@ -275,6 +653,39 @@ impl<'index> Writer<'index> {
         self.update_value(key, ReadValue::Deleted(addr), batch_idx)
     }
 
+    pub fn merge(&mut self, key: Key, addr: Address) {
+        let batch_idx = self.next_batch_index();
+        self.update_value(key, ReadValue::Merged(addr), batch_idx)
+    }
+
+    /// Gives `dst` the value `src` has as of this write,
+    /// including earlier writes in the same commit.
+    ///
+    /// `dst` then refers to the log records of `src`;
+    /// `addr` is the address of the copy record itself.
+    pub fn copy(&mut self, src: &Key, dst: Key, addr: Address) {
+        let next_commit = Commit(self.commit.0.checked_add(1).expect("overflow"));
+        let lookup = self.state.key_true_value(next_commit, src);
+        match lookup {
+            Some(Lookup { base, merges, .. }) => {
+                match base {
+                    Some(base) => {
+                        let batch_idx = self.next_batch_index();
+                        self.update_value(dst.clone(), ReadValue::CopiedWrite(base), batch_idx);
+                    },
+                    None => self.delete(dst.clone(), addr),
+                }
+                for merge in merges {
+                    let batch_idx = self.next_batch_index();
+                    self.update_value(dst.clone(), ReadValue::CopiedMerge(merge), batch_idx);
+                }
+            },
+            None => {
+                self.delete(dst, addr);
+            },
+        }
+    }
+
     pub fn delete_range(&mut self, range: Range<Key>, addr: Address)
     {
         let batch_idx = self.next_batch_index();
@@ -288,6 +699,27 @@ impl<'index> Writer<'index> {
             // key already exists
             let mut history = node.history.write().expect("lock");
             history.push((self.commit, value, batch_idx));
+            self.state.versions.fetch_add(1, Ordering::Relaxed);
+            self.state.changes.lock().expect("lock")
+                .entry(self.commit).or_default()
+                .insert(key.clone());
+            let mut collapsible = self.state.collapsible.lock().expect("lock");
+            if !collapsible.contains(&key) {
+                collapsible.insert(key.clone());
+            }
+            drop(collapsible);
+            if let Some(oldest_read) = self.oldest_read {
+                if self.max_history > 0 && history.len() > self.max_history {
+                    let excess = history.len() - self.max_history;
+                    let trimmable = oldest_needed(&history, oldest_read);
+                    if trimmable < excess {
+                        warn!("keeping {} versions of a key beyond the limit of {} for an open read view",
+                              excess - trimmable, self.max_history);
+                    }
+                    let discarded: Vec<_> = history.drain(..excess.min(trimmable)).collect();
+                    self.state.discarded(&key, &history, &discarded);
+                }
+            }
             new_node = None;
         } else if let Some((_, next)) = self.state.keymap.range(key.clone()..).next() {
             // next key exists
@@ -304,7 +736,7 @@ impl<'index> Writer<'index> {
             }
             *next_prev = Some(new.clone());
             new_node = Some(new);
-        } else if let Some((_, prev)) = self.state.keymap.range(..=key.clone()).rev().next() {
+        } else if let Some((_, prev)) = self.state.keymap.range(..=key.clone()).next_back() {
             // prev key exists
             let mut prev_next = prev.next.write().expect("lock");
             let new = Arc::new(Node {
@@ -331,6 +763,10 @@ impl<'index> Writer<'index> {
             new_node = Some(new);
         }
         if let Some(new_node) = new_node {
+            self.state.versions.fetch_add(1, Ordering::Relaxed);
+            self.state.changes.lock().expect("lock")
+                .entry(self.commit).or_default()
+                .insert(key.clone());
             self.state.keymap.insert(key, new_node);
         }
     }
@@ -346,5 +782,8 @@ impl<'index> Drop for Writer<'index> {
     fn drop(&mut self) {
         let next_commit = self.commit.0.checked_add(1).expect("overflow");
         self.maybe_next_commit.store(next_commit, Ordering::SeqCst);
+
+        #[cfg(feature = "lock-stats")]
+        self.write_lock_holds.record(self.locked_at.elapsed());
     }
 }
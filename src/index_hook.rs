@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A key changed by a committing batch.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Change {
+    pub key: Vec<u8>,
+    /// The value before the batch commits, or `None` if absent.
+    pub old_value: Option<Vec<u8>>,
+    /// The value written by the batch, or `None` if deleted.
+    pub new_value: Option<Vec<u8>>,
+}
+
+/// A write derived by an index hook, committed with the batch.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexWrite {
+    pub tree: String,
+    pub key: Vec<u8>,
+    /// The value to write, or `None` to delete the key.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Derives index writes from a batch's changes to one tree.
+pub type IndexHook = Arc<dyn Fn(&[Change]) -> Vec<IndexWrite> + Send + Sync>;
+
+/// The index hooks registered for each tree.
+#[derive(Clone, Default)]
+pub struct IndexHooks {
+    hooks: BTreeMap<String, Vec<IndexHook>>,
+}
+
+impl IndexHooks {
+    pub fn register(&mut self, tree: &str, hook: IndexHook) {
+        self.hooks.entry(tree.to_string()).or_default().push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn get(&self, tree: &str) -> &[IndexHook] {
+        self.hooks.get(tree).map(|hooks| &hooks[..]).unwrap_or(&[])
+    }
+}
+
+impl fmt::Debug for IndexHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.hooks.iter().map(|(tree, hooks)| (tree, hooks.len())))
+            .finish()
+    }
+}
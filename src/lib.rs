@@ -16,6 +16,8 @@ mod imp;
 
 /// A multi-tree database with atomic commits.
 mod basic_db;
+/// Database statistics.
+mod stats;
 
 /// A single key/value tree, composed of a log and index.
 mod tree;
@@ -23,13 +25,31 @@ mod tree;
 mod log;
 /// An in-memory index of the log.
 mod index;
+/// A cache of values read from the log.
+mod value_cache;
 /// Adds committed batches from the log to the index.
 mod batch_player;
 
 /// Commands in a tree's log.
 mod command;
+/// Runtime invariant checking levels.
+mod validation;
+/// Durability levels for commits.
+mod durability;
+/// Transformation of values on their way to and from the log.
+mod value_transform;
+/// Compression of large values in the log.
+mod compression;
+/// Hooks deriving index writes from committed changes.
+mod index_hook;
+/// Merge operators for read-modify-write.
+mod merge;
+/// Policies for compacting trees in the background.
+mod compaction;
 /// Basic key, value, batch, commit definitions.
 mod types;
+/// Typed errors.
+mod error;
 
 /// A logging interface.
 mod log_file;
@@ -42,6 +62,8 @@ mod simple_log_file;
 mod commit_log;
 /// A section of a log file.
 mod frame;
+/// Encodings of log records.
+mod codec;
 /// Off-thread async file I/O.
 mod fs_thread;
 /// Loads a set of trees from logs and commit log.
@@ -49,6 +71,15 @@ mod loader;
 
 /// A tree that compacts other trees.
 mod compacting_tree;
+/// Deferred reclamation of trees still visible to readers,
+/// and tracking of the commits they can read.
+mod epoch;
+/// Durable per-tree metadata.
+mod tree_metadata;
+/// Small files replaced whole.
+mod atomic_file;
+/// An external log of committed changes.
+mod binlog;
 
 /// A simple script language for exercising the database.
 #[doc(hidden)]
@@ -57,12 +88,39 @@ pub mod cmdscript;
 /// Public access to building blocks
 #[doc(hidden)]
 pub mod raw {
+    pub mod basic_db {
+        pub use crate::basic_db::*;
+    }
+    pub mod batch_player {
+        pub use crate::batch_player::*;
+    }
+    pub mod codec {
+        pub use crate::codec::*;
+    }
+    pub mod command {
+        pub use crate::command::*;
+    }
+    pub mod commit_log {
+        pub use crate::commit_log::*;
+    }
+    pub mod compacting_tree {
+        pub use crate::compacting_tree::*;
+    }
+    pub mod epoch {
+        pub use crate::epoch::*;
+    }
     pub mod fs_thread {
         pub use crate::fs_thread::*;
     }
     pub mod log {
         pub use crate::log::*;
     }
+    pub mod log_file {
+        pub use crate::log_file::*;
+    }
+    pub mod mem_log_file {
+        pub use crate::mem_log_file::*;
+    }
     pub mod simple_log_file {
         pub use crate::simple_log_file::*;
     }
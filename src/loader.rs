@@ -1,60 +1,133 @@
 use anyhow::{Result, bail};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use crate::commit_log::{CommitLog, CommitCommand};
+use crate::log::torn_tail;
 use crate::tree::Tree;
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use crate::types::{Batch, BatchCommit, Commit};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// The number of trees to replay at once if not configured.
+pub fn default_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
 
-pub async fn load(commit_log: &CommitLog, trees: &BTreeMap<String, Tree>) -> Result<DbInitState> {
-    if commit_log.is_empty().await? {
-        for tree in trees.values() {
-            tree.skip_init();
+pub async fn load(commit_log: &CommitLog, trees: &BTreeMap<String, Arc<Tree>>,
+                  concurrency: usize) -> Result<DbInitState> {
+    assert!(concurrency > 0);
+
+    let commit_log_is_empty = commit_log.is_empty().await?;
+
+    // Trees added since the database was created
+    // take no part in the batches before they were added,
+    // and those with empty logs were added just now.
+    let mut first_batches = BTreeMap::new();
+    let mut new_trees = vec![];
+    for (tree_name, tree) in trees.iter() {
+        let first_batch = match tree.first_batch().await {
+            Ok(first_batch) => first_batch,
+            // Nothing was ever committed, so nothing logged matters
+            Err(e) if commit_log_is_empty && torn_tail(&e).is_some() => {
+                tree.discard_log().await?;
+                None
+            },
+            Err(e) => {
+                return Err(e.context(format!("reading the first batch of tree {}", tree_name)));
+            },
+        };
+        match first_batch {
+            Some(first_batch) => {
+                first_batches.insert(tree_name, first_batch);
+            },
+            None => {
+                tree.skip_init();
+                new_trees.push(tree);
+            },
         }
+    }
+
+    // Nothing was ever logged. Trees that logged anything without a commit
+    // crashed before committing, and are replayed below so those batches
+    // are aborted and their numbers aren't reused.
+    if commit_log_is_empty && first_batches.is_empty() {
         return Ok(DbInitState {
             next_batch: Batch(0),
             next_batch_commit: BatchCommit(0),
             next_commit: Commit(0),
+            batches_applied: 0,
+            batches_aborted: 0,
         });
     }
 
-    let mut commit_replay_stream = commit_log.replay();
-
-    let mut tree_players: BTreeMap<_, _> = trees.iter().map(|(tree_name, tree)| {
-        (tree_name, tree.init_replayer())
-    }).collect();
+    let mut tree_players: BTreeMap<_, _> = trees.iter()
+        .filter(|(tree_name, _)| first_batches.contains_key(tree_name))
+        .map(|(tree_name, tree)| {
+            (tree_name, tree.init_replayer())
+        }).collect();
 
     let mut max_commit = None;
+    let mut batches_applied = 0;
+
+    let mut commit_replay_stream = if !commit_log_is_empty {
+        commit_log.replay().left_stream()
+    } else {
+        stream::empty().right_stream()
+    };
+
+    // A torn last commit record was never acknowledged,
+    // and is discarded once everything else is read.
+    let mut commit_log_torn_tail = None;
 
     while let Some(next_commit) = commit_replay_stream.next().await {
         log::trace!("next commit {:?}", next_commit);
-        let next_commit = next_commit?;
+        let next_commit = match next_commit {
+            Ok(next_commit) => next_commit,
+            Err(e) => match torn_tail(&e) {
+                Some(address) => {
+                    commit_log_torn_tail = Some(address);
+                    break;
+                },
+                None => return Err(e),
+            },
+        };
 
         // FIXME: If a tree doesn't participate in a batch,
         // then this will not work as expected and eat
         // a bunch of memory.
         // Fix for this is to do ready-commit under its
         // own lock so that it is serialized.
-        for (_, player) in tree_players.iter_mut() {
-            player.replay_commit(next_commit.batch,
-                                 next_commit.batch_commit,
-                                 next_commit.commit).await?;
-        }
+        let replays = tree_players.iter_mut()
+            .filter(|(tree_name, _)| first_batches[*tree_name] <= next_commit.batch)
+            .map(|(_, player)| {
+                player.replay_commit(next_commit.batch,
+                                     next_commit.batch_commit,
+                                     next_commit.commit)
+            });
+        stream::iter(replays)
+            .buffer_unordered(concurrency)
+            .try_collect::<Vec<_>>().await?;
 
         if let Some(max_commit) = max_commit {
-            if !(max_commit < next_commit.commit) {
+            if max_commit >= next_commit.commit {
                 bail!("non-monotonic commit number");
             }
         }
 
         max_commit = Some(next_commit.commit);
+        batches_applied += 1;
     }
 
     let mut max_batch = None;
     let mut max_batch_commit = None;
+    let mut aborted_batches = BTreeSet::new();
 
     for (_, player) in tree_players.iter_mut() {
         let (tree_max_batch, tree_max_batch_commit)
             = player.replay_rest().await?;
+        aborted_batches.extend(player.aborted_batches().iter().copied());
 
         match (max_batch, tree_max_batch) {
             (None, tree_max_batch) => {
@@ -77,18 +150,44 @@ pub async fn load(commit_log: &CommitLog, trees: &BTreeMap<String, Tree>) -> Res
         }
     }
 
+    // Only now that every log has been read are torn tails discarded,
+    // so logs are left as they were if loading fails.
+    if let Some(address) = commit_log_torn_tail {
+        commit_log.truncate(address).await?;
+    }
+    for (_, player) in tree_players.iter_mut() {
+        player.discard_torn_tail().await?;
+    }
+
     for (_, player) in tree_players.into_iter() {
         player.init_success();
     }
 
-    let next_batch = Batch(max_batch.map(|b| b.0.checked_add(1).expect("overflow")).unwrap_or(0));
+    let mut next_batch = Batch(max_batch.map(|b| b.0.checked_add(1).expect("overflow")).unwrap_or(0));
     let next_batch_commit = BatchCommit(max_batch_commit.map(|b| b.0.checked_add(1).expect("overflow")).unwrap_or(0));
     let next_commit = Commit(max_commit.map(|b| b.0.checked_add(1).expect("overflow")).unwrap_or(0));
 
+    // Trees that missed the last commits are read as of them too
+    for tree in trees.values() {
+        tree.advance_to(next_commit);
+    }
+
+    // Each new tree is logged as starting at a batch of its own,
+    // so later opens know which batches it missed.
+    // Without any commits it missed none.
+    if !commit_log_is_empty {
+        for tree in new_trees {
+            tree.start_at_batch(next_batch).await?;
+            next_batch = Batch(next_batch.0.checked_add(1).expect("overflow"));
+        }
+    }
+
     Ok(DbInitState {
         next_batch,
         next_batch_commit,
         next_commit,
+        batches_applied,
+        batches_aborted: u64::try_from(aborted_batches.len()).expect("u64"),
     })
 }
 
@@ -97,4 +196,8 @@ pub struct DbInitState {
     pub next_batch: Batch,
     pub next_batch_commit: BatchCommit,
     pub next_commit: Commit,
+    /// Commits replayed from the commit log.
+    pub batches_applied: u64,
+    /// Batches that logged changes to some tree but never committed.
+    pub batches_aborted: u64,
 }
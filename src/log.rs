@@ -1,26 +1,73 @@
 use serde::{Serialize, Deserialize};
-use anyhow::Result;
+use anyhow::{Result, bail};
 use futures::{stream, Stream, StreamExt};
+use std::fmt;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::warn;
 
 use crate::log_file::LogFile;
+use crate::frame;
 use crate::types::Address;
+use crate::validation::Validation;
 
 pub struct Log<Cmd>
 where Cmd: Serialize + for <'de> Deserialize<'de>
 {
     log_file: Arc<LogFile<Cmd>>,
+    /// Unique among the logs of this process
+    id: u64,
+    /// Bumped each time records are discarded,
+    /// after which addresses may be reused
+    generation: AtomicU64,
+    /// Size in bytes as of the last append
+    size: AtomicU64,
+    validation: Validation,
 }
 
+/// Which log, and which generation of it, a record was read from.
+///
+/// While this is unchanged, the record at an address is unchanged.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LogGeneration {
+    log: u64,
+    generation: u64,
+}
+
+impl LogGeneration {
+    /// Whether this is a later generation of the same log as `other`.
+    pub fn is_newer_than(&self, other: LogGeneration) -> bool {
+        self.log == other.log && self.generation > other.generation
+    }
+}
+
+static NEXT_LOG_ID: AtomicU64 = AtomicU64::new(0);
+
 impl<Cmd> Log<Cmd>
 where Cmd: Serialize + for <'de> Deserialize<'de>
 {
     pub fn new(log_file: LogFile<Cmd>) -> Log<Cmd> {
         Log {
             log_file: Arc::new(log_file),
+            id: NEXT_LOG_ID.fetch_add(1, Ordering::Relaxed),
+            generation: AtomicU64::new(0),
+            size: AtomicU64::new(0),
+            validation: Validation::default(),
         }
     }
 
+    pub fn with_validation(mut self, validation: Validation) -> Log<Cmd> {
+        self.validation = validation;
+        self
+    }
+
+    /// Reads every command in the log, in order.
+    ///
+    /// A record that fails its checksum ends the replay with an error.
+    /// If nothing follows it, the error is a [`TornTail`],
+    /// which whoever knows nothing refers to the record
+    /// can discard with [`Log::truncate`].
+    /// The log itself is never changed.
     pub fn replay(&self) -> impl Stream<Item = Result<(Cmd, Address)>> + Unpin {
         let addr = Address(0);
         let state = Some((self.log_file.clone(), addr));
@@ -29,6 +76,19 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
                 Some((log_file, addr)) => {
                     let cmd = log_file.read_at(addr).await;
                     match cmd {
+                        Err(e) if frame::is_torn(&e) => {
+                            // Only the last record can be an incomplete write
+                            match is_last(&log_file, addr, &e).await {
+                                Ok(true) => {
+                                    Some((Err(e.context(TornTail { address: addr })), None))
+                                },
+                                Ok(false) => {
+                                    let e = e.context(format!("corrupt record at address {} before the end of the log", addr.0));
+                                    Some((Err(e), None))
+                                },
+                                Err(e) => Some((Err(e), None)),
+                            }
+                        },
                         Err(e) => {
                             Some((Err(e), None))
                         },
@@ -49,19 +109,111 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
     }
 
     pub async fn is_empty(&self) -> Result<bool> {
-        Ok(self.log_file.is_empty().await?)
+        self.log_file.is_empty().await
     }
 
     pub async fn append(&self, cmd: Cmd) -> Result<Address> {
-        Ok(self.log_file.append(cmd).await?)
+        // Check the log returns what was written where it says it was
+        let expected = if self.validation == Validation::Paranoid {
+            Some(serde_cbor::to_vec(&cmd)?)
+        } else {
+            None
+        };
+
+        let (addr, size) = self.log_file.append(cmd).await?;
+        self.size.fetch_max(size, Ordering::SeqCst);
+
+        if let Some(expected) = expected {
+            let (actual, _) = self.log_file.read_at(addr).await?;
+            if serde_cbor::to_vec(&actual)? != expected {
+                bail!("log record at address {} does not match the appended record", addr.0);
+            }
+        }
+
+        Ok(addr)
+    }
+
+    /// The size of the log in bytes as of the most recent append,
+    /// or 0 if nothing has been appended since opening.
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// The start and end byte offsets of the live part of the log.
+    ///
+    /// Logs are never truncated at the front,
+    /// so the live part starts at the beginning.
+    /// Unlike `size`, the end is known before anything is appended.
+    pub async fn extent(&self) -> Result<(u64, u64)> {
+        Ok((0, self.log_file.size().await?))
     }
 
     pub async fn read_at(&self, address: Address) -> Result<Cmd> {
-        Ok(self.log_file.read_at(address).await
-           .map(|(cmd, _)| cmd)?)
+        self.log_file.read_at(address).await
+           .map(|(cmd, _)| cmd)
+    }
+
+    /// The current generation of the log.
+    pub fn generation(&self) -> LogGeneration {
+        LogGeneration {
+            log: self.id,
+            generation: self.generation.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Discards the log from `address` on.
+    pub async fn truncate(&self, address: Address) -> Result<()> {
+        warn!("discarding the torn end of a log from address {}", address.0);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.log_file.truncate(address).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.log_file.flush().await
     }
 
     pub async fn sync(&self) -> Result<()> {
-        Ok(self.log_file.sync().await?)
+        self.log_file.sync().await
+    }
+
+    /// Deletes the log in the background.
+    ///
+    /// The log must not be used afterwards.
+    pub fn remove(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.log_file.remove()
+    }
+}
+
+/// The error replaying a log whose last record is torn,
+/// as an append interrupted by a crash leaves it.
+#[derive(Debug)]
+pub struct TornTail {
+    /// Where the torn record starts.
+    pub address: Address,
+}
+
+impl fmt::Display for TornTail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "torn record at the end of the log, at address {}", self.address.0)
+    }
+}
+
+/// Where the torn record starts, if `e` is a [`TornTail`].
+pub fn torn_tail(e: &anyhow::Error) -> Option<Address> {
+    e.downcast_ref::<TornTail>().map(|torn| torn.address)
+}
+
+/// Whether the unreadable record at `addr` is the last in the log.
+async fn is_last<Cmd>(log_file: &LogFile<Cmd>, addr: Address, e: &anyhow::Error) -> Result<bool>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    match e.downcast_ref::<frame::ChecksumMismatch>() {
+        Some(mismatch) => {
+            let end = addr.0.saturating_add(mismatch.frame_bytes);
+            Ok(end >= log_file.size().await?)
+        },
+        // The log ended partway through the record
+        None => Ok(true),
     }
 }
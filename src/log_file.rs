@@ -5,11 +5,25 @@ use crate::types::Address;
 use anyhow::Result;
 use futures::future::BoxFuture;
 
+pub type AppendFn<Cmd> = Box<dyn Fn(Cmd) -> BoxFuture<'static, Result<(Address, u64)>> + Send + Sync>;
+pub type ReadAtFn<Cmd> = Box<dyn Fn(Address) -> BoxFuture<'static, Result<(Cmd, Option<Address>)>> + Send + Sync>;
+
 pub struct LogFile<Cmd> where Cmd: Serialize + for <'de> Deserialize<'de> {
     pub is_empty: Box<dyn Fn() -> BoxFuture<'static, Result<bool>> + Send + Sync>,
-    pub append: Box<dyn Fn(Cmd) -> BoxFuture<'static, Result<Address>> + Send + Sync>,
-    pub read_at: Box<dyn Fn(Address) -> BoxFuture<'static, Result<(Cmd, Option<Address>)>> + Send + Sync>,
-    pub sync: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>
+    /// Returns the address of the appended command, and the size of the log after appending.
+    pub append: AppendFn<Cmd>,
+    pub read_at: ReadAtFn<Cmd>,
+    /// Writes any buffered appends to the OS.
+    pub flush: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    pub sync: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    /// The size of the log in bytes, including buffered appends.
+    pub size: Box<dyn Fn() -> BoxFuture<'static, Result<u64>> + Send + Sync>,
+    /// Discards the command at the address, and everything after it.
+    pub truncate: Box<dyn Fn(Address) -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    /// Deletes the log once operations already started on it are done,
+    /// without waiting for the deletion.
+    /// The log must not be used afterwards.
+    pub remove: Box<dyn Fn() + Send + Sync>,
 }
 
 impl<Cmd> LogFile<Cmd>
@@ -19,7 +33,7 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
         (self.is_empty)().await
     }
 
-    pub async fn append(&self, cmd: Cmd) -> Result<Address> {
+    pub async fn append(&self, cmd: Cmd) -> Result<(Address, u64)> {
         (self.append)(cmd).await
     }
 
@@ -27,8 +41,24 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
         (self.read_at)(addr).await
     }
 
+    pub async fn flush(&self) -> Result<()> {
+        (self.flush)().await
+    }
+
     pub async fn sync(&self) -> Result<()> {
         (self.sync)().await
     }
+
+    pub async fn size(&self) -> Result<u64> {
+        (self.size)().await
+    }
+
+    pub async fn truncate(&self, addr: Address) -> Result<()> {
+        (self.truncate)(addr).await
+    }
+
+    pub fn remove(&self) {
+        (self.remove)()
+    }
 }
 
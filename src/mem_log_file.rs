@@ -3,7 +3,7 @@ use crate::types::Address;
 use anyhow::{Result, anyhow};
 use std::future::Future;
 use std::sync::{Arc, RwLock};
-use crate::log_file::LogFile;
+use crate::log_file::{LogFile, AppendFn, ReadAtFn};
 use crate::fs_thread::FsThread;
 use serde::{Serialize, Deserialize};
 use std::path::PathBuf;
@@ -14,11 +14,15 @@ use crate::frame;
 pub fn create<Cmd>() -> LogFile<Cmd>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
-    let buffers = RwLock::new(vec![]);
+    let buffers = RwLock::new(Buffers { records: vec![], size: 0 });
     let state1 = Arc::new(State { buffers });
     let state2 = state1.clone();
     let state3 = state1.clone();
     let state4 = state1.clone();
+    let state5 = state1.clone();
+    let state6 = state1.clone();
+    let state7 = state1.clone();
+    let state8 = state1.clone();
 
     let is_empty_impl: Box<dyn Fn() -> BoxFuture<'static, Result<bool>> + Send + Sync> = {
         Box::new(move || {
@@ -26,50 +30,86 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
         })
     };
 
-    let append_impl: Box<dyn Fn(Cmd) -> BoxFuture<'static, Result<Address>> + Send + Sync> = {
+    let append_impl: AppendFn<Cmd> = {
         Box::new(move |cmd| {
             Box::pin(append(state2.clone(), cmd))
         })
     };
-    let read_at_impl: Box<dyn Fn(Address) -> BoxFuture<'static, Result<(Cmd, Option<Address>)>> + Send + Sync> = {
+    let read_at_impl: ReadAtFn<Cmd> = {
         Box::new(move |addr| {
             Box::pin(read_at(state3.clone(), addr))
         })
     };
+    let flush_impl: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move || {
+            Box::pin(flush(state5.clone()))
+        })
+    };
     let sync_impl: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync> = {
         Box::new(move || {
             Box::pin(sync(state4.clone()))
         })
     };
 
+    let size_impl: Box<dyn Fn() -> BoxFuture<'static, Result<u64>> + Send + Sync> = {
+        Box::new(move || {
+            Box::pin(size(state6.clone()))
+        })
+    };
+
+    let truncate_impl: Box<dyn Fn(Address) -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move |addr| {
+            Box::pin(truncate(state7.clone(), addr))
+        })
+    };
+
+    let remove_impl: Box<dyn Fn() + Send + Sync> = {
+        Box::new(move || {
+            remove(&state8)
+        })
+    };
+
     LogFile {
         is_empty: is_empty_impl,
         append: append_impl,
         read_at: read_at_impl,
+        flush: flush_impl,
         sync: sync_impl,
+        size: size_impl,
+        truncate: truncate_impl,
+        remove: remove_impl,
     }
 }
 
 struct State {
-    buffers: RwLock<Vec<Buffer>>,
+    buffers: RwLock<Buffers>,
 }
 
+// NB: Addresses are buffer indexes, but sizes are in bytes
+
 type Buffer = Vec<u8>;
 
+struct Buffers {
+    records: Vec<Buffer>,
+    /// The total length of `records`, kept as they change
+    size: u64,
+}
+
 async fn is_empty(state: Arc<State>) -> Result<bool> {
     let buffers = state.buffers.read().expect("lock");
-    Ok(buffers.is_empty())
+    Ok(buffers.records.is_empty())
 }
 
-async fn append<Cmd>(state: Arc<State>, cmd: Cmd) -> Result<Address>
+async fn append<Cmd>(state: Arc<State>, cmd: Cmd) -> Result<(Address, u64)>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
     let bin = serde_cbor::to_vec(&cmd)?;
     let mut buffers = state.buffers.write().expect("lock");
-    buffers.push(bin);
-    let addr = u64::try_from(buffers.len()).expect("u64");
+    buffers.size += u64::try_from(bin.len()).expect("u64");
+    buffers.records.push(bin);
+    let addr = u64::try_from(buffers.records.len()).expect("u64");
     let addr = addr - 1;
-    Ok(Address(addr))
+    Ok((Address(addr), buffers.size))
 }
 
 async fn read_at<Cmd>(state: Arc<State>, addr: Address) -> Result<(Cmd, Option<Address>)>
@@ -77,17 +117,42 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
     let addr = usize::try_from(addr.0).expect("usize");
     let buffers = state.buffers.read().expect("lock");
-    let bin = buffers.get(addr).ok_or_else(|| {
+    let bin = buffers.records.get(addr).ok_or_else(|| {
         anyhow!("no command at address {}", addr)
     })?;
     let cmd = serde_cbor::from_slice(bin)?;
     let next = addr.checked_add(1).expect("overflow");
-    let next = buffers.get(next).map(|_| next);
+    let next = buffers.records.get(next).map(|_| next);
     let next = next.map(|n| u64::try_from(n).expect("u64"));
-    let next = next.map(|n| Address(n));
+    let next = next.map(Address);
     Ok((cmd, next))
 }
 
+async fn size(state: Arc<State>) -> Result<u64> {
+    let buffers = state.buffers.read().expect("lock");
+    Ok(buffers.size)
+}
+
+async fn flush(state: Arc<State>) -> Result<()> {
+    Ok(( /* nop */ ))
+}
+
 async fn sync(state: Arc<State>) -> Result<()> {
     Ok(( /* nop */ ))
 }
+
+fn remove(state: &State) {
+    let mut buffers = state.buffers.write().expect("lock");
+    buffers.records.clear();
+    buffers.size = 0;
+}
+
+async fn truncate(state: Arc<State>, addr: Address) -> Result<()> {
+    let addr = usize::try_from(addr.0).expect("usize");
+    let mut buffers = state.buffers.write().expect("lock");
+    if addr < buffers.records.len() {
+        let removed = buffers.records.drain(addr..).map(|b| b.len()).sum::<usize>();
+        buffers.size -= u64::try_from(removed).expect("u64");
+    }
+    Ok(())
+}
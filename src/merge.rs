@@ -0,0 +1,44 @@
+use anyhow::{Result, bail};
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Combines an existing value with a merge operand.
+///
+/// Called with the key, the value being merged into
+/// (`None` if the key has no value),
+/// and the operand.
+pub type MergeFn = Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// A user-supplied merge operator, set per tree in `DbConfig`.
+pub trait MergeOperator: Debug + Send + Sync {
+    /// Combines `existing`, `None` if the key has no value,
+    /// with `operand`.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub fn from_operator(operator: Arc<dyn MergeOperator>) -> MergeFn {
+    Arc::new(move |key, existing, operand| operator.merge(key, existing, operand))
+}
+
+/// A merge operator that adds little-endian `i64` counters.
+///
+/// Missing values count as zero.
+pub fn counter() -> MergeFn {
+    Arc::new(|_key, existing, operand| {
+        let existing = existing.map(decode_counter).transpose()?.unwrap_or(0);
+        let delta = decode_counter(operand)?;
+        Ok(encode_counter(existing.wrapping_add(delta)))
+    })
+}
+
+pub fn encode_counter(value: i64) -> Vec<u8> {
+    value.to_le_bytes().to_vec()
+}
+
+pub fn decode_counter(bytes: &[u8]) -> Result<i64> {
+    match bytes.try_into() {
+        Ok(bytes) => Ok(i64::from_le_bytes(bytes)),
+        Err(_) => bail!("counter value is {} bytes, expected 8", bytes.len()),
+    }
+}
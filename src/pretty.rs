@@ -1,8 +1,35 @@
 use crate::imp;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub use anyhow::{self, Result};
 
 pub type DbConfig = imp::DbConfig;
+pub type Validation = imp::Validation;
+pub type Durability = imp::Durability;
+pub type SyncPolicy = imp::SyncPolicy;
+pub use imp::ValueTransform;
+pub use imp::MergeOperator;
+pub type CompactionPolicy = imp::CompactionPolicy;
+pub type RecordFormat = imp::RecordFormat;
+pub type DbError = imp::DbError;
+pub type Stats = imp::Stats;
+pub type CollapseReport = imp::CollapseReport;
+pub type CompactionStats = imp::CompactionStats;
+pub type RecoverySummary = imp::RecoverySummary;
+pub type Change = imp::Change;
+pub type IndexWrite = imp::IndexWrite;
+pub type BinlogRecord = imp::BinlogRecord;
+pub type BinlogChange = imp::BinlogChange;
+pub type TreeStats = imp::TreeStats;
+pub type TreeStorageStats = imp::TreeStorageStats;
+pub type LatencyHistogram = imp::LatencyHistogram;
+pub type CursorStream = imp::CursorStream;
+pub type Batch = imp::Batch;
+pub type BatchCommit = imp::BatchCommit;
+pub type Commit = imp::Commit;
 
 #[derive(Clone, Debug)]
 pub struct Db(imp::Db);
@@ -14,37 +41,85 @@ pub struct WriteTree<'batch>(imp::WriteTree<'batch>);
 pub struct ReadView(imp::ReadView);
 pub struct ReadTree<'view>(imp::ReadTree<'view>);
 pub struct Cursor(imp::Cursor);
+pub struct RangeIter(imp::RangeIter);
+pub struct SyncCursor(imp::SyncCursor);
 
 impl Db {
     pub async fn open(config: DbConfig) -> Result<Db> { imp::Db::open(config).await.map(Db) }
+    pub async fn open_with_summary(config: DbConfig) -> Result<(Db, RecoverySummary)> { imp::Db::open_with_summary(config).await.map(|(db, summary)| (Db(db), summary)) }
     pub async fn write_batch(&self) -> Result<WriteBatch> { Ok(WriteBatch(self.0.write_batch().await?)) }
     pub fn read_view(&self) -> ReadView { ReadView(self.0.read_view()) }
+    pub fn tree_names(&self) -> Vec<String> { self.0.tree_names() }
+    pub fn has_tree(&self, tree: &str) -> bool { self.0.has_tree(tree) }
+    pub async fn create_tree(&self, tree: &str) -> Result<()> { self.0.create_tree(tree).await }
+    pub async fn drop_tree(&self, tree: &str) -> Result<()> { self.0.drop_tree(tree).await }
+    pub async fn set_tree_metadata(&self, tree: &str, key: &str, value: &str) -> Result<()> { self.0.set_tree_metadata(tree, key, value).await }
+    pub fn get_tree_metadata(&self, tree: &str, key: &str) -> Result<Option<String>> { self.0.get_tree_metadata(tree, key) }
+    pub fn register_index_hook(&self, tree: &str, hook: impl Fn(&[Change]) -> Vec<IndexWrite> + Send + Sync + 'static) -> Result<()> { self.0.register_index_hook(tree, hook) }
+    pub fn read_view_stale(&self, max_staleness: Duration) -> ReadView { ReadView(self.0.read_view_stale(max_staleness)) }
+    pub fn stats(&self) -> Stats { self.0.stats() }
+    pub fn collapse_range(&self, tree: &str, start_key: &[u8], end_key: &[u8]) -> Result<CollapseReport> { self.0.collapse_range(tree, start_key, end_key) }
+    pub fn collapse_key(&self, tree: &str, key: &[u8]) -> Result<()> { self.0.collapse_key(tree, key) }
+    pub fn pending_compactions(&self) -> Vec<String> { self.0.pending_compactions() }
+    pub fn compaction_stats(&self) -> CompactionStats { self.0.compaction_stats() }
+    pub async fn log_extent(&self, tree: &str) -> Result<(u64, u64)> { self.0.log_extent(tree).await }
+    pub async fn tree_stats(&self, tree: &str) -> Result<TreeStorageStats> { self.0.tree_stats(tree).await }
+    pub async fn apply_map(&self, tree: &str, entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Result<u64> { self.0.apply_map(tree, entries).await }
+    pub async fn compact_to(&self, dest_dir: &Path) -> Result<()> { self.0.compact_to(dest_dir).await }
+    pub async fn open_checkpoint_readonly(dir: &Path) -> Result<ReadView> { imp::Db::open_checkpoint_readonly(dir).await.map(ReadView) }
+    pub async fn tail_to(&self, path: &Path) -> Result<()> { self.0.tail_to(path).await }
+    pub async fn replace_tree(&self, tree: &str, entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> { self.0.replace_tree(tree, entries).await }
+    pub async fn flush(&self) -> Result<()> { self.0.flush().await }
     pub async fn sync(&self) -> Result<()> { self.0.sync().await }
+    pub async fn barrier(&self) -> Result<Option<u64>> { self.0.barrier().await }
+    pub fn is_durable(&self, commit: u64) -> bool { self.0.is_durable(commit) }
 }
 
 impl WriteBatch {
-    pub fn tree<'batch>(&'batch self, tree: &str) -> WriteTree<'batch> { WriteTree(self.0.tree(tree)) }
+    pub fn number(&self) -> Batch { self.0.number() }
+    pub fn tree<'batch>(&'batch self, tree: &str) -> Result<WriteTree<'batch>> { self.0.tree(tree).map(WriteTree) }
+    pub fn tree_ns<'batch>(&'batch self, tree: &str, prefix: &[u8]) -> Result<WriteTree<'batch>> { self.0.tree_ns(tree, prefix).map(WriteTree) }
+    pub async fn move_key(&self, from_tree: &str, to_tree: &str, key: &[u8]) -> Result<()> { self.0.move_key(from_tree, to_tree, key).await }
+    pub async fn move_key_ns(&self, from_tree: &str, to_tree: &str, prefix: &[u8], key: &[u8]) -> Result<()> { self.0.move_key_ns(from_tree, to_tree, prefix, key).await }
     pub async fn push_save_point(&self) -> Result<()> { self.0.push_save_point().await }
     pub async fn pop_save_point(&self) -> Result<()> { self.0.pop_save_point().await }
     pub async fn rollback_save_point(&self) -> Result<()> { self.0.rollback_save_point().await }
+    pub fn len(&self) -> usize { self.0.len() }
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
     pub async fn commit(&self) -> Result<()> { self.0.commit().await }
+    pub async fn commit_with(&self, durability: Durability) -> Result<()> { self.0.commit_with(durability).await }
     pub async fn abort(&self) { self.0.abort().await }
     pub async fn close(self) { self.0.close().await }
 }
 
 impl ReadView {
-    pub fn tree<'view>(&'view self, tree: &str) -> ReadTree<'view> { ReadTree(self.0.tree(tree)) }
+    pub fn tree<'view>(&'view self, tree: &str) -> Result<ReadTree<'view>> { self.0.tree(tree).map(ReadTree) }
+    pub fn tree_ns<'view>(&'view self, tree: &str, prefix: &[u8]) -> Result<ReadTree<'view>> { self.0.tree_ns(tree, prefix).map(ReadTree) }
+    pub fn sync_cursor(&self, tree: &str) -> Result<SyncCursor> { self.0.sync_cursor(tree).map(SyncCursor) }
 }
 
 impl<'batch> WriteTree<'batch> {
     pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> { self.0.write(key, value).await }
     pub async fn delete(&self, key: &[u8]) -> Result<()> { self.0.delete(key).await }
+    pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
     pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> { self.0.delete_range(start_key, end_key).await }
+    pub async fn copy(&self, src_key: &[u8], dst_key: &[u8]) -> Result<()> { self.0.copy(src_key, dst_key).await }
+    pub async fn append_raw(&self, record: &[u8]) -> Result<()> { self.0.append_raw(record).await }
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> { self.0.merge(key, operand).await }
+    pub async fn increment(&self, key: &[u8], delta: i64) -> Result<()> { self.0.increment(key, delta).await }
 }
 
 impl<'view> ReadTree<'view> {
     pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
+    pub async fn read_arc(&self, key: &[u8]) -> Result<Option<Arc<[u8]>>> { self.0.read_arc(key).await }
+    pub async fn read_counter(&self, key: &[u8]) -> Result<i64> { self.0.read_counter(key).await }
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<(u64, Option<Vec<u8>>)>> { self.0.history(key).await }
+    pub fn changed_keys(&self, low: u64, high: u64) -> Vec<Vec<u8>> { self.0.changed_keys(low, high) }
+    pub async fn stats(&self) -> Result<TreeStats> { self.0.stats().await }
+    pub fn count(&self) -> Result<usize> { self.0.count() }
+    pub async fn read_range(&self, start_key: &[u8], end_key: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> { self.0.read_range(start_key, end_key, limit).await }
     pub fn cursor(&self) -> Cursor { Cursor(self.0.cursor()) }
+    pub fn range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> RangeIter { RangeIter(self.0.range(start, end)) }
 }
 
 impl Cursor {
@@ -57,4 +132,15 @@ impl Cursor {
     pub fn seek_last(&mut self) { self.0.seek_last() }
     pub fn seek_key(&mut self, key: &[u8]) { self.0.seek_key(key) }
     pub fn seek_key_rev(&mut self, key: &[u8]) { self.0.seek_key_rev(key) }
+    pub fn seek_prefix(&mut self, prefix: &[u8]) { self.0.seek_prefix(prefix) }
+    pub fn valid_for_prefix(&self, prefix: &[u8]) -> bool { self.0.valid_for_prefix(prefix) }
+    pub fn into_stream(self) -> CursorStream { self.0.into_stream() }
+}
+
+impl RangeIter {
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> { self.0.next().await }
+}
+
+impl SyncCursor {
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Option<Vec<u8>>)>> { self.0.next().await }
 }
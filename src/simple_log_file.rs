@@ -1,23 +1,71 @@
+use log::error;
 use crate::types::Address;
 use anyhow::Result;
 use std::future::Future;
 use std::sync::Arc;
-use crate::log_file::LogFile;
+use crate::log_file::{LogFile, AppendFn, ReadAtFn};
 use crate::fs_thread::FsThread;
 use serde::{Serialize, Deserialize};
 use std::path::PathBuf;
 use futures::future::BoxFuture;
-use std::io::{Seek, SeekFrom, BufReader};
+use futures::lock::Mutex;
+use std::io::{Seek, SeekFrom, BufReader, Read, Write};
+use std::convert::TryFrom;
 use crate::frame;
+use crate::codec::RecordFormat;
+
+/// The number of bytes read at once when reading a log in order.
+pub const DEFAULT_READ_AHEAD_BYTES: usize = 256 * 1024;
 
 pub fn create<Cmd>(path: PathBuf, fs_thread: Arc<FsThread>) -> LogFile<Cmd>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    create_buffered(path, fs_thread, 0)
+}
+
+/// Creates a log that buffers up to `buffer_bytes` of appends
+/// in memory before writing them to the file.
+///
+/// With a `buffer_bytes` of 0 every append is written immediately.
+pub fn create_buffered<Cmd>(path: PathBuf, fs_thread: Arc<FsThread>, buffer_bytes: usize) -> LogFile<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    create_with_read_ahead(path, fs_thread, buffer_bytes, DEFAULT_READ_AHEAD_BYTES)
+}
+
+/// Creates a log that, when commands are read in order,
+/// reads `read_ahead_bytes` of the file at a time
+/// and decodes the following commands from memory.
+///
+/// This makes replaying the log take far fewer file reads.
+/// With a `read_ahead_bytes` of 0 every command is read from the file.
+pub fn create_with_read_ahead<Cmd>(path: PathBuf, fs_thread: Arc<FsThread>,
+                                   buffer_bytes: usize, read_ahead_bytes: usize) -> LogFile<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    create_with_format(path, fs_thread, buffer_bytes, read_ahead_bytes, RecordFormat::default())
+}
+
+/// Creates a log that appends commands encoded in `format`.
+///
+/// Commands are read in whatever format they were appended in.
+pub fn create_with_format<Cmd>(path: PathBuf, fs_thread: Arc<FsThread>,
+                               buffer_bytes: usize, read_ahead_bytes: usize,
+                               format: RecordFormat) -> LogFile<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
     let path = Arc::new(path);
-    let state1 = Arc::new(State { path, fs_thread });
+    let buffer = Mutex::new(Buffer { base: None, bytes: vec![], written: 0 });
+    let read_ahead = std::sync::Mutex::new(ReadAhead { next: 0, base: 0, chunk: vec![] });
+    let partial_append = std::sync::Mutex::new(None);
+    let state1 = Arc::new(State { path, fs_thread, format, buffer_bytes, buffer, read_ahead_bytes, read_ahead, partial_append });
     let state2 = state1.clone();
     let state3 = state1.clone();
     let state4 = state1.clone();
+    let state5 = state1.clone();
+    let state6 = state1.clone();
+    let state7 = state1.clone();
+    let state8 = state1.clone();
 
     let is_empty_impl: Box<dyn Fn() -> BoxFuture<'static, Result<bool>> + Send + Sync> = {
         Box::new(move || {
@@ -25,36 +73,100 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
         })
     };
 
-    let append_impl: Box<dyn Fn(Cmd) -> BoxFuture<'static, Result<Address>> + Send + Sync> = {
+    let append_impl: AppendFn<Cmd> = {
         Box::new(move |cmd| {
             Box::pin(append(state2.clone(), cmd))
         })
     };
-    let read_at_impl: Box<dyn Fn(Address) -> BoxFuture<'static, Result<(Cmd, Option<Address>)>> + Send + Sync> = {
+    let read_at_impl: ReadAtFn<Cmd> = {
         Box::new(move |addr| {
             Box::pin(read_at(state3.clone(), addr))
         })
     };
+    let flush_impl: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move || {
+            Box::pin(flush(state5.clone()))
+        })
+    };
     let sync_impl: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync> = {
         Box::new(move || {
             Box::pin(sync(state4.clone()))
         })
     };
 
+    let size_impl: Box<dyn Fn() -> BoxFuture<'static, Result<u64>> + Send + Sync> = {
+        Box::new(move || {
+            Box::pin(size(state6.clone()))
+        })
+    };
+
+    let truncate_impl: Box<dyn Fn(Address) -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move |addr| {
+            Box::pin(truncate(state7.clone(), addr))
+        })
+    };
+
+    let remove_impl: Box<dyn Fn() + Send + Sync> = {
+        Box::new(move || {
+            remove(&state8)
+        })
+    };
+
     LogFile {
         is_empty: is_empty_impl,
         append: append_impl,
         read_at: read_at_impl,
+        flush: flush_impl,
         sync: sync_impl,
+        size: size_impl,
+        truncate: truncate_impl,
+        remove: remove_impl,
     }
 }
 
 struct State {
     path: Arc<PathBuf>,
-    fs_thread: Arc<FsThread>
+    fs_thread: Arc<FsThread>,
+    format: RecordFormat,
+    buffer_bytes: usize,
+    buffer: Mutex<Buffer>,
+    read_ahead_bytes: usize,
+    read_ahead: std::sync::Mutex<ReadAhead>,
+    /// Where an unbuffered append that failed part way through,
+    /// and couldn't then be undone, began
+    partial_append: std::sync::Mutex<Option<u64>>,
+}
+
+/// Appends not yet written to the file.
+struct Buffer {
+    /// The file offset of the first buffered byte,
+    /// which is the end of the file.
+    /// Unknown until the first buffered append.
+    base: Option<u64>,
+    bytes: Vec<u8>,
+    /// The length of the start of `bytes` already written
+    /// by a flush that failed part way through.
+    written: usize,
+}
+
+/// File contents read past the last command read.
+struct ReadAhead {
+    /// The address following the last command read.
+    /// A read here continues reading in order.
+    next: u64,
+    /// The file offset of the first byte of `chunk`.
+    base: u64,
+    chunk: Vec<u8>,
 }
 
 async fn is_empty(state: Arc<State>) -> Result<bool> {
+    {
+        let buffer = state.buffer.lock().await;
+        if !buffer.bytes.is_empty() {
+            return Ok(false);
+        }
+    }
+
     let path = state.path.clone();
     let future = state.fs_thread.run(move |ctx| -> Result<_> {
         let mut file = ctx.open_read(&path)?;
@@ -66,25 +178,229 @@ async fn is_empty(state: Arc<State>) -> Result<bool> {
             Ok(false)
         }
     });
-    Ok(future.await?)
+    future.await
 }
 
-async fn append<Cmd>(state: Arc<State>, cmd: Cmd) -> Result<Address>
+async fn append<Cmd>(state: Arc<State>, cmd: Cmd) -> Result<(Address, u64)>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
-    let path = state.path.clone();
-    let future = state.fs_thread.run(move |ctx| -> Result<_> {
-        let mut file = ctx.open_append(&path)?;
-        let pos = file.seek(SeekFrom::Current(0))?;
-        frame::write(file, &cmd)?;
+    if state.buffer_bytes > 0 {
+        return append_buffered(state, cmd).await;
+    }
+
+    let mut bytes = vec![];
+    frame::write(&mut bytes, &cmd, state.format)?;
+
+    let fs_thread = state.fs_thread.clone();
+    let future = fs_thread.run(move |ctx| -> Result<_> {
+        let file = ctx.open_append(&state.path)?;
+        let mut partial_append = state.partial_append.lock().expect("lock");
+        if let Some(pos) = *partial_append {
+            file.set_len(pos)?;
+            *partial_append = None;
+        }
+        // An append handle's position is only at the end after its first write
+        let pos = file.seek(SeekFrom::End(0))?;
+        if let Err(e) = file.write_all(&bytes) {
+            // Don't leave part of a record for the next append to follow,
+            // which would make the log unreadable past it
+            if file.set_len(pos).is_err() {
+                *partial_append = Some(pos);
+            }
+            return Err(e.into());
+        }
+        let size = file.stream_position()?;
         let addr = Address(pos);
-        Ok(addr)
+        Ok((addr, size))
     });
-    Ok(future.await?)
+    future.await
+}
+
+async fn append_buffered<Cmd>(state: Arc<State>, cmd: Cmd) -> Result<(Address, u64)>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    let mut buffer = state.buffer.lock().await;
+
+    let base = match buffer.base {
+        Some(base) => base,
+        None => {
+            let path = state.path.clone();
+            let base = state.fs_thread.run(move |ctx| -> Result<_> {
+                let file = ctx.open_append(&path)?;
+                Ok(file.seek(SeekFrom::End(0))?)
+            }).await?;
+            buffer.base = Some(base);
+            base
+        }
+    };
+
+    let mut bytes = vec![];
+    frame::write(&mut bytes, &cmd, state.format)?;
+
+    // Make room first, so that if flushing fails
+    // this command is neither buffered nor given an address.
+    // A command larger than the buffer waits alone for the next flush.
+    if !buffer.bytes.is_empty() && buffer.bytes.len() + bytes.len() > state.buffer_bytes {
+        flush_buffer(&state, &mut buffer).await?;
+    }
+
+    let base = buffer.base.expect("base");
+    let buffered = u64::try_from(buffer.bytes.len()).expect("u64");
+    let addr = Address(base.checked_add(buffered).expect("overflow"));
+    buffer.bytes.extend_from_slice(&bytes);
+    let buffered = u64::try_from(buffer.bytes.len()).expect("u64");
+    let size = base.checked_add(buffered).expect("overflow");
+
+    Ok((addr, size))
 }
 
 async fn read_at<Cmd>(state: Arc<State>, addr: Address) -> Result<(Cmd, Option<Address>)>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    if state.buffer_bytes == 0 {
+        return read_at_file(&state, addr).await;
+    }
+
+    // Read straight from the buffer if the command is still in it
+    let buffered_base = {
+        let buffer = state.buffer.lock().await;
+        let buffered_base = buffer.base.filter(|_| !buffer.bytes.is_empty());
+        if let Some(base) = buffered_base {
+            if addr.0 >= base {
+                let offset = usize::try_from(addr.0 - base).expect("usize");
+                let mut reader = &buffer.bytes[offset..];
+                let cmd = frame::read(&mut reader)?;
+                let consumed = buffer.bytes.len() - offset - reader.len();
+                let next_addr = if !reader.is_empty() {
+                    let consumed = u64::try_from(consumed).expect("u64");
+                    Some(Address(addr.0.checked_add(consumed).expect("overflow")))
+                } else {
+                    None
+                };
+                return Ok((cmd, next_addr));
+            }
+        }
+        buffered_base
+    };
+
+    // Everything before the buffer is wholly in the file,
+    // and stays there if the buffer is flushed meanwhile,
+    // so the file is read without holding the buffer.
+    let (cmd, next_addr) = read_at_file(&state, addr).await?;
+
+    // The last command in the file is followed by the buffer
+    let next_addr = next_addr.or(buffered_base.map(Address));
+
+    Ok((cmd, next_addr))
+}
+
+async fn read_at_file<Cmd>(state: &State, addr: Address) -> Result<(Cmd, Option<Address>)>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    if state.read_ahead_bytes == 0 {
+        return read_one_at_file(state, addr).await;
+    }
+
+    let in_order = {
+        let read_ahead = state.read_ahead.lock().expect("lock");
+        if let Some((cmd, next_addr)) = read_from_chunk(&read_ahead, addr) {
+            drop(read_ahead);
+            finish_read(state, next_addr);
+            return Ok((cmd, next_addr));
+        }
+        addr.0 == read_ahead.next
+    };
+
+    let (cmd, next_addr) = if in_order {
+        read_chunk_at_file(state, addr).await?
+    } else {
+        read_one_at_file(state, addr).await?
+    };
+    finish_read(state, next_addr);
+
+    Ok((cmd, next_addr))
+}
+
+/// Decodes the command at `addr` from the read-ahead chunk.
+///
+/// Returns `None` unless the whole command,
+/// and the start of the one after it, are in the chunk.
+fn read_from_chunk<Cmd>(read_ahead: &ReadAhead, addr: Address) -> Option<(Cmd, Option<Address>)>
+where Cmd: for <'de> Deserialize<'de>
+{
+    let offset = usize::try_from(addr.0.checked_sub(read_ahead.base)?).ok()?;
+    let mut reader = read_ahead.chunk.get(offset..)?;
+    let cmd = frame::read(&mut reader).ok()?;
+    if reader.is_empty() {
+        // The chunk can't say if the file continues
+        return None;
+    }
+    let consumed = u64::try_from(read_ahead.chunk.len() - offset - reader.len()).expect("u64");
+    Some((cmd, Some(Address(addr.0.checked_add(consumed).expect("overflow")))))
+}
+
+/// Records where reading in order continues,
+/// discarding the chunk once the end of the file is reached.
+fn finish_read(state: &State, next_addr: Option<Address>) {
+    let mut read_ahead = state.read_ahead.lock().expect("lock");
+    match next_addr {
+        Some(next_addr) => {
+            read_ahead.next = next_addr.0;
+        },
+        None => {
+            read_ahead.chunk = vec![];
+        },
+    }
+}
+
+/// Reads a chunk of the file starting with the command at `addr`,
+/// keeping it for the reads that follow.
+async fn read_chunk_at_file<Cmd>(state: &State, addr: Address) -> Result<(Cmd, Option<Address>)>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    let path = state.path.clone();
+    let read_ahead_bytes = u64::try_from(state.read_ahead_bytes).expect("u64");
+    let future = state.fs_thread.run(move |ctx| -> Result<_> {
+        let file = ctx.open_read(&path)?;
+        let eof = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(addr.0))?;
+        let mut chunk = vec![];
+        (&mut *file).take(read_ahead_bytes).read_to_end(&mut chunk)?;
+
+        let mut reader = &chunk[..];
+        let (cmd, pos) = match frame::read(&mut reader) {
+            Ok(cmd) => {
+                let consumed = u64::try_from(chunk.len() - reader.len()).expect("u64");
+                (cmd, addr.0.checked_add(consumed).expect("overflow"))
+            },
+            Err(_) => {
+                // The command is larger than the chunk, or is broken.
+                // Read it alone, which reports any error.
+                file.seek(SeekFrom::Start(addr.0))?;
+                let mut file = BufReader::new(file);
+                let cmd = frame::read(&mut file)?;
+                (cmd, file.stream_position()?)
+            },
+        };
+
+        let next_addr = if pos != eof {
+            Some(Address(pos))
+        } else {
+            None
+        };
+        Ok((cmd, next_addr, chunk))
+    });
+    let (cmd, next_addr, chunk) = future.await?;
+
+    let mut read_ahead = state.read_ahead.lock().expect("lock");
+    read_ahead.base = addr.0;
+    read_ahead.chunk = chunk;
+
+    Ok((cmd, next_addr))
+}
+
+async fn read_one_at_file<Cmd>(state: &State, addr: Address) -> Result<(Cmd, Option<Address>)>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
     let path = state.path.clone();
     let future = state.fs_thread.run(move |ctx| -> Result<_> {
@@ -92,7 +408,7 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
         let mut file = BufReader::new(file);
         file.seek(SeekFrom::Start(addr.0))?;
         let cmd = frame::read(&mut file)?;
-        let pos = file.seek(SeekFrom::Current(0))?;
+        let pos = file.stream_position()?;
         let eof = file.seek(SeekFrom::End(0))?;
         file.seek(SeekFrom::Start(pos))?;
         let next_addr = if pos != eof {
@@ -102,15 +418,124 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
         };
         Ok((cmd, next_addr))
     });
-    Ok(future.await?)
+    future.await
+}
+
+async fn size(state: Arc<State>) -> Result<u64> {
+    // Held so the buffer isn't flushed while the file is measured
+    let buffer = state.buffer.lock().await;
+
+    if let Some(base) = buffer.base.filter(|_| !buffer.bytes.is_empty()) {
+        let buffered = u64::try_from(buffer.bytes.len()).expect("u64");
+        return Ok(base.checked_add(buffered).expect("overflow"));
+    }
+
+    let path = state.path.clone();
+    let future = state.fs_thread.run(move |ctx| -> Result<_> {
+        let file = ctx.open_read(&path)?;
+        Ok(file.metadata()?.len())
+    });
+    future.await
+}
+
+async fn flush(state: Arc<State>) -> Result<()> {
+    let mut buffer = state.buffer.lock().await;
+    flush_buffer(&state, &mut buffer).await
+}
+
+async fn flush_buffer(state: &State, buffer: &mut Buffer) -> Result<()> {
+    if buffer.bytes.is_empty() {
+        return Ok(());
+    }
+
+    // Keep the buffer until it is written,
+    // since its addresses have already been handed out.
+    // Only what an earlier failed flush didn't write is written,
+    // so a retry never writes anything twice.
+    let path = state.path.clone();
+    let bytes = buffer.bytes[buffer.written..].to_vec();
+    let future = state.fs_thread.run(move |ctx| -> (usize, Result<()>) {
+        let mut written = 0;
+        let file = match ctx.open_append(&path) {
+            Ok(file) => file,
+            Err(e) => return (written, Err(e)),
+        };
+        while written < bytes.len() {
+            match file.write(&bytes[written..]) {
+                Ok(0) => {
+                    let e = std::io::Error::from(std::io::ErrorKind::WriteZero);
+                    return (written, Err(e.into()));
+                },
+                Ok(n) => written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => { },
+                Err(e) => return (written, Err(e.into())),
+            }
+        }
+        (written, Ok(()))
+    });
+    let (written, result) = future.await;
+    buffer.written += written;
+    result?;
+
+    let flushed = u64::try_from(buffer.bytes.len()).expect("u64");
+    let base = buffer.base.expect("base");
+    buffer.base = Some(base.checked_add(flushed).expect("overflow"));
+    buffer.bytes.clear();
+    buffer.written = 0;
+
+    Ok(())
 }
 
 async fn sync(state: Arc<State>) -> Result<()> {
+    flush(state.clone()).await?;
+
     let path = state.path.clone();
     let future = state.fs_thread.run(move |ctx| -> Result<_> {
         let file = ctx.open_append(&path)?;
         file.sync_all()?;
         Ok(())
     });
-    Ok(future.await?)
+    future.await
+}
+
+async fn truncate(state: Arc<State>, addr: Address) -> Result<()> {
+    let mut buffer = state.buffer.lock().await;
+    flush_buffer(&state, &mut buffer).await?;
+
+    let path = state.path.clone();
+    let future = state.fs_thread.run(move |ctx| -> Result<_> {
+        // A read-only log is left as it is
+        if ctx.is_read_only() {
+            return Ok(());
+        }
+        let file = ctx.open_append(&path)?;
+        file.set_len(addr.0)?;
+        file.sync_all()?;
+        Ok(())
+    });
+    future.await?;
+
+    // Both describe the file as it was
+    buffer.base = None;
+    let mut read_ahead = state.read_ahead.lock().expect("lock");
+    read_ahead.next = 0;
+    read_ahead.chunk = vec![];
+
+    Ok(())
+}
+
+/// Deletes the file, discarding any buffered appends.
+fn remove(state: &State) {
+    let path = state.path.clone();
+    state.fs_thread.spawn(move |ctx| {
+        ctx.close(&path);
+        // A read-only log is left as it is
+        if ctx.is_read_only() {
+            return;
+        }
+        match std::fs::remove_file(&*path) {
+            Ok(()) => ctx.mark_dir_dirty(),
+            Err(e) => error!("removing log {}: {}", path.display(), e),
+        }
+    });
 }
@@ -0,0 +1,291 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use std::convert::TryFrom;
+
+/// A snapshot of database statistics.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// The number of commits that modified each number of trees.
+    ///
+    /// Index `n` is the number of commits that modified exactly `n` trees.
+    pub trees_per_commit: Vec<u64>,
+    /// The number of times the database directory was synced.
+    pub dir_syncs: u64,
+    /// The number of commits synced to disk before completing.
+    pub synced_commits: u64,
+    /// The time taken by each commit,
+    /// from the start of `WriteBatch::commit` until it completes.
+    pub commit_latency: LatencyHistogram,
+    /// The number of times a tree's index write lock was taken.
+    #[cfg(feature = "lock-stats")]
+    pub index_write_locks: u64,
+    /// The total time index write locks were held.
+    #[cfg(feature = "lock-stats")]
+    pub index_write_lock_hold: Duration,
+    /// The longest time any index write lock was held.
+    ///
+    /// Reads of the tree are blocked for this long.
+    #[cfg(feature = "lock-stats")]
+    pub max_index_write_lock_hold: Duration,
+}
+
+/// Latencies counted in logarithmic buckets.
+///
+/// Each power of two microseconds is split into four buckets,
+/// so a reported latency is at most 25% above the true value.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+}
+
+const SUB_BUCKET_BITS: u32 = 2;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const LATENCY_BUCKETS: usize = ((64 - SUB_BUCKET_BITS) as usize + 1) * SUB_BUCKETS as usize;
+
+fn latency_bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let msb = 63 - micros.leading_zeros();
+    let sub_bucket = (micros >> (msb - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((msb - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// The largest latency in microseconds that falls in a bucket.
+fn latency_bucket_max(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = (bucket / SUB_BUCKETS - 1) as u32;
+    let low = (SUB_BUCKETS + bucket % SUB_BUCKETS) << shift;
+    low + ((1 << shift) - 1)
+}
+
+impl LatencyHistogram {
+    /// The number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The latency that `percentile` percent of recorded latencies
+    /// are no greater than.
+    ///
+    /// Returns zero if nothing has been recorded.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((percentile / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank {
+                return Duration::from_micros(latency_bucket_max(bucket));
+            }
+        }
+
+        Duration::from_micros(latency_bucket_max(self.counts.len() - 1))
+    }
+}
+
+/// Statistics for a single tree as seen by a read view.
+///
+/// These are exact,
+/// and computed by reading every live value in the tree from its log.
+#[derive(Clone, Debug, Default)]
+pub struct TreeStats {
+    /// The number of keys with a value.
+    pub live_keys: u64,
+    /// The number of keys whose most recent version is a delete.
+    pub tombstones: u64,
+    /// The total size of every live value, in bytes.
+    pub total_value_bytes: u64,
+}
+
+/// Storage used by a single tree's log.
+///
+/// The live and dead figures are estimates,
+/// made from the index without reading the log.
+/// They assume each live key takes one record
+/// and that records are all the same size,
+/// so keys built from merges, and values of varying size,
+/// skew them.
+#[derive(Clone, Debug, Default)]
+pub struct TreeStorageStats {
+    /// The size of the log, in bytes.
+    pub log_bytes: u64,
+    /// The number of writes, deletes, merges and copies in the log.
+    pub change_records: u64,
+    /// The number of keys with a value.
+    pub live_keys: u64,
+    /// The estimated bytes of the log that compaction would reclaim.
+    pub dead_bytes: u64,
+}
+
+/// The result of collapsing the history of a range of keys.
+#[derive(Clone, Debug, Default)]
+pub struct CollapseReport {
+    /// The number of keys in the range with more than one version.
+    pub keys: u64,
+    /// The number of old versions discarded.
+    pub versions_discarded: u64,
+}
+
+/// Progress of background history compaction under a `CompactionPolicy`.
+///
+/// These compactions free memory only; logs are not rewritten.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionStats {
+    /// The number of times a tree was checked against the policy.
+    pub checks: u64,
+    /// The number of checks that compacted the tree.
+    pub compactions: u64,
+    /// The number of keys those compactions examined.
+    pub keys: u64,
+    /// The number of old versions those compactions discarded.
+    pub versions_discarded: u64,
+}
+
+/// What opening a database recovered from its logs.
+#[derive(Clone, Debug, Default)]
+pub struct RecoverySummary {
+    /// The number of batch commits replayed.
+    pub batches_applied: u64,
+    /// The number of batches that logged changes but never committed,
+    /// whose changes were discarded.
+    pub batches_aborted: u64,
+    /// How long replaying the logs took.
+    pub duration: Duration,
+}
+
+impl TreeStats {
+    /// The mean size of a live value, in bytes.
+    pub fn average_value_bytes(&self) -> f64 {
+        if self.live_keys == 0 {
+            0.0
+        } else {
+            self.total_value_bytes as f64 / self.live_keys as f64
+        }
+    }
+}
+
+/// Live statistics counters, updated without exclusive locking.
+pub struct StatsCollector {
+    /// Grown as trees are created
+    trees_per_commit: RwLock<Vec<AtomicU64>>,
+    dir_syncs: AtomicU64,
+    synced_commits: AtomicU64,
+    commit_latency: Vec<AtomicU64>,
+    compaction_checks: AtomicU64,
+    compactions: AtomicU64,
+    compacted_keys: AtomicU64,
+    compacted_versions: AtomicU64,
+}
+
+impl StatsCollector {
+    pub fn new(tree_count: usize) -> StatsCollector {
+        StatsCollector {
+            trees_per_commit: RwLock::new((0..=tree_count).map(|_| AtomicU64::new(0)).collect()),
+            dir_syncs: AtomicU64::new(0),
+            synced_commits: AtomicU64::new(0),
+            commit_latency: (0..LATENCY_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            compaction_checks: AtomicU64::new(0),
+            compactions: AtomicU64::new(0),
+            compacted_keys: AtomicU64::new(0),
+            compacted_versions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_commit(&self, trees_modified: usize) {
+        self.trees_per_commit.read().expect("lock")[trees_modified].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Makes room to count commits that modify one more tree.
+    pub fn add_tree(&self) {
+        self.trees_per_commit.write().expect("lock").push(AtomicU64::new(0));
+    }
+
+    pub fn record_dir_sync(&self) {
+        self.dir_syncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_synced_commit(&self) {
+        self.synced_commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_commit_latency(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.commit_latency[latency_bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_compaction_check(&self) {
+        self.compaction_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_compaction(&self, report: &CollapseReport) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compacted_keys.fetch_add(report.keys, Ordering::Relaxed);
+        self.compacted_versions.fetch_add(report.versions_discarded, Ordering::Relaxed);
+    }
+
+    pub fn compaction_snapshot(&self) -> CompactionStats {
+        CompactionStats {
+            checks: self.compaction_checks.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            keys: self.compacted_keys.load(Ordering::Relaxed),
+            versions_discarded: self.compacted_versions.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            trees_per_commit: self.trees_per_commit.read().expect("lock").iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            dir_syncs: self.dir_syncs.load(Ordering::Relaxed),
+            synced_commits: self.synced_commits.load(Ordering::Relaxed),
+            commit_latency: LatencyHistogram {
+                counts: self.commit_latency.iter()
+                    .map(|count| count.load(Ordering::Relaxed))
+                    .collect(),
+            },
+            #[cfg(feature = "lock-stats")]
+            index_write_locks: 0,
+            #[cfg(feature = "lock-stats")]
+            index_write_lock_hold: Duration::ZERO,
+            #[cfg(feature = "lock-stats")]
+            max_index_write_lock_hold: Duration::ZERO,
+        }
+    }
+}
+
+/// Hold times of a lock.
+#[cfg(feature = "lock-stats")]
+#[derive(Debug, Default)]
+pub struct LockHolds {
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+#[cfg(feature = "lock-stats")]
+impl LockHolds {
+    pub fn record(&self, held: Duration) {
+        let nanos = u64::try_from(held.as_nanos()).unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Adds these holds to the index write lock statistics.
+    pub fn add_to_index_stats(&self, stats: &mut Stats) {
+        let max = Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed));
+        stats.index_write_locks += self.count.load(Ordering::Relaxed);
+        stats.index_write_lock_hold += Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed));
+        stats.max_index_write_lock_hold = stats.max_index_write_lock_hold.max(max);
+    }
+}
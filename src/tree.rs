@@ -1,14 +1,23 @@
 use std::pin::Pin;
 use std::collections::{BTreeMap, BTreeSet};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::convert::TryFrom;
+use std::ops::Range;
 use crate::types::{Batch, BatchCommit, Commit, Key, Value, Address};
 use crate::command::Command;
-use crate::log::Log;
-use crate::batch_player::{BatchPlayer, IndexOp};
-use crate::index::{self, Index};
-use anyhow::{Result, anyhow, bail};
+use crate::codec::{RecordCodec, RecordFormat};
+use crate::log::{Log, torn_tail};
+use crate::batch_player::{BatchPlayer, IndexOp, StagedBase, StagedValue};
+use crate::index::{self, Index, Lookup, ReadValue};
+use crate::merge::{self, MergeFn};
+use crate::value_cache::ValueCache;
+use crate::stats::{CollapseReport, TreeStats, TreeStorageStats};
+use crate::validation::Validation;
+use crate::value_transform::ValueTransformRef;
+use crate::compression;
+use crate::error::DbError;
+use anyhow::{Result, Context, anyhow, bail};
 use futures::{Stream, StreamExt};
 
 pub struct Tree {
@@ -16,59 +25,240 @@ pub struct Tree {
     log: Arc<Log<Command>>,
     batch_player: Arc<BatchPlayer>,
     index: Arc<Index>,
+    merge_fn: MergeFn,
+    value_transform: Option<ValueTransformRef>,
+    compression_min_bytes: Option<usize>,
+    value_cache: ValueCache,
+    max_log_bytes: Option<u64>,
+    compaction_requested: Arc<AtomicBool>,
+    /// The log size when the tree was last compacted
+    compacted_log_size: Arc<AtomicU64>,
+    max_key_bytes: usize,
+    max_value_bytes: usize,
+    append_only: bool,
+    validation: Validation,
+    record_format: RecordFormat,
+    /// Held while a tree created in an open database
+    /// logs the batch it starts at,
+    /// so no later batch logs to it first.
+    starting: Arc<futures::lock::Mutex<()>>,
+    /// Writes, deletes, merges and copies in the log
+    change_records: Arc<AtomicU64>,
+    /// Whether the log is deleted once the tree is dropped
+    remove_log_on_drop: AtomicBool,
 }
 
+/// The default key length limit.
+///
+/// Together with `DEFAULT_MAX_VALUE_BYTES` this keeps every record,
+/// even holding two keys, within `frame::MAX_BODY_LENGTH`
+/// once encoded.
+pub const DEFAULT_MAX_KEY_BYTES: usize = 1 << 16;
+/// The default value length limit, as stored.
+pub const DEFAULT_MAX_VALUE_BYTES: usize = 1 << 28;
+
+#[derive(Clone)]
+pub struct TreeConfig {
+    pub merge_fn: MergeFn,
+    /// The number of values to cache; 0 disables the cache.
+    pub value_cache_entries: usize,
+    /// Request compaction once the log grows by more than this
+    /// since the tree was last compacted.
+    pub max_log_bytes: Option<u64>,
+    pub validation: Validation,
+    pub value_transform: Option<ValueTransformRef>,
+    /// Compress written values of at least this many bytes,
+    /// before `value_transform` is applied.
+    /// `None` disables compression.
+    pub compression_min_bytes: Option<usize>,
+    pub max_key_bytes: usize,
+    /// Limits values after `value_transform` is applied.
+    pub max_value_bytes: usize,
+    /// Rejects deletes and range deletes.
+    /// Writes still overwrite by key.
+    pub append_only: bool,
+    /// The versions of each key to keep in the index; 0 keeps all.
+    ///
+    /// Older versions are trimmed as keys are written,
+    /// unless an open read view needs them.
+    pub max_history_per_key: usize,
+    /// The format of the log's records,
+    /// which records passed to `BatchWriter::decode_raw` are in.
+    pub record_format: RecordFormat,
+}
+
+#[derive(Clone)]
 pub struct BatchWriter {
     batch: Batch,
     log: Arc<Log<Command>>,
     batch_player: Arc<BatchPlayer>,
     index: Arc<Index>,
+    merge_fn: MergeFn,
+    value_transform: Option<ValueTransformRef>,
+    compression_min_bytes: Option<usize>,
+    max_log_bytes: Option<u64>,
+    compaction_requested: Arc<AtomicBool>,
+    /// The log size when the tree was last compacted
+    compacted_log_size: Arc<AtomicU64>,
+    starting: Arc<futures::lock::Mutex<()>>,
+    change_records: Arc<AtomicU64>,
+    max_key_bytes: usize,
+    max_value_bytes: usize,
+    append_only: bool,
+    validation: Validation,
+    /// Whether the batch has logged a copy
+    has_copies: Arc<AtomicBool>,
+    record_format: RecordFormat,
 }
 
 pub struct Cursor {
     log: Arc<Log<Command>>,
+    merge_fn: MergeFn,
+    value_transform: Option<ValueTransformRef>,
     index_cursor: index::Cursor,
     value: Option<Value>,
 }
 
+type CmdStream = Pin<Box<dyn Stream<Item = Result<(Command, Address)>>>>;
+
 pub struct InitReplayer<'tree> {
     initialized: &'tree AtomicBool,
-    cmd_stream: Pin<Box<dyn Stream<Item = Result<(Command, Address)>>>>,
+    log: &'tree Log<Command>,
+    cmd_stream: CmdStream,
     index: &'tree Index,
+    change_records: &'tree AtomicU64,
     batch_players: BTreeMap<Batch, BatchPlayer>,
     previous_commit: Option<Commit>,
     max_batch_seen: Option<Batch>,
     max_batch_commit_seen: Option<BatchCommit>,
     waiting_to_commit: BTreeSet<(Batch, BatchCommit)>,
+    open_batches: BTreeMap<Batch, BatchOutcome>,
+    aborted_batches: BTreeSet<Batch>,
+    torn_tail: Option<Address>,
     init_success: bool,
 }
 
+/// What an open batch has done so far during init replay.
+#[derive(Default)]
+struct BatchOutcome {
+    changed: bool,
+    committed: bool,
+}
+
+impl Default for TreeConfig {
+    fn default() -> TreeConfig {
+        TreeConfig {
+            merge_fn: merge::counter(),
+            value_cache_entries: 0,
+            max_log_bytes: None,
+            validation: Validation::default(),
+            value_transform: None,
+            compression_min_bytes: None,
+            max_key_bytes: DEFAULT_MAX_KEY_BYTES,
+            max_value_bytes: DEFAULT_MAX_VALUE_BYTES,
+            append_only: false,
+            max_history_per_key: 0,
+            record_format: RecordFormat::default(),
+        }
+    }
+}
+
 impl Tree {
-    pub fn new(log: Log<Command>) -> Tree {
+    pub fn new(log: Log<Command>, config: TreeConfig) -> Tree {
         Tree {
             initialized: AtomicBool::new(false),
-            log: Arc::new(log),
+            log: Arc::new(log.with_validation(config.validation)),
             batch_player: Arc::new(BatchPlayer::new()),
-            index: Arc::new(Index::new()),
+            index: Arc::new(Index::new()
+                            .with_validation(config.validation)
+                            .with_max_history(config.max_history_per_key)),
+            merge_fn: config.merge_fn,
+            value_transform: config.value_transform,
+            compression_min_bytes: config.compression_min_bytes,
+            value_cache: ValueCache::new(config.value_cache_entries),
+            max_log_bytes: config.max_log_bytes,
+            compaction_requested: Arc::new(AtomicBool::new(false)),
+            compacted_log_size: Arc::new(AtomicU64::new(0)),
+            max_key_bytes: config.max_key_bytes,
+            max_value_bytes: config.max_value_bytes,
+            append_only: config.append_only,
+            validation: config.validation,
+            record_format: config.record_format,
+            starting: Arc::new(futures::lock::Mutex::new(())),
+            change_records: Arc::new(AtomicU64::new(0)),
+            remove_log_on_drop: AtomicBool::new(false),
         }
     }
 
-    pub fn init_replayer(&self) -> InitReplayer {
+    pub fn init_replayer(&self) -> InitReplayer<'_> {
         assert!(!self.initialized.load(Ordering::SeqCst));
 
         InitReplayer {
             initialized: &self.initialized,
+            log: &self.log,
             cmd_stream: Box::pin(self.log.replay()),
-            index: &*self.index,
+            index: &self.index,
+            change_records: &self.change_records,
             batch_players: BTreeMap::new(),
             previous_commit: None,
             max_batch_seen: None,
             max_batch_commit_seen: None,
             waiting_to_commit: BTreeSet::new(),
+            open_batches: BTreeMap::new(),
+            aborted_batches: BTreeSet::new(),
+            torn_tail: None,
             init_success: false,
         }
     }
 
+    /// The batch of the first command in the log,
+    /// or `None` if the log is empty.
+    ///
+    /// A tree takes part in every batch from this one on,
+    /// but in no batch before it.
+    ///
+    /// If appending the first command was interrupted,
+    /// the error is a [`TornTail`](crate::log::TornTail).
+    pub async fn first_batch(&self) -> Result<Option<Batch>> {
+        if self.log.is_empty().await? {
+            return Ok(None);
+        }
+        match self.log.replay().next().await {
+            Some(cmd) => Ok(Some(cmd?.0.batch())),
+            None => Ok(None),
+        }
+    }
+
+    /// Discards a log whose first command is torn.
+    ///
+    /// Only for use when nothing was ever committed.
+    pub async fn discard_log(&self) -> Result<()> {
+        self.log.truncate(Address(0)).await
+    }
+
+    /// Accounts for commits before `next_commit`
+    /// made before the tree was added.
+    pub fn advance_to(&self, next_commit: Commit) {
+        self.index.advance_to(next_commit)
+    }
+
+    /// Marks a tree added to an existing database as starting at `batch`,
+    /// by logging it as an empty batch.
+    ///
+    /// The tree must have an empty log,
+    /// and `batch` must precede every batch the tree writes later.
+    pub async fn start_at_batch(&self, batch: Batch) -> Result<()> {
+        let writer = self.batch(batch);
+        writer.open().await?;
+        writer.close().await
+    }
+
+    /// The lock to hold while a tree created in an open database
+    /// logs the batch it starts at.
+    pub fn starting(&self) -> Arc<futures::lock::Mutex<()>> {
+        self.starting.clone()
+    }
+
     pub fn skip_init(&self) {
         self.initialized.store(true, Ordering::SeqCst);
     }
@@ -81,28 +271,214 @@ impl Tree {
             log: self.log.clone(),
             batch_player: self.batch_player.clone(),
             index: self.index.clone(),
+            merge_fn: self.merge_fn.clone(),
+            value_transform: self.value_transform.clone(),
+            compression_min_bytes: self.compression_min_bytes,
+            max_log_bytes: self.max_log_bytes,
+            compaction_requested: self.compaction_requested.clone(),
+            compacted_log_size: self.compacted_log_size.clone(),
+            starting: self.starting.clone(),
+            change_records: self.change_records.clone(),
+            max_key_bytes: self.max_key_bytes,
+            max_value_bytes: self.max_value_bytes,
+            append_only: self.append_only,
+            validation: self.validation,
+            has_copies: Arc::new(AtomicBool::new(false)),
+            record_format: self.record_format,
         }
     }
 
+    /// One past the last commit that changed this tree.
+    pub fn next_commit(&self) -> Commit {
+        self.index.next_commit()
+    }
+
+    /// Whether the tree never holds deletes.
+    pub fn is_append_only(&self) -> bool {
+        self.append_only
+    }
+
+    /// Whether the log has grown more than `max_log_bytes`
+    /// since the tree was last compacted,
+    /// and is waiting to be compacted.
+    pub fn compaction_requested(&self) -> bool {
+        self.compaction_requested.load(Ordering::SeqCst)
+    }
+
+    /// Notes that the tree was compacted when its log was `log_size` bytes,
+    /// clearing any request for compaction.
+    ///
+    /// The log does not shrink,
+    /// so only growth past `log_size` requests compaction again.
+    pub fn finish_compaction(&self, log_size: u64) {
+        self.compacted_log_size.store(log_size, Ordering::SeqCst);
+        self.compaction_requested.store(false, Ordering::SeqCst);
+    }
+
     pub async fn read(&self, commit_limit: Commit, key: &Key) -> Result<Option<Value>> {
+        if self.value_cache.is_enabled() {
+            return Ok(self.read_arc(commit_limit, key).await?
+                      .map(|value| Value(value.to_vec())));
+        }
+
         assert!(self.initialized.load(Ordering::SeqCst));
+        match self.index.read(commit_limit, key) {
+            Some(lookup) => {
+                let value = resolve(&self.log, &self.merge_fn, &self.value_transform, key, &lookup).await?;
+                Ok(Some(value))
+            },
+            None => Ok(None),
+        }
+    }
 
-        let addr = self.index.read(commit_limit, key);
+    /// The newest commit before `commit_limit` that wrote, deleted,
+    /// merged into, or range deleted `key`.
+    pub fn latest_commit(&self, commit_limit: Commit, key: &Key) -> Option<Commit> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.index.latest_commit(commit_limit, key)
+    }
 
-        if let Some(addr) = addr {
-            let cmd = self.log.read_at(addr).await?;
-            match cmd {
-                Command::Write { key: log_key , value, .. } => {
-                    assert_eq!(key, &log_key);
-                    Ok(Some(value))
-                }
-                _ => {
-                    Err(anyhow!(UNEXPECTED_LOG))
+    /// Reads a value, sharing it with the value cache.
+    ///
+    /// Merged values are not cached.
+    pub async fn read_arc(&self, commit_limit: Commit, key: &Key) -> Result<Option<Arc<[u8]>>> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        // Read before the index, so a value is never cached
+        // under a generation newer than the log it was read from
+        let generation = self.log.generation();
+        // The index lock is released before the log is read
+        let lookup = self.index.read(commit_limit, key);
+
+        match lookup {
+            Some(lookup) if lookup.merges.is_empty() && lookup.base.is_some() => {
+                let addr = lookup.base.expect("base");
+                if let Some(value) = self.value_cache.get(generation, addr) {
+                    return Ok(Some(value));
                 }
+                let value = resolve(&self.log, &self.merge_fn, &self.value_transform, key, &lookup).await?;
+                Ok(Some(self.value_cache.insert(generation, addr, value.0.into())))
+            },
+            Some(lookup) => {
+                let value = resolve(&self.log, &self.merge_fn, &self.value_transform, key, &lookup).await?;
+                Ok(Some(value.0.into()))
+            },
+            None => {
+                Ok(None)
+            },
+        }
+    }
+
+    pub async fn history(&self, commit_limit: Commit, key: &Key) -> Result<Vec<(Commit, Option<Value>)>> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let versions = self.index.history(commit_limit, key);
+        let mut history = Vec::with_capacity(versions.len());
+
+        // Report the value as of the end of each commit,
+        // which accounts for merges and range deletes.
+        for (commit, _) in versions {
+            let next_commit = Commit(commit.0.checked_add(1).expect("overflow"));
+            let value = self.read(next_commit, key).await?;
+            history.push((commit, value));
+        }
+
+        Ok(history)
+    }
+
+    /// Discards versions of `key` not needed for reads at or after `commit_limit`.
+    pub fn collapse_key(&self, commit_limit: Commit, key: &Key) -> usize {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.index.collapse(commit_limit, key)
+    }
+
+    /// Collapses every key in `range` with more than one version.
+    pub fn collapse_range(&self, commit_limit: Commit, range: Range<Key>) -> CollapseReport {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.collapse_keys(commit_limit, self.index.collapsible_keys_in_range(range))
+    }
+
+    /// Collapses every key in the tree with more than one version.
+    pub fn collapse_all(&self, commit_limit: Commit) -> CollapseReport {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.collapse_keys(commit_limit, self.index.collapsible_keys())
+    }
+
+    fn collapse_keys(&self, commit_limit: Commit, keys: Vec<Key>) -> CollapseReport {
+        let mut report = CollapseReport::default();
+        for key in keys {
+            let discarded = self.index.collapse(commit_limit, &key);
+            report.keys += 1;
+            report.versions_discarded += u64::try_from(discarded).expect("u64");
+        }
+        report
+    }
+
+    /// The number of keys, and of versions across all of them.
+    pub fn version_counts(&self) -> (u64, u64) {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.index.version_counts()
+    }
+
+    /// The size of the log as of the most recent append.
+    pub fn log_size(&self) -> u64 {
+        self.log.size()
+    }
+
+    pub fn changed_keys(&self, commit_limit: Commit, low: Commit, prefix: &[u8]) -> Vec<Key> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.index.changed_keys(commit_limit, low, prefix)
+    }
+
+    pub fn next_versioned_key(&self, commit_limit: Commit, after: Option<&Key>) -> Option<Key> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.index.next_versioned_key(commit_limit, after)
+    }
+
+    /// The keys written or deleted, and the ranges deleted,
+    /// by commits in `[low, commit_limit)`.
+    ///
+    /// Fails if versions from those commits have been discarded,
+    /// by collapsing or by the limit on history kept per key.
+    pub fn changes_since(&self, commit_limit: Commit, low: Commit) -> Result<index::Changes> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+        self.index.changes_since(commit_limit, low).ok_or_else(|| {
+            anyhow!("versions committed from commit {} on are no longer in history", low.0)
+        })
+    }
+
+    /// Counts the keys starting with `prefix`, reading every live value.
+    ///
+    /// Value sizes are not kept in the index,
+    /// since merges and value transforms make them known only on reading,
+    /// so this reads the log for each live key.
+    /// It backs only `ReadTree::stats`, never periodic statistics.
+    pub async fn scan_stats(&self, commit_limit: Commit, prefix: &[u8]) -> Result<TreeStats> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let mut stats = TreeStats::default();
+        for (key, lookup) in self.index.entries(commit_limit, prefix) {
+            match lookup {
+                Some(lookup) => {
+                    let value = resolve(&self.log, &self.merge_fn, &self.value_transform, &key, &lookup).await?;
+                    stats.live_keys += 1;
+                    stats.total_value_bytes += value.0.len() as u64;
+                },
+                None => {
+                    stats.tombstones += 1;
+                },
             }
-        } else {
-            Ok(None)
         }
+
+        Ok(stats)
+    }
+
+    /// The number of live keys starting with `prefix`,
+    /// counted from the index without reading any values.
+    pub fn count(&self, commit_limit: Commit, prefix: &[u8]) -> usize {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        self.index.count(commit_limit, prefix)
     }
 
     pub fn cursor(&self, commit_limit: Commit) -> Cursor {
@@ -110,85 +486,383 @@ impl Tree {
 
         Cursor {
             log: self.log.clone(),
+            merge_fn: self.merge_fn.clone(),
+            value_transform: self.value_transform.clone(),
             index_cursor: self.index.cursor(commit_limit),
             value: None,
         }
     }
 
+    pub async fn flush(&self) -> Result<()> {
+        self.log.flush().await
+    }
+
+    #[cfg(feature = "lock-stats")]
+    pub fn index_write_lock_holds(&self) -> &crate::stats::LockHolds {
+        self.index.write_lock_holds()
+    }
+
     pub async fn sync(&self) -> Result<()> {
-        Ok(self.log.sync().await?)
+        self.log.sync().await
+    }
+
+    /// Deletes the tree's log in the background.
+    ///
+    /// The tree must not be used afterwards.
+    pub fn remove_log(&self) {
+        self.log.remove()
+    }
+
+    /// Sets whether the tree's log is deleted once the tree is dropped,
+    /// when nothing can use it any more.
+    pub fn set_remove_log_on_drop(&self, remove: bool) {
+        self.remove_log_on_drop.store(remove, Ordering::SeqCst);
+    }
+
+    /// The byte range of the log that is live.
+    pub async fn log_extent(&self) -> Result<(u64, u64)> {
+        self.log.extent().await
+    }
+
+    /// The size of the log, the changes it records,
+    /// and estimates of how much of it is still live.
+    pub async fn storage_stats(&self, commit_limit: Commit) -> Result<TreeStorageStats> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let (_, log_bytes) = self.log.extent().await?;
+        let change_records = self.change_records.load(Ordering::Relaxed);
+        let live_keys = u64::try_from(self.index.count(commit_limit, &[])).expect("u64");
+
+        // Each live key is assumed to need one record,
+        // and every record to be the same size
+        let dead_records = change_records.saturating_sub(live_keys);
+        let dead_bytes = match change_records {
+            0 => 0,
+            _ => u64::try_from(
+                u128::from(log_bytes) * u128::from(dead_records) / u128::from(change_records)
+            ).expect("u64"),
+        };
+
+        Ok(TreeStorageStats {
+            log_bytes,
+            change_records,
+            live_keys,
+            dead_bytes,
+        })
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        if self.remove_log_on_drop.load(Ordering::SeqCst) {
+            self.log.remove();
+        }
     }
 }
 
 impl BatchWriter {
+    /// Waits until a tree created in an open database
+    /// has logged the batch it starts at.
+    pub async fn wait_started(&self) {
+        drop(self.starting.lock().await);
+    }
+
     pub async fn open(&self) -> Result<()> {
-        Ok(self.append_record(Command::Open {
+        self.append_record(Command::Open {
             batch: self.batch,
-        }).await?)
+        }).await
     }
 
     pub async fn write(&self, key: Key, value: Value) -> Result<()> {
-        Ok(self.append_record(Command::Write {
+        self.check_key(&key)?;
+        let (value, compressed) = self.compress(value);
+        let value = self.transform(value);
+        self.check_value(&value)?;
+        let batch = self.batch;
+        let cmd = if compressed {
+            Command::WriteCompressed { batch, key, value }
+        } else {
+            Command::Write { batch, key, value }
+        };
+        self.append_record(cmd).await
+    }
+
+    pub async fn delete(&self, key: Key) -> Result<()> {
+        self.check_delete()?;
+        self.check_key(&key)?;
+        self.append_record(Command::Delete {
             batch: self.batch,
             key,
-            value,
-        }).await?)
+        }).await
     }
 
-    pub async fn delete(&self, key: Key) -> Result<()> {
-        Ok(self.append_record(Command::Delete {
+    pub async fn merge(&self, key: Key, operand: Value) -> Result<()> {
+        self.check_key(&key)?;
+        let operand = self.transform(operand);
+        self.check_value(&operand)?;
+        self.append_record(Command::Merge {
             batch: self.batch,
             key,
-        }).await?)
+            operand,
+        }).await
+    }
+
+    pub async fn copy(&self, src_key: Key, dst_key: Key) -> Result<()> {
+        self.check_key(&src_key)?;
+        self.check_key(&dst_key)?;
+        self.has_copies.store(true, Ordering::SeqCst);
+        self.append_record(Command::Copy {
+            batch: self.batch,
+            src_key,
+            dst_key,
+        }).await
     }
 
     pub async fn delete_range(&self, start_key: Key, end_key: Key) -> Result<()> {
         //assert!(start_key <= end_key);
-        Ok(self.append_record(Command::DeleteRange {
+        self.check_delete()?;
+        self.check_key(&start_key)?;
+        self.check_range_end(&end_key)?;
+        self.append_record(Command::DeleteRange {
             batch: self.batch,
             start_key,
             end_key,
-        }).await?)
+        }).await
+    }
+
+    /// Decodes a command encoded in the log's record format,
+    /// as one read from another database's log,
+    /// to append to this batch with `append_raw`.
+    ///
+    /// The command must be a write, delete, delete-range,
+    /// merge or copy within this tree's limits.
+    /// Whatever batch it was logged in,
+    /// the returned command is for this batch.
+    pub fn decode_raw(&self, bytes: &[u8]) -> Result<Command> {
+        let mut cmd: Command = self.record_format.decode(bytes)
+            .context("decoding raw command")?;
+
+        match &mut cmd {
+            Command::Write { batch, key, value } |
+            Command::WriteCompressed { batch, key, value } |
+            Command::Merge { batch, key, operand: value } => {
+                self.check_key(key)?;
+                self.check_value(value)?;
+                *batch = self.batch;
+            },
+            Command::Delete { batch, key } => {
+                self.check_delete()?;
+                self.check_key(key)?;
+                *batch = self.batch;
+            },
+            Command::DeleteRange { batch, start_key, end_key } => {
+                self.check_delete()?;
+                self.check_key(start_key)?;
+                self.check_range_end(end_key)?;
+                *batch = self.batch;
+            },
+            Command::Copy { batch, src_key, dst_key } => {
+                self.check_key(src_key)?;
+                self.check_key(dst_key)?;
+                *batch = self.batch;
+            },
+            _ => {
+                bail!("only data commands can be appended raw");
+            },
+        }
+
+        Ok(cmd)
+    }
+
+    /// Appends a command from `decode_raw`,
+    /// returning its address.
+    ///
+    /// Values are appended as encoded,
+    /// without applying the value transform.
+    pub async fn append_raw(&self, cmd: Command) -> Result<Address> {
+        assert_eq!(cmd.batch(), self.batch);
+        if let Command::Copy { .. } = cmd {
+            self.has_copies.store(true, Ordering::SeqCst);
+        }
+        self.append_addressed_record(cmd).await
     }
 
     pub async fn push_save_point(&self) -> Result<()> {
-        Ok(self.append_record(Command::PushSavePoint {
+        self.append_record(Command::PushSavePoint {
             batch: self.batch,
-        }).await?)
+        }).await
     }
 
     pub async fn pop_save_point(&self) -> Result<()> {
-        Ok(self.append_record(Command::PopSavePoint {
+        self.append_record(Command::PopSavePoint {
             batch: self.batch,
-        }).await?)
+        }).await
     }
 
     pub async fn rollback_save_point(&self) -> Result<()> {
-        Ok(self.append_record(Command::RollbackSavePoint {
+        self.append_record(Command::RollbackSavePoint {
             batch: self.batch,
-        }).await?)
+        }).await
     }
 
     pub async fn ready_commit(&self, batch_commit: BatchCommit) -> Result<()> {
-        Ok(self.append_record(Command::ReadyCommit {
+        self.append_record(Command::ReadyCommit {
             batch: self.batch,
             batch_commit,
-        }).await?)
+        }).await?;
+
+        // The batch must reach the file before the master commit
+        self.log.flush().await
     }
 
     pub async fn abort_commit(&self, batch_commit: BatchCommit) -> Result<()> {
-        Ok(self.append_record(Command::AbortCommit {
+        self.append_record(Command::AbortCommit {
             batch: self.batch,
             batch_commit,
-        }).await?)
+        }).await
     }
 
-    pub fn commit_to_index(&self, batch_commit: BatchCommit, commit: Commit) {
-        commit_to_index(&*self.batch_player,
-                        &*self.index,
+    /// In paranoid mode, checks that the batch logged its ready-commit
+    /// for `batch_commit`, so the master commit won't commit a batch
+    /// this tree isn't ready for.
+    pub fn check_ready(&self, batch_commit: BatchCommit) -> Result<()> {
+        if self.validation == Validation::Paranoid
+            && !self.batch_player.is_ready(self.batch, batch_commit)
+        {
+            bail!("batch {} is not ready to commit batch commit {}",
+                  self.batch.0, batch_commit.0);
+        }
+        Ok(())
+    }
+
+    /// Checks that the source of every copy in the batch will exist
+    /// when the batch is committed before `commit_limit`.
+    ///
+    /// NB: This must be called under the commit lock,
+    /// so the index doesn't change before the commit.
+    pub fn check_copies(&self, batch_commit: BatchCommit, commit_limit: Commit) -> Result<()> {
+        if !self.has_copies.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // Whether keys touched by the batch exist,
+        // and the ranges it deleted.
+        let mut exists = BTreeMap::new();
+        let mut deleted_ranges = vec![];
+        for op in self.batch_player.replay(self.batch, batch_commit) {
+            match op {
+                IndexOp::Write { key, .. } | IndexOp::Merge { key, .. } => {
+                    exists.insert(key, true);
+                },
+                IndexOp::Delete { key, .. } => {
+                    exists.insert(key, false);
+                },
+                IndexOp::DeleteRange { start_key, end_key, .. } => {
+                    for (_, key_exists) in exists.range_mut(start_key.clone()..end_key.clone()) {
+                        *key_exists = false;
+                    }
+                    deleted_ranges.push(start_key..end_key);
+                },
+                IndexOp::Copy { src_key, dst_key, .. } => {
+                    let src_exists = match exists.get(&src_key) {
+                        Some(src_exists) => *src_exists,
+                        None if deleted_ranges.iter().any(|r| r.contains(&src_key)) => false,
+                        None => self.index.read(commit_limit, &src_key).is_some(),
+                    };
+                    if !src_exists {
+                        bail!("copy source key does not exist");
+                    }
+                    exists.insert(dst_key, true);
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads `key` as the batch would commit it,
+    /// on top of the values committed before `commit_limit`.
+    ///
+    /// Rolled-back writes are not seen.
+    pub async fn read(&self, commit_limit: Commit, key: &Key) -> Result<Option<Value>> {
+        let lookup = match self.batch_player.staged(self.batch, key) {
+            None => self.index.read(commit_limit, key),
+            Some(StagedValue::Deleted) => None,
+            Some(StagedValue::Records { base, merges, copied }) => {
+                let (base, mut lookup_merges, committed_copied) = match base {
+                    StagedBase::Written(address) => (Some(address), vec![], 0),
+                    StagedBase::Empty => (None, vec![], 0),
+                    StagedBase::Committed(src_key) => match self.index.read(commit_limit, &src_key) {
+                        Some(lookup) => (lookup.base, lookup.merges, lookup.copied),
+                        None => (None, vec![], 0),
+                    },
+                };
+                // Copies take every record before them
+                let copied = match copied {
+                    Some(copied) => usize::from(base.is_some()) + lookup_merges.len() + copied,
+                    None => committed_copied,
+                };
+                lookup_merges.extend(merges);
+                if base.is_none() && lookup_merges.is_empty() {
+                    None
+                } else {
+                    Some(Lookup { base, merges: lookup_merges, copied })
+                }
+            },
+        };
+
+        match lookup {
+            Some(lookup) => {
+                let value = resolve(&self.log, &self.merge_fn, &self.value_transform, key, &lookup).await?;
+                Ok(Some(value))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// The log records making up the committed value of `key`.
+    ///
+    /// These identify a version of the value,
+    /// so comparing them tells whether the key has changed.
+    pub fn lookup(&self, commit_limit: Commit, key: &Key) -> Option<Lookup> {
+        self.index.read(commit_limit, key)
+    }
+
+    /// The number of index operations the batch's next commit would apply.
+    pub fn pending_ops(&self) -> usize {
+        self.batch_player.staged_ops(self.batch)
+    }
+
+    /// The newest commit before `commit_limit` that changed `key`.
+    pub fn latest_commit(&self, commit_limit: Commit, key: &Key) -> Option<Commit> {
+        self.index.latest_commit(commit_limit, key)
+    }
+
+    /// Returns the number of index operations committed.
+    pub fn commit_to_index(&self, batch_commit: BatchCommit, commit: Commit) -> usize {
+        commit_to_index(&self.batch_player,
+                        &self.index,
+                        self.batch,
+                        batch_commit,
+                        commit,
+                        None)
+    }
+
+    /// Like `commit_to_index`, also trimming the history of the keys written
+    /// to `TreeConfig::max_history_per_key` versions,
+    /// but keeping any version reads at `oldest_read` or later need.
+    pub fn commit_to_index_trimming(&self, batch_commit: BatchCommit, commit: Commit,
+                                    oldest_read: Commit) -> usize {
+        commit_to_index(&self.batch_player,
+                        &self.index,
                         self.batch,
                         batch_commit,
-                        commit)                        
+                        commit,
+                        Some(oldest_read))
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.log.sync().await
     }
 
     /// NB: This must only be called after the batch is committed
@@ -201,14 +875,83 @@ impl BatchWriter {
             self.batch_player.emergency_close(self.batch);
             Err(e)
         } else {
-            Ok(())
+            Ok(self.log.flush().await?)
+        }
+    }
+
+    fn check_key(&self, key: &Key) -> Result<()> {
+        if key.0.len() > self.max_key_bytes {
+            return Err(DbError::KeyTooLarge.into());
+        }
+        Ok(())
+    }
+
+    fn check_range_end(&self, end_key: &Key) -> Result<()> {
+        // The end is exclusive, so may be one byte longer
+        // than any key, to cover every key.
+        if end_key.0.len() > self.max_key_bytes.saturating_add(1) {
+            return Err(DbError::KeyTooLarge.into());
+        }
+        Ok(())
+    }
+
+    fn check_delete(&self) -> Result<()> {
+        if self.append_only {
+            bail!("cannot delete from an append-only tree");
+        }
+        Ok(())
+    }
+
+    fn check_value(&self, value: &Value) -> Result<()> {
+        if value.0.len() > self.max_value_bytes {
+            return Err(DbError::ValueTooLarge.into());
+        }
+        Ok(())
+    }
+
+    /// Compresses `value` if the tree is compressed,
+    /// it is large enough and compressing shrinks it,
+    /// returning whether it did.
+    fn compress(&self, value: Value) -> (Value, bool) {
+        match self.compression_min_bytes {
+            Some(min_bytes) if value.0.len() >= min_bytes => {
+                match compression::compress(&value.0) {
+                    Some(compressed) => (Value(compressed), true),
+                    None => (value, false),
+                }
+            },
+            _ => (value, false),
+        }
+    }
+
+    fn transform(&self, value: Value) -> Value {
+        match &self.value_transform {
+            Some(value_transform) => Value(value_transform.on_write(&value.0)),
+            None => value,
         }
     }
 
     async fn append_record(&self, cmd: Command) -> Result<()> {
+        self.append_addressed_record(cmd).await?;
+        Ok(())
+    }
+
+    async fn append_addressed_record(&self, cmd: Command) -> Result<Address> {
         let address = self.log.append(cmd.clone()).await?;
         self.batch_player.record(&cmd, address);
-        Ok(())
+        if cmd.is_change() {
+            self.change_records.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Schedule, but don't wait for, compaction
+        if let Some(max_log_bytes) = self.max_log_bytes {
+            let growth = self.log.size().saturating_sub(self.compacted_log_size.load(Ordering::SeqCst));
+            if growth > max_log_bytes {
+                self.compaction_requested.store(true, Ordering::SeqCst);
+            }
+        }
+
+        Ok(address)
     }
 }
 
@@ -226,20 +969,19 @@ impl Cursor {
         if let Some(value) = &self.value {
             Ok(value.clone())
         } else {
-            let addr = self.index_cursor.address();
-            let cmd = self.log.read_at(addr).await?;
-            match cmd {
-                Command::Write { key, value, .. } => {
-                    assert_eq!(key, self.key());
-                    Ok(value)
-                },
-                _ => {
-                    Err(anyhow!(UNEXPECTED_LOG))
-                }
-            }
+            let lookup = self.index_cursor.lookup();
+            let value = resolve(&self.log, &self.merge_fn, &self.value_transform, &self.key(), &lookup).await?;
+            self.value = Some(value.clone());
+            Ok(value)
         }
     }
 
+    /// Whether `key` has any version in this tree,
+    /// including deletes, before the cursor's commit limit.
+    pub fn touches(&self, key: &Key) -> bool {
+        self.index_cursor.touches(key)
+    }
+
     pub fn next(&mut self) {
         self.value = None;
         self.index_cursor.next()
@@ -283,7 +1025,10 @@ impl<'tree> InitReplayer<'tree> {
         if self.waiting_to_commit.remove(&(target_batch, target_batch_commit)) {
             let batch_player = self.batch_players.get(&batch);
             if let Some(batch_player) = batch_player {
-                commit_to_index(&batch_player, &self.index, batch, batch_commit, commit);
+                // No view is open during replay,
+                // so history is trimmed as if only read from here on.
+                commit_to_index(batch_player, self.index, batch, batch_commit, commit, Some(commit));
+                self.track_commit(batch);
                 return Ok(());
             } else {
                 bail!("batch closed before commit during init replay");
@@ -291,8 +1036,11 @@ impl<'tree> InitReplayer<'tree> {
         }
         
         while let Some(next_cmd) = self.cmd_stream.next().await {
-            let (next_cmd, addr) = next_cmd?;
+            let (next_cmd, addr) = next_cmd.with_context(|| {
+                format!("replaying batch {} of commit {}", target_batch.0, commit.0)
+            })?;
             log::trace!("next cmd {:?}", next_cmd);
+            self.track_batch(&next_cmd);
 
             let new_batch = Some(next_cmd.batch());
             let mut new_batch_commit = None;
@@ -324,7 +1072,9 @@ impl<'tree> InitReplayer<'tree> {
 
                     let is_target_batch = target_batch == batch;
                     let is_target_batch_commit = target_batch_commit == batch_commit;
-                    let bad_batch_combo = is_target_batch ^ is_target_batch_commit;
+                    // The target batch may have tried to commit before,
+                    // but each batch commit number is only ever used by one batch
+                    let bad_batch_combo = is_target_batch_commit && !is_target_batch;
 
                     if bad_batch_combo {
                         bail!(BATCH_MISMATCH);
@@ -334,7 +1084,8 @@ impl<'tree> InitReplayer<'tree> {
 
                     if must_commit {
                         let batch_player = self.batch_players.get(&batch).expect("batch");
-                        commit_to_index(&batch_player, &self.index, batch, batch_commit, commit);
+                        commit_to_index(batch_player, self.index, batch, batch_commit, commit, Some(commit));
+                        self.track_commit(batch);
                         done = true;
                     } else {
                         // This ready-commit log happend out-of-order
@@ -354,7 +1105,8 @@ impl<'tree> InitReplayer<'tree> {
 
                     let is_target_batch = target_batch == batch;
                     let is_target_batch_commit = target_batch_commit == batch_commit;
-                    let bad_batch_combo = is_target_batch ^ is_target_batch_commit;
+                    // As with ready-commits
+                    let bad_batch_combo = is_target_batch_commit && !is_target_batch;
 
                     if bad_batch_combo {
                         bail!(BATCH_MISMATCH);
@@ -366,16 +1118,16 @@ impl<'tree> InitReplayer<'tree> {
                         // Nothing to commit on abort,
                         // but other trees may have committed.
                         done = true;
+                    } else if self.waiting_to_commit.remove(&(batch, batch_commit)) {
+                        // The master commit for a ready-commit failed,
+                        // and the ready-commit was invalidated.
+                        // There's no master commit to wait for.
                     } else {
                         // This ready-commit log happend out-of-order
                         // of the final commit.
                         // It will be committed later,
                         // so have it to the side.
-                        if self.waiting_to_commit.contains(&(batch, batch_commit)) {
-                            bail!(DUPLICATE_BATCH_COMMIT);
-                        } else {
-                            self.waiting_to_commit.insert((batch, batch_commit));
-                        }
+                        self.waiting_to_commit.insert((batch, batch_commit));
                     }
                 },
                 _ => {
@@ -394,9 +1146,23 @@ impl<'tree> InitReplayer<'tree> {
               target_batch.0, target_batch_commit.0, commit.0);
     }
 
+    /// Replays the commands after the last commit.
+    ///
+    /// None of them were committed,
+    /// so a torn tail among them is noted for `discard_torn_tail`.
     pub async fn replay_rest(&mut self) -> Result<(Option<Batch>, Option<BatchCommit>)> {
         while let Some(next_cmd) = self.cmd_stream.next().await {
-            let (next_cmd, addr) = next_cmd?;
+            let (next_cmd, addr) = match next_cmd {
+                Ok(next_cmd) => next_cmd,
+                Err(e) => match torn_tail(&e) {
+                    Some(address) => {
+                        self.torn_tail = Some(address);
+                        break;
+                    },
+                    None => return Err(e),
+                },
+            };
+            self.track_batch(&next_cmd);
 
             let new_batch = Some(next_cmd.batch());
             let mut new_batch_commit = None;
@@ -414,9 +1180,66 @@ impl<'tree> InitReplayer<'tree> {
             self.update_max_batch_and_batch_commit(new_batch, new_batch_commit);
         }
 
+        // Batches never closed are as good as aborted
+        for (batch, outcome) in std::mem::take(&mut self.open_batches) {
+            if outcome.changed && !outcome.committed {
+                self.aborted_batches.insert(batch);
+            }
+        }
+
         Ok((self.max_batch_seen, self.max_batch_commit_seen))
     }
 
+    /// Discards the torn tail of the log found by `replay_rest`, if any.
+    pub async fn discard_torn_tail(&mut self) -> Result<()> {
+        if let Some(address) = self.torn_tail.take() {
+            self.log.truncate(address).await?;
+        }
+        Ok(())
+    }
+
+    /// Batches whose logged changes never committed.
+    ///
+    /// Complete once `replay_rest` has returned.
+    pub fn aborted_batches(&self) -> &BTreeSet<Batch> {
+        &self.aborted_batches
+    }
+
+    /// Notes which batches log changes, and which of those are closed
+    /// without committing.
+    fn track_batch(&mut self, cmd: &Command) {
+        match cmd {
+            Command::Open { batch } => {
+                self.open_batches.insert(*batch, BatchOutcome::default());
+            },
+            Command::Close { batch } => {
+                if let Some(outcome) = self.open_batches.remove(batch) {
+                    if outcome.changed && !outcome.committed {
+                        self.aborted_batches.insert(*batch);
+                    }
+                }
+            },
+            Command::Write { batch, .. }
+            | Command::WriteCompressed { batch, .. }
+            | Command::Delete { batch, .. }
+            | Command::DeleteRange { batch, .. }
+            | Command::Merge { batch, .. }
+            | Command::Copy { batch, .. } => {
+                self.change_records.fetch_add(1, Ordering::Relaxed);
+                if let Some(outcome) = self.open_batches.get_mut(batch) {
+                    outcome.changed = true;
+                }
+            },
+            _ => { },
+        }
+    }
+
+    fn track_commit(&mut self, batch: Batch) {
+        if let Some(outcome) = self.open_batches.get_mut(&batch) {
+            outcome.committed = true;
+        }
+    }
+
     fn record_cmd(&mut self, cmd: Command, addr: Address) -> Result<()> {
         log::trace!("record cmd {:?}", cmd);
         let batch = cmd.batch();
@@ -463,10 +1286,16 @@ fn commit_to_index(batch_player: &BatchPlayer,
                    index: &Index,
                    batch: Batch,
                    batch_commit: BatchCommit,
-                   commit: Commit) {
+                   commit: Commit,
+                   oldest_read: Option<Commit>) -> usize {
     let index_ops = batch_player.replay(batch, batch_commit);
     let mut writer = index.writer(commit);
+    if let Some(oldest_read) = oldest_read {
+        writer.trim_history(oldest_read);
+    }
+    let mut op_count = 0;
     for op in index_ops {
+        op_count += 1;
         match op {
             IndexOp::Write { key, address } => {
                 writer.write(key, address);
@@ -477,10 +1306,75 @@ fn commit_to_index(batch_player: &BatchPlayer,
             IndexOp::DeleteRange { start_key, end_key, address } => {
                 writer.delete_range(start_key..end_key, address);
             },
+            IndexOp::Merge { key, address } => {
+                writer.merge(key, address);
+            },
+            IndexOp::Copy { src_key, dst_key, address } => {
+                writer.copy(&src_key, dst_key, address);
+            },
+        }
+    }
+    op_count
+}
+
+/// Reads a value and its merge operands from the log and combines them.
+async fn resolve(log: &Log<Command>, merge_fn: &MergeFn, value_transform: &Option<ValueTransformRef>,
+                 key: &Key, lookup: &index::Lookup) -> Result<Value> {
+    let untransform = |value: Value| -> Result<Value> {
+        match value_transform {
+            Some(value_transform) => Ok(Value(value_transform.on_read(&value.0)?)),
+            None => Ok(value),
+        }
+    };
+
+    // The records copied from another key come first
+    let mut copied = lookup.copied;
+    let mut check_key = |log_key: &Key| {
+        if copied > 0 {
+            copied -= 1;
+        } else {
+            assert_eq!(key, log_key);
+        }
+    };
+
+    let mut value = match lookup.base {
+        Some(addr) => {
+            let cmd = log.read_at(addr).await?;
+            match cmd {
+                Command::Write { key: log_key, value, .. } => {
+                    check_key(&log_key);
+                    Some(untransform(value)?)
+                }
+                Command::WriteCompressed { key: log_key, value, .. } => {
+                    check_key(&log_key);
+                    Some(Value(compression::decompress(&untransform(value)?.0)?))
+                }
+                _ => {
+                    return Err(anyhow!(UNEXPECTED_LOG));
+                }
+            }
+        },
+        None => None,
+    };
+
+    for addr in &lookup.merges {
+        let cmd = log.read_at(*addr).await?;
+        match cmd {
+            Command::Merge { key: log_key, operand, .. } => {
+                check_key(&log_key);
+                let operand = untransform(operand)?;
+                let merged = merge_fn(&key.0, value.as_ref().map(|v| &v.0[..]), &operand.0)?;
+                value = Some(Value(merged));
+            }
+            _ => {
+                return Err(anyhow!(UNEXPECTED_LOG));
+            }
         }
     }
+
+    Ok(value.expect("lookup with no base or merges"))
 }
 
-static UNEXPECTED_LOG: &'static str = "unexpected command in log";
-static BATCH_MISMATCH: &'static str = "mismatch in batch / batch_commit between commit log and tree log";
-static DUPLICATE_BATCH_COMMIT: &'static str = "duplicate batch / batch_ commit during replay";
+static UNEXPECTED_LOG: &str = "unexpected command in log";
+static BATCH_MISMATCH: &str = "mismatch in batch / batch_commit between commit log and tree log";
+static DUPLICATE_BATCH_COMMIT: &str = "duplicate batch / batch_ commit during replay";
@@ -0,0 +1,92 @@
+//! Small per-tree settings kept in a file beside the logs.
+//!
+//! The whole file is replaced on every change,
+//! so after a crash it holds either the old or the new metadata.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::atomic_file;
+
+/// Doesn't end in `.toml`, so can't be any tree's log.
+const FILE_NAME: &str = "tree_metadata";
+
+pub type Metadata = BTreeMap<String, BTreeMap<String, String>>;
+
+#[derive(Debug)]
+pub struct TreeMetadata {
+    dir: Option<PathBuf>,
+    trees: Mutex<Metadata>,
+}
+
+impl TreeMetadata {
+    /// Metadata that is never written to disk.
+    pub fn in_memory() -> TreeMetadata {
+        TreeMetadata {
+            dir: None,
+            trees: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Loads the metadata stored in `dir`, if any.
+    pub fn load(dir: &Path) -> Result<TreeMetadata> {
+        let trees = match atomic_file::read(&dir.join(FILE_NAME))? {
+            Some(bytes) => toml::from_slice(&bytes)?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(TreeMetadata {
+            dir: Some(dir.to_owned()),
+            trees: Mutex::new(trees),
+        })
+    }
+
+    pub fn get(&self, tree: &str, key: &str) -> Option<String> {
+        let trees = self.trees.lock().expect("lock");
+        trees.get(tree)?.get(key).cloned()
+    }
+
+    /// Durably stores `value` before making it visible to `get`.
+    pub fn set(&self, tree: &str, key: &str, value: &str) -> Result<()> {
+        self.update(|trees| {
+            trees.entry(tree.to_string()).or_default()
+                .insert(key.to_string(), value.to_string());
+        })
+    }
+
+    /// Durably removes all of `tree`'s metadata.
+    pub fn remove_tree(&self, tree: &str) -> Result<()> {
+        self.update(|trees| {
+            trees.remove(tree);
+        })
+    }
+
+    pub fn snapshot(&self) -> Metadata {
+        self.trees.lock().expect("lock").clone()
+    }
+
+    /// Durably replaces all metadata with `trees`.
+    pub fn replace(&self, trees: Metadata) -> Result<()> {
+        self.update(|old_trees| *old_trees = trees)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Metadata)) -> Result<()> {
+        // Held while writing so concurrent changes can't be lost
+        let mut trees = self.trees.lock().expect("lock");
+        let mut new_trees = trees.clone();
+        f(&mut new_trees);
+
+        if let Some(dir) = &self.dir {
+            write(dir, &new_trees)?;
+        }
+
+        *trees = new_trees;
+        Ok(())
+    }
+}
+
+fn write(dir: &Path, trees: &Metadata) -> Result<()> {
+    let bytes = toml::to_string_pretty(trees)?.into_bytes();
+    atomic_file::replace(&dir.join(FILE_NAME), &bytes)
+}
@@ -1,7 +1,9 @@
 use serde::{Serialize, Deserialize};
+use std::fmt;
 use std::sync::Arc;
 
 #[derive(Eq, PartialEq)]
+#[derive(Ord, PartialOrd)]
 #[derive(Copy, Clone)]
 #[derive(Debug)]
 pub struct Address(pub u64);
@@ -52,3 +54,57 @@ impl Value {
         Value(other.to_vec())
     }
 }
+
+impl Batch {
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for Batch {
+    fn from(other: u64) -> Batch {
+        Batch(other)
+    }
+}
+
+impl fmt::Display for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl BatchCommit {
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for BatchCommit {
+    fn from(other: u64) -> BatchCommit {
+        BatchCommit(other)
+    }
+}
+
+impl fmt::Display for BatchCommit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Commit {
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for Commit {
+    fn from(other: u64) -> Commit {
+        Commit(other)
+    }
+}
+
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,13 @@
+/// How many runtime invariant checks to perform.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Validation {
+    /// Also perform expensive checks,
+    /// such as reading back every log record after appending it.
+    Paranoid,
+    /// Perform cheap checks.
+    #[default]
+    Normal,
+    /// Skip checks that only guard against bugs in the database itself.
+    Off,
+}
+
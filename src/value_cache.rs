@@ -0,0 +1,86 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use crate::log::LogGeneration;
+use crate::types::Address;
+
+/// A bounded cache of values by log address.
+///
+/// Log records are immutable until the log is truncated or replaced,
+/// which starts a new [`LogGeneration`].
+/// Entries are kept for one generation of one log,
+/// and the cache is cleared when the generation changes.
+/// Entries are evicted in insertion order.
+pub struct ValueCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    generation: Option<LogGeneration>,
+    values: BTreeMap<Address, Arc<[u8]>>,
+    order: VecDeque<Address>,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            state: Mutex::new(CacheState {
+                generation: None,
+                values: BTreeMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Whether values are cached at all.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&self, generation: LogGeneration, addr: Address) -> Option<Arc<[u8]>> {
+        let state = self.state.lock().expect("lock");
+        if state.generation != Some(generation) {
+            return None;
+        }
+        state.values.get(&addr).cloned()
+    }
+
+    /// Cache a value read from `generation` of a log,
+    /// returning the cached copy.
+    ///
+    /// If another reader cached the same address first,
+    /// that value is returned instead.
+    /// Values from a generation older than the cached one aren't cached.
+    pub fn insert(&self, generation: LogGeneration, addr: Address, value: Arc<[u8]>) -> Arc<[u8]> {
+        if self.capacity == 0 {
+            return value;
+        }
+
+        let mut state = self.state.lock().expect("lock");
+        match state.generation {
+            Some(cached) if cached == generation => { },
+            Some(cached) if cached.is_newer_than(generation) => {
+                return value;
+            },
+            _ => {
+                state.generation = Some(generation);
+                state.values.clear();
+                state.order.clear();
+            },
+        }
+
+        if let Some(existing) = state.values.get(&addr) {
+            return existing.clone();
+        }
+
+        while state.order.len() >= self.capacity {
+            let evicted = state.order.pop_front().expect("entry");
+            state.values.remove(&evicted);
+        }
+
+        state.values.insert(addr, value.clone());
+        state.order.push_back(addr);
+        value
+    }
+}
@@ -0,0 +1,15 @@
+use anyhow::Result;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// A reversible transformation of stored values, such as encryption.
+///
+/// Values are transformed as they are written to the log,
+/// and transformed back as they are read.
+/// Keys are never transformed, so they keep their order.
+pub trait ValueTransform: Debug + Send + Sync {
+    fn on_write(&self, value: &[u8]) -> Vec<u8>;
+    fn on_read(&self, value: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub type ValueTransformRef = Arc<dyn ValueTransform>;
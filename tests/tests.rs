@@ -74,3 +74,6217 @@ read-assert t1 k1 v1
 
 ")
 }
+
+fn mem_config() -> db::DbConfig {
+    db::DbConfig::in_memory(vec!["t1".to_string(), "t2".to_string()])
+}
+
+async fn commit_write(db: &db::Db, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+    let batch = db.write_batch().await?;
+    batch.tree(tree)?.write(key, value).await?;
+    batch.commit().await?;
+    batch.close().await;
+    Ok(())
+}
+
+async fn commit_delete(db: &db::Db, tree: &str, key: &[u8]) -> Result<()> {
+    let batch = db.write_batch().await?;
+    batch.tree(tree)?.delete(key).await?;
+    batch.commit().await?;
+    batch.close().await;
+    Ok(())
+}
+
+#[test]
+fn key_history() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k2", b"x").await?;
+        commit_write(&db, "t1", b"k1", b"v2").await?;
+        let old_view = db.read_view();
+        commit_delete(&db, "t1", b"k1").await?;
+        commit_write(&db, "t1", b"k1", b"v3").await?;
+
+        let view = db.read_view();
+        let history = view.tree("t1")?.history(b"k1").await?;
+        assert_eq!(history, vec![
+            (0, Some(b"v1".to_vec())),
+            (2, Some(b"v2".to_vec())),
+            (3, None),
+            (4, Some(b"v3".to_vec())),
+        ]);
+
+        let history = old_view.tree("t1")?.history(b"k1").await?;
+        assert_eq!(history, vec![
+            (0, Some(b"v1".to_vec())),
+            (2, Some(b"v2".to_vec())),
+        ]);
+
+        assert!(view.tree("t1")?.history(b"k3").await?.is_empty());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn disk_full_on_master_commit() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::commit_log::CommitCommand;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    block_on(async {
+        let disk_full = Arc::new(AtomicBool::new(false));
+
+        let commit_log = {
+            let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = mem_log_file::create::<CommitCommand>();
+            let disk_full = disk_full.clone();
+            LogFile {
+                is_empty,
+                append: Box::new(move |cmd| {
+                    if disk_full.load(Ordering::SeqCst) {
+                        Box::pin(async {
+                            Err(io::Error::from(io::ErrorKind::StorageFull).into())
+                        })
+                    } else {
+                        append(cmd)
+                    }
+                }),
+                read_at,
+                flush,
+                sync,
+                size,
+                truncate,
+                remove,
+            }
+        };
+
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(mem_log_file::create()));
+        tree_logs.insert("t2".to_string(), Log::new(mem_log_file::create()));
+
+        let db = bdb::Db::new(tree_logs, Log::new(commit_log));
+        db.init().await?;
+
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.open("t2").await?;
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+
+        disk_full.store(true, Ordering::SeqCst);
+
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.ready_commit("t2", batch_commit).await?;
+        let err = batch.commit(batch_commit).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::DiskFull)));
+
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, None);
+
+        disk_full.store(false, Ordering::SeqCst);
+
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.ready_commit("t2", batch_commit).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+        batch.close("t2").await?;
+
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, Some(Value::from_slice(b"v1")));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn reopen_after_disk_full_or_torn_master_commit() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::commit_log::CommitCommand;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let dir = temp_dir("disk-full-reopen");
+    std::fs::create_dir_all(&dir)?;
+
+    let open = |disk_full: &Arc<AtomicBool>| -> Result<bdb::Db> {
+        let fs_thread = Arc::new(FsThread::start()?);
+        let commit_log = {
+            let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } =
+                simple_log_file::create::<CommitCommand>(dir.join("commits.log"), fs_thread.clone());
+            let disk_full = disk_full.clone();
+            LogFile {
+                is_empty,
+                append: Box::new(move |cmd| {
+                    if disk_full.load(Ordering::SeqCst) {
+                        Box::pin(async {
+                            Err(io::Error::from(io::ErrorKind::StorageFull).into())
+                        })
+                    } else {
+                        append(cmd)
+                    }
+                }),
+                read_at,
+                flush,
+                sync,
+                size,
+                truncate,
+                remove,
+            }
+        };
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(simple_log_file::create(dir.join("t1.log"), fs_thread)));
+        Ok(bdb::Db::new(tree_logs, Log::new(commit_log)))
+    };
+
+    block_on(async {
+        let disk_full = Arc::new(AtomicBool::new(false));
+        let db = open(&disk_full)?;
+        db.init().await?;
+
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        disk_full.store(true, Ordering::SeqCst);
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        let err = batch.commit(batch_commit).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::DiskFull)));
+        drop(batch);
+        drop(db);
+
+        // The aborted commit isn't loaded
+        disk_full.store(false, Ordering::SeqCst);
+        let db = open(&disk_full)?;
+        db.init().await?;
+        assert_eq!(db.view().read("t1", &Key::from_slice(b"k1")).await?, None);
+
+        // And commits still work, retried or new
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(b"k2"), Value::from_slice(b"v2")).await?;
+        disk_full.store(true, Ordering::SeqCst);
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        assert!(batch.commit(batch_commit).await.is_err());
+        disk_full.store(false, Ordering::SeqCst);
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+        drop(db);
+
+        let db = open(&disk_full)?;
+        db.init().await?;
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, None);
+        assert_eq!(view.read("t1", &Key::from_slice(b"k2")).await?, Some(Value::from_slice(b"v2")));
+        assert_eq!(view.history("t1", &Key::from_slice(b"k2")).await?.len(), 1);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    // A master commit record cut short, as by a crash while writing it
+    let dir = temp_dir("torn-master-commit");
+    let config = db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: vec!["t1".to_string()],
+        ..db::DbConfig::default()
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+        drop(db);
+
+        let commit_log = std::fs::OpenOptions::new().write(true).open(dir.join("commits.toml"))?;
+        commit_log.set_len(commit_log.metadata()?.len() - 3)?;
+        drop(commit_log);
+
+        let db = db::Db::open(config.clone()).await?;
+        assert_eq!(db.read_view().tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(db.read_view().tree("t1")?.read(b"k2").await?, None);
+        commit_write(&db, "t1", b"k3", b"v3").await?;
+        drop(db);
+
+        // The torn record was discarded, not left before later commits
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, Some(b"v3".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn epoch_reclamation_stress() {
+    use db::raw::epoch::Epochs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    const RESOURCES: usize = 2000;
+
+    struct Resource {
+        id: usize,
+        freed: Arc<Vec<AtomicBool>>,
+    }
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            self.freed[self.id].store(true, Ordering::SeqCst);
+        }
+    }
+
+    let epochs = Epochs::new();
+    let freed: Arc<Vec<AtomicBool>> = Arc::new((0..RESOURCES).map(|_| AtomicBool::new(false)).collect());
+    let current = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..4).map(|_| {
+        let epochs = epochs.clone();
+        let freed = freed.clone();
+        let current = current.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                let guard = epochs.enter();
+                let id = current.load(Ordering::SeqCst);
+                for _ in 0..10 {
+                    assert!(!freed[id].load(Ordering::SeqCst));
+                    thread::yield_now();
+                }
+                drop(guard);
+            }
+        })
+    }).collect();
+
+    // The "compactor" replaces the current resource and retires the old one
+    let mut live = Resource { id: 0, freed: freed.clone() };
+    for id in 1..RESOURCES {
+        let old = std::mem::replace(&mut live, Resource { id, freed: freed.clone() });
+        current.store(id, Ordering::SeqCst);
+        epochs.retire(old);
+    }
+
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().expect("join");
+    }
+
+    epochs.collect();
+    assert_eq!(epochs.pending(), 0);
+    assert!(freed[..RESOURCES - 1].iter().all(|f| f.load(Ordering::SeqCst)));
+    assert!(!freed[RESOURCES - 1].load(Ordering::SeqCst));
+}
+
+#[test]
+fn read_views_pin_epochs() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::log::Log;
+    use db::raw::mem_log_file;
+    use std::collections::BTreeMap;
+
+    block_on(async {
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(mem_log_file::create()));
+        let db = bdb::Db::new(tree_logs, Log::new(mem_log_file::create()));
+        db.init().await?;
+
+        let view = db.view();
+        let cursor = view.cursor("t1")?;
+        db.epochs().retire(vec![0u8; 16]);
+
+        drop(view);
+        assert_eq!(db.epochs().pending(), 1);
+        drop(cursor);
+        assert_eq!(db.epochs().pending(), 0);
+
+        Ok(())
+    })
+}
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("blocksy3-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn dir_size(dir: &std::path::Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        size += entry?.metadata()?.len();
+    }
+    Ok(size)
+}
+
+#[test]
+fn compact_to_new_dir() -> Result<()> {
+    let src_dir = temp_dir("compact-src");
+    let dest_dir = temp_dir("compact-dest");
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            dir: Some(src_dir.clone()),
+            trees: vec!["t1".to_string(), "t2".to_string()],
+            ..db::DbConfig::default()
+        }).await?;
+
+        for i in 0..20 {
+            let value = format!("v{}", i);
+            commit_write(&db, "t1", b"k1", value.as_bytes()).await?;
+            commit_write(&db, "t2", b"k2", value.as_bytes()).await?;
+        }
+        commit_write(&db, "t1", b"k3", b"gone").await?;
+        commit_delete(&db, "t1", b"k3").await?;
+        db.sync().await?;
+
+        db.compact_to(&dest_dir).await?;
+        drop(db);
+
+        assert!(dir_size(&dest_dir)? < dir_size(&src_dir)?);
+
+        let db = db::Db::open(db::DbConfig {
+            dir: Some(dest_dir.clone()),
+            trees: vec!["t1".to_string(), "t2".to_string()],
+            ..db::DbConfig::default()
+        }).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v19".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v19".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, None);
+
+        assert!(db.compact_to(&src_dir).await.is_err());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&src_dir)?;
+    std::fs::remove_dir_all(&dest_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn stats_trees_per_commit() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t2", b"k1", b"v1").await?;
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k2", b"v2").await?;
+        batch.tree("t2")?.delete(b"k1").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let batch = db.write_batch().await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        // The empty batch is not a commit
+        assert_eq!(db.stats().trees_per_commit, vec![0, 2, 1]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn cancelled_commit_is_all_or_nothing() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::commit_log::CommitCommand;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Key, Value};
+    use futures::FutureExt;
+    use std::collections::BTreeMap;
+
+    block_on(async {
+        // Commit-log appends wait for a go-ahead
+        let (gate_tx, gate_rx) = async_channel::unbounded::<()>();
+
+        let commit_log = {
+            let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = mem_log_file::create::<CommitCommand>();
+            LogFile {
+                is_empty,
+                append: Box::new(move |cmd| {
+                    let gate_rx = gate_rx.clone();
+                    let append = append(cmd);
+                    Box::pin(async move {
+                        gate_rx.recv().await?;
+                        append.await
+                    })
+                }),
+                read_at,
+                flush,
+                sync,
+                size,
+                truncate,
+                remove,
+            }
+        };
+
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(mem_log_file::create()));
+
+        let db = bdb::Db::new(tree_logs, Log::new(commit_log));
+        db.init().await?;
+
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+
+        // Cancel the commit while it waits on the master commit write
+        assert!(batch.commit(batch_commit).now_or_never().is_none());
+
+        // Nothing is visible yet
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, None);
+
+        // Once the write is done readers see the commit,
+        // without waiting for another commit
+        gate_tx.send(()).await?;
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, Some(Value::from_slice(b"v1")));
+        assert_eq!(view.history("t1", &Key::from_slice(b"k1")).await?.len(), 1);
+
+        // Later commits continue with the next commit number
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v2")).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        gate_tx.send(()).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+
+        let view = db.view();
+        let history = view.history("t1", &Key::from_slice(b"k1")).await?;
+        let commits: Vec<_> = history.iter().map(|(commit, _)| commit.0).collect();
+        assert_eq!(commits, vec![0, 1]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn tree_namespaces() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"a", b"outside").await?;
+        batch.tree_ns("t1", b"ns1/")?.write(b"k1", b"ns1-v1").await?;
+        batch.tree_ns("t1", b"ns1/")?.write(b"k2", b"ns1-v2").await?;
+        batch.tree_ns("t1", b"ns2/")?.write(b"k1", b"ns2-v1").await?;
+        batch.tree("t1")?.write(b"z", b"outside").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree_ns("t1", b"ns1/")?.read(b"k1").await?, Some(b"ns1-v1".to_vec()));
+        assert_eq!(view.tree_ns("t1", b"ns2/")?.read(b"k1").await?, Some(b"ns2-v1".to_vec()));
+        assert_eq!(view.tree_ns("t1", b"ns2/")?.read(b"k2").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"ns1/k2").await?, Some(b"ns1-v2".to_vec()));
+
+        let tree = view.tree_ns("t1", b"ns1/")?;
+        let mut cursor = tree.cursor();
+        let mut keys = vec![];
+        cursor.seek_first();
+        while cursor.valid() {
+            keys.push(cursor.key());
+            cursor.next();
+        }
+        assert_eq!(keys, vec![b"k1".to_vec(), b"k2".to_vec()]);
+
+        cursor.seek_last();
+        assert_eq!(cursor.key(), b"k2".to_vec());
+        cursor.prev();
+        assert_eq!(cursor.key(), b"k1".to_vec());
+        cursor.prev();
+        assert!(!cursor.valid());
+
+        let tree = view.tree_ns("t1", b"ns2/")?;
+        let mut cursor = tree.cursor();
+        cursor.seek_last();
+        assert_eq!(cursor.key(), b"k1".to_vec());
+        assert_eq!(cursor.value().await?, b"ns2-v1".to_vec());
+        cursor.next();
+        assert!(!cursor.valid());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn empty_commit_is_noop() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.history(b"k1").await?, vec![(0, Some(b"v1".to_vec()))]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn batch_len_counts_staged_ops() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        assert!(batch.is_empty());
+        batch.tree("t1")?.write(b"k1", b"v1").await?;
+        batch.tree("t1")?.write(b"k1", b"v2").await?;
+        batch.tree("t1")?.delete(b"k2").await?;
+        batch.tree("t2")?.increment(b"c", 1).await?;
+        assert_eq!(batch.len(), 3);
+        assert!(!batch.is_empty());
+        batch.commit().await?;
+        batch.close().await;
+
+        // Everything rolled back, so the commit is a no-op
+        let batch = db.write_batch().await?;
+        batch.push_save_point().await?;
+        batch.tree("t1")?.write(b"k3", b"v3").await?;
+        assert_eq!(batch.len(), 1);
+        batch.rollback_save_point().await?;
+        assert_eq!(batch.len(), 0);
+        assert!(batch.is_empty());
+        batch.commit().await?;
+        batch.close().await;
+
+        // Operations a copy reads are kept,
+        // and nested save points restore the count
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t2")?;
+        tree.increment(b"n", 1).await?;
+        tree.increment(b"n", 1).await?;
+        tree.copy(b"n", b"m").await?;
+        tree.write(b"n", b"v").await?;
+        assert_eq!(batch.len(), 4);
+        batch.push_save_point().await?;
+        tree.write(b"m", b"v").await?;
+        batch.push_save_point().await?;
+        tree.delete_range(b"a", b"z").await?;
+        assert_eq!(batch.len(), 6);
+        batch.rollback_save_point().await?;
+        assert_eq!(batch.len(), 5);
+        batch.rollback_save_point().await?;
+        assert_eq!(batch.len(), 4);
+        tree.write(b"m", b"w").await?;
+        tree.write(b"m", b"x").await?;
+        assert_eq!(batch.len(), 5);
+        batch.close().await;
+
+        commit_write(&db, "t1", b"k4", b"v4").await?;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.history(b"k1").await?, vec![(0, Some(b"v2".to_vec()))]);
+        assert_eq!(view.tree("t1")?.history(b"k3").await?, vec![]);
+        assert_eq!(view.tree("t1")?.history(b"k4").await?, vec![(1, Some(b"v4".to_vec()))]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn concurrent_counter_increments() -> Result<()> {
+    let dir = temp_dir("counters");
+    let config = db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: vec!["t1".to_string(), "t2".to_string()],
+        ..db::DbConfig::default()
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+
+        assert_eq!(db.read_view().tree("t1")?.read_counter(b"c").await?, 0);
+
+        let threads: Vec<_> = (0..4i64).map(|thread| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                block_on(async {
+                    for i in 0..10 {
+                        let batch = db.write_batch().await?;
+                        batch.tree("t1")?.increment(b"c", thread * 10 + i).await?;
+                        batch.tree("t1")?.increment(b"c", -1).await?;
+                        batch.commit().await?;
+                        batch.close().await;
+                    }
+                    Ok(())
+                })
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().expect("join")?;
+        }
+
+        // sum(0..40) - 40
+        let expected = (0..40).sum::<i64>() - 40;
+        assert_eq!(db.read_view().tree("t1")?.read_counter(b"c").await?, expected);
+
+        // Increment on top of a plain write and a delete
+        commit_write(&db, "t1", b"c", &100i64.to_le_bytes()).await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.increment(b"c", 5).await?;
+        batch.commit().await?;
+        batch.close().await;
+        assert_eq!(db.read_view().tree("t1")?.read_counter(b"c").await?, 105);
+
+        commit_delete(&db, "t1", b"c").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.increment(b"c", 7).await?;
+        batch.commit().await?;
+        batch.close().await;
+        assert_eq!(db.read_view().tree("t1")?.read_counter(b"c").await?, 7);
+
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config).await?;
+        assert_eq!(db.read_view().tree("t1")?.read_counter(b"c").await?, 7);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn dir_sync_only_after_file_creation() -> Result<()> {
+    let dir = temp_dir("dir-sync");
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            dir: Some(dir.clone()),
+            trees: vec!["t1".to_string(), "t2".to_string()],
+            ..db::DbConfig::default()
+        }).await?;
+
+        // The first batch creates the log files
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        db.sync().await?;
+        assert_eq!(db.stats().dir_syncs, 1);
+
+        // Plain appends don't need the directory synced
+        commit_write(&db, "t1", b"k1", b"v2").await?;
+        db.sync().await?;
+        db.sync().await?;
+        assert_eq!(db.stats().dir_syncs, 1);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn read_arc_shares_cached_values() -> Result<()> {
+    use std::sync::Arc;
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            value_cache_entries: 16,
+            ..mem_config()
+        }).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+
+        let view = db.read_view();
+        let a = view.tree("t1")?.read_arc(b"k1").await?.expect("value");
+        let b = view.tree("t1")?.read_arc(b"k1").await?.expect("value");
+        assert_eq!(&a[..], b"v1");
+        assert!(Arc::ptr_eq(&a, &b));
+
+        assert!(view.tree("t1")?.read_arc(b"k2").await?.is_none());
+
+        // Without a cache values are still returned, just not shared
+        let db = db::Db::open(mem_config()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        let view = db.read_view();
+        let a = view.tree("t1")?.read_arc(b"k1").await?.expect("value");
+        let b = view.tree("t1")?.read_arc(b"k1").await?.expect("value");
+        assert_eq!(a, b);
+        assert!(!Arc::ptr_eq(&a, &b));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn batch_wide_save_points() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        assert!(batch.rollback_save_point().await.is_err());
+        assert!(batch.pop_save_point().await.is_err());
+
+        batch.tree("t1")?.write(b"k1", b"v1").await?;
+        batch.tree("t2")?.write(b"k2", b"v2").await?;
+        batch.push_save_point().await?;
+        batch.tree("t1")?.write(b"k1", b"v1-rolled-back").await?;
+        batch.tree("t2")?.write(b"k3", b"v3-rolled-back").await?;
+        batch.push_save_point().await?;
+        batch.tree("t2")?.delete(b"k2").await?;
+        batch.pop_save_point().await?;
+        batch.rollback_save_point().await?;
+        assert!(batch.rollback_save_point().await.is_err());
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k3").await?, None);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn max_log_bytes_requests_compaction() -> Result<()> {
+    use std::time::Duration;
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            max_log_bytes: Some(1024),
+            ..mem_config()
+        }).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        assert!(db.pending_compactions().is_empty());
+
+        let big_value = vec![7; 2048];
+        commit_write(&db, "t1", b"k1", &big_value).await?;
+        assert_eq!(db.pending_compactions(), vec!["t1".to_string()]);
+
+        // The triggering write still completed
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(big_value.clone()));
+        drop(view);
+        drop(db);
+
+        // The request is compacted in the background, and cleared
+        let db = db::Db::open(db::DbConfig {
+            max_log_bytes: Some(1024),
+            compaction_policy: Some(db::CompactionPolicy {
+                interval: Duration::from_millis(10),
+                min_live_ratio: 0.0,
+                log_growth_bytes: 0,
+            }),
+            ..mem_config()
+        }).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(db.compaction_stats().compactions, 0);
+
+        commit_write(&db, "t1", b"k1", &big_value).await?;
+        let start = std::time::Instant::now();
+        while !db.pending_compactions().is_empty() || db.compaction_stats().compactions == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(db.compaction_stats().compactions, 1);
+        assert_eq!(db.compaction_stats().versions_discarded, 1);
+        assert_eq!(db.read_view().tree("t1")?.history(b"k1").await?.len(), 1);
+
+        // Only growth past the compacted size requests it again
+        commit_write(&db, "t1", b"k1", b"v2").await?;
+        assert!(db.pending_compactions().is_empty());
+        commit_write(&db, "t1", b"k1", &big_value).await?;
+        let start = std::time::Instant::now();
+        while db.compaction_stats().compactions < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn apply_map_is_one_commit() -> Result<()> {
+    use std::collections::BTreeMap;
+
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        commit_write(&db, "t1", b"k1", b"old").await?;
+        commit_write(&db, "t1", b"k2", b"old").await?;
+
+        let mut entries = BTreeMap::new();
+        entries.insert(b"k1".to_vec(), None);
+        entries.insert(b"k2".to_vec(), Some(b"new".to_vec()));
+        entries.insert(b"k3".to_vec(), Some(b"new".to_vec()));
+        let commit = db.apply_map("t1", entries).await?;
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        assert_eq!(tree.read(b"k1").await?, None);
+        assert_eq!(tree.read(b"k2").await?, Some(b"new".to_vec()));
+        assert_eq!(tree.read(b"k3").await?, Some(b"new".to_vec()));
+
+        // Every entry landed in the same commit
+        assert_eq!(tree.history(b"k1").await?.last(), Some(&(commit, None)));
+        assert_eq!(tree.history(b"k2").await?.last(), Some(&(commit, Some(b"new".to_vec()))));
+        assert_eq!(tree.history(b"k3").await?, vec![(commit, Some(b"new".to_vec()))]);
+        assert_eq!(db.stats().trees_per_commit.len() as u64, commit + 1);
+
+        assert!(db.apply_map("t1", BTreeMap::new()).await.is_err());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn batches_respect_max_batch_bytes() -> Result<()> {
+    use std::collections::BTreeMap;
+
+    block_on(async {
+        let mut config = mem_config();
+        config.max_batch_bytes = 10;
+        let db = db::Db::open(config).await?;
+
+        // Keys and values together count against the limit
+        let mut entries = BTreeMap::new();
+        entries.insert(b"k1".to_vec(), Some(b"value".to_vec()));
+        entries.insert(b"k2".to_vec(), None);
+        db.apply_map("t1", entries.clone()).await?;
+        let commits = db.stats().trees_per_commit.len();
+
+        // An oversized map is rejected and nothing is committed
+        entries.insert(b"k3".to_vec(), Some(b"v".to_vec()));
+        let err = db.apply_map("t1", entries).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::BatchTooLarge)));
+        assert_eq!(db.read_view().tree("t1")?.read(b"k3").await?, None);
+        assert_eq!(db.stats().trees_per_commit.len(), commits);
+
+        // A batch fails the write that goes past the limit, and keeps the rest
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k4", b"1234").await?;
+        let err = batch.tree("t2")?.write(b"k5", b"1234").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::BatchTooLarge)));
+        batch.commit().await?;
+        batch.close().await;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k4").await?, Some(b"1234".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k5").await?, None);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn compacting_tree_layers() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, Layer};
+    use db::raw::epoch::Epochs;
+    use db::raw::log::Log;
+    use db::raw::mem_log_file;
+    use db::raw::tree::{Tree, TreeConfig};
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    block_on(async {
+        let active = Tree::new(Log::new(mem_log_file::create()), TreeConfig::default());
+        active.skip_init();
+
+        let batch = active.batch(Batch(0));
+        batch.open().await?;
+        batch.write(Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(0));
+        batch.close().await?;
+
+        let tree = CompactingTree::new(active, Epochs::new());
+        let key = Key::from_slice(b"k1");
+        let commit_limit = Commit(1);
+
+        // Before any compaction there is only an active layer
+        assert_eq!(tree.read_layer(Layer::Active, commit_limit, &key).await?,
+                   Some(Value::from_slice(b"v1")));
+        assert_eq!(tree.read_layer(Layer::Compacting, commit_limit, &key).await?, None);
+        assert_eq!(tree.read_layer(Layer::Compacted, commit_limit, &key).await?, None);
+        assert_eq!(tree.read_layers(commit_limit, &key).await?,
+                   vec![(Layer::Active, Some(Value::from_slice(b"v1")))]);
+
+        Ok(())
+    })
+}
+
+/// Creates trees for a `CompactingTree` with numbered logs in `dir`.
+fn new_tree_fn(dir: &std::path::Path) -> Result<db::raw::compacting_tree::NewTreeFn> {
+    new_tree_fn_with_config(dir, db::raw::tree::TreeConfig::default())
+}
+
+fn new_tree_fn_with_config(dir: &std::path::Path, config: db::raw::tree::TreeConfig) -> Result<db::raw::compacting_tree::NewTreeFn> {
+    use db::raw::compacting_tree::NewTreeRole;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::tree::Tree;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    std::fs::create_dir_all(dir)?;
+    let dir = dir.to_path_buf();
+    let fs_thread = Arc::new(FsThread::start()?);
+    let next_log = AtomicUsize::new(0);
+
+    Ok(Arc::new(move |role| {
+        let n = next_log.fetch_add(1, Ordering::SeqCst);
+        let name = match role {
+            NewTreeRole::Active => format!("t1-{}.toml", n),
+            NewTreeRole::CompactedWip => format!("t1-{}.toml.compacting", n),
+        };
+        let log = Log::new(simple_log_file::create(dir.join(name), fs_thread.clone()));
+        let tree = Tree::new(log, config.clone());
+        tree.skip_init();
+        Ok(tree)
+    }))
+}
+
+#[test]
+fn compacting_tree_move_active_to_compacting() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, Layer, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    let dir = temp_dir("compacting-tree-move");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        let batch = tree.batch(Batch(0));
+        batch.open().await?;
+        batch.write(Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(0));
+        batch.close().await?;
+
+        tree.move_active_tree_to_compacting()?;
+
+        let batch = tree.batch(Batch(1));
+        batch.open().await?;
+        batch.write(Key::from_slice(b"k2"), Value::from_slice(b"v2")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(1));
+        batch.close().await?;
+
+        let k1 = Key::from_slice(b"k1");
+        let k2 = Key::from_slice(b"k2");
+
+        // Writes before the move are in the compacting layer,
+        // and writes after it in the new active layer.
+        // The compacting tree sees no commits after the move,
+        // so is only read below the commit that followed it.
+        assert_eq!(tree.read_layers(Commit(1), &k1).await?,
+                   vec![(Layer::Active, None),
+                        (Layer::Compacting, Some(Value::from_slice(b"v1")))]);
+        assert_eq!(tree.read_layer(Layer::Compacting, Commit(1), &k2).await?, None);
+        assert_eq!(tree.read_layer(Layer::Active, Commit(2), &k2).await?,
+                   Some(Value::from_slice(b"v2")));
+
+        // A second move while compacting is refused,
+        // leaving the layers as they were
+        assert!(tree.move_active_tree_to_compacting().is_err());
+        assert_eq!(tree.read_layer(Layer::Active, Commit(2), &k2).await?,
+                   Some(Value::from_slice(b"v2")));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_create_compacted_wip_tree() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    let dir = temp_dir("compacting-tree-wip");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new());
+        assert!(tree.create_compacted_wip_tree().is_err());
+
+        let tree = tree.with_new_tree_fn(new_tree.clone());
+        let wip = tree.create_compacted_wip_tree()?;
+
+        let mut cursor = wip.cursor(Commit(0));
+        cursor.seek_first();
+        assert!(!cursor.valid());
+
+        // Written the way compaction writes it
+        let batch = wip.batch(Batch(0));
+        batch.open().await?;
+        batch.write(Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(0));
+        batch.close().await?;
+
+        assert_eq!(wip.read(Commit(1), &Key::from_slice(b"k1")).await?,
+                   Some(Value::from_slice(b"v1")));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    // Its log is named apart from finished logs
+    assert!(dir.join("t1-1.toml.compacting").exists());
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_waits_for_writes_to_compacting_tree() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    let dir = temp_dir("compacting-tree-wait");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        let batch1 = tree.batch(Batch(0));
+        let batch2 = tree.batch(Batch(1));
+        batch1.open().await?;
+        batch2.open().await?;
+
+        tree.move_active_tree_to_compacting()?;
+
+        // Batches made after the move don't hold up compaction
+        let _batch3 = tree.batch(Batch(2));
+
+        let wait = tree.wait_for_all_writes_to_compacting_tree();
+        futures::pin_mut!(wait);
+        assert!(futures::poll!(&mut wait).is_pending());
+
+        batch1.write(Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        batch1.ready_commit(BatchCommit(0)).await?;
+        batch1.commit_to_index(BatchCommit(0), Commit(0));
+        batch1.close().await?;
+        drop(batch1);
+        assert!(futures::poll!(&mut wait).is_pending());
+
+        batch2.write(Key::from_slice(b"k2"), Value::from_slice(b"v2")).await?;
+        batch2.ready_commit(BatchCommit(0)).await?;
+        batch2.commit_to_index(BatchCommit(0), Commit(1));
+        batch2.close().await?;
+        drop(batch2);
+
+        assert_eq!(wait.await?, Some(Commit(1)));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_compact() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::compacting_tree::{CompactingTree, Layer, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+    use futures::TryStreamExt;
+    use std::sync::Arc;
+
+    enum Op<'a> {
+        Write(&'a [u8], &'a [u8]),
+        Delete(&'a [u8]),
+        DeleteRange(&'a [u8], &'a [u8]),
+    }
+
+    async fn commit(tree: &CompactingTree, batch: u64, commit: u64, ops: &[Op<'_>]) -> Result<()> {
+        let batch = tree.batch(Batch(batch));
+        batch.open().await?;
+        for op in ops {
+            match *op {
+                Op::Write(key, value) => batch.write(Key::from_slice(key), Value::from_slice(value)).await?,
+                Op::Delete(key) => batch.delete(Key::from_slice(key)).await?,
+                Op::DeleteRange(start, end) => batch.delete_range(Key::from_slice(start), Key::from_slice(end)).await?,
+            }
+        }
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(commit));
+        batch.close().await?;
+        Ok(())
+    }
+
+    /// The key-value pairs written to a compacted log.
+    async fn compacted_writes(path: std::path::PathBuf) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let log = Log::<Command>::new(simple_log_file::create(path, Arc::new(FsThread::start()?)));
+        let cmds: Vec<(Command, _)> = log.replay().try_collect().await?;
+        Ok(cmds.into_iter().filter_map(|(cmd, _)| match cmd {
+            Command::Write { key, value, .. } => Some((key.0, value.0)),
+            _ => None,
+        }).collect())
+    }
+
+    let dir = temp_dir("compacting-tree-compact");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        commit(&tree, 0, 0, &[Op::Write(b"k1", b"v1"), Op::Write(b"k2", b"v2")]).await?;
+        commit(&tree, 1, 1, &[Op::Write(b"k1", b"v1b"), Op::Write(b"k3", b"v3")]).await?;
+        commit(&tree, 2, 2, &[Op::Delete(b"k2")]).await?;
+
+        assert!(tree.compact(&[]).await?);
+
+        // Logs 1 and 2 are the new active and compacted trees
+        assert_eq!(compacted_writes(dir.join("t1-2.toml.compacting")).await?,
+                   vec![(b"k1".to_vec(), b"v1b".to_vec()), (b"k3".to_vec(), b"v3".to_vec())]);
+        assert_eq!(tree.read_layers(Commit(3), &Key::from_slice(b"k1")).await?,
+                   vec![(Layer::Active, None), (Layer::Compacted, Some(Value::from_slice(b"v1b")))]);
+
+        // Deletes of keys that are only in the compacted tree
+        commit(&tree, 3, 3, &[Op::Delete(b"k1"), Op::Write(b"k4", b"v4")]).await?;
+        commit(&tree, 4, 4, &[Op::DeleteRange(b"k3", b"k4")]).await?;
+
+        assert!(tree.compact(&[]).await?);
+
+        assert_eq!(compacted_writes(dir.join("t1-4.toml.compacting")).await?,
+                   vec![(b"k4".to_vec(), b"v4".to_vec())]);
+        for (key, value) in [(&b"k1"[..], None), (b"k2", None), (b"k3", None), (b"k4", Some(b"v4"))] {
+            assert_eq!(tree.read_layer(Layer::Compacted, Commit(5), &Key::from_slice(key)).await?,
+                       value.map(|v| Value::from_slice(v)));
+        }
+        assert_eq!(tree.read_layer(Layer::Compacting, Commit(5), &Key::from_slice(b"k4")).await?, None);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_compact_keeps_compacted_keys() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, Layer, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    async fn commit_write(tree: &CompactingTree, batch: u64, commit: u64, key: &[u8], value: &[u8]) -> Result<()> {
+        let batch = tree.batch(Batch(batch));
+        batch.open().await?;
+        batch.write(Key::from_slice(key), Value::from_slice(value)).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(commit));
+        batch.close().await?;
+        Ok(())
+    }
+
+    let dir = temp_dir("compacting-tree-compacted-keys");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        commit_write(&tree, 0, 0, b"k1", b"v1").await?;
+        assert!(tree.compact(&[]).await?);
+
+        // k1 is now only in the compacted tree,
+        // and must be merged into the next one.
+        commit_write(&tree, 1, 1, b"k2", b"v2").await?;
+        assert!(tree.compact(&[]).await?);
+
+        for (key, value) in [(&b"k1"[..], &b"v1"[..]), (b"k2", b"v2")] {
+            assert_eq!(tree.read_layer(Layer::Compacted, Commit(2), &Key::from_slice(key)).await?,
+                       Some(Value::from_slice(value)));
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_compact_keeps_snapshots() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, Layer, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    async fn commit(tree: &CompactingTree, batch: u64, commit: u64, writes: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        let batch = tree.batch(Batch(batch));
+        batch.open().await?;
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.write(Key::from_slice(key), Value::from_slice(value)).await?,
+                None => batch.delete(Key::from_slice(key)).await?,
+            }
+        }
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(commit));
+        batch.close().await?;
+        Ok(())
+    }
+
+    let dir = temp_dir("compacting-tree-snapshots");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        commit(&tree, 0, 0, &[(b"k1", Some(b"v1")), (b"k2", Some(b"v2"))]).await?;
+        commit(&tree, 1, 1, &[(b"k1", Some(b"v1b")), (b"k2", None)]).await?;
+        commit(&tree, 2, 2, &[(b"k3", Some(b"v3"))]).await?;
+
+        // A view at limit 1 still reads the first commit
+        assert!(tree.compact(&[Commit(1)]).await?);
+
+        let expected = [
+            (Commit(1), &b"k1"[..], Some(&b"v1"[..])), (Commit(1), b"k2", Some(b"v2")), (Commit(1), b"k3", None),
+            (Commit(3), b"k1", Some(b"v1b")), (Commit(3), b"k2", None), (Commit(3), b"k3", Some(b"v3")),
+        ];
+        for (commit_limit, key, value) in expected.iter() {
+            assert_eq!(tree.read_layer(Layer::Compacted, *commit_limit, &Key::from_slice(key)).await?,
+                       value.map(Value::from_slice));
+        }
+
+        // Retired logs are deleted on the file thread,
+        // behind which this commit's file operations queue
+        commit(&tree, 3, 3, &[(b"k4", Some(b"v4"))]).await?;
+        assert!(!dir.join("t1-0.toml").exists());
+
+        assert!(tree.compact(&[]).await?);
+        commit(&tree, 4, 4, &[(b"k5", Some(b"v5"))]).await?;
+        assert!(!dir.join("t1-1.toml").exists());
+        assert!(!dir.join("t1-2.toml.compacting").exists());
+        assert!(dir.join("t1-4.toml.compacting").exists());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_cursor_merges_layers() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    async fn commit(tree: &CompactingTree, batch: u64, commit: u64, writes: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        let batch = tree.batch(Batch(batch));
+        batch.open().await?;
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.write(Key::from_slice(key), Value::from_slice(value)).await?,
+                None => batch.delete(Key::from_slice(key)).await?,
+            }
+        }
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(commit));
+        batch.close().await?;
+        Ok(())
+    }
+
+    let dir = temp_dir("compacting-tree-cursor");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        // One version of each key in each of the three layers
+        commit(&tree, 0, 0, &[(b"k1", Some(b"a0")), (b"k2", Some(b"b0")), (b"k3", Some(b"c0"))]).await?;
+        assert!(tree.compact(&[]).await?);
+        commit(&tree, 1, 1, &[(b"k2", Some(b"b1")), (b"k4", Some(b"d1"))]).await?;
+        tree.move_active_tree_to_compacting()?;
+        commit(&tree, 2, 2, &[(b"k1", Some(b"a2")), (b"k4", Some(b"d2")), (b"k3", None)]).await?;
+
+        let expected = vec![
+            (b"k1".to_vec(), b"a2".to_vec()),
+            (b"k2".to_vec(), b"b1".to_vec()),
+            (b"k4".to_vec(), b"d2".to_vec()),
+        ];
+
+        let mut cursor = tree.cursor(Commit(3));
+        let mut forward = vec![];
+        cursor.seek_first();
+        while cursor.valid() {
+            forward.push((cursor.key().0, cursor.value().await?.0));
+            cursor.next();
+        }
+        assert_eq!(forward, expected);
+
+        let mut backward = vec![];
+        cursor.seek_last();
+        while cursor.valid() {
+            backward.push((cursor.key().0, cursor.value().await?.0));
+            cursor.prev();
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        cursor.seek_key(Key::from_slice(b"k3"));
+        assert_eq!(cursor.key(), Key::from_slice(b"k4"));
+        cursor.seek_key_rev(Key::from_slice(b"k3"));
+        assert_eq!(cursor.key(), Key::from_slice(b"k2"));
+
+        // Before the last commit, the compacting layer's versions win
+        let mut cursor = tree.cursor(Commit(2));
+        cursor.seek_key(Key::from_slice(b"k3"));
+        assert_eq!(cursor.value().await?, Value::from_slice(b"c0"));
+        cursor.next();
+        assert_eq!(cursor.value().await?, Value::from_slice(b"d1"));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_reads_during_compaction() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+    use std::cell::Cell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Yields to the executor once.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    async fn read_all(tree: &CompactingTree, commit_limit: Commit) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut cursor = tree.cursor(commit_limit);
+        let mut pairs = vec![];
+        cursor.seek_first();
+        while cursor.valid() {
+            pairs.push((cursor.key().0, cursor.value().await?.0));
+            cursor.next();
+        }
+        Ok(pairs)
+    }
+
+    async fn read_each(tree: &CompactingTree, commit_limit: Commit) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = vec![];
+        for i in 0..100u32 {
+            let key = Key(format!("k{:03}", i).into_bytes());
+            if let Some(value) = tree.read(commit_limit, &key).await? {
+                pairs.push((key.0, value.0));
+            }
+        }
+        Ok(pairs)
+    }
+
+    let dir = temp_dir("compacting-tree-reads");
+    let new_tree = new_tree_fn(&dir)?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        let batch = tree.batch(Batch(0));
+        batch.open().await?;
+        for i in 0..100u32 {
+            let key = format!("k{:03}", i);
+            batch.write(Key::from_slice(key.as_bytes()), Value::from_slice(b"v0")).await?;
+        }
+        batch.delete(Key::from_slice(b"k050")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(0));
+        batch.close().await?;
+        drop(batch);
+
+        let expected = read_all(&tree, Commit(1)).await?;
+        assert_eq!(expected.len(), 99);
+
+        // A batch opened before compaction holds it up
+        // until the batch is dropped
+        let batch = tree.batch(Batch(1));
+        batch.open().await?;
+
+        let done = Cell::new(false);
+        let compaction = async {
+            let compacted = tree.compact(&[]).await;
+            done.set(true);
+            compacted
+        };
+
+        // Reads between the steps of compaction see every key.
+        // The first is made while compaction waits for the batch.
+        let reads = async {
+            let mut batch = Some(batch);
+            while !done.get() {
+                assert_eq!(read_all(&tree, Commit(1)).await?, expected);
+                assert_eq!(read_each(&tree, Commit(1)).await?, expected);
+                if let Some(batch) = batch.take() {
+                    batch.abort_commit(BatchCommit(0)).await?;
+                    batch.close().await?;
+                }
+                YieldNow(false).await;
+            }
+            assert_eq!(read_all(&tree, Commit(1)).await?, expected);
+            assert_eq!(read_each(&tree, Commit(1)).await?, expected);
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let (compacted, read) = futures::join!(compaction, reads);
+        assert!(compacted?);
+        read?;
+
+        // A delete in the active tree hides the compacted value
+        let batch = tree.batch(Batch(2));
+        batch.open().await?;
+        batch.delete(Key::from_slice(b"k001")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(1));
+        batch.close().await?;
+        drop(batch);
+
+        let k001 = Key::from_slice(b"k001");
+        assert_eq!(tree.read(Commit(1), &k001).await?, Some(Value::from_slice(b"v0")));
+        assert_eq!(tree.read(Commit(2), &k001).await?, None);
+        tree.sync().await?;
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compacting_tree_compact_retries_after_failure() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::compacting_tree::{CompactingTree, Layer, NewTreeFn, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::tree::{Tree, TreeConfig};
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Fails appends while `fail` is set, and counts removals.
+    fn failing(log_file: LogFile<Command>, fail: &Arc<AtomicBool>, removed: &Arc<AtomicUsize>) -> LogFile<Command> {
+        let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = log_file;
+        let (fail, removed) = (fail.clone(), removed.clone());
+        LogFile {
+            is_empty,
+            append: Box::new(move |cmd| {
+                if fail.load(Ordering::SeqCst) {
+                    return Box::pin(async { Err(anyhow::anyhow!("injected append failure")) });
+                }
+                append(cmd)
+            }),
+            read_at,
+            flush,
+            sync,
+            size,
+            truncate,
+            remove: Box::new(move || {
+                removed.fetch_add(1, Ordering::SeqCst);
+                remove()
+            }),
+        }
+    }
+
+    let fail = Arc::new(AtomicBool::new(true));
+    let wips_removed = Arc::new(AtomicUsize::new(0));
+    let new_tree: NewTreeFn = {
+        let (fail, wips_removed) = (fail.clone(), wips_removed.clone());
+        Arc::new(move |role| {
+            let log_file = match role {
+                NewTreeRole::Active => mem_log_file::create(),
+                NewTreeRole::CompactedWip => failing(mem_log_file::create(), &fail, &wips_removed),
+            };
+            let tree = Tree::new(Log::new(log_file), TreeConfig::default());
+            tree.skip_init();
+            Ok(tree)
+        })
+    };
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        let (k1, k2) = (Key::from_slice(b"k1"), Key::from_slice(b"k2"));
+        let batch = tree.batch(Batch(0));
+        batch.open().await?;
+        batch.write(k1.clone(), Value::from_slice(b"v1")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(0));
+        batch.close().await?;
+        drop(batch);
+
+        // The failed compaction leaves the compacting tree to be read,
+        // and discards what it wrote
+        assert!(tree.compact(&[]).await.is_err());
+        assert_eq!(wips_removed.load(Ordering::SeqCst), 1);
+        assert_eq!(tree.read_layer(Layer::Compacting, Commit(1), &k1).await?, Some(Value::from_slice(b"v1")));
+        assert_eq!(tree.read(Commit(1), &k1).await?, Some(Value::from_slice(b"v1")));
+
+        // Writes go on to the new active tree
+        let batch = tree.batch(Batch(1));
+        batch.open().await?;
+        batch.write(k2.clone(), Value::from_slice(b"v2")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(1));
+        batch.close().await?;
+        drop(batch);
+
+        // The next compaction finishes the compacting tree
+        fail.store(false, Ordering::SeqCst);
+        assert!(tree.compact(&[]).await?);
+        assert_eq!(wips_removed.load(Ordering::SeqCst), 1);
+        assert_eq!(tree.read_layer(Layer::Compacting, Commit(2), &k1).await?, None);
+        assert_eq!(tree.read_layer(Layer::Compacted, Commit(2), &k1).await?, Some(Value::from_slice(b"v1")));
+        assert_eq!(tree.read(Commit(2), &k1).await?, Some(Value::from_slice(b"v1")));
+        assert_eq!(tree.read(Commit(2), &k2).await?, Some(Value::from_slice(b"v2")));
+
+        // And compaction carries on as normal
+        assert!(tree.compact(&[]).await?);
+        assert_eq!(tree.read(Commit(2), &k1).await?, Some(Value::from_slice(b"v1")));
+        assert_eq!(tree.read(Commit(2), &k2).await?, Some(Value::from_slice(b"v2")));
+
+        Ok::<_, anyhow::Error>(())
+    })
+}
+
+#[test]
+fn compacting_tree_append_only_compact() -> Result<()> {
+    use db::raw::compacting_tree::{CompactingTree, Layer, NewTreeRole};
+    use db::raw::epoch::Epochs;
+    use db::raw::tree::TreeConfig;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    async fn commit_writes(tree: &CompactingTree, batch: u64, commit: u64, writes: &[(&[u8], &[u8])]) -> Result<()> {
+        let batch = tree.batch(Batch(batch));
+        batch.open().await?;
+        for (key, value) in writes {
+            batch.write(Key::from_slice(key), Value::from_slice(value)).await?;
+        }
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(commit));
+        batch.close().await?;
+        Ok(())
+    }
+
+    let dir = temp_dir("compacting-tree-append-only");
+    let new_tree = new_tree_fn_with_config(&dir, TreeConfig {
+        append_only: true,
+        ..TreeConfig::default()
+    })?;
+
+    block_on(async {
+        let tree = CompactingTree::new(new_tree(NewTreeRole::Active)?, Epochs::new())
+            .with_new_tree_fn(new_tree.clone());
+
+        commit_writes(&tree, 0, 0, &[(b"k1", b"v1"), (b"k2", b"v2")]).await?;
+        commit_writes(&tree, 1, 1, &[(b"k1", b"v1b")]).await?;
+
+        let batch = tree.batch(Batch(2));
+        batch.open().await?;
+        assert!(batch.delete(Key::from_slice(b"k2")).await.is_err());
+        assert!(batch.delete_range(Key::from_slice(b"k0"), Key::from_slice(b"k9")).await.is_err());
+        batch.abort_commit(BatchCommit(0)).await?;
+        batch.close().await?;
+        drop(batch);
+
+        assert!(tree.compact(&[]).await?);
+
+        // Overwrites in the compacting tree win over the old compacted tree
+        commit_writes(&tree, 3, 2, &[(b"k2", b"v2b"), (b"k3", b"v3")]).await?;
+
+        assert!(tree.compact(&[]).await?);
+
+        for (key, value) in [(&b"k1"[..], &b"v1b"[..]), (b"k2", b"v2b"), (b"k3", b"v3")] {
+            assert_eq!(tree.read_layer(Layer::Compacted, Commit(3), &Key::from_slice(key)).await?,
+                       Some(Value::from_slice(value)));
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn batch_and_commit_numbers() {
+    let batch = db::Batch::from(3);
+    let batch_commit = db::BatchCommit::from(4);
+    let commit = db::Commit::from(5);
+
+    assert_eq!(batch.value(), 3);
+    assert_eq!(batch_commit.value(), 4);
+    assert_eq!(commit.value(), 5);
+
+    assert_eq!(batch.to_string(), "3");
+    assert_eq!(batch_commit.to_string(), "4");
+    assert_eq!(commit.to_string(), "5");
+
+    assert!(db::Commit::from(1) < commit);
+}
+
+#[test]
+fn reopen_with_added_tree() -> Result<()> {
+    let dir = temp_dir("added-tree");
+    let trees = |names: &[&str]| names.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig::new(&dir, trees(&["t1", "t2"]))).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t2", b"k2", b"v2").await?;
+        db.sync().await?;
+        drop(db);
+
+        // A new tree needs only to be named
+        let config = db::DbConfig::new(&dir, trees(&["t1", "t3", "t2"]));
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        let mut cursor = view.tree("t3")?.cursor();
+        cursor.seek_first();
+        assert!(!cursor.valid());
+
+        commit_write(&db, "t3", b"k3", b"v3").await?;
+        db.sync().await?;
+        drop(view);
+        drop(db);
+
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t3")?.read(b"k3").await?, Some(b"v3".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        drop(view);
+        drop(db);
+
+        // A tree added in an open that committed nothing
+        let config = db::DbConfig::new(&dir, trees(&["t1", "t2", "t3", "t4"]));
+        drop(db::Db::open(config.clone()).await?);
+        let db = db::Db::open(config).await?;
+        assert_eq!(db.read_view().tree("t4")?.read(b"k1").await?, None);
+        commit_write(&db, "t4", b"k4", b"v4").await?;
+        assert_eq!(db.read_view().tree("t4")?.read(b"k4").await?, Some(b"v4".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn read_only_db() -> Result<()> {
+    let dir = temp_dir("read-only");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+    let read_only = db::DbConfig {
+        read_only: true,
+        ..config.clone()
+    };
+
+    // Nothing is created for a database that doesn't exist
+    assert!(block_on(db::Db::open(read_only.clone())).is_err());
+    assert!(!dir.exists());
+
+    block_on(async {
+        let db = db::Db::open(config).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        db.sync().await?;
+        drop(db);
+
+        let size = dir_size(&dir)?;
+        let db = db::Db::open(read_only.clone()).await?;
+        assert_eq!(db.read_view().tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+
+        assert!(db.write_batch().await.is_err());
+        let entries = std::iter::once((b"k2".to_vec(), Some(b"v2".to_vec()))).collect();
+        assert!(db.apply_map("t1", entries).await.is_err());
+        db.sync().await?;
+        assert_eq!(dir_size(&dir)?, size);
+
+        // The files themselves are opened without write access
+        {
+            use db::raw::command::Command;
+            use db::raw::fs_thread::FsThread;
+            use db::raw::log::Log;
+            use db::raw::simple_log_file;
+            use db::raw::types::Batch;
+
+            let fs_thread = std::sync::Arc::new(FsThread::start_read_only(0)?);
+            let log = Log::<Command>::new(simple_log_file::create(dir.join("t1.toml"), fs_thread));
+            assert!(log.append(Command::Open { batch: Batch(100) }).await.is_err());
+            assert_eq!(dir_size(&dir)?, size);
+        }
+
+        // New trees can't be added
+        let added = db::DbConfig {
+            trees: vec!["t1".to_string(), "t2".to_string()],
+            ..read_only
+        };
+        assert!(db::Db::open(added).await.is_err());
+        assert!(!dir.join("t2.toml").exists());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn binary_keys_survive_reopen() -> Result<()> {
+    let dir = temp_dir("binary-keys");
+    let config = db::DbConfig::new(dir.clone(), vec!["t1".to_string()]);
+
+    // Little-endian timestamps, which are not valid UTF-8,
+    // along with NUL and high bytes.
+    let mut keys: Vec<Vec<u8>> = vec![
+        1_600_000_000_000u64.to_le_bytes().to_vec(),
+        1_600_000_000_255u64.to_le_bytes().to_vec(),
+        vec![0x00],
+        vec![0x00, 0x00],
+        vec![b'k', 0x00, b'k'],
+        vec![0xFF],
+        vec![0xC3, 0x28],
+    ];
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        for (i, key) in keys.iter().enumerate() {
+            commit_write(&db, "t1", key, &[i as u8]).await?;
+        }
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(tree.read(key).await?, Some(vec![i as u8]));
+        }
+
+        let mut cursor = tree.cursor();
+        cursor.seek_first();
+        let mut found = vec![];
+        while cursor.valid() {
+            found.push(cursor.key());
+            cursor.next();
+        }
+        keys.sort();
+        assert_eq!(found, keys);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn sync_policy() -> Result<()> {
+    use std::time::Duration;
+
+    let dir = temp_dir("sync-policy");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+
+    block_on(async {
+        // Commits are synced before completing
+        let db = db::Db::open(db::DbConfig {
+            sync_policy: db::SyncPolicy::PerCommit,
+            ..config.clone()
+        }).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        assert!(db.is_durable(0));
+        assert_eq!(db.stats().synced_commits, 1);
+        db.apply_map("t1", vec![(b"k2".to_vec(), Some(b"v2".to_vec()))].into_iter().collect()).await?;
+        assert!(db.is_durable(1));
+
+        // Unless asked not to
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k3", b"v3").await?;
+        batch.commit_with(db::Durability::None).await?;
+        batch.close().await;
+        assert!(!db.is_durable(2));
+        assert_eq!(db.stats().synced_commits, 2);
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k4", b"v4").await?;
+        assert!(!db.is_durable(3));
+        assert_eq!(db.stats().synced_commits, 0);
+        drop(db);
+
+        // Synced in the background
+        let db = db::Db::open(db::DbConfig {
+            sync_policy: db::SyncPolicy::Interval(Duration::from_millis(10)),
+            ..config.clone()
+        }).await?;
+        commit_write(&db, "t1", b"k5", b"v5").await?;
+        let start = std::time::Instant::now();
+        while !db.is_durable(4) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(db.stats().synced_commits, 0);
+        drop(db);
+
+        let db = db::Db::open(config).await?;
+        assert_eq!(db.read_view().tree("t1")?.read(b"k5").await?, Some(b"v5".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn fsync_commit_waits_for_commit_log_sync() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::commit_log::CommitCommand;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    block_on(async {
+        // Commit-log syncs wait for a go-ahead
+        let (gate_tx, gate_rx) = async_channel::unbounded::<()>();
+        let syncs = Arc::new(AtomicUsize::new(0));
+
+        let commit_log = {
+            let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = mem_log_file::create::<CommitCommand>();
+            let syncs = syncs.clone();
+            LogFile {
+                is_empty,
+                append,
+                read_at,
+                flush,
+                sync: Box::new(move || {
+                    let gate_rx = gate_rx.clone();
+                    let syncs = syncs.clone();
+                    let sync = sync();
+                    Box::pin(async move {
+                        gate_rx.recv().await?;
+                        sync.await?;
+                        syncs.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    })
+                }),
+                size,
+                truncate,
+                remove,
+            }
+        };
+
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(mem_log_file::create()));
+        let db = bdb::Db::new(tree_logs, Log::new(commit_log));
+        db.init().await?;
+
+        // As `SyncPolicy::PerCommit` commits
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        let commit = batch.commit_with(batch_commit, db::Durability::Fsync);
+        futures::pin_mut!(commit);
+        assert!(futures::poll!(&mut commit).is_pending());
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+
+        gate_tx.send(()).await?;
+        commit.await?;
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+        batch.close("t1").await?;
+
+        // Commits that don't ask for it never sync,
+        // which would now fail
+        drop(gate_tx);
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(b"k2"), Value::from_slice(b"v2")).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.commit_with(batch_commit, db::Durability::None).await?;
+        batch.close("t1").await?;
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn commit_with_durability() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k1", b"v1").await?;
+        batch.commit_with(db::Durability::Fsync).await?;
+        batch.close().await;
+        assert_eq!(db.stats().synced_commits, 1);
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k2", b"v2").await?;
+        batch.commit_with(db::Durability::None).await?;
+        batch.close().await;
+        assert_eq!(db.stats().synced_commits, 1);
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn is_durable() -> Result<()> {
+    let dir = temp_dir("is-durable");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+
+        commit_write(&db, "t1", b"k0", b"v0").await?;
+        assert!(!db.is_durable(0));
+
+        // A synced commit makes the earlier ones durable too
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k1", b"v1").await?;
+        batch.commit_with(db::Durability::Fsync).await?;
+        batch.close().await;
+        assert!(db.is_durable(0));
+        assert!(db.is_durable(1));
+
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+        assert!(!db.is_durable(2));
+        db.sync().await?;
+        assert!(db.is_durable(2));
+
+        commit_write(&db, "t1", b"k3", b"v3").await?;
+        assert!(!db.is_durable(3));
+        assert_eq!(db.barrier().await?, Some(3));
+        assert!(db.is_durable(3));
+        assert!(!db.is_durable(4));
+        drop(db);
+
+        // Nothing is known to be durable until synced after opening
+        let db = db::Db::open(config).await?;
+        assert!(!db.is_durable(3));
+        db.sync().await?;
+        assert!(db.is_durable(3));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn commit_latency_percentiles() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        assert_eq!(db.stats().commit_latency.count(), 0);
+        assert_eq!(db.stats().commit_latency.percentile(99.0), std::time::Duration::ZERO);
+
+        for i in 0..200u32 {
+            commit_write(&db, "t1", &i.to_be_bytes(), b"v").await?;
+        }
+
+        // Empty commits do nothing and are not recorded
+        let batch = db.write_batch().await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let latency = db.stats().commit_latency;
+        assert_eq!(latency.count(), 200);
+        let p50 = latency.percentile(50.0);
+        let p99 = latency.percentile(99.0);
+        let max = latency.percentile(100.0);
+        assert!(p50 <= p99);
+        assert!(p99 <= max);
+        assert!(max < std::time::Duration::from_secs(10));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn move_key_between_trees() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+
+        let before = db.read_view();
+
+        let batch = db.write_batch().await?;
+        batch.move_key("t1", "t2", b"k1").await?;
+        let during = db.read_view();
+        batch.commit().await?;
+        batch.close().await;
+
+        let after = db.read_view();
+
+        // Every view sees the key in exactly one tree
+        for view in &[&before, &during] {
+            assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+            assert_eq!(view.tree("t2")?.read(b"k1").await?, None);
+        }
+        assert_eq!(after.tree("t1")?.read(b"k1").await?, None);
+        assert_eq!(after.tree("t2")?.read(b"k1").await?, Some(b"v1".to_vec()));
+
+        // The source must exist
+        let batch = db.write_batch().await?;
+        assert!(batch.move_key("t1", "t2", b"k1").await.is_err());
+        assert!(batch.move_key("t1", "t2", b"k2").await.is_err());
+        batch.close().await;
+
+        // A change to the source after it is read fails the commit
+        let batch = db.write_batch().await?;
+        batch.move_key("t2", "t1", b"k1").await?;
+        commit_write(&db, "t2", b"k1", b"v2").await?;
+        assert!(batch.commit().await.is_err());
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, None);
+        assert_eq!(view.tree("t2")?.read(b"k1").await?, Some(b"v2".to_vec()));
+
+        // The batch's own writes and deletes are moved, not the committed value
+        let batch = db.write_batch().await?;
+        batch.tree("t2")?.write(b"k1", b"v3").await?;
+        batch.move_key("t2", "t1", b"k1").await?;
+        assert!(batch.move_key("t2", "t1", b"k1").await.is_err());
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v3".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k1").await?, None);
+
+        // Keys are moved within a namespace
+        let batch = db.write_batch().await?;
+        batch.tree_ns("t1", b"ns/")?.write(b"k1", b"v4").await?;
+        batch.move_key_ns("t1", "t2", b"ns/", b"k1").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"ns/k1").await?, None);
+        assert_eq!(view.tree("t2")?.read(b"ns/k1").await?, Some(b"v4".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v3".to_vec()));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn barrier_makes_prior_commits_durable() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Commit, Key, Value};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the name of the log on each sync.
+    fn counted<Cmd>(log_file: LogFile<Cmd>, name: &str, syncs: &Arc<Mutex<Vec<String>>>) -> LogFile<Cmd>
+    where Cmd: serde::Serialize + for <'de> serde::Deserialize<'de>
+    {
+        let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = log_file;
+        let (name, syncs) = (name.to_string(), syncs.clone());
+        LogFile {
+            is_empty,
+            append,
+            read_at,
+            flush,
+            sync: Box::new(move || {
+                syncs.lock().expect("lock").push(name.clone());
+                sync()
+            }),
+            size,
+            truncate,
+            remove,
+        }
+    }
+
+    block_on(async {
+        let syncs = Arc::new(Mutex::new(vec![]));
+        let mut tree_logs = BTreeMap::new();
+        for tree in ["t1", "t2"] {
+            tree_logs.insert(tree.to_string(), Log::new(counted(mem_log_file::create(), tree, &syncs)));
+        }
+        let commit_log = Log::new(counted(mem_log_file::create(), "commits", &syncs));
+
+        let db = bdb::Db::new(tree_logs, commit_log);
+        db.init().await?;
+        assert_eq!(db.barrier().await?, None);
+        syncs.lock().expect("lock").clear();
+
+        for tree in ["t1", "t2"] {
+            let batch = db.batch();
+            batch.open("t1").await?;
+            batch.open("t2").await?;
+            batch.write(tree, Key::from_slice(b"k"), Value::from_slice(b"v")).await?;
+            let batch_commit = batch.new_batch_commit_number();
+            batch.ready_commit("t1", batch_commit).await?;
+            batch.ready_commit("t2", batch_commit).await?;
+            batch.commit(batch_commit).await?;
+            batch.close("t1").await?;
+            batch.close("t2").await?;
+        }
+        assert!(syncs.lock().expect("lock").is_empty());
+
+        // Every tree is synced before the commit log refers to its batches
+        assert_eq!(db.barrier().await?, Some(Commit(1)));
+        assert_eq!(*syncs.lock().expect("lock"), vec!["t1", "t2", "commits"]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn tree_stats() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        for i in 0..10 {
+            let key = format!("k{}", i);
+            commit_write(&db, "t1", key.as_bytes(), &[0; 100]).await?;
+        }
+        commit_write(&db, "t1", b"k0", &[0; 50]).await?;
+        commit_delete(&db, "t1", b"k9").await?;
+        commit_write(&db, "t2", b"k0", &[0; 10]).await?;
+
+        let view = db.read_view();
+        let stats = view.tree("t1")?.stats().await?;
+        assert_eq!(stats.live_keys, 9);
+        assert_eq!(stats.tombstones, 1);
+        assert_eq!(stats.total_value_bytes, 850);
+        assert!(stats.average_value_bytes() > 94.0 && stats.average_value_bytes() < 95.0);
+
+        let stats = view.tree("t2")?.stats().await?;
+        assert_eq!(stats.live_keys, 1);
+        assert_eq!(stats.total_value_bytes, 10);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn copy_key() -> Result<()> {
+    let dir = temp_dir("copy");
+    let config = db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: vec!["t1".to_string(), "t2".to_string()],
+        ..db::DbConfig::default()
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"src", b"v1").await?;
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.copy(b"src", b"dst").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        // The copy is independent of later changes to the source
+        commit_write(&db, "t1", b"src", b"v2").await?;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"dst").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"src").await?, Some(b"v2".to_vec()));
+
+        db.sync().await?;
+        drop(view);
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"dst").await?, Some(b"v1".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn copy_absent_key_fails() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k1", b"v1").await?;
+        batch.tree("t1")?.copy(b"missing", b"dst").await?;
+        assert!(batch.commit().await.is_err());
+        batch.close().await;
+
+        // Nothing in the failed batch was committed
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"dst").await?, None);
+
+        // Nor can a key deleted earlier in the batch be copied
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.delete_range(b"k0", b"k9").await?;
+        batch.tree("t1")?.copy(b"k1", b"dst").await?;
+        assert!(batch.commit().await.is_err());
+        batch.close().await;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn copy_sees_same_batch_writes() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        commit_write(&db, "t1", b"src", b"old").await?;
+
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+        tree.write(b"src", b"new").await?;
+        tree.copy(b"src", b"dst").await?;
+        tree.write(b"src", b"newer").await?;
+        tree.write(b"fresh", b"v1").await?;
+        tree.copy(b"fresh", b"dst2").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"dst").await?, Some(b"new".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"src").await?, Some(b"newer".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"dst2").await?, Some(b"v1".to_vec()));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn log_read_ahead() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Batch, Key, Value};
+    use futures::TryStreamExt;
+    use std::sync::Arc;
+
+    let dir = temp_dir("log-read-ahead");
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("log.toml");
+
+    block_on(async {
+        let fs_thread = Arc::new(FsThread::start()?);
+        let log = Log::<Command>::new(simple_log_file::create(path.clone(), fs_thread.clone()));
+        for i in 0..1000 {
+            log.append(Command::Write {
+                batch: Batch(0),
+                key: Key(format!("k{}", i).into_bytes()),
+                value: Value(vec![b'v'; i % 300]),
+            }).await?;
+        }
+
+        // Replays the log with its own FsThread, counting its operations
+        let path = &path;
+        let replay = |read_ahead_bytes| async move {
+            let fs_thread = Arc::new(FsThread::start()?);
+            let log_file = simple_log_file::create_with_read_ahead(path.clone(), fs_thread.clone(), 0, read_ahead_bytes);
+            let cmds: Vec<(Command, _)> = Log::new(log_file).replay().try_collect().await?;
+            let cmds: Vec<_> = cmds.iter().map(|cmd| format!("{:?}", cmd)).collect();
+            Ok::<_, anyhow::Error>((cmds, fs_thread.operations()))
+        };
+
+        let (unbuffered, unbuffered_ops) = replay(0).await?;
+        assert_eq!(unbuffered.len(), 1000);
+        assert_eq!(unbuffered_ops, 1000);
+
+        // Most records straddle chunks this small,
+        // and some are larger than a chunk.
+        let (small_chunks, _) = replay(200).await?;
+        assert_eq!(small_chunks, unbuffered);
+
+        let (read_ahead, read_ahead_ops) = replay(simple_log_file::DEFAULT_READ_AHEAD_BYTES).await?;
+        assert_eq!(read_ahead, unbuffered);
+        assert!(read_ahead_ops * 100 < unbuffered_ops);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    // A database opens to the same state with any read-ahead
+    let config = db::DbConfig::new(dir.join("db"), vec!["t1".to_string()]);
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        for i in 0..100u32 {
+            commit_write(&db, "t1", &i.to_be_bytes(), &vec![b'v'; i as usize]).await?;
+        }
+        commit_delete(&db, "t1", &7u32.to_be_bytes()).await?;
+        db.sync().await?;
+        drop(db);
+
+        let mut states = vec![];
+        for log_read_ahead_bytes in [0, 1, 150] {
+            let config = db::DbConfig { log_read_ahead_bytes, ..config.clone() };
+            let db = db::Db::open(config).await?;
+            let view = db.read_view();
+            let mut cursor = view.tree("t1")?.cursor();
+            cursor.seek_first();
+            let mut state = vec![];
+            while cursor.valid() {
+                state.push((cursor.key(), cursor.value().await?));
+                cursor.next();
+            }
+            assert_eq!(state.len(), 99);
+            states.push(state);
+        }
+        assert_eq!(states[0], states[1]);
+        assert_eq!(states[0], states[2]);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn buffered_log_matches_unbuffered() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Batch, Key, Value};
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    let dir = temp_dir("buffered-log");
+    std::fs::create_dir_all(&dir)?;
+
+    block_on(async {
+        let fs_thread = Arc::new(FsThread::start()?);
+        let unbuffered_path = dir.join("unbuffered.toml");
+        let buffered_path = dir.join("buffered.toml");
+        let unbuffered = Log::<Command>::new(simple_log_file::create(unbuffered_path.clone(), fs_thread.clone()));
+        let buffered = Log::<Command>::new(simple_log_file::create_buffered(buffered_path.clone(), fs_thread.clone(), 1024));
+
+        let mut addresses = vec![];
+        for i in 0..20 {
+            let cmd = Command::Write {
+                batch: Batch(0),
+                key: Key(format!("k{}", i).into_bytes()),
+                value: Value(vec![b'v'; i * 10]),
+            };
+            let unbuffered_addr = unbuffered.append(cmd.clone()).await?;
+            let buffered_addr = buffered.append(cmd.clone()).await?;
+            assert_eq!(buffered_addr, unbuffered_addr);
+
+            // Readable whether or not it has been flushed
+            let read = buffered.read_at(buffered_addr).await?;
+            assert_eq!(format!("{:?}", read), format!("{:?}", cmd));
+
+            addresses.push((buffered_addr, cmd));
+        }
+
+        // Replay crosses from the file into the buffer
+        assert_eq!(buffered.replay().count().await, 20);
+
+        buffered.flush().await?;
+        assert_eq!(std::fs::read(&buffered_path)?, std::fs::read(&unbuffered_path)?);
+
+        for (addr, cmd) in addresses {
+            let read = buffered.read_at(addr).await?;
+            assert_eq!(format!("{:?}", read), format!("{:?}", cmd));
+        }
+
+        // Committed batches are flushed without a sync
+        let config = db::DbConfig {
+            dir: Some(dir.join("db")),
+            trees: vec!["t1".to_string(), "t2".to_string()],
+            log_buffer_bytes: 4096,
+            ..db::DbConfig::default()
+        };
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t2", b"k2", b"v2").await?;
+        drop(db);
+
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v2".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn buffered_log_reports_flush_errors() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Batch, Key, Value};
+
+    block_on(async {
+        // Every write to /dev/full fails with no space
+        let fs_thread = std::sync::Arc::new(FsThread::start()?);
+        let log = Log::<Command>::new(simple_log_file::create_buffered("/dev/full".into(), fs_thread, 64));
+        let write = |i: u8| Command::Write {
+            batch: Batch(0),
+            key: Key(vec![b'k', i]),
+            value: Value(vec![b'v'; 20]),
+        };
+
+        let addr = log.append(write(0)).await?;
+
+        // Full, so the next append flushes first and fails
+        let mut failed = false;
+        for i in 1..10 {
+            if log.append(write(i)).await.is_err() {
+                failed = true;
+                break;
+            }
+        }
+        assert!(failed);
+        assert!(log.flush().await.is_err());
+
+        // Still readable from the buffer, at the address handed out
+        let read = log.read_at(addr).await?;
+        assert_eq!(format!("{:?}", read), format!("{:?}", write(0)));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn collapse_key_history() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        for i in 0..20 {
+            commit_write(&db, "t1", b"k1", format!("v{}", i).as_bytes()).await?;
+        }
+        let pinned_view = db.read_view();
+        for i in 20..50 {
+            commit_write(&db, "t1", b"k1", format!("v{}", i).as_bytes()).await?;
+        }
+        assert_eq!(db.read_view().tree("t1")?.history(b"k1").await?.len(), 50);
+
+        // Versions the pinned view can see are kept
+        db.collapse_key("t1", b"k1")?;
+        assert_eq!(db.read_view().tree("t1")?.history(b"k1").await?.len(), 31);
+        assert_eq!(pinned_view.tree("t1")?.read(b"k1").await?, Some(b"v19".to_vec()));
+
+        drop(pinned_view);
+        db.collapse_key("t1", b"k1")?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.history(b"k1").await?, vec![(49, Some(b"v49".to_vec()))]);
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v49".to_vec()));
+
+        // Merges are kept along with the value they apply to
+        for _ in 0..10 {
+            let batch = db.write_batch().await?;
+            batch.tree("t1")?.increment(b"count", 1).await?;
+            batch.commit().await?;
+            batch.close().await;
+        }
+        db.collapse_key("t1", b"count")?;
+        assert_eq!(db.read_view().tree("t1")?.read_counter(b"count").await?, 10);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn cursor_stream() -> Result<()> {
+    use futures::StreamExt;
+
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        for i in 0..10 {
+            let key = format!("k{}", i);
+            let value = format!("v{}", i);
+            commit_write(&db, "t1", key.as_bytes(), value.as_bytes()).await?;
+        }
+
+        let view = db.read_view();
+        let mut cursor = view.tree("t1")?.cursor();
+        cursor.seek_key(b"k3");
+        let items: Vec<_> = cursor.into_stream().take(4).collect().await;
+        let items = items.into_iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(items, vec![
+            (b"k3".to_vec(), b"v3".to_vec()),
+            (b"k4".to_vec(), b"v4".to_vec()),
+            (b"k5".to_vec(), b"v5".to_vec()),
+            (b"k6".to_vec(), b"v6".to_vec()),
+        ]);
+
+        // The stream ends with the tree
+        let mut cursor = view.tree("t1")?.cursor();
+        cursor.seek_key(b"k8");
+        assert_eq!(cursor.into_stream().count().await, 2);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn recovery_concurrency_limit() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::command::Command;
+    use db::raw::commit_log::CommitCommand;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+
+    /// Yields to the executor once.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Log files shared between two dbs, with reads instrumented.
+    struct Logs {
+        tree_logs: Vec<Arc<LogFile<Command>>>,
+        commit_log: Arc<LogFile<CommitCommand>>,
+        active: Arc<AtomicUsize>,
+        max_active: Arc<AtomicUsize>,
+    }
+
+    fn shared<Cmd>(log_file: &Arc<LogFile<Cmd>>, active: &Arc<AtomicUsize>, max_active: &Arc<AtomicUsize>) -> LogFile<Cmd>
+    where Cmd: serde::Serialize + for <'de> serde::Deserialize<'de> + Send + 'static
+    {
+        let (f1, f2, f3, f4, f5, f6, f7, f8) = (log_file.clone(), log_file.clone(), log_file.clone(), log_file.clone(), log_file.clone(), log_file.clone(), log_file.clone(), log_file.clone());
+        let (active, max_active) = (active.clone(), max_active.clone());
+        LogFile {
+            is_empty: Box::new(move || { let f = f1.clone(); Box::pin(async move { f.is_empty().await }) }),
+            append: Box::new(move |cmd| { let f = f2.clone(); Box::pin(async move { f.append(cmd).await }) }),
+            read_at: Box::new(move |addr| {
+                let f = f3.clone();
+                let (active, max_active) = (active.clone(), max_active.clone());
+                Box::pin(async move {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    YieldNow(false).await;
+                    let r = f.read_at(addr).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    r
+                })
+            }),
+            flush: Box::new(move || { let f = f4.clone(); Box::pin(async move { f.flush().await }) }),
+            sync: Box::new(move || { let f = f5.clone(); Box::pin(async move { f.sync().await }) }),
+            size: Box::new(move || { let f = f6.clone(); Box::pin(async move { f.size().await }) }),
+            truncate: Box::new(move |addr| { let f = f7.clone(); Box::pin(async move { f.truncate(addr).await }) }),
+            remove: Box::new(move || f8.remove()),
+        }
+    }
+
+    fn make_db(logs: &Logs, tree_count: usize) -> bdb::Db {
+        let tree_logs: BTreeMap<_, _> = (0..tree_count).map(|i| {
+            (format!("t{}", i), Log::new(shared(&logs.tree_logs[i], &logs.active, &logs.max_active)))
+        }).collect();
+        let commit_log = Log::new(shared(&logs.commit_log, &logs.active, &logs.max_active));
+        bdb::Db::new(tree_logs, commit_log)
+    }
+
+    let tree_count = 8;
+    let logs = Logs {
+        tree_logs: (0..tree_count).map(|_| Arc::new(mem_log_file::create())).collect(),
+        commit_log: Arc::new(mem_log_file::create()),
+        active: Arc::new(AtomicUsize::new(0)),
+        max_active: Arc::new(AtomicUsize::new(0)),
+    };
+
+    block_on(async {
+        let db = make_db(&logs, tree_count);
+        db.init().await?;
+        for round in 0..3 {
+            let batch = db.batch();
+            for i in 0..tree_count {
+                let tree = format!("t{}", i);
+                batch.open(&tree).await?;
+                batch.write(&tree, Key::from_slice(b"k"), Value(format!("v{}", round).into_bytes())).await?;
+            }
+            let batch_commit = batch.new_batch_commit_number();
+            for i in 0..tree_count {
+                batch.ready_commit(&format!("t{}", i), batch_commit).await?;
+            }
+            batch.commit(batch_commit).await?;
+            for i in 0..tree_count {
+                batch.close(&format!("t{}", i)).await?;
+            }
+        }
+        drop(db);
+
+        for (concurrency, expect_parallel) in [(1, false), (4, true)] {
+            logs.max_active.store(0, Ordering::SeqCst);
+            let db = make_db(&logs, tree_count);
+            db.init_concurrently(concurrency).await?;
+
+            let max_active = logs.max_active.load(Ordering::SeqCst);
+            assert!(max_active <= concurrency);
+            assert_eq!(max_active > 1, expect_parallel);
+
+            let view = db.view();
+            for i in 0..tree_count {
+                let value = view.read(&format!("t{}", i), &Key::from_slice(b"k")).await?;
+                assert_eq!(value, Some(Value::from_slice(b"v2")));
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn paranoid_validation_catches_bad_addresses() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::command::Command;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::tree::TreeConfig;
+    use db::raw::types::{Address, Key, Value};
+    use std::collections::BTreeMap;
+
+    /// A log that reports every record as being at the start.
+    fn misaddressing_log() -> Log<Command> {
+        let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = mem_log_file::create::<Command>();
+        Log::new(LogFile {
+            is_empty,
+            append: Box::new(move |cmd| {
+                let append = append(cmd);
+                Box::pin(async move {
+                    let (_, size) = append.await?;
+                    Ok((Address(0), size))
+                })
+            }),
+            read_at,
+            flush,
+            sync,
+            size,
+            truncate,
+            remove,
+        })
+    }
+
+    block_on(async {
+        for validation in [db::Validation::Normal, db::Validation::Paranoid] {
+            let mut tree_logs = BTreeMap::new();
+            tree_logs.insert("t1".to_string(), misaddressing_log());
+            let mut tree_configs = BTreeMap::new();
+            tree_configs.insert("t1".to_string(), TreeConfig {
+                validation,
+                ..TreeConfig::default()
+            });
+            let db = bdb::Db::with_tree_configs(tree_logs, Log::new(mem_log_file::create()), tree_configs);
+            db.init().await?;
+
+            let batch = db.batch();
+            batch.open("t1").await?;
+            let r = batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await;
+            assert_eq!(r.is_err(), validation == db::Validation::Paranoid);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn resurrect_after_delete() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        let view_written = db.read_view();
+        commit_delete(&db, "t1", b"k1").await?;
+        let view_deleted = db.read_view();
+        commit_write(&db, "t1", b"k1", b"v2").await?;
+        let view_resurrected = db.read_view();
+
+        assert_eq!(view_written.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view_deleted.tree("t1")?.read(b"k1").await?, None);
+        assert_eq!(view_resurrected.tree("t1")?.read(b"k1").await?, Some(b"v2".to_vec()));
+        assert_eq!(view_resurrected.tree("t1")?.history(b"k1").await?, vec![
+            (0, Some(b"v1".to_vec())),
+            (1, None),
+            (2, Some(b"v2".to_vec())),
+        ]);
+
+        // Likewise after a range delete
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.delete_range(b"k0", b"k9").await?;
+        batch.commit().await?;
+        batch.close().await;
+        let view_range_deleted = db.read_view();
+        commit_write(&db, "t1", b"k1", b"v3").await?;
+        let view_range_resurrected = db.read_view();
+
+        assert_eq!(view_range_deleted.tree("t1")?.read(b"k1").await?, None);
+        assert_eq!(view_range_resurrected.tree("t1")?.read(b"k1").await?, Some(b"v3".to_vec()));
+
+        let mut cursor = view_range_resurrected.tree("t1")?.cursor();
+        cursor.seek_first();
+        assert!(cursor.valid());
+        assert_eq!(cursor.key(), b"k1".to_vec());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn max_open_files() -> Result<()> {
+    use db::raw::fs_thread::FsThread;
+
+    let dir = temp_dir("max-open-files");
+    let trees: Vec<_> = (0..12).map(|i| format!("t{}", i)).collect();
+    let config = db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: trees.clone(),
+        max_open_files: 3,
+        ..db::DbConfig::default()
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        for round in 0..3 {
+            for tree in &trees {
+                let value = format!("{}-{}", tree, round);
+                commit_write(&db, tree, b"k", value.as_bytes()).await?;
+            }
+        }
+        let view = db.read_view();
+        for tree in &trees {
+            let value = format!("{}-2", tree);
+            assert_eq!(view.tree(tree)?.read(b"k").await?, Some(value.into_bytes()));
+        }
+        db.sync().await?;
+        drop(view);
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        for tree in &trees {
+            commit_write(&db, tree, b"k2", b"v").await?;
+        }
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        for tree in &trees {
+            let value = format!("{}-2", tree);
+            assert_eq!(view.tree(tree)?.read(b"k").await?, Some(value.into_bytes()));
+            assert_eq!(view.tree(tree)?.read(b"k2").await?, Some(b"v".to_vec()));
+        }
+
+        // Handles are closed to stay within the limit
+        let fs_thread = FsThread::start_with_max_open_files(2)?;
+        let paths: Vec<_> = (0..5).map(|i| dir.join(format!("scratch{}", i))).collect();
+        let open_files = fs_thread.run(move |ctx| -> Result<_> {
+            let mut open_files = vec![];
+            for path in &paths {
+                ctx.open_append(path)?;
+                ctx.open_read(path)?;
+                open_files.push(ctx.open_files());
+            }
+            Ok(open_files)
+        }).await?;
+        assert_eq!(open_files, vec![2, 2, 2, 2, 2]);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn append_after_reopening_log() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Batch, Key, Value};
+    use std::sync::Arc;
+
+    let dir = temp_dir("append-after-reopen");
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("t1.toml");
+    let write = |i: u8| Command::Write {
+        batch: Batch(0),
+        key: Key(vec![b'k', i]),
+        value: Value(vec![b'v'; 10]),
+    };
+
+    block_on(async {
+        let log = Log::<Command>::new(simple_log_file::create(path.clone(), Arc::new(FsThread::start()?)));
+        let first = log.append(write(0)).await?;
+        drop(log);
+
+        // A fresh append handle is positioned at the start
+        // until its first write, so the address comes from the end
+        let log = Log::<Command>::new(simple_log_file::create(path.clone(), Arc::new(FsThread::start()?)));
+        let second = log.append(write(1)).await?;
+        assert!(second.0 > first.0);
+        assert_eq!(format!("{:?}", log.read_at(first).await?), format!("{:?}", write(0)));
+        assert_eq!(format!("{:?}", log.read_at(second).await?), format!("{:?}", write(1)));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn value_transform() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    use db::ValueTransform;
+
+    #[derive(Debug)]
+    struct Xor;
+
+    impl ValueTransform for Xor {
+        fn on_write(&self, value: &[u8]) -> Vec<u8> {
+            value.iter().map(|b| b ^ 0x5a).collect()
+        }
+
+        fn on_read(&self, value: &[u8]) -> Result<Vec<u8>> {
+            Ok(value.iter().map(|b| b ^ 0x5a).collect())
+        }
+    }
+
+    let dir = temp_dir("value-transform");
+    let config = db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: vec!["t1".to_string(), "t2".to_string()],
+        value_transform: Some(Arc::new(Xor)),
+        ..db::DbConfig::default()
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"plaintext").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.increment(b"count", 3).await?;
+        batch.tree("t1")?.increment(b"count", 4).await?;
+        batch.commit().await?;
+        batch.close().await;
+        db.sync().await?;
+        drop(db);
+
+        // The log holds the transformed value
+        let fs_thread = Arc::new(FsThread::start()?);
+        let log = Log::<Command>::new(simple_log_file::create(dir.join("t1.toml"), fs_thread));
+        let stored: Vec<_> = log.replay().collect().await;
+        let stored = stored.into_iter().find_map(|r| match r {
+            Ok((Command::Write { value, .. }, _)) => Some(value.0),
+            _ => None,
+        });
+        assert_eq!(stored, Some(Xor.on_write(b"plaintext")));
+        assert_ne!(stored, Some(b"plaintext".to_vec()));
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"plaintext".to_vec()));
+        assert_eq!(view.tree("t1")?.read_counter(b"count").await?, 7);
+
+        let mut cursor = view.tree("t1")?.cursor();
+        cursor.seek_key(b"k1");
+        assert_eq!(cursor.value().await?, b"plaintext".to_vec());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn changed_keys_in_commit_range() -> Result<()> {
+    use std::collections::BTreeMap;
+
+    async fn apply(db: &db::Db, key: &[u8], value: Option<&[u8]>) -> Result<u64> {
+        let mut entries = BTreeMap::new();
+        entries.insert(key.to_vec(), value.map(|v| v.to_vec()));
+        db.apply_map("t1", entries).await
+    }
+
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let c1 = apply(&db, b"a", Some(b"1")).await?;
+        let _c2 = apply(&db, b"b", Some(b"1")).await?;
+        let c3 = apply(&db, b"c", Some(b"1")).await?;
+        let _c4 = apply(&db, b"a", Some(b"2")).await?;
+        let c5 = apply(&db, b"b", None).await?;
+        let view = db.read_view();
+        let c6 = apply(&db, b"c", Some(b"2")).await?;
+
+        let tree = view.tree("t1")?;
+        // a was last changed at c1, which is not in the window
+        assert_eq!(tree.changed_keys(c1, c3), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(tree.changed_keys(c3, c5), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(tree.changed_keys(c5, c5), Vec::<Vec<u8>>::new());
+        // c6 is after the view
+        assert_eq!(tree.changed_keys(c5, u64::MAX), Vec::<Vec<u8>>::new());
+        assert_eq!(tree.changed_keys(0, c1), Vec::<Vec<u8>>::new());
+        drop(view);
+
+        // Keys covered by a range delete change when it commits
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.delete_range(b"a", b"c").await?;
+        batch.commit().await?;
+        batch.close().await;
+        let c7 = c6 + 1;
+        assert_eq!(apply(&db, b"d", Some(b"1")).await?, c7 + 1);
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        assert_eq!(tree.changed_keys(c6, c7), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(tree.changed_keys(c5, c7), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(view.tree_ns("t1", b"a")?.changed_keys(c6, c7), vec![b"".to_vec()]);
+        assert_eq!(view.tree_ns("t1", b"c")?.changed_keys(c6, c7), Vec::<Vec<u8>>::new());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn compact_to_streams_values() -> Result<()> {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    /// Counts values read but not yet written
+    #[derive(Debug, Default)]
+    struct Outstanding {
+        current: AtomicI64,
+        max: AtomicI64,
+    }
+
+    impl db::ValueTransform for Outstanding {
+        fn on_write(&self, value: &[u8]) -> Vec<u8> {
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            value.to_vec()
+        }
+
+        fn on_read(&self, value: &[u8]) -> Result<Vec<u8>> {
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+            Ok(value.to_vec())
+        }
+    }
+
+    const VALUES: usize = 4;
+    const VALUE_BYTES: usize = 1 << 16;
+
+    let src_dir = temp_dir("compact-stream-src");
+    let dest_dir = temp_dir("compact-stream-dest");
+    let outstanding = Arc::new(Outstanding::default());
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            dir: Some(src_dir.clone()),
+            trees: vec!["t1".to_string()],
+            value_transform: Some(outstanding.clone()),
+            ..db::DbConfig::default()
+        }).await?;
+
+        for i in 0..VALUES {
+            let key = format!("k{}", i);
+            let value = vec![i as u8; VALUE_BYTES];
+            commit_write(&db, "t1", key.as_bytes(), &value).await?;
+        }
+
+        outstanding.current.store(0, Ordering::SeqCst);
+        outstanding.max.store(0, Ordering::SeqCst);
+        db.compact_to(&dest_dir).await?;
+        drop(db);
+
+        // Every value was written out before the next was read
+        assert_eq!(outstanding.max.load(Ordering::SeqCst), 1);
+
+        let db = db::Db::open(db::DbConfig {
+            dir: Some(dest_dir.clone()),
+            trees: vec!["t1".to_string()],
+            value_transform: Some(outstanding.clone()),
+            ..db::DbConfig::default()
+        }).await?;
+        let view = db.read_view();
+        for i in 0..VALUES {
+            let key = format!("k{}", i);
+            assert_eq!(view.tree("t1")?.read(key.as_bytes()).await?, Some(vec![i as u8; VALUE_BYTES]));
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&src_dir)?;
+    std::fs::remove_dir_all(&dest_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn flush_survives_process_crash() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts flushes once they have written to the file.
+    fn counted<Cmd>(log_file: LogFile<Cmd>, flushes: &Arc<AtomicUsize>) -> LogFile<Cmd>
+    where Cmd: serde::Serialize + for <'de> serde::Deserialize<'de>
+    {
+        let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = log_file;
+        let flushes = flushes.clone();
+        LogFile {
+            is_empty,
+            append,
+            read_at,
+            flush: Box::new(move || {
+                let flush = flush();
+                let flushes = flushes.clone();
+                Box::pin(async move {
+                    flush.await?;
+                    flushes.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                })
+            }),
+            sync,
+            size,
+            truncate,
+            remove,
+        }
+    }
+
+    let dir = temp_dir("flush-crash");
+    std::fs::create_dir_all(&dir)?;
+    let tree_path = dir.join("t1.log");
+    let commit_path = dir.join("commits.log");
+
+    // Every log buffers its appends
+    let open = |flushes: &Arc<AtomicUsize>| -> Result<bdb::Db> {
+        let fs_thread = Arc::new(FsThread::start()?);
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(counted(
+            simple_log_file::create_buffered(tree_path.clone(), fs_thread.clone(), 1 << 20), flushes)));
+        let commit_log = Log::new(counted(
+            simple_log_file::create_buffered(commit_path.clone(), fs_thread, 1 << 20), flushes));
+        Ok(bdb::Db::new(tree_logs, commit_log))
+    };
+
+    block_on(async {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let db = open(&flushes)?;
+        db.init().await?;
+
+        for (key, value) in [(b"k1", b"v1"), (b"k2", b"v2")] {
+            let batch = db.batch();
+            batch.open("t1").await?;
+            batch.write("t1", Key::from_slice(key), Value::from_slice(value)).await?;
+            let batch_commit = batch.new_batch_commit_number();
+            batch.ready_commit("t1", batch_commit).await?;
+            batch.commit(batch_commit).await?;
+            batch.close("t1").await?;
+        }
+
+        // The master commits are still buffered
+        assert_eq!(std::fs::metadata(&commit_path).map(|m| m.len()).unwrap_or(0), 0);
+
+        let flushed = flushes.load(Ordering::SeqCst);
+        db.flush().await?;
+        assert_eq!(flushes.load(Ordering::SeqCst), flushed + 2);
+        assert!(std::fs::metadata(&commit_path)?.len() > 0);
+
+        // Crash without closing or syncing any files
+        std::mem::forget(db);
+
+        let db = open(&Arc::new(AtomicUsize::new(0)))?;
+        db.init().await?;
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, Some(Value::from_slice(b"v1")));
+        assert_eq!(view.read("t1", &Key::from_slice(b"k2")).await?, Some(Value::from_slice(b"v2")));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn flush_does_not_sync() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Calls {
+        flushes: AtomicUsize,
+        syncs: AtomicUsize,
+    }
+
+    fn counted<Cmd>(log_file: LogFile<Cmd>, calls: &Arc<Calls>) -> LogFile<Cmd>
+    where Cmd: serde::Serialize + for <'de> serde::Deserialize<'de>
+    {
+        let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = log_file;
+        let (flush_calls, sync_calls) = (calls.clone(), calls.clone());
+        LogFile {
+            is_empty,
+            append,
+            read_at,
+            flush: Box::new(move || {
+                flush_calls.flushes.fetch_add(1, Ordering::SeqCst);
+                flush()
+            }),
+            sync: Box::new(move || {
+                sync_calls.syncs.fetch_add(1, Ordering::SeqCst);
+                sync()
+            }),
+            size,
+            truncate,
+            remove,
+        }
+    }
+
+    block_on(async {
+        let commit_calls = Arc::new(Calls::default());
+        let tree_calls = Arc::new(Calls::default());
+
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(counted(mem_log_file::create(), &tree_calls)));
+        let commit_log = Log::new(counted(mem_log_file::create(), &commit_calls));
+
+        let db = bdb::Db::new(tree_logs, commit_log);
+        db.init().await?;
+
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+
+        // Committing flushes too, so count only what flushing adds
+        let flushes = [&commit_calls, &tree_calls].map(|calls| calls.flushes.load(Ordering::SeqCst));
+        db.flush().await?;
+        for (calls, flushes) in [&commit_calls, &tree_calls].iter().zip(flushes.iter()) {
+            assert_eq!(calls.flushes.load(Ordering::SeqCst), flushes + 1);
+            assert_eq!(calls.syncs.load(Ordering::SeqCst), 0);
+        }
+
+        // Sync still syncs every log
+        db.sync().await?;
+        for calls in [&commit_calls, &tree_calls] {
+            assert_eq!(calls.syncs.load(Ordering::SeqCst), 1);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn oversized_keys_and_values() -> Result<()> {
+    let dir = temp_dir("oversized");
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            dir: Some(dir.clone()),
+            trees: vec!["t1".to_string()],
+            max_key_bytes: 8,
+            max_value_bytes: 16,
+            ..db::DbConfig::default()
+        }).await?;
+
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+        tree.write(b"k1", b"v1").await?;
+        let size = dir_size(&dir)?;
+
+        let err = tree.write(b"k2", &[0; 17]).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::ValueTooLarge)));
+        let err = tree.write(&[0; 9], b"v2").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::KeyTooLarge)));
+        let err = tree.delete(&[0; 9]).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::KeyTooLarge)));
+
+        // Nothing was written for the rejected commands
+        assert_eq!(dir_size(&dir)?, size);
+
+        tree.write(b"k2", &[0; 16]).await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(vec![0; 16]));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn log_extent() -> Result<()> {
+    let dir = temp_dir("log-extent");
+    let compacted_dir = temp_dir("log-extent-compacted");
+
+    block_on(async {
+        let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+        let db = db::Db::open(config.clone()).await?;
+
+        assert_eq!(db.log_extent("t1").await?, (0, 0));
+        assert!(db.log_extent("t2").await.is_err());
+
+        for i in 0..10u8 {
+            commit_write(&db, "t1", b"k", &[i; 100]).await?;
+        }
+
+        let (start, end) = db.log_extent("t1").await?;
+        assert_eq!(start, 0);
+        assert_eq!(end, std::fs::metadata(dir.join("t1.toml"))?.len());
+
+        db.compact_to(&compacted_dir).await?;
+        drop(db);
+
+        // Known on reopening, before anything is appended
+        let db = db::Db::open(config.clone()).await?;
+        assert_eq!(db.log_extent("t1").await?, (0, end));
+
+        let compacted = db::Db::open(db::DbConfig {
+            dir: Some(compacted_dir.clone()),
+            ..config
+        }).await?;
+        let (_, compacted_end) = compacted.log_extent("t1").await?;
+        assert!(compacted_end < end);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+    std::fs::remove_dir_all(&compacted_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn append_only_tree_rejects_deletes() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            append_only_trees: vec!["t1".to_string()],
+            ..mem_config()
+        }).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k1", b"v2").await?;
+
+        let batch = db.write_batch().await?;
+        assert!(batch.tree("t1")?.delete(b"k1").await.is_err());
+        assert!(batch.tree("t1")?.delete_range(b"k0", b"k9").await.is_err());
+        assert!(batch.move_key("t1", "t2", b"k1").await.is_err());
+        // Other trees still accept deletes
+        batch.tree("t2")?.delete(b"k1").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        // Writes still overwrite
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v2".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k1").await?, None);
+
+        let config = db::DbConfig {
+            append_only_trees: vec!["t3".to_string()],
+            ..mem_config()
+        };
+        assert!(config.validate().is_err());
+
+        Ok::<_, anyhow::Error>(())
+    })
+}
+
+#[test]
+fn replace_tree() -> Result<()> {
+    block_on(async {
+        // Nothing is deleted, even in an append-only tree
+        let db = db::Db::open(db::DbConfig {
+            append_only_trees: vec!["t1".to_string()],
+            max_key_bytes: 16,
+            ..mem_config()
+        }).await?;
+
+        commit_write(&db, "t1", b"a", b"old").await?;
+        commit_write(&db, "t1", b"b", b"old").await?;
+        commit_write(&db, "t1", &[0xFF; 16], b"old").await?;
+        commit_write(&db, "t2", b"a", b"other").await?;
+        let old_view = db.read_view();
+        let stale_batch = db.write_batch().await?;
+        stale_batch.tree("t2")?.write(b"b", b"stale").await?;
+
+        let entries = vec![
+            (b"b".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"new".to_vec()),
+        ];
+        db.replace_tree("t1", entries.into_iter()).await?;
+
+        // Batches begun before can't commit to the old tree
+        assert!(stale_batch.commit().await.is_err());
+        stale_batch.close().await;
+
+        // Nothing is replaced if an entry is rejected
+        let entries = vec![
+            (b"d".to_vec(), b"new".to_vec()),
+            (vec![0; 17], b"new".to_vec()),
+        ];
+        assert!(db.replace_tree("t1", entries.into_iter()).await.is_err());
+
+        let old_tree = old_view.tree("t1")?;
+        assert_eq!(old_tree.read(b"a").await?, Some(b"old".to_vec()));
+        assert_eq!(old_tree.read(b"b").await?, Some(b"old".to_vec()));
+        assert_eq!(old_tree.read(b"c").await?, None);
+
+        let view = db.read_view();
+        let mut cursor = view.tree("t1")?.cursor();
+        cursor.seek_first();
+        let mut entries = vec![];
+        while cursor.valid() {
+            entries.push((cursor.key(), cursor.value().await?));
+            cursor.next();
+        }
+        assert_eq!(entries, vec![
+            (b"b".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"new".to_vec()),
+        ]);
+        assert_eq!(view.tree("t2")?.read(b"a").await?, Some(b"other".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"b").await?, None);
+
+        // Later batches write to the replacement
+        commit_write(&db, "t1", b"d", b"later").await?;
+        assert_eq!(db.read_view().tree("t1")?.read(b"d").await?, Some(b"later".to_vec()));
+        assert_eq!(old_view.tree("t1")?.read(b"d").await?, None);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn replace_tree_swaps_its_log() -> Result<()> {
+    let dir = temp_dir("replace-tree");
+    let config = db::DbConfig::new(dir.clone(), vec!["t1".to_string(), "t2".to_string()]);
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"a", b"old").await?;
+        commit_write(&db, "t1", b"b", b"old").await?;
+
+        let old_view = db.read_view();
+        let entries = vec![(b"c".to_vec(), b"new".to_vec())];
+        db.replace_tree("t1", entries.into_iter()).await?;
+        assert!(dir.join("t1.toml.1").exists());
+
+        // The old log is kept for the old view, then deleted
+        assert!(dir.join("t1.toml").exists());
+        assert_eq!(old_view.tree("t1")?.read(b"a").await?, Some(b"old".to_vec()));
+        drop(old_view);
+        commit_write(&db, "t2", b"k", b"v").await?;
+        assert!(!dir.join("t1.toml").exists());
+
+        commit_write(&db, "t1", b"d", b"later").await?;
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"a").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"c").await?, Some(b"new".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"d").await?, Some(b"later".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k").await?, Some(b"v".to_vec()));
+
+        // Replacing again takes a newer log
+        let entries = vec![(b"e".to_vec(), b"newer".to_vec())];
+        db.replace_tree("t1", entries.into_iter()).await?;
+        drop(view);
+        commit_write(&db, "t2", b"k", b"v2").await?;
+        assert!(!dir.join("t1.toml.1").exists());
+        assert!(dir.join("t1.toml.2").exists());
+        drop(db);
+
+        // As a crash can leave them, an older log whose deletion was lost
+        // and a newer one written before the commit was
+        std::fs::write(dir.join("t1.toml"), b"")?;
+        std::fs::write(dir.join("t1.toml.3"), b"")?;
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"e").await?, Some(b"newer".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"c").await?, None);
+        assert!(dir.join("t1.toml.2").exists());
+        assert!(!dir.join("t1.toml").exists());
+        assert!(!dir.join("t1.toml.3").exists());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "lock-stats")]
+#[test]
+fn index_write_lock_hold_stats() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        let before = db.stats();
+
+        let batch = db.write_batch().await?;
+        for i in 0..1000 {
+            let key = format!("k{}", i);
+            batch.tree("t1")?.write(key.as_bytes(), b"v").await?;
+        }
+        batch.commit().await?;
+        batch.close().await;
+
+        let after = db.stats();
+        assert!(after.index_write_locks > before.index_write_locks);
+        assert!(after.index_write_lock_hold > before.index_write_lock_hold);
+        assert!(after.max_index_write_lock_hold > std::time::Duration::ZERO);
+        assert!(after.max_index_write_lock_hold <= after.index_write_lock_hold);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn read_view_stale() -> Result<()> {
+    use std::time::Duration;
+
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        let max_staleness = Duration::from_secs(60);
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        let view = db.read_view_stale(max_staleness);
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        drop(view);
+
+        // Within the window the same view is reused
+        commit_write(&db, "t1", b"k1", b"v2").await?;
+        let view = db.read_view_stale(max_staleness);
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        drop(view);
+
+        // An older view is replaced
+        std::thread::sleep(Duration::from_millis(10));
+        let view = db.read_view_stale(Duration::from_millis(5));
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v2".to_vec()));
+        drop(view);
+
+        // Only until it expires does the shared view hold back collapsing
+        commit_write(&db, "t1", b"k1", b"v3").await?;
+        db.collapse_key("t1", b"k1")?;
+        assert_eq!(db.read_view().tree("t1")?.history(b"k1").await?.len(), 2);
+        std::thread::sleep(Duration::from_millis(10));
+        db.collapse_key("t1", b"k1")?;
+        assert_eq!(db.read_view().tree("t1")?.history(b"k1").await?.len(), 1);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn append_raw_command() -> Result<()> {
+    use db::raw::codec::{RecordCodec, RecordFormat};
+    use db::raw::command::Command;
+    use db::raw::types::{Batch, BatchCommit, Key, Value};
+
+    let dir = temp_dir("append-raw");
+    let config = db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: vec!["t1".to_string()],
+        record_format: RecordFormat::Json,
+        ..db::DbConfig::default()
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+
+        let batch = db.write_batch().await?;
+        // As forwarded from another database's log
+        let write = Command::Write {
+            batch: Batch(batch.number().value() + 100),
+            key: Key::from_slice(b"k1"),
+            value: Value::from_slice(b"v1"),
+        };
+        batch.tree("t1")?.append_raw(&RecordFormat::Json.encode(&write)?).await?;
+
+        let other_format = Command::Write {
+            batch: batch.number(),
+            key: Key::from_slice(b"k2"),
+            value: Value::from_slice(b"v2"),
+        };
+        assert!(batch.tree("t1")?.append_raw(&RecordFormat::Cbor.encode(&other_format)?).await.is_err());
+        let ready = Command::ReadyCommit {
+            batch: batch.number(),
+            batch_commit: BatchCommit(0),
+        };
+        assert!(batch.tree("t1")?.append_raw(&RecordFormat::Json.encode(&ready)?).await.is_err());
+        assert!(batch.tree("t1")?.append_raw(b"garbage").await.is_err());
+        assert!(batch.tree_ns("t1", b"ns")?.append_raw(&RecordFormat::Json.encode(&write)?).await.is_err());
+
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, None);
+        drop(view);
+        drop(db);
+
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn write_delete_round_trip() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+        tree.write(b"k1", b"v1").await?;
+        tree.write(b"k2", b"v2").await?;
+        // Keys and values are bytes, not necessarily UTF-8
+        tree.write(&[0xFF, 0xFE], &[0x80]).await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(tree.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(tree.read(&[0xFF, 0xFE]).await?, Some(vec![0x80]));
+
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+        tree.delete(b"k1").await?;
+        tree.delete_range(b"k2", &[0xFF, 0xFF]).await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        assert_eq!(tree.read(b"k1").await?, None);
+        assert_eq!(tree.read(b"k2").await?, None);
+        assert_eq!(tree.read(&[0xFF, 0xFE]).await?, None);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn read_never_written_tree() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let assert_empty = |view: db::ReadView| async move {
+            let tree = view.tree("t2")?;
+            assert_eq!(tree.read(b"k1").await?, None);
+            assert_eq!(tree.history(b"k1").await?, vec![]);
+
+            let mut cursor = tree.cursor();
+            cursor.seek_first();
+            assert!(!cursor.valid());
+            cursor.seek_last();
+            assert!(!cursor.valid());
+            cursor.seek_key(b"k1");
+            assert!(!cursor.valid());
+            cursor.seek_key_rev(b"k1");
+            assert!(!cursor.valid());
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        // Before anything is committed
+        assert_empty(db.read_view()).await?;
+
+        // After commits to other trees only
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        assert_empty(db.read_view()).await?;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn read_committed_with_outstanding_batch() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+
+        let outstanding = db.write_batch().await?;
+        outstanding.tree("t1")?.write(b"k1", b"uncommitted").await?;
+        outstanding.tree("t1")?.write(b"k3", b"uncommitted").await?;
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(tree.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(tree.read(b"k3").await?, None);
+
+        let mut cursor = tree.cursor();
+        cursor.seek_first();
+        let mut entries = vec![];
+        while cursor.valid() {
+            entries.push((cursor.key(), cursor.value().await?));
+            cursor.next();
+        }
+        assert_eq!(entries, vec![
+            (b"k1".to_vec(), b"v1".to_vec()),
+            (b"k2".to_vec(), b"v2".to_vec()),
+        ]);
+
+        outstanding.commit().await?;
+        outstanding.close().await;
+
+        // The earlier view is unchanged by the later commit
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(tree.read(b"k3").await?, None);
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, Some(b"uncommitted".to_vec()));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn cursor_forward_and_backward() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k4", b"v4").await?;
+        commit_write(&db, "t1", b"k3", b"v3").await?;
+        commit_delete(&db, "t1", b"k3").await?;
+
+        let expected = vec![
+            (b"k1".to_vec(), b"v1".to_vec()),
+            (b"k2".to_vec(), b"v2".to_vec()),
+            (b"k4".to_vec(), b"v4".to_vec()),
+        ];
+
+        let view = db.read_view();
+        let mut cursor = view.tree("t1")?.cursor();
+
+        cursor.seek_first();
+        let mut forward = vec![];
+        while cursor.valid() {
+            forward.push((cursor.key(), cursor.value().await?));
+            cursor.next();
+        }
+        assert_eq!(forward, expected);
+
+        cursor.seek_last();
+        let mut backward = vec![];
+        while cursor.valid() {
+            backward.push((cursor.key(), cursor.value().await?));
+            cursor.prev();
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn db_config_validation() -> Result<()> {
+    let dir = temp_dir("config");
+    let trees = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    let config = db::DbConfig::new(&dir, trees(&["t1", "t2"]));
+    assert_eq!(config.dir.as_deref(), Some(dir.as_path()));
+    config.validate()?;
+
+    for bad in [&["t1", "commits"][..], &[""], &["t1", "t1"], &["a/b"]] {
+        let config = db::DbConfig::new(&dir, trees(bad));
+        assert!(config.validate().is_err());
+        assert!(block_on(db::Db::open(config)).is_err());
+    }
+
+    // Memory databases are checked too
+    let config = db::DbConfig {
+        dir: None,
+        trees: trees(&["commits"]),
+        ..db::DbConfig::default()
+    };
+    assert!(block_on(db::Db::open(config)).is_err());
+
+    assert!(!dir.exists());
+
+    Ok(())
+}
+
+#[test]
+fn cursor_skips_keys_deleted_later() -> Result<()> {
+    async fn scan(view: &db::ReadView, forward: bool) -> Result<Vec<Vec<u8>>> {
+        let mut cursor = view.tree("t1")?.cursor();
+        let mut keys = vec![];
+        if forward {
+            cursor.seek_first();
+        } else {
+            cursor.seek_last();
+        }
+        while cursor.valid() {
+            keys.push(cursor.key());
+            if forward {
+                cursor.next();
+            } else {
+                cursor.prev();
+            }
+        }
+        if !forward {
+            keys.reverse();
+        }
+        Ok(keys)
+    }
+
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        for key in [b"k1", b"k2", b"k3", b"k4"] {
+            commit_write(&db, "t1", key, b"v").await?;
+        }
+        let before = db.read_view();
+        commit_delete(&db, "t1", b"k1").await?;
+        commit_delete(&db, "t1", b"k3").await?;
+        let after = db.read_view();
+        commit_delete(&db, "t1", b"k4").await?;
+        let last = db.read_view();
+
+        let all = vec![b"k1".to_vec(), b"k2".to_vec(), b"k3".to_vec(), b"k4".to_vec()];
+        for forward in [true, false] {
+            assert_eq!(scan(&before, forward).await?, all);
+            assert_eq!(scan(&after, forward).await?, vec![b"k2".to_vec(), b"k4".to_vec()]);
+            assert_eq!(scan(&last, forward).await?, vec![b"k2".to_vec()]);
+        }
+
+        let mut cursor = after.tree("t1")?.cursor();
+        cursor.seek_key(b"k3");
+        assert_eq!(cursor.key(), b"k4".to_vec());
+        cursor.seek_key_rev(b"k1");
+        assert!(!cursor.valid());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn cursor_ignores_commits_during_iteration() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k3", b"v3").await?;
+        commit_write(&db, "t1", b"k5", b"v5").await?;
+
+        let view = db.read_view();
+        let mut cursor = view.tree("t1")?.cursor();
+        cursor.seek_first();
+        assert_eq!(cursor.key(), b"k1".to_vec());
+
+        // Commit a batch numbered after the view while it iterates
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k2", b"new").await?;
+        batch.tree("t1")?.write(b"k3", b"new").await?;
+        batch.tree("t1")?.delete(b"k5").await?;
+        batch.tree("t1")?.write(b"k6", b"new").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let mut entries = vec![];
+        while cursor.valid() {
+            entries.push((cursor.key(), cursor.value().await?));
+            cursor.next();
+        }
+        assert_eq!(entries, vec![
+            (b"k1".to_vec(), b"v1".to_vec()),
+            (b"k3".to_vec(), b"v3".to_vec()),
+            (b"k5".to_vec(), b"v5".to_vec()),
+        ]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn tree_cursor_skips_deleted_keys() -> Result<()> {
+    use db::raw::log::Log;
+    use db::raw::mem_log_file;
+    use db::raw::tree::{Tree, TreeConfig};
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    block_on(async {
+        let tree = Tree::new(Log::new(mem_log_file::create()), TreeConfig::default());
+        tree.skip_init();
+
+        let batch = tree.batch(Batch(0));
+        batch.open().await?;
+        batch.write(Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        batch.write(Key::from_slice(b"k2"), Value::from_slice(b"v2")).await?;
+        batch.delete(Key::from_slice(b"k2")).await?;
+        batch.delete(Key::from_slice(b"k3")).await?;
+        batch.write(Key::from_slice(b"k4"), Value::from_slice(b"v4")).await?;
+        batch.ready_commit(BatchCommit(0)).await?;
+        batch.commit_to_index(BatchCommit(0), Commit(0));
+        batch.close().await?;
+
+        let mut cursor = tree.cursor(Commit(1));
+        let mut entries = vec![];
+        cursor.seek_first();
+        while cursor.valid() {
+            entries.push((cursor.key(), cursor.value().await?));
+            // A second read returns the same value
+            assert_eq!(cursor.value().await?, entries.last().expect("entry").1);
+            cursor.next();
+        }
+        assert_eq!(entries, vec![
+            (Key::from_slice(b"k1"), Value::from_slice(b"v1")),
+            (Key::from_slice(b"k4"), Value::from_slice(b"v4")),
+        ]);
+
+        cursor.seek_last();
+        assert_eq!(cursor.key(), Key::from_slice(b"k4"));
+        cursor.prev();
+        assert_eq!(cursor.key(), Key::from_slice(b"k1"));
+        cursor.seek_key(Key::from_slice(b"k2"));
+        assert_eq!(cursor.key(), Key::from_slice(b"k4"));
+        cursor.seek_key_rev(Key::from_slice(b"k3"));
+        assert_eq!(cursor.key(), Key::from_slice(b"k1"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn index_hook_reverse_index() -> Result<()> {
+    use db::{Change, IndexWrite};
+
+    // Maintains t2 as a map from t1's values to its keys
+    fn reverse_index(changes: &[Change]) -> Vec<IndexWrite> {
+        let mut writes = vec![];
+        for change in changes {
+            if let Some(old_value) = &change.old_value {
+                writes.push(IndexWrite { tree: "t2".to_string(), key: old_value.clone(), value: None });
+            }
+            if let Some(new_value) = &change.new_value {
+                writes.push(IndexWrite { tree: "t2".to_string(), key: new_value.clone(), value: Some(change.key.clone()) });
+            }
+        }
+        writes
+    }
+
+    async fn assert_consistent(db: &db::Db) -> Result<()> {
+        let view = db.read_view();
+        let mut primary = vec![];
+        let mut cursor = view.tree("t1")?.cursor();
+        cursor.seek_first();
+        while cursor.valid() {
+            primary.push((cursor.value().await?, cursor.key()));
+            cursor.next();
+        }
+        primary.sort();
+
+        let mut index = vec![];
+        let mut cursor = view.tree("t2")?.cursor();
+        cursor.seek_first();
+        while cursor.valid() {
+            index.push((cursor.key(), cursor.value().await?));
+            cursor.next();
+        }
+
+        assert_eq!(primary, index);
+        Ok(())
+    }
+
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        db.register_index_hook("t1", reverse_index)?;
+        assert!(db.register_index_hook("t3", reverse_index).is_err());
+
+        commit_write(&db, "t1", b"k1", b"a").await?;
+        commit_write(&db, "t1", b"k2", b"b").await?;
+        assert_consistent(&db).await?;
+
+        commit_write(&db, "t1", b"k1", b"c").await?;
+        commit_delete(&db, "t1", b"k2").await?;
+        assert_consistent(&db).await?;
+        assert_eq!(db.read_view().tree("t2")?.read(b"c").await?, Some(b"k1".to_vec()));
+
+        // Rolled-back changes are not indexed
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k3", b"d").await?;
+        batch.push_save_point().await?;
+        batch.tree("t1")?.write(b"k4", b"e").await?;
+        batch.rollback_save_point().await?;
+        batch.commit().await?;
+        batch.close().await;
+        assert_consistent(&db).await?;
+        assert_eq!(db.read_view().tree("t2")?.read(b"e").await?, None);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn in_memory_db() -> Result<()> {
+    let config = db::DbConfig::in_memory(vec!["t1".to_string(), "t2".to_string()]);
+    assert_eq!(config.dir, None);
+
+    block_on(async {
+        let db = db::Db::open(config).await?;
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        let old_view = db.read_view();
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k1", b"v2").await?;
+        batch.tree("t2")?.write(b"k2", b"v2").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(old_view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v2".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v2".to_vec()));
+
+        drop(old_view);
+        let report = db.collapse_range("t1", b"k", b"l")?;
+        assert_eq!(report.versions_discarded, 1);
+
+        db.sync().await?;
+        assert_eq!(db.barrier().await?, Some(1));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn max_history_per_key() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            max_history_per_key: 3,
+            ..mem_config()
+        }).await?;
+
+        for i in 0..10 {
+            commit_write(&db, "t1", b"k", format!("v{}", i).as_bytes()).await?;
+            let history = db.read_view().tree("t1")?.history(b"k").await?;
+            assert_eq!(history.len(), (i + 1).min(3));
+            assert_eq!(history.last().map(|(_, v)| v.clone()), Some(Some(format!("v{}", i).into_bytes())));
+        }
+
+        // Versions an open view reads are kept past the limit
+        let pinned = db.read_view();
+        for i in 10..15 {
+            commit_write(&db, "t1", b"k", format!("v{}", i).as_bytes()).await?;
+        }
+        assert_eq!(pinned.tree("t1")?.read(b"k").await?, Some(b"v9".to_vec()));
+        assert_eq!(db.read_view().tree("t1")?.history(b"k").await?.len(), 6);
+
+        drop(pinned);
+        commit_write(&db, "t1", b"k", b"v15").await?;
+        assert_eq!(db.read_view().tree("t1")?.history(b"k").await?.len(), 3);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn collapse_range() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        for i in 0..3 {
+            let value = format!("v{}", i);
+            for key in [&b"a1"[..], b"a2", b"a3", b"b1"] {
+                commit_write(&db, "t1", key, value.as_bytes()).await?;
+            }
+        }
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.delete_range(b"a", b"b").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let report = db.collapse_range("t1", b"a", b"b")?;
+        assert_eq!(report.keys, 3);
+        assert_eq!(report.versions_discarded, 6);
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        for key in [&b"a1"[..], b"a2", b"a3"] {
+            assert_eq!(tree.read(key).await?, None);
+            assert_eq!(tree.history(key).await?.len(), 1);
+        }
+        assert_eq!(tree.read(b"b1").await?, Some(b"v2".to_vec()));
+        assert_eq!(tree.history(b"b1").await?.len(), 3);
+
+        // Nothing is left to discard
+        let report = db.collapse_range("t1", b"a", b"b")?;
+        assert_eq!(report.versions_discarded, 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn missing_tree_errors() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        assert_eq!(db.tree_names(), ["t1".to_string(), "t2".to_string()]);
+        assert!(db.has_tree("t1"));
+        assert!(!db.has_tree("t3"));
+
+        let batch = db.write_batch().await?;
+        let err = batch.tree("t3").err().expect("missing tree");
+        assert_eq!(err.to_string(), "no tree named \"t3\"");
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::UnknownTree(tree)) if tree == "t3"));
+        assert!(batch.tree_ns("t3", b"ns/").is_err());
+        assert!(batch.move_key("t1", "t3", b"k1").await.is_err());
+        batch.tree("t1")?.write(b"k1", b"v1").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert!(view.tree("t3").is_err());
+        assert!(view.tree_ns("t3", b"ns/").is_err());
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+
+        assert!(db.collapse_range("t3", b"a", b"z").is_err());
+        assert!(db.collapse_key("t3", b"k1").is_err());
+        assert!(db.log_extent("t3").await.is_err());
+        assert!(db.register_index_hook("t3", |_| vec![]).is_err());
+        assert!(db.apply_map("t3", Default::default()).await.is_err());
+        assert!(db.replace_tree("t3", std::iter::empty()).await.is_err());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn record_codecs_round_trip() -> Result<()> {
+    use db::raw::codec::{RecordCodec, RecordFormat, TomlCodec, CborCodec, JsonCodec};
+    use db::raw::command::Command;
+    use db::raw::commit_log::CommitCommand;
+    use db::raw::types::{Batch, BatchCommit, Commit, Key, Value};
+
+    let batch = Batch(3);
+    let cmds = vec![
+        Command::Open { batch },
+        Command::Write { batch, key: Key(b"k1".to_vec()), value: Value(vec![0, 255, b'\n']) },
+        Command::Write { batch, key: Key(vec![]), value: Value(vec![]) },
+        Command::WriteCompressed { batch, key: Key(b"k4".to_vec()), value: Value(vec![1, 0, 0, 0, 0]) },
+        Command::WriteCompressed { batch, key: Key(vec![0, 255]), value: Value((0..=255).cycle().take(1000).collect()) },
+        Command::WriteCompressed { batch, key: Key(vec![]), value: Value(vec![]) },
+        Command::Delete { batch, key: Key(b"k1".to_vec()) },
+        Command::DeleteRange { batch, start_key: Key(b"a".to_vec()), end_key: Key(b"z".to_vec()) },
+        Command::Merge { batch, key: Key(b"k2".to_vec()), operand: Value(b"+1".to_vec()) },
+        Command::Copy { batch, src_key: Key(b"k2".to_vec()), dst_key: Key(b"k3".to_vec()) },
+        Command::PushSavePoint { batch },
+        Command::PopSavePoint { batch },
+        Command::RollbackSavePoint { batch },
+        Command::ReadyCommit { batch, batch_commit: BatchCommit(1 << 40) },
+        Command::AbortCommit { batch, batch_commit: BatchCommit(0) },
+        Command::Close { batch },
+    ];
+    let commit_cmd = CommitCommand { batch, batch_commit: BatchCommit(1), commit: Commit(2) };
+
+    fn round_trip(codec: &impl RecordCodec, cmds: &[Command], commit_cmd: &CommitCommand) -> Result<()> {
+        for cmd in cmds {
+            let decoded: Command = codec.decode(&codec.encode(cmd)?)?;
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", cmd));
+        }
+        // A compressed write must not decode as a plain write of the same bytes
+        let write = Command::Write { batch: Batch(0), key: Key(b"k".to_vec()), value: Value(vec![1]) };
+        let compressed = Command::WriteCompressed { batch: Batch(0), key: Key(b"k".to_vec()), value: Value(vec![1]) };
+        assert_ne!(codec.encode(&write)?, codec.encode(&compressed)?);
+        let decoded: Command = codec.decode(&codec.encode(&compressed)?)?;
+        assert!(matches!(decoded, Command::WriteCompressed { .. }));
+        let decoded: CommitCommand = codec.decode(&codec.encode(commit_cmd)?)?;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", commit_cmd));
+        Ok(())
+    }
+
+    round_trip(&TomlCodec, &cmds, &commit_cmd)?;
+    round_trip(&CborCodec, &cmds, &commit_cmd)?;
+    round_trip(&JsonCodec, &cmds, &commit_cmd)?;
+    for format in [RecordFormat::Toml, RecordFormat::Cbor, RecordFormat::Json] {
+        round_trip(&format, &cmds, &commit_cmd)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn record_formats_mix_in_one_log() -> Result<()> {
+    use db::raw::codec::RecordFormat;
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Batch, Key, Value};
+    use futures::TryStreamExt;
+    use std::sync::Arc;
+
+    let dir = temp_dir("record-formats");
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("log.toml");
+    let formats = [RecordFormat::Toml, RecordFormat::Cbor, RecordFormat::Json];
+
+    block_on(async {
+        let fs_thread = Arc::new(FsThread::start()?);
+        let mut expected = vec![];
+        for (i, format) in formats.iter().enumerate() {
+            let log_file = simple_log_file::create_with_format(path.clone(), fs_thread.clone(), 0, 0, *format);
+            let log = Log::<Command>::new(log_file);
+            let cmd = Command::Write {
+                batch: Batch(0),
+                key: Key(format!("k{}", i).into_bytes()),
+                value: Value(vec![b'\n'; i]),
+            };
+            expected.push(format!("{:?}", cmd));
+            log.append(cmd).await?;
+        }
+
+        for read_ahead_bytes in [0, simple_log_file::DEFAULT_READ_AHEAD_BYTES] {
+            let log_file = simple_log_file::create_with_read_ahead(path.clone(), fs_thread.clone(), 0, read_ahead_bytes);
+            let cmds: Vec<(Command, _)> = Log::new(log_file).replay().try_collect().await?;
+            let cmds: Vec<_> = cmds.iter().map(|(cmd, _)| format!("{:?}", cmd)).collect();
+            assert_eq!(cmds, expected);
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    // A database reopens with a different format
+    let mut config = db::DbConfig::new(dir.join("db"), vec!["t1".to_string()]);
+    block_on(async {
+        for (i, format) in formats.iter().enumerate() {
+            config.record_format = *format;
+            let db = db::Db::open(config.clone()).await?;
+            commit_write(&db, "t1", format!("k{}", i).as_bytes(), b"v").await?;
+            db.sync().await?;
+            drop(db);
+        }
+
+        config.record_format = RecordFormat::Toml;
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        for i in 0..formats.len() {
+            assert_eq!(view.tree("t1")?.read(format!("k{}", i).as_bytes()).await?, Some(b"v".to_vec()));
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn unknown_tree_errors_in_basic_db() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::log::Log;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Commit, Key, Value};
+    use std::collections::BTreeMap;
+
+    fn is_unknown(r: Result<impl std::fmt::Debug>) -> bool {
+        matches!(r.unwrap_err().downcast_ref::<db::DbError>(), Some(db::DbError::UnknownTree(tree)) if tree == "t3")
+    }
+
+    block_on(async {
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(mem_log_file::create()));
+        let db = bdb::Db::new(tree_logs, Log::new(mem_log_file::create()));
+        db.init().await?;
+
+        let key = || Key::from_slice(b"k1");
+        let batch = db.batch();
+        assert!(is_unknown(batch.open("t3").await));
+        assert!(is_unknown(batch.write("t3", key(), Value::from_slice(b"v1")).await));
+        assert!(is_unknown(batch.delete("t3", key()).await));
+        assert!(is_unknown(batch.delete_range("t3", key(), Key::from_slice(b"k2")).await));
+        assert!(is_unknown(batch.merge("t3", key(), Value::from_slice(b"+1")).await));
+        assert!(is_unknown(batch.copy("t3", key(), Key::from_slice(b"k2")).await));
+        assert!(is_unknown(batch.append_raw("t3", b"raw").await));
+        assert!(is_unknown(batch.push_save_point("t3").await));
+        assert!(is_unknown(batch.pop_save_point("t3").await));
+        assert!(is_unknown(batch.rollback_save_point("t3").await));
+        assert!(is_unknown(batch.expect_unchanged("t3", key(), Commit(0))));
+        let batch_commit = batch.new_batch_commit_number();
+        assert!(is_unknown(batch.ready_commit("t3", batch_commit).await));
+        assert!(is_unknown(batch.abort_commit("t3", batch_commit).await));
+        assert!(is_unknown(batch.close("t3").await));
+
+        let view = db.view();
+        assert!(is_unknown(view.read("t3", &key()).await));
+        assert!(is_unknown(view.read_arc("t3", &key()).await));
+        assert!(is_unknown(view.history("t3", &key()).await));
+        assert!(is_unknown(view.changed_keys("t3", Commit(0), Commit(1), b"")));
+        assert!(is_unknown(view.scan_tree_stats("t3", b"").await));
+        assert!(is_unknown(view.cursor("t3").map(|_| ())));
+
+        assert!(is_unknown(db.collapse_range("t3", key()..Key::from_slice(b"k2"))));
+        assert!(is_unknown(db.collapse_key("t3", &key())));
+        assert!(is_unknown(db.log_extent("t3").await));
+
+        // The database is still usable
+        batch.open("t1").await?;
+        batch.write("t1", key(), Value::from_slice(b"v1")).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+        assert_eq!(db.view().read("t1", &key()).await?, Some(Value::from_slice(b"v1")));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn open_with_recovery_summary() -> Result<()> {
+    let dir = temp_dir("recovery-summary");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string(), "t2".to_string()]);
+
+    block_on(async {
+        let (db, summary) = db::Db::open_with_summary(config.clone()).await?;
+        assert_eq!(summary.batches_applied, 0);
+        assert_eq!(summary.batches_aborted, 0);
+
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t2", b"k2", b"v2").await?;
+
+        // Closed without committing
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k3", b"v3").await?;
+        batch.tree("t2")?.write(b"k3", b"v3").await?;
+        batch.close().await;
+
+        // Nothing to abort
+        let batch = db.write_batch().await?;
+        batch.close().await;
+
+        // Never closed
+        let batch = db.write_batch().await?;
+        batch.tree("t2")?.delete(b"k2").await?;
+        std::mem::forget(batch);
+
+        drop(db);
+
+        let (db, summary) = db::Db::open_with_summary(config.clone()).await?;
+        assert_eq!(summary.batches_applied, 2);
+        assert_eq!(summary.batches_aborted, 2);
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, None);
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v2".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn write_batch_reads_own_writes() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+        commit_write(&db, "t1", b"k3", b"v3").await?;
+
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+
+        // Committed values show through
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(tree.read(b"k9").await?, None);
+
+        // Write then read
+        tree.write(b"k1", b"v1-new").await?;
+        tree.write(b"k4", b"v4").await?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1-new".to_vec()));
+        assert_eq!(tree.read(b"k4").await?, Some(b"v4".to_vec()));
+
+        // Delete then read
+        tree.delete(b"k2").await?;
+        assert_eq!(tree.read(b"k2").await?, None);
+        tree.delete_range(b"k3", b"k5").await?;
+        assert_eq!(tree.read(b"k3").await?, None);
+        assert_eq!(tree.read(b"k4").await?, None);
+        tree.write(b"k3", b"v3-new").await?;
+        assert_eq!(tree.read(b"k3").await?, Some(b"v3-new".to_vec()));
+
+        // Rollback then read
+        batch.push_save_point().await?;
+        tree.write(b"k1", b"v1-rolled-back").await?;
+        tree.delete(b"k3").await?;
+        tree.copy(b"k1", b"k5").await?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1-rolled-back".to_vec()));
+        assert_eq!(tree.read(b"k3").await?, None);
+        assert_eq!(tree.read(b"k5").await?, Some(b"v1-rolled-back".to_vec()));
+        batch.rollback_save_point().await?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1-new".to_vec()));
+        assert_eq!(tree.read(b"k3").await?, Some(b"v3-new".to_vec()));
+        assert_eq!(tree.read(b"k5").await?, None);
+
+        // Nested save points roll back one at a time,
+        // range deletes included
+        batch.push_save_point().await?;
+        tree.write(b"k6", b"v6").await?;
+        batch.push_save_point().await?;
+        tree.write(b"k7", b"v7").await?;
+        batch.pop_save_point().await?;
+        batch.push_save_point().await?;
+        tree.delete_range(b"k1", b"k8").await?;
+        assert_eq!(tree.read(b"k1").await?, None);
+        assert_eq!(tree.read(b"k2").await?, None);
+        assert_eq!(tree.read(b"k6").await?, None);
+        batch.rollback_save_point().await?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1-new".to_vec()));
+        assert_eq!(tree.read(b"k6").await?, Some(b"v6".to_vec()));
+        assert_eq!(tree.read(b"k7").await?, Some(b"v7".to_vec()));
+        batch.rollback_save_point().await?;
+        assert_eq!(tree.read(b"k6").await?, None);
+        assert_eq!(tree.read(b"k7").await?, None);
+
+        // Merges apply on top of the batch's value,
+        // or the committed one
+        tree.increment(b"n", 2).await?;
+        tree.increment(b"n", 3).await?;
+        assert_eq!(tree.read(b"n").await?, Some(5i64.to_le_bytes().to_vec()));
+        commit_write(&db, "t2", b"c", &7i64.to_le_bytes()).await?;
+        let t2 = batch.tree("t2")?;
+        t2.increment(b"c", 1).await?;
+        t2.copy(b"c", b"d").await?;
+        t2.increment(b"d", 1).await?;
+        assert_eq!(t2.read(b"c").await?, Some(8i64.to_le_bytes().to_vec()));
+        assert_eq!(t2.read(b"d").await?, Some(9i64.to_le_bytes().to_vec()));
+
+        // Namespaces read their own keys
+        let ns = batch.tree_ns("t1", b"ns/")?;
+        ns.write(b"k1", b"ns-v1").await?;
+        assert_eq!(ns.read(b"k1").await?, Some(b"ns-v1".to_vec()));
+        assert_eq!(tree.read(b"ns/k1").await?, Some(b"ns-v1".to_vec()));
+
+        // Other batches and views don't see uncommitted writes
+        assert_eq!(db.read_view().tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1-new".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, Some(b"v3-new".to_vec()));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn read_range() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        for i in 0..20 {
+            let key = format!("k{:02}", i);
+            batch.tree("t1")?.write(key.as_bytes(), format!("v{}", i).as_bytes()).await?;
+        }
+        batch.tree("t1")?.delete(b"k06").await?;
+        batch.tree_ns("t1", b"k1")?.write(b"", b"namespaced").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        let pair = |i: usize| (format!("k{:02}", i).into_bytes(), format!("v{}", i).into_bytes());
+
+        let pairs = tree.read_range(b"k03", b"k15", 4).await?;
+        assert_eq!(pairs, vec![pair(3), pair(4), pair(5), pair(7)]);
+
+        // The end is exclusive
+        let pairs = tree.read_range(b"k10", b"k15", 100).await?;
+        assert_eq!(pairs.len(), 5);
+        assert_eq!(pairs, (10..15).map(pair).collect::<Vec<_>>());
+
+        assert!(tree.read_range(b"k03", b"k15", 0).await?.is_empty());
+        assert!(tree.read_range(b"k15", b"k03", 10).await?.is_empty());
+
+        let ns = view.tree_ns("t1", b"k1")?;
+        let pairs = ns.read_range(b"", b"3", 10).await?;
+        assert_eq!(pairs, vec![
+            (b"".to_vec(), b"namespaced".to_vec()),
+            (b"0".to_vec(), b"v10".to_vec()),
+            (b"1".to_vec(), b"v11".to_vec()),
+            (b"2".to_vec(), b"v12".to_vec()),
+        ]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn delete_range_visibility_by_commit() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        for i in 0..10 {
+            batch.tree("t1")?.write(format!("k{}", i).as_bytes(), b"v").await?;
+        }
+        batch.commit().await?;
+        batch.close().await;
+        let before = db.read_view();
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.delete_range(b"k3", b"k7").await?;
+        batch.commit().await?;
+        batch.close().await;
+        let after = db.read_view();
+
+        commit_write(&db, "t1", b"k5", b"v-new").await?;
+        let rewritten = db.read_view();
+
+        for i in 0..10 {
+            let key = format!("k{}", i);
+            let deleted = (3..7).contains(&i);
+            assert!(before.tree("t1")?.read(key.as_bytes()).await?.is_some());
+            assert_eq!(after.tree("t1")?.read(key.as_bytes()).await?.is_some(), !deleted);
+            assert_eq!(rewritten.tree("t1")?.read(key.as_bytes()).await?.is_some(), !deleted || i == 5);
+        }
+        assert_eq!(rewritten.tree("t1")?.read(b"k5").await?, Some(b"v-new".to_vec()));
+
+        let keys: Vec<_> = after.tree("t1")?.read_range(b"", b"z", 100).await?
+            .into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, [b"k0", b"k1", b"k2", b"k7", b"k8", b"k9"]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn count_matches_cursor_scan() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::command::Command;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        for i in 0..50 {
+            batch.tree("t1")?.write(format!("k{:02}", i).as_bytes(), b"v").await?;
+        }
+        batch.tree("t1")?.increment(b"n", 1).await?;
+        batch.commit().await?;
+        batch.close().await;
+        let old_view = db.read_view();
+
+        let batch = db.write_batch().await?;
+        for i in (0..50).step_by(3) {
+            batch.tree("t1")?.delete(format!("k{:02}", i).as_bytes()).await?;
+        }
+        batch.tree("t1")?.delete_range(b"k40", b"k45").await?;
+        batch.tree("t1")?.write(b"k42", b"v").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        for (view, prefix) in [(&old_view, &b""[..]), (&view, b""), (&view, b"k1"), (&view, b"none")] {
+            let tree = view.tree_ns("t1", prefix)?;
+            let mut scanned = 0;
+            let mut cursor = tree.cursor();
+            cursor.seek_first();
+            while cursor.valid() {
+                scanned += 1;
+                cursor.next();
+            }
+            assert_eq!(tree.count()?, scanned);
+        }
+        assert_eq!(old_view.tree("t1")?.count()?, 51);
+        assert_eq!(view.tree("t2")?.count()?, 0);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    // Counting reads nothing from the log
+    block_on(async {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let tree_log = {
+            let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = mem_log_file::create::<Command>();
+            let reads = reads.clone();
+            LogFile {
+                is_empty,
+                append,
+                read_at: Box::new(move |addr| {
+                    reads.fetch_add(1, Ordering::SeqCst);
+                    read_at(addr)
+                }),
+                flush,
+                sync,
+                size,
+                truncate,
+                remove,
+            }
+        };
+
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(tree_log));
+        let db = bdb::Db::new(tree_logs, Log::new(mem_log_file::create()));
+        db.init().await?;
+
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        batch.write("t1", Key::from_slice(b"k2"), Value::from_slice(b"v2")).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+
+        let reads_before = reads.load(Ordering::SeqCst);
+        assert_eq!(db.view().count("t1", b"")?, 2);
+        assert_eq!(reads.load(Ordering::SeqCst), reads_before);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn paranoid_commit_requires_every_tree_ready() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::log::Log;
+    use db::raw::mem_log_file;
+    use db::raw::tree::TreeConfig;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+
+    block_on(async {
+        let mut tree_logs = BTreeMap::new();
+        let mut tree_configs = BTreeMap::new();
+        for tree in ["t1", "t2"] {
+            tree_logs.insert(tree.to_string(), Log::new(mem_log_file::create()));
+            tree_configs.insert(tree.to_string(), TreeConfig {
+                validation: db::Validation::Paranoid,
+                ..TreeConfig::default()
+            });
+        }
+        let db = bdb::Db::with_tree_configs(tree_logs, Log::new(mem_log_file::create()), tree_configs);
+        db.init().await?;
+
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.open("t2").await?;
+        batch.write("t1", Key::from_slice(b"k1"), Value::from_slice(b"v1")).await?;
+        batch.write("t2", Key::from_slice(b"k2"), Value::from_slice(b"v2")).await?;
+
+        // t2 never records its ready-commit
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        let err = batch.commit(batch_commit).await.unwrap_err();
+        assert!(err.to_string().contains("not ready"));
+
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, None);
+        assert_eq!(view.read("t2", &Key::from_slice(b"k2")).await?, None);
+
+        // The aborted commit can be retried with every tree ready
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.ready_commit("t2", batch_commit).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+        batch.close("t2").await?;
+
+        let view = db.view();
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, Some(Value::from_slice(b"v1")));
+        assert_eq!(view.read("t2", &Key::from_slice(b"k2")).await?, Some(Value::from_slice(b"v2")));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn cursor_prefix_scan() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+        for key in [
+            &b"user:12:name"[..], b"user:123:email", b"user:123:profile",
+            b"user:1234:email", b"user:124:email", b"user:12", b"zzz",
+            b"\xff\xff", b"\xff\xff\x00", b"\xff\xff\xff", b"\xff",
+        ] {
+            tree.write(key, key).await?;
+        }
+        batch.tree_ns("t2", b"user:123:")?.write(b"profile", b"namespaced").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        let scan = |cursor: &mut db::Cursor, prefix: &[u8]| {
+            let mut keys = vec![];
+            cursor.seek_prefix(prefix);
+            while cursor.valid_for_prefix(prefix) {
+                keys.push(cursor.key());
+                cursor.next();
+            }
+            keys
+        };
+
+        let mut cursor = view.tree("t1")?.cursor();
+        assert_eq!(scan(&mut cursor, b"user:123:"), vec![
+            b"user:123:email".to_vec(),
+            b"user:123:profile".to_vec(),
+        ]);
+        // The scan stops at the first key past the prefix
+        assert!(cursor.valid());
+        assert_eq!(cursor.key(), b"user:124:email");
+        assert!(!cursor.valid_for_prefix(b"user:123:"));
+
+        assert_eq!(scan(&mut cursor, b"user:12").len(), 6);
+        assert!(scan(&mut cursor, b"user:125").is_empty());
+        assert_eq!(scan(&mut cursor, b"").len(), 11);
+
+        // A prefix of 0xff bytes has no successor
+        assert_eq!(scan(&mut cursor, b"\xff\xff"), vec![
+            b"\xff\xff".to_vec(),
+            b"\xff\xff\x00".to_vec(),
+            b"\xff\xff\xff".to_vec(),
+        ]);
+        assert!(!cursor.valid());
+        assert_eq!(scan(&mut cursor, b"\xff\xff\xff\xff"), Vec::<Vec<u8>>::new());
+
+        // Prefixes are relative to a namespace
+        let mut cursor = view.tree_ns("t2", b"user:")?.cursor();
+        assert_eq!(scan(&mut cursor, b"123:"), vec![b"123:profile".to_vec()]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn tree_metadata_survives_reopen() -> Result<()> {
+    let dir = temp_dir("tree-metadata");
+    let compacted_dir = temp_dir("tree-metadata-compacted");
+    let trees = vec!["t1".to_string(), "t2".to_string()];
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig::new(&dir, trees.clone())).await?;
+        assert_eq!(db.get_tree_metadata("t1", "schema")?, None);
+        db.set_tree_metadata("t1", "schema", "1").await?;
+        db.set_tree_metadata("t1", "schema", "2").await?;
+        db.set_tree_metadata("t1", "compression", "none").await?;
+        db.set_tree_metadata("t2", "schema", "7").await?;
+        assert_eq!(db.get_tree_metadata("t1", "schema")?, Some("2".to_string()));
+        assert!(db.set_tree_metadata("t3", "schema", "1").await.is_err());
+        assert!(db.get_tree_metadata("t3", "schema").is_err());
+        drop(db);
+
+        // A temporary file left by a crash mid-write is ignored
+        std::fs::write(dir.join("tree_metadata.tmp"), b"garbage")?;
+
+        let db = db::Db::open(db::DbConfig::new(&dir, trees.clone())).await?;
+        assert_eq!(db.get_tree_metadata("t1", "schema")?, Some("2".to_string()));
+        assert_eq!(db.get_tree_metadata("t1", "compression")?, Some("none".to_string()));
+        assert_eq!(db.get_tree_metadata("t2", "schema")?, Some("7".to_string()));
+        assert_eq!(db.get_tree_metadata("t2", "compression")?, None);
+
+        db.compact_to(&compacted_dir).await?;
+        drop(db);
+
+        let db = db::Db::open(db::DbConfig {
+            read_only: true,
+            ..db::DbConfig::new(&compacted_dir, trees.clone())
+        }).await?;
+        assert_eq!(db.get_tree_metadata("t2", "schema")?, Some("7".to_string()));
+        assert!(db.set_tree_metadata("t2", "schema", "8").await.is_err());
+
+        let db = db::Db::open(mem_config()).await?;
+        db.set_tree_metadata("t1", "schema", "1").await?;
+        assert_eq!(db.get_tree_metadata("t1", "schema")?, Some("1".to_string()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+    std::fs::remove_dir_all(&compacted_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn read_does_not_hold_index_lock_across_log_read() -> Result<()> {
+    use db::raw::basic_db as bdb;
+    use db::raw::command::Command;
+    use db::raw::log::Log;
+    use db::raw::log_file::LogFile;
+    use db::raw::mem_log_file;
+    use db::raw::types::{Key, Value};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, OnceLock};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    async fn commit_write(db: &bdb::Db, key: &[u8], value: &[u8]) -> Result<()> {
+        let batch = db.batch();
+        batch.open("t1").await?;
+        batch.write("t1", Key::from_slice(key), Value::from_slice(value)).await?;
+        let batch_commit = batch.new_batch_commit_number();
+        batch.ready_commit("t1", batch_commit).await?;
+        batch.commit(batch_commit).await?;
+        batch.close("t1").await?;
+        Ok(())
+    }
+
+    // Commits from another thread while a read is waiting on the log.
+    // The commit needs the index write lock,
+    // so it can only finish if the read isn't holding the index lock.
+    let db_cell: Arc<OnceLock<Arc<bdb::Db>>> = Arc::new(OnceLock::new());
+    let armed = Arc::new(AtomicBool::new(false));
+    let tree_log = {
+        let LogFile { is_empty, append, read_at, flush, sync, size, truncate, remove } = mem_log_file::create::<Command>();
+        let db_cell = db_cell.clone();
+        let armed = armed.clone();
+        LogFile {
+            is_empty,
+            append,
+            read_at: Box::new(move |addr| {
+                if armed.swap(false, Ordering::SeqCst) {
+                    let db = db_cell.get().expect("db").clone();
+                    let (tx, rx) = std::sync::mpsc::channel();
+                    std::thread::spawn(move || {
+                        let _ = tx.send(block_on(commit_write(&db, b"k2", b"v2")));
+                    });
+                    let committed = rx.recv_timeout(Duration::from_secs(10));
+                    assert!(matches!(committed, Ok(Ok(()))), "commit blocked by read");
+                }
+                read_at(addr)
+            }),
+            flush,
+            sync,
+            size,
+            truncate,
+            remove,
+        }
+    };
+
+    block_on(async {
+        let mut tree_logs = BTreeMap::new();
+        tree_logs.insert("t1".to_string(), Log::new(tree_log));
+        let db = Arc::new(bdb::Db::new(tree_logs, Log::new(mem_log_file::create())));
+        db.init().await?;
+        assert!(db_cell.set(db.clone()).is_ok());
+
+        commit_write(&db, b"k1", b"v1").await?;
+
+        let view = db.view();
+        armed.store(true, Ordering::SeqCst);
+        assert_eq!(view.read("t1", &Key::from_slice(b"k1")).await?, Some(Value::from_slice(b"v1")));
+        assert!(!armed.load(Ordering::SeqCst));
+
+        // The concurrent commit is visible only to later views
+        assert_eq!(view.read("t1", &Key::from_slice(b"k2")).await?, None);
+        assert_eq!(db.view().read("t1", &Key::from_slice(b"k2")).await?, Some(Value::from_slice(b"v2")));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn range_iter() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+
+        let batch = db.write_batch().await?;
+        for i in 0..10 {
+            batch.tree("t1")?.write(format!("k{}", i).as_bytes(), format!("v{}", i).as_bytes()).await?;
+        }
+        batch.tree("t1")?.delete(b"k4").await?;
+        batch.tree_ns("t1", b"k2")?.write(b"x", b"namespaced").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        async fn collect(mut iter: db::RangeIter) -> Result<Vec<Vec<u8>>> {
+            let mut keys = vec![];
+            while let Some((key, _)) = iter.next().await? {
+                keys.push(key);
+            }
+            Ok(keys)
+        }
+        let keys = |names: &[&str]| names.iter().map(|k| k.as_bytes().to_vec()).collect::<Vec<_>>();
+
+        // The start is inclusive and the end exclusive
+        assert_eq!(collect(tree.range(Some(b"k2"), Some(b"k6"))).await?, keys(&["k2", "k2x", "k3", "k5"]));
+        assert_eq!(collect(tree.range(None, Some(b"k2"))).await?, keys(&["k0", "k1"]));
+        assert_eq!(collect(tree.range(Some(b"k8"), None)).await?, keys(&["k8", "k9"]));
+        assert_eq!(collect(tree.range(None, None)).await?.len(), 10);
+
+        let mut iter = tree.range(Some(b"k1"), Some(b"k2"));
+        assert_eq!(iter.next().await?, Some((b"k1".to_vec(), b"v1".to_vec())));
+        assert_eq!(iter.next().await?, None);
+        assert_eq!(iter.next().await?, None);
+
+        // Empty and reversed ranges yield nothing
+        assert!(collect(tree.range(Some(b"k3"), Some(b"k3"))).await?.is_empty());
+        assert!(collect(tree.range(Some(b"k4"), Some(b"k5"))).await?.is_empty());
+        assert!(collect(tree.range(Some(b"k6"), Some(b"k2"))).await?.is_empty());
+        assert!(collect(tree.range(Some(b"z"), None)).await?.is_empty());
+
+        // Bounds are relative to a namespace
+        let ns = view.tree_ns("t1", b"k2")?;
+        let mut iter = ns.range(Some(b""), None);
+        assert_eq!(iter.next().await?, Some((b"".to_vec(), b"v2".to_vec())));
+        assert_eq!(iter.next().await?, Some((b"x".to_vec(), b"namespaced".to_vec())));
+        assert_eq!(iter.next().await?, None);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn corrupt_log_tail_is_truncated_on_load() -> Result<()> {
+    let dir = temp_dir("corrupt-tail");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string(), "t2".to_string()]);
+
+    // Flips a bit in the body of the last record
+    let corrupt_last_record = |path: &std::path::Path| -> Result<u64> {
+        let mut bytes = std::fs::read(path)?;
+        let len = bytes.len();
+        // The body is followed by three newlines
+        bytes[len - 4] ^= 1;
+        std::fs::write(path, &bytes)?;
+        Ok(len as u64)
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+        commit_write(&db, "t1", b"k3", b"v3").await?;
+        db.sync().await?;
+        drop(db);
+
+        // The last commit record was torn
+        let commits = dir.join("commits.toml");
+        let corrupt_len = corrupt_last_record(&commits)?;
+
+        let db = db::Db::open(config.clone()).await?;
+        assert!(std::fs::metadata(&commits)?.len() < corrupt_len);
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, None);
+        drop(view);
+
+        // Commits after recovery follow the last good record
+        commit_write(&db, "t1", b"k4", b"v4").await?;
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"k4").await?, Some(b"v4".to_vec()));
+        drop(view);
+        drop(db);
+
+        // A torn tree log record after its batch committed
+        let tree_log = dir.join("t1.toml");
+        let corrupt_len = corrupt_last_record(&tree_log)?;
+
+        let db = db::Db::open(config.clone()).await?;
+        assert!(std::fs::metadata(&tree_log)?.len() < corrupt_len);
+        assert_eq!(db.read_view().tree("t1")?.read(b"k4").await?, Some(b"v4".to_vec()));
+        commit_write(&db, "t1", b"k5", b"v5").await?;
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k4").await?, Some(b"v4".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k5").await?, Some(b"v5".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn corrupt_log_record_that_was_committed_fails_load() -> Result<()> {
+    let dir = temp_dir("corrupt-committed");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+    let commits = dir.join("commits.toml");
+    let tree_log = dir.join("t1.toml");
+
+    let find = |bytes: &[u8], from: usize, needle: &[u8]| -> Option<usize> {
+        bytes[from..].windows(needle.len())
+            .position(|w| w == needle)
+            .map(|pos| from + pos)
+    };
+    // Flips a bit in the body of the first record
+    let corrupt_first_record = |path: &std::path::Path| -> Result<Vec<u8>> {
+        let mut bytes = std::fs::read(path)?;
+        let body = find(&bytes, 0, b"# BODY\n\n").expect("body") + 9;
+        bytes[body] ^= 1;
+        std::fs::write(path, &bytes)?;
+        Ok(bytes)
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+        db.sync().await?;
+        drop(db);
+        let good_commits = std::fs::read(&commits)?;
+        let good_tree_log = std::fs::read(&tree_log)?;
+
+        // Records after a corrupt one aren't a torn tail
+        let corrupt = corrupt_first_record(&commits)?;
+        assert!(db::Db::open(config.clone()).await.is_err());
+        assert_eq!(std::fs::read(&commits)?, corrupt);
+        std::fs::write(&commits, &good_commits)?;
+
+        let corrupt = corrupt_first_record(&tree_log)?;
+        assert!(db::Db::open(config.clone()).await.is_err());
+        assert_eq!(std::fs::read(&tree_log)?, corrupt);
+
+        // A torn tree log tail that a commit refers to
+        let mut bytes = good_tree_log.clone();
+        let ready = (0..bytes.len()).rev()
+            .find(|&pos| bytes[pos..].starts_with(b"ReadyCommit"))
+            .expect("ready-commit");
+        if let Some(next) = find(&bytes, ready, b"[[frames]] # HEADER") {
+            bytes.truncate(next);
+        }
+        let len = bytes.len();
+        bytes[len - 4] ^= 1;
+        std::fs::write(&tree_log, &bytes)?;
+        assert!(db::Db::open(config.clone()).await.is_err());
+        assert_eq!(std::fs::read(&tree_log)?, bytes);
+
+        std::fs::write(&tree_log, &good_tree_log)?;
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        drop(view);
+        drop(db);
+
+        // A tree whose first record was torn before anything committed
+        let dir = temp_dir("corrupt-committed-empty");
+        let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+        drop(db::Db::open(config.clone()).await?);
+        std::fs::write(dir.join("t1.toml"), b"[[frames]] # HEA")?;
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        db.sync().await?;
+        drop(db);
+        let db = db::Db::open(config).await?;
+        assert_eq!(db.read_view().tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        drop(db);
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn torn_tree_log_tail_hides_uncommitted_batch() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use db::raw::types::{Batch, BatchCommit, Key, Value};
+
+    let dir = temp_dir("torn-tree-tail");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+    let tree_log = dir.join("t1.toml");
+
+    // Logs a batch that became ready but crashed before its commit record,
+    // then the start of another record.
+    let tree_log = &tree_log;
+    let append_orphan = |batch: Batch, torn: &'static [u8]| async move {
+        let fs_thread = std::sync::Arc::new(FsThread::start()?);
+        let log = Log::<Command>::new(simple_log_file::create(tree_log.clone(), fs_thread));
+        log.append(Command::Open { batch }).await?;
+        log.append(Command::Write {
+            batch,
+            key: Key::from_slice(b"orphan"),
+            value: Value::from_slice(b"v"),
+        }).await?;
+        log.append(Command::ReadyCommit { batch, batch_commit: BatchCommit(batch.0) }).await?;
+        log.flush().await?;
+        drop(log);
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(tree_log)?;
+        std::io::Write::write_all(&mut file, torn)?;
+        Ok::<_, anyhow::Error>(std::fs::metadata(tree_log)?.len())
+    };
+
+    block_on(async {
+        // Torn in the frame header, with nothing ever committed
+        let db = db::Db::open(config.clone()).await?;
+        drop(db);
+        let torn_len = append_orphan(Batch(0), b"[[frames]] # HEADER\n\nlength = 1").await?;
+
+        let db = db::Db::open(config.clone()).await?;
+        assert!(std::fs::metadata(tree_log)?.len() < torn_len);
+        assert_eq!(db.read_view().tree("t1")?.read(b"orphan").await?, None);
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"orphan").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        drop(view);
+        drop(db);
+
+        // Torn in the frame body, after a commit
+        let torn_len = append_orphan(Batch(10), b"[[frames]] # HEADER\n\nlength = 100\n# BODY\n\ntype = ").await?;
+
+        let db = db::Db::open(config.clone()).await?;
+        assert!(std::fs::metadata(tree_log)?.len() < torn_len);
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"orphan").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        drop(view);
+        commit_write(&db, "t1", b"k2", b"v2").await?;
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"orphan").await?, None);
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        drop(view);
+        drop(db);
+
+        // A tree whose first record was torn after commits were logged
+        // can't be told apart from one whose log was lost
+        std::fs::write(dir.join("t2.toml"), b"[[frames]] # HEA")?;
+        let config = db::DbConfig {
+            trees: vec!["t1".to_string(), "t2".to_string()],
+            ..config
+        };
+
+        assert!(db::Db::open(config.clone()).await.is_err());
+        assert_eq!(std::fs::read(dir.join("t2.toml"))?, b"[[frames]] # HEA");
+
+        std::fs::remove_file(dir.join("t2.toml"))?;
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t2", b"k3", b"v3").await?;
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k3").await?, Some(b"v3".to_vec()));
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn last_write_or_delete_in_batch_wins() -> Result<()> {
+    use db::raw::batch_player::{BatchPlayer, IndexOp};
+    use db::raw::command::Command;
+    use db::raw::types::{Address, Batch, BatchCommit, Key, Value};
+
+    let batch = Batch(0);
+    let key = |k: &[u8]| Key::from_slice(k);
+    let player = BatchPlayer::new();
+    let mut addr = 0;
+    let mut record = |cmd: Command| {
+        player.record(&cmd, Address(addr));
+        addr += 1;
+    };
+    record(Command::Open { batch });
+    record(Command::Write { batch, key: key(b"k1"), value: Value::from_slice(b"v1") });
+    record(Command::Delete { batch, key: key(b"k1") });
+    record(Command::Delete { batch, key: key(b"k2") });
+    record(Command::Write { batch, key: key(b"k2"), value: Value::from_slice(b"v2") });
+    record(Command::Write { batch, key: key(b"k3"), value: Value::from_slice(b"v3") });
+    record(Command::Delete { batch, key: key(b"k3") });
+    record(Command::Merge { batch, key: key(b"k3"), operand: Value::from_slice(b"m3") });
+    record(Command::Write { batch, key: key(b"k3"), value: Value::from_slice(b"v3") });
+    record(Command::ReadyCommit { batch, batch_commit: BatchCommit(0) });
+
+    assert_eq!(player.staged_ops(batch), 3);
+    let ops: Vec<_> = player.replay(batch, BatchCommit(0)).collect();
+    assert_eq!(ops, vec![
+        IndexOp::Delete { key: key(b"k1"), address: Address(2) },
+        IndexOp::Write { key: key(b"k2"), address: Address(4) },
+        IndexOp::Write { key: key(b"k3"), address: Address(8) },
+    ]);
+
+    // Write then delete, delete then write, and write, delete, write
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        commit_write(&db, "t1", b"k2", b"old").await?;
+
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+        tree.write(b"k1", b"v1").await?;
+        tree.delete(b"k1").await?;
+        tree.delete(b"k2").await?;
+        tree.write(b"k2", b"v2").await?;
+        tree.write(b"k3", b"v3a").await?;
+        tree.delete(b"k3").await?;
+        tree.write(b"k3", b"v3b").await?;
+        assert_eq!(tree.read(b"k1").await?, None);
+        assert_eq!(tree.read(b"k3").await?, Some(b"v3b".to_vec()));
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        let tree = view.tree("t1")?;
+        assert_eq!(tree.read(b"k1").await?, None);
+        assert_eq!(tree.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(tree.read(b"k3").await?, Some(b"v3b".to_vec()));
+        assert_eq!(tree.history(b"k2").await?.len(), 2);
+        assert_eq!(tree.count()?, 2);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn tail_to_binlog_resumes() -> Result<()> {
+    use db::{BinlogChange, BinlogRecord};
+    use futures::future::{self, Either};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Yields to the executor once.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    let dir = temp_dir("binlog");
+    let config = db::DbConfig::new(dir.join("db"), vec!["t1".to_string(), "t2".to_string()]);
+    let binlog = dir.join("changes.jsonl");
+
+    let read_binlog = |binlog: &std::path::Path| -> Result<Vec<BinlogRecord>> {
+        let text = std::fs::read_to_string(binlog)?;
+        Ok(text.lines().map(serde_json::from_str).collect::<Result<_, _>>()?)
+    };
+
+    // Tails until the binlog holds `records` records, committing with `commit` meanwhile
+    async fn tail_until<F: Future<Output = Result<()>>>(db: &db::Db, binlog: &std::path::Path, records: usize,
+                                                         commit: F,
+                                                         read_binlog: &impl Fn(&std::path::Path) -> Result<Vec<BinlogRecord>>) -> Result<()> {
+        let tail = Box::pin(db.tail_to(binlog));
+        let wait = Box::pin(async {
+            commit.await?;
+            while !binlog.exists() || read_binlog(binlog)?.len() < records {
+                YieldNow(false).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        match future::select(tail, wait).await {
+            Either::Left((r, _)) => panic!("tailing ended: {:?}", r),
+            Either::Right((r, _)) => r,
+        }
+    }
+
+    let write = |commit: u64, tree: &str, key: &[u8], value: &[u8]| BinlogRecord {
+        commit,
+        tree: tree.to_string(),
+        change: BinlogChange::Write { key: key.to_vec(), value: value.to_vec() },
+    };
+    let delete = |commit: u64, tree: &str, key: &[u8]| BinlogRecord {
+        commit,
+        tree: tree.to_string(),
+        change: BinlogChange::Delete { key: key.to_vec() },
+    };
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t2", b"k2", b"v2").await?;
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.write(b"k3", b"v3").await?;
+        batch.tree("t1")?.delete_range(b"k0", b"k2").await?;
+        batch.tree("t2")?.delete(b"k2").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        // Tails what was committed before it started, and what is committed after
+        tail_until(&db, &binlog, 6, commit_write(&db, "t1", b"k4", b"v4"), &read_binlog).await?;
+
+        // Commits while not tailing
+        commit_write(&db, "t2", b"k2", b"v2b").await?;
+        commit_write(&db, "t2", b"k2", b"v2c").await?;
+        drop(db);
+
+        // Written after the last recorded position, so discarded
+        let mut bytes = std::fs::read(&binlog)?;
+        bytes.extend_from_slice(b"{\"commit\": 99, \"tree\"");
+        std::fs::write(&binlog, &bytes)?;
+
+        let db = db::Db::open(config).await?;
+        tail_until(&db, &binlog, 9, commit_write(&db, "t1", b"k1", b"v1b"), &read_binlog).await?;
+
+        // Versions not yet tailed were collapsed away
+        commit_write(&db, "t2", b"k2", b"v2d").await?;
+        commit_write(&db, "t2", b"k2", b"v2e").await?;
+        db.collapse_range("t2", b"k2", b"k3")?;
+        assert!(db.tail_to(&binlog).await.is_err());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    assert_eq!(read_binlog(&binlog)?, vec![
+        write(0, "t1", b"k1", b"v1"),
+        write(1, "t2", b"k2", b"v2"),
+        BinlogRecord {
+            commit: 2,
+            tree: "t1".to_string(),
+            change: BinlogChange::DeleteRange { start_key: b"k0".to_vec(), end_key: b"k2".to_vec() },
+        },
+        write(2, "t1", b"k3", b"v3"),
+        delete(2, "t2", b"k2"),
+        write(3, "t1", b"k4", b"v4"),
+        write(4, "t2", b"k2", b"v2b"),
+        write(5, "t2", b"k2", b"v2c"),
+        write(6, "t1", b"k1", b"v1b"),
+    ]);
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn open_checkpoint_readonly_reads_compacted_copy() -> Result<()> {
+    let src_dir = temp_dir("checkpoint-src");
+    let checkpoint_dir = temp_dir("checkpoint");
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig::new(&src_dir, vec!["t1".to_string(), "t2".to_string()])).await?;
+        for i in 0..20 {
+            let value = format!("v{}", i);
+            commit_write(&db, "t1", b"k1", value.as_bytes()).await?;
+        }
+        commit_write(&db, "t2", b"k2", b"v2").await?;
+        db.sync().await?;
+        db.compact_to(&checkpoint_dir).await?;
+
+        // Not in the checkpoint
+        commit_write(&db, "t1", b"k1", b"later").await?;
+        drop(db);
+
+        let size = dir_size(&checkpoint_dir)?;
+        let view = db::Db::open_checkpoint_readonly(&checkpoint_dir).await?;
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v19".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        assert!(view.tree("commits").is_err());
+        drop(view);
+        assert_eq!(dir_size(&checkpoint_dir)?, size);
+
+        // Only the checkpoint's single commit is replayed
+        let (_db, summary) = db::Db::open_with_summary(db::DbConfig {
+            read_only: true,
+            ..db::DbConfig::new(&checkpoint_dir, vec!["t1".to_string(), "t2".to_string()])
+        }).await?;
+        assert_eq!(summary.batches_applied, 1);
+
+        // Not a database
+        assert!(db::Db::open_checkpoint_readonly(&src_dir.join("missing")).await.is_err());
+        std::fs::create_dir_all(src_dir.join("empty"))?;
+        assert!(db::Db::open_checkpoint_readonly(&src_dir.join("empty")).await.is_err());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&src_dir)?;
+    std::fs::remove_dir_all(&checkpoint_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn write_after_range_delete_is_visible() -> Result<()> {
+    let dir = temp_dir("write-after-range-delete");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        for i in 0..5 {
+            commit_write(&db, "t1", format!("k{}", i).as_bytes(), b"old").await?;
+        }
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.delete_range(b"k0", b"k9").await?;
+        batch.commit().await?;
+        batch.close().await;
+        assert_eq!(db.barrier().await?, Some(5));
+        let after_delete = db.read_view();
+
+        commit_write(&db, "t1", b"k2", b"new").await?;
+        assert_eq!(db.barrier().await?, Some(6));
+        commit_write(&db, "t1", b"x", b"other").await?;
+        assert_eq!(db.barrier().await?, Some(7));
+        let after_write = db.read_view();
+
+        // A range delete and a later write to the same key in one batch
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1")?;
+        tree.delete_range(b"k0", b"k9").await?;
+        tree.write(b"k3", b"same batch").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        async fn check(view: &db::ReadView) -> Result<()> {
+            let tree = view.tree("t1")?;
+            assert_eq!(tree.read(b"k1").await?, None);
+            assert_eq!(tree.read(b"k2").await?, None);
+            assert_eq!(tree.read(b"k3").await?, Some(b"same batch".to_vec()));
+            let mut keys = vec![];
+            let mut cursor = tree.cursor();
+            cursor.seek_first();
+            while cursor.valid() {
+                keys.push(cursor.key());
+                cursor.next();
+            }
+            assert_eq!(keys, vec![b"k3".to_vec(), b"x".to_vec()]);
+            Ok(())
+        }
+
+        assert_eq!(after_delete.tree("t1")?.read(b"k2").await?, None);
+        assert_eq!(after_delete.tree("t1")?.read(b"k3").await?, None);
+        drop(after_delete);
+        assert_eq!(after_write.tree("t1")?.read(b"k1").await?, None);
+        assert_eq!(after_write.tree("t1")?.read(b"k2").await?, Some(b"new".to_vec()));
+        drop(after_write);
+        check(&db.read_view()).await?;
+        db.sync().await?;
+        drop(db);
+
+        // Replay orders them the same way
+        let db = db::Db::open(config).await?;
+        check(&db.read_view()).await?;
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compressed_tree_round_trips() -> Result<()> {
+    use db::raw::command::Command;
+    use db::raw::fs_thread::FsThread;
+    use db::raw::log::Log;
+    use db::raw::simple_log_file;
+    use futures::TryStreamExt;
+
+    let dir = temp_dir("compressed-tree");
+    let config = db::DbConfig::new(&dir, vec!["t1".to_string()]);
+    let compressed = db::DbConfig {
+        compressed_trees: vec!["t1".to_string()],
+        ..config.clone()
+    };
+    let tree_log = dir.join("t1.toml");
+
+    let large = br#"{"name": "blocksy", "tags": ["a", "b", "c"]}"#.repeat(200);
+
+    block_on(async {
+        // Written before the tree was compressed
+        let db = db::Db::open(config).await?;
+        commit_write(&db, "t1", b"before", &large).await?;
+        db.sync().await?;
+        drop(db);
+        let uncompressed_size = std::fs::metadata(&tree_log)?.len();
+
+        let db = db::Db::open(compressed.clone()).await?;
+        commit_write(&db, "t1", b"large", &large).await?;
+        commit_write(&db, "t1", b"tiny", b"tiny").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.copy(b"large", b"copy").await?;
+        batch.commit().await?;
+        batch.close().await;
+        db.sync().await?;
+
+        assert!(std::fs::metadata(&tree_log)?.len() - uncompressed_size < large.len() as u64 / 4);
+
+        let large = &large;
+        let check = |db: db::Db| async move {
+            let view = db.read_view();
+            let tree = view.tree("t1")?;
+            assert_eq!(tree.read(b"before").await?, Some(large.clone()));
+            assert_eq!(tree.read(b"large").await?, Some(large.clone()));
+            assert_eq!(tree.read(b"tiny").await?, Some(b"tiny".to_vec()));
+            assert_eq!(tree.read(b"copy").await?, Some(large.clone()));
+            let mut cursor = tree.cursor();
+            cursor.seek_key(b"large");
+            assert_eq!(&cursor.value().await?, large);
+            Ok::<_, anyhow::Error>(())
+        };
+        check(db).await?;
+        check(db::Db::open(compressed).await?).await?;
+
+        // Only the large value written since is logged compressed
+        let fs_thread = std::sync::Arc::new(FsThread::start()?);
+        let log = Log::<Command>::new(simple_log_file::create(tree_log.clone(), fs_thread));
+        let cmds: Vec<(Command, _)> = log.replay().try_collect().await?;
+        let writes: Vec<_> = cmds.into_iter().filter_map(|(cmd, _)| match cmd {
+            Command::Write { key, .. } => Some((key.0, false)),
+            Command::WriteCompressed { key, .. } => Some((key.0, true)),
+            _ => None,
+        }).collect();
+        assert_eq!(writes, vec![
+            (b"before".to_vec(), false),
+            (b"large".to_vec(), true),
+            (b"tiny".to_vec(), false),
+        ]);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn max_inflight_commits_applies_backpressure() -> Result<()> {
+    use std::sync::Mutex;
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+    use std::time::Duration;
+
+    let db = block_on(db::Db::open(db::DbConfig {
+        max_inflight_commits: 1,
+        ..mem_config()
+    }))?;
+
+    // Holds a commit in progress until released
+    let (entered_tx, entered) = channel();
+    let (release, release_rx) = channel::<()>();
+    let entered_tx = Mutex::new(entered_tx);
+    let release_rx = Mutex::new(release_rx);
+    db.register_index_hook("t1", move |_| {
+        entered_tx.lock().expect("lock").send(()).expect("send");
+        release_rx.lock().expect("lock").recv().expect("recv");
+        vec![]
+    })?;
+
+    let timeout = Duration::from_secs(10);
+    let blocked = Duration::from_millis(200);
+
+    let held = {
+        let db = db.clone();
+        std::thread::spawn(move || block_on(commit_write(&db, "t1", b"k0", b"v")))
+    };
+    entered.recv_timeout(timeout)?;
+
+    // A commit to a tree without hooks waits for the slot
+    // before logging that it is ready
+    let (batch, log_bytes) = block_on(async {
+        let batch = db.write_batch().await?;
+        batch.tree("t2")?.write(b"k1", b"v").await?;
+        let log_bytes = db.tree_stats("t2").await?.log_bytes;
+        Ok::<_, anyhow::Error>((batch, log_bytes))
+    })?;
+    let (done_tx, done) = channel();
+    let waiting = std::thread::spawn(move || {
+        let r = block_on(async {
+            batch.commit().await?;
+            batch.close().await;
+            Ok::<_, anyhow::Error>(())
+        });
+        done_tx.send(()).expect("send");
+        r
+    });
+    assert_eq!(done.recv_timeout(blocked), Err(RecvTimeoutError::Timeout));
+    assert_eq!(block_on(db.tree_stats("t2"))?.log_bytes, log_bytes);
+
+    // Finishing the held commit lets it in
+    release.send(())?;
+    done.recv_timeout(timeout)?;
+    held.join().expect("join")?;
+    waiting.join().expect("join")?;
+
+    block_on(async {
+        assert_eq!(db.barrier().await?, Some(1));
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k0").await?, Some(b"v".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k1").await?, Some(b"v".to_vec()));
+        Ok::<_, anyhow::Error>(())
+    })
+}
+
+#[test]
+fn sync_cursor_yields_live_and_deleted_keys() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(mem_config()).await?;
+        for key in [&b"a"[..], b"b", b"c", b"d", b"e"] {
+            commit_write(&db, "t1", key, b"v1").await?;
+        }
+        commit_delete(&db, "t1", b"b").await?;
+        commit_delete(&db, "t1", b"z").await?;
+        commit_write(&db, "t1", b"c", b"v2").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.delete_range(b"d", b"e").await?;
+        batch.commit().await?;
+        batch.close().await;
+        commit_write(&db, "t2", b"other", b"v1").await?;
+
+        let view = db.read_view();
+
+        // Not in the view
+        commit_write(&db, "t1", b"f", b"v1").await?;
+        commit_delete(&db, "t1", b"a").await?;
+
+        let mut cursor = view.sync_cursor("t1")?;
+        let mut entries = vec![];
+        while let Some(entry) = cursor.next().await? {
+            entries.push(entry);
+        }
+        assert_eq!(entries, vec![
+            (b"a".to_vec(), Some(b"v1".to_vec())),
+            (b"b".to_vec(), None),
+            (b"c".to_vec(), Some(b"v2".to_vec())),
+            (b"d".to_vec(), None),
+            (b"e".to_vec(), Some(b"v1".to_vec())),
+            (b"z".to_vec(), None),
+        ]);
+        assert_eq!(cursor.next().await?, None);
+
+        assert!(view.sync_cursor("t3").is_err());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn merge_operator_per_tree() -> Result<()> {
+    use std::sync::Arc;
+
+    use db::MergeOperator;
+
+    #[derive(Debug)]
+    struct AddDecimal;
+
+    impl MergeOperator for AddDecimal {
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+            let parse = |bytes: &[u8]| -> Result<i64> {
+                Ok(std::str::from_utf8(bytes)?.parse()?)
+            };
+            let existing = existing.map(parse).transpose()?.unwrap_or(0);
+            Ok((existing + parse(operand)?).to_string().into_bytes())
+        }
+    }
+
+    let dir = temp_dir("merge-operator");
+    let mut config = db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: vec!["t1".to_string(), "t2".to_string()],
+        ..db::DbConfig::default()
+    };
+    config.merge_operators.insert("t1".to_string(), Arc::new(AddDecimal));
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k", b"10").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.merge(b"k", b"5").await?;
+        batch.tree("t1")?.merge(b"k", b"-3").await?;
+        batch.tree("t1")?.merge(b"fresh", b"4").await?;
+        batch.tree("t2")?.increment(b"count", 2).await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k").await?, Some(b"12".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"fresh").await?, Some(b"4".to_vec()));
+        assert_eq!(view.tree("t2")?.read_counter(b"count").await?, 2);
+
+        // A merge on top of a delete starts from nothing
+        commit_delete(&db, "t1", b"k").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1")?.merge(b"k", b"7").await?;
+        batch.commit().await?;
+        batch.close().await;
+        assert_eq!(db.read_view().tree("t1")?.read(b"k").await?, Some(b"7".to_vec()));
+
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config.clone()).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k").await?, Some(b"7".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"fresh").await?, Some(b"4".to_vec()));
+        assert_eq!(view.tree("t2")?.read_counter(b"count").await?, 2);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    let mut config = mem_config();
+    config.merge_operators.insert("t3".to_string(), Arc::new(AddDecimal));
+    assert!(config.validate().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn write_conflicts_are_detected() -> Result<()> {
+    block_on(async {
+        let config = db::DbConfig {
+            detect_write_conflicts: true,
+            ..mem_config()
+        };
+        let db = db::Db::open(config).await?;
+        commit_write(&db, "t1", b"k", b"v0").await?;
+
+        let first = db.write_batch().await?;
+        let second = db.write_batch().await?;
+        let third = db.write_batch().await?;
+        first.tree("t1")?.write(b"k", b"v1").await?;
+        second.tree("t1")?.write(b"k", b"v2").await?;
+        third.tree("t1")?.write(b"other", b"v3").await?;
+        first.commit().await?;
+
+        // The batch still reads what was committed when it was created
+        assert_eq!(third.tree("t1")?.read(b"k").await?, Some(b"v0".to_vec()));
+
+        let err = second.commit().await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::Conflict(tree)) if tree == "t1"));
+        third.commit().await?;
+        first.close().await;
+        second.close().await;
+        third.close().await;
+
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"other").await?, Some(b"v3".to_vec()));
+
+        // Deletes conflict too
+        let first = db.write_batch().await?;
+        let second = db.write_batch().await?;
+        first.tree("t1")?.delete(b"other").await?;
+        second.tree("t1")?.merge(b"other", b"x").await?;
+        first.commit().await?;
+        assert!(second.commit().await.is_err());
+        first.close().await;
+        second.close().await;
+
+        // Without detection the last commit wins
+        let db = db::Db::open(mem_config()).await?;
+        let first = db.write_batch().await?;
+        let second = db.write_batch().await?;
+        first.tree("t1")?.write(b"k", b"v1").await?;
+        second.tree("t1")?.write(b"k", b"v2").await?;
+        first.commit().await?;
+        second.commit().await?;
+        first.close().await;
+        second.close().await;
+        assert_eq!(db.read_view().tree("t1")?.read(b"k").await?, Some(b"v2".to_vec()));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn create_tree_at_runtime() -> Result<()> {
+    let dir = temp_dir("create-tree");
+    let config = db::DbConfig::new(dir.clone(), vec!["t1".to_string(), "t2".to_string()]);
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+
+        let old_view = db.read_view();
+        let old_batch = db.write_batch().await?;
+        old_batch.tree("t1")?.write(b"k2", b"v2").await?;
+
+        db.create_tree("t3").await?;
+        assert_eq!(db.tree_names(), ["t1", "t2", "t3"]);
+        assert!(db.has_tree("t3"));
+        assert!(db.create_tree("t3").await.is_err());
+        assert!(db.create_tree("commits").await.is_err());
+        assert!(db.create_tree("").await.is_err());
+
+        // Made before the tree existed
+        assert!(old_view.tree("t3").is_err());
+        assert!(old_batch.tree("t3").is_err());
+        old_batch.commit().await?;
+        old_batch.close().await;
+
+        commit_write(&db, "t3", b"k3", b"v3").await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t3")?.read(b"k3").await?, Some(b"v3".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+
+        db.sync().await?;
+        drop(view);
+        drop(old_view);
+        drop(db);
+
+        let config = db::DbConfig::new(dir.clone(), vec!["t1".to_string(), "t2".to_string(), "t3".to_string()]);
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t3")?.read(b"k3").await?, Some(b"v3".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        assert_eq!(view.tree("t1")?.read(b"k2").await?, Some(b"v2".to_vec()));
+
+        drop(view);
+        drop(db);
+
+        // Unlisted, but its log is still there
+        let db = db::Db::open(db::DbConfig::new(dir.clone(), vec!["t1".to_string()])).await?;
+        assert!(db.create_tree("t3").await.is_err());
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    let db = block_on(db::Db::open(mem_config()))?;
+    block_on(async {
+        db.create_tree("t3").await?;
+        commit_write(&db, "t3", b"k", b"v").await?;
+        assert_eq!(db.read_view().tree("t3")?.read(b"k").await?, Some(b"v".to_vec()));
+        Ok(())
+    })
+}
+
+#[test]
+fn drop_tree_deletes_its_log() -> Result<()> {
+    let dir = temp_dir("drop-tree");
+    let config = db::DbConfig::new(dir.clone(), vec!["t1".to_string(), "t2".to_string()]);
+
+    block_on(async {
+        let db = db::Db::open(config.clone()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        commit_write(&db, "t2", b"k2", b"v2").await?;
+        db.set_tree_metadata("t2", "owner", "test").await?;
+        db.sync().await?;
+        assert!(dir.join("t2.toml").exists());
+
+        // Handles that reach the tree keep it, and its log
+        let view = db.read_view();
+        let batch = db.write_batch().await?;
+        let stale_view = db.read_view_stale(std::time::Duration::from_millis(5));
+        drop(stale_view);
+        db.drop_tree("t2").await?;
+        assert!(db.drop_tree("t2").await.is_err());
+        assert!(db.drop_tree("t3").await.is_err());
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, Some(b"v2".to_vec()));
+        batch.tree("t2")?.write(b"k3", b"v3").await?;
+        assert!(batch.commit().await.is_err());
+        batch.close().await;
+        drop(view);
+        db.sync().await?;
+        assert!(dir.join("t2.toml").exists());
+
+        // The shared stale view keeps it until it expires
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        db.collapse_range("t1", b"", b"z")?;
+        db.sync().await?;
+        assert!(!dir.join("t2.toml").exists());
+
+        assert_eq!(db.tree_names(), ["t1"]);
+        assert!(!db.has_tree("t2"));
+        let err = db.read_view().tree("t2").err().expect("dropped tree");
+        assert!(matches!(err.downcast_ref::<db::DbError>(), Some(db::DbError::UnknownTree(tree)) if tree == "t2"));
+        let batch = db.write_batch().await?;
+        assert!(batch.tree("t2").is_err());
+        batch.close().await;
+
+        // Other trees carry on
+        commit_write(&db, "t1", b"k3", b"v3").await?;
+        assert_eq!(db.read_view().tree("t1")?.read(b"k1").await?, Some(b"v1".to_vec()));
+        db.sync().await?;
+        drop(db);
+
+        // Listed again it starts empty
+        let db = db::Db::open(config).await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1")?.read(b"k3").await?, Some(b"v3".to_vec()));
+        assert_eq!(view.tree("t2")?.read(b"k2").await?, None);
+        assert_eq!(db.get_tree_metadata("t2", "owner")?, None);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn compaction_policy_compacts_in_background() -> Result<()> {
+    use std::time::Duration;
+
+    block_on(async {
+        assert!(db::Db::open(db::DbConfig {
+            compaction_policy: Some(db::CompactionPolicy {
+                interval: Duration::ZERO,
+                ..db::CompactionPolicy::default()
+            }),
+            ..mem_config()
+        }).await.is_err());
+
+        // Compacted once overwrites leave few keys per version
+        let db = db::Db::open(db::DbConfig {
+            compaction_policy: Some(db::CompactionPolicy {
+                interval: Duration::from_millis(10),
+                min_live_ratio: 0.5,
+                log_growth_bytes: 0,
+            }),
+            ..mem_config()
+        }).await?;
+        for i in 0..10 {
+            commit_write(&db, "t1", b"k1", format!("v{}", i).as_bytes()).await?;
+        }
+        let start = std::time::Instant::now();
+        while db.read_view().tree("t1")?.history(b"k1").await?.len() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let stats = db.compaction_stats();
+        assert!(stats.compactions >= 1);
+        assert!(stats.checks >= stats.compactions);
+        assert_eq!(stats.versions_discarded, 9);
+        assert_eq!(db.read_view().tree("t1")?.read(b"k1").await?, Some(b"v9".to_vec()));
+        drop(db);
+
+        // Compacted once the log grows
+        let db = db::Db::open(db::DbConfig {
+            compaction_policy: Some(db::CompactionPolicy {
+                interval: Duration::from_millis(10),
+                min_live_ratio: 0.0,
+                log_growth_bytes: 1,
+            }),
+            ..mem_config()
+        }).await?;
+        commit_write(&db, "t2", b"k1", b"v1").await?;
+        let start = std::time::Instant::now();
+        while db.compaction_stats().compactions == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Only keys with history are collapsed
+        assert_eq!(db.compaction_stats().keys, 0);
+        drop(db);
+
+        // Not compacted again while a view pins the history
+        let db = db::Db::open(db::DbConfig {
+            compaction_policy: Some(db::CompactionPolicy {
+                interval: Duration::from_millis(1),
+                min_live_ratio: 0.5,
+                log_growth_bytes: 0,
+            }),
+            ..mem_config()
+        }).await?;
+        commit_write(&db, "t1", b"k1", b"v0").await?;
+        let view = db.read_view();
+        for i in 1..10 {
+            commit_write(&db, "t1", b"k1", format!("v{}", i).as_bytes()).await?;
+        }
+        let start = std::time::Instant::now();
+        while db.compaction_stats().compactions == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let stats = db.compaction_stats();
+        while db.compaction_stats().checks < stats.checks + 5 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(db.compaction_stats().compactions, stats.compactions);
+        assert_eq!(view.tree("t1")?.read(b"k1").await?, Some(b"v0".to_vec()));
+        drop(view);
+        while db.compaction_stats().versions_discarded == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // No policy, no checks
+        let db = db::Db::open(mem_config()).await?;
+        commit_write(&db, "t1", b"k1", b"v1").await?;
+        assert_eq!(db.compaction_stats().checks, 0);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    Ok(())
+}
+
+#[test]
+fn tree_stats_estimate_dead_bytes() -> Result<()> {
+    let src_dir = temp_dir("tree-stats-src");
+    let dest_dir = temp_dir("tree-stats-dest");
+
+    block_on(async {
+        let config = db::DbConfig::new(&src_dir, vec!["t1".to_string(), "t2".to_string()]);
+        let db = db::Db::open(config.clone()).await?;
+
+        let stats = db.tree_stats("t1").await?;
+        assert_eq!(stats.log_bytes, 0);
+        assert_eq!(stats.dead_bytes, 0);
+
+        for round in 0..5 {
+            for i in 0..10 {
+                let key = format!("k{}", i);
+                let value = format!("v{}", round);
+                commit_write(&db, "t1", key.as_bytes(), value.as_bytes()).await?;
+            }
+        }
+        commit_delete(&db, "t1", b"k9").await?;
+
+        let stats = db.tree_stats("t1").await?;
+        assert!(stats.log_bytes > 0);
+        assert_eq!(stats.change_records, 51);
+        assert_eq!(stats.live_keys, 9);
+        assert!(stats.dead_bytes > 0);
+        assert!(stats.dead_bytes < stats.log_bytes);
+
+        // Reclaiming memory leaves the log as it was
+        db.collapse_range("t1", b"", b"z")?;
+        assert_eq!(db.tree_stats("t1").await?.dead_bytes, stats.dead_bytes);
+        assert!(db.tree_stats("t3").await.is_err());
+
+        db.compact_to(&dest_dir).await?;
+        drop(db);
+
+        // Counted again when the log is replayed
+        let db = db::Db::open(config).await?;
+        assert_eq!(db.tree_stats("t1").await?.change_records, 51);
+        drop(db);
+
+        let db = db::Db::open(db::DbConfig::new(&dest_dir, vec!["t1".to_string(), "t2".to_string()])).await?;
+        let stats = db.tree_stats("t1").await?;
+        assert!(stats.log_bytes > 0);
+        assert_eq!(stats.change_records, 9);
+        assert_eq!(stats.live_keys, 9);
+        assert_eq!(stats.dead_bytes, 0);
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    std::fs::remove_dir_all(&src_dir)?;
+    std::fs::remove_dir_all(&dest_dir)?;
+
+    Ok(())
+}